@@ -0,0 +1,67 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use ipfs_sqlite_block_store::{
+    workload::{apply, churn, random_dag, unixfs_file, Rng},
+    Block, BlockStore, Config, SizeTargets,
+};
+
+fn put(c: &mut Criterion) {
+    let mut group = c.benchmark_group("put");
+    group.bench_function("unixfs_file_16mb", |b| {
+        b.iter_batched(
+            || {
+                let store = BlockStore::memory(Config::default()).unwrap();
+                let (_, blocks) = unixfs_file(&mut Rng::new(0), 1 << 24, 262144, 174);
+                (store, blocks)
+            },
+            |(mut store, blocks)| store.put_blocks(blocks, None).unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("random_dag_10000", |b| {
+        b.iter_batched(
+            || {
+                let store = BlockStore::memory(Config::default()).unwrap();
+                let (_, blocks) = random_dag(&mut Rng::new(0), 10000, 3, 1024);
+                (store, blocks)
+            },
+            |(mut store, blocks)| store.put_blocks(blocks, None).unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn get(c: &mut Criterion) {
+    let mut store = BlockStore::memory(Config::default()).unwrap();
+    let (_, blocks) = random_dag(&mut Rng::new(0), 10000, 3, 1024);
+    let cids = blocks.iter().map(|block| *block.cid()).collect::<Vec<_>>();
+    store.put_blocks(blocks, None).unwrap();
+    let mut rng = Rng::new(1);
+    c.bench_function("get_block", |b| {
+        b.iter(|| store.get_block(&cids[rng.below(cids.len())]).unwrap())
+    });
+    c.bench_function("get_blocks_100", |b| {
+        b.iter(|| {
+            let batch = (0..100).map(|_| cids[rng.below(cids.len())]);
+            store.get_blocks(batch).unwrap().count()
+        })
+    });
+}
+
+fn gc(c: &mut Criterion) {
+    c.bench_function("gc_after_churn", |b| {
+        b.iter_batched(
+            || {
+                let config = Config::default().with_size_targets(SizeTargets::max_value());
+                let mut store = BlockStore::memory(config).unwrap();
+                apply(&mut store, churn(&mut Rng::new(0), 20, 1000, 5, 10, 0)).unwrap();
+                store
+            },
+            |mut store| store.gc_bytes(u64::max_value()).unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(benches, put, get, gc);
+criterion_main!(benches);
@@ -1,24 +1,41 @@
-use crate::{Block, BlockStore, StoreStats, TempPin};
-use futures::channel::oneshot;
+use crate::{
+    hooks::StoreEvent, reader::BlockReader, AliasChange, AliasStats, ApproxStats, Batch, Block,
+    BlockStore, BlockStoreError, Capabilities, CheckpointMode, CheckpointStats, DagDiff,
+    ExportReport, GcStats, GraphStats, MaintenanceStats, Manifest, OrphanStats, PinReason,
+    PutStats, ReverseAlias, SnapshotDiff, StoreStats, TempPin, TempPinStats, Throttle,
+};
+use futures::channel::{mpsc, oneshot};
 use futures::future::BoxFuture;
 use futures::prelude::*;
 use libipld::Cid;
 use std::{
     iter::FromIterator,
+    ops::RangeBounds,
+    path::PathBuf,
     sync::{Arc, Mutex},
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
 use tracing::*;
 
 #[derive(Clone)]
 pub struct AsyncBlockStore<R> {
     inner: Option<Arc<Mutex<Inner>>>,
+    /// see [BlockStore::reader]
+    reader: Option<BlockReader>,
+    /// see [Config::with_throttle](crate::Config::with_throttle)
+    throttle: Throttle,
+    /// see [Config::with_gc_slice](crate::Config::with_gc_slice)
+    gc_slice: Option<Duration>,
+    /// see [Config::with_adaptive_gc](crate::Config::with_adaptive_gc)
+    adaptive_gc: Option<AdaptiveBudget>,
     runtime: R,
 }
 
 struct Inner {
     store: BlockStore,
     complete: oneshot::Sender<()>,
+    /// maximum wait for the store of a call other than gc since the last gc step
+    wait: Duration,
 }
 
 impl<R> Drop for AsyncBlockStore<R> {
@@ -72,7 +89,15 @@ impl<R: RuntimeAdapter> AsyncBlockStore<R> {
         (
             Self {
                 runtime,
-                inner: Some(Arc::new(Mutex::new(Inner { store, complete }))),
+                reader: store.reader(),
+                throttle: store.config.throttle,
+                gc_slice: store.config.gc_slice,
+                adaptive_gc: store.config.adaptive_gc,
+                inner: Some(Arc::new(Mutex::new(Inner {
+                    store,
+                    complete,
+                    wait: Duration::default(),
+                }))),
             },
             receiver.map(|_| ()).boxed(),
         )
@@ -82,10 +107,61 @@ impl<R: RuntimeAdapter> AsyncBlockStore<R> {
         self.unblock(|store| Ok(AsyncTempPin::new(store.temp_pin())))
     }
 
+    pub fn extend_temp_pin(&self, pin: &AsyncTempPin, cids: Vec<Cid>) -> AsyncResult<()> {
+        let pin = pin.clone();
+        self.unblock(move |store| store.extend_temp_pin(&pin.0, cids))
+    }
+
     pub fn alias(&self, name: Vec<u8>, link: Option<Cid>) -> AsyncResult<()> {
         self.unblock(move |store| store.alias(&name, link.as_ref()))
     }
 
+    pub fn aliases(&self) -> AsyncResult<Vec<(Vec<u8>, Cid)>> {
+        self.unblock(|store| store.aliases())
+    }
+
+    pub fn aliases_in_range(
+        &self,
+        range: impl RangeBounds<Vec<u8>> + Send + 'static,
+    ) -> AsyncResult<Vec<(Vec<u8>, Cid)>> {
+        self.unblock(move |store| store.aliases_in_range(range))
+    }
+
+    pub fn aliases_with_prefix(&self, prefix: Vec<u8>) -> AsyncResult<Vec<(Vec<u8>, Cid)>> {
+        self.unblock(move |store| store.aliases_with_prefix(prefix))
+    }
+
+    pub fn resolve(&self, name: Vec<u8>) -> AsyncResult<Option<Cid>> {
+        self.unblock(move |store| store.resolve(name))
+    }
+
+    pub fn alias_direct(&self, name: Vec<u8>, link: Option<Cid>) -> AsyncResult<()> {
+        self.unblock(move |store| store.alias_direct(&name, link.as_ref()))
+    }
+
+    pub fn protect(&self, cids: Vec<Cid>) -> AsyncResult<()> {
+        self.unblock(move |store| store.protect(cids))
+    }
+
+    pub fn unprotect(&self, cids: Vec<Cid>) -> AsyncResult<()> {
+        self.unblock(move |store| store.unprotect(cids))
+    }
+
+    pub fn protected_cids(&self) -> AsyncResult<Vec<Cid>> {
+        self.unblock(|store| store.protected_cids())
+    }
+
+    /// Convert a temp pin into a permanent alias. This affects all clones of the temp pin.
+    pub fn assign_temp_pin_to_alias(
+        &self,
+        pin: &AsyncTempPin,
+        name: Vec<u8>,
+        root: Cid,
+    ) -> AsyncResult<()> {
+        let pin = pin.clone();
+        self.unblock(move |store| store.assign_temp_pin_to_alias(&pin.0, name, &root))
+    }
+
     pub fn alias_many(
         &self,
         aliases: impl IntoIterator<Item = (impl AsRef<[u8]>, Option<Cid>)> + Send + 'static,
@@ -93,40 +169,164 @@ impl<R: RuntimeAdapter> AsyncBlockStore<R> {
         self.unblock(move |store| store.alias_many(aliases))
     }
 
-    pub fn gc(&self) -> AsyncResult<()> {
-        self.unblock(|store| store.gc())
+    pub fn pause_gc(&self) -> AsyncResult<()> {
+        self.unblock(|store| {
+            store.pause_gc();
+            Ok(())
+        })
+    }
+
+    pub fn resume_gc(&self) -> AsyncResult<()> {
+        self.unblock(|store| {
+            store.resume_gc();
+            Ok(())
+        })
+    }
+
+    pub fn is_gc_paused(&self) -> AsyncResult<bool> {
+        self.unblock(|store| Ok(store.is_gc_paused()))
+    }
+
+    pub fn set_read_only(&self, read_only: bool) -> AsyncResult<()> {
+        self.unblock(move |store| store.set_read_only(read_only))
+    }
+
+    pub fn is_read_only(&self) -> AsyncResult<bool> {
+        self.unblock(|store| Ok(store.is_read_only()))
+    }
+
+    pub fn maintenance(&self) -> AsyncResult<()> {
+        self.unblock(|store| store.maintenance())
+    }
+
+    pub fn serialize(&self) -> AsyncResult<Vec<u8>> {
+        self.unblock(|store| store.serialize())
+    }
+
+    pub fn diff_snapshot(&self, path: PathBuf) -> AsyncResult<SnapshotDiff> {
+        self.unblock(move |store| store.diff_snapshot(&path))
+    }
+
+    pub fn backup_to_writer(
+        &self,
+        writer: impl std::io::Write + Send + 'static,
+    ) -> AsyncResult<u64> {
+        self.unblock(move |store| store.backup_to_writer(writer))
+    }
+
+    pub fn integrity_check(&self) -> AsyncResult<()> {
+        self.unblock(|store| store.integrity_check())
+    }
+
+    pub fn has_changed(&self) -> AsyncResult<bool> {
+        self.unblock(|store| store.has_changed())
+    }
+
+    pub fn subscribe(&self) -> AsyncResult<mpsc::UnboundedReceiver<StoreEvent>> {
+        self.unblock(|store| Ok(store.subscribe()))
+    }
+
+    pub fn commit_seq(&self) -> AsyncResult<u64> {
+        self.unblock(|store| store.commit_seq())
+    }
+
+    /// Perform a full gc, see [BlockStore::gc]
+    ///
+    /// Gc is done in chunks, with pauses according to the configured [Throttle] in between.
+    pub async fn gc(&self) -> crate::Result<()> {
+        while !self.unblock_throttled(BlockStore::gc_chunk).await? {}
+        Ok(())
     }
 
     pub async fn incremental_gc(
         &self,
         min_blocks: usize,
         max_duration: Duration,
-    ) -> crate::Result<bool> {
-        self.unblock(move |store| store.incremental_gc(min_blocks, max_duration))
+    ) -> crate::Result<GcStats> {
+        self.unblock_throttled(move |store| store.incremental_gc(min_blocks, max_duration))
             .await
     }
 
-    pub fn incremental_delete_orphaned(
+    pub fn gc_bytes(&self, bytes: u64) -> AsyncResult<u64> {
+        self.unblock(move |store| store.gc_bytes(bytes))
+    }
+
+    pub fn repair_refcounts(&self) -> AsyncResult<()> {
+        self.unblock(|store| store.repair_refcounts())
+    }
+
+    pub async fn incremental_delete_orphaned(
         &self,
         min_blocks: usize,
         max_duration: Duration,
-    ) -> BoxFuture<'static, crate::Result<bool>> {
-        self.unblock(move |store| store.incremental_delete_orphaned(min_blocks, max_duration))
+    ) -> crate::Result<bool> {
+        self.unblock_throttled(move |store| {
+            store.incremental_delete_orphaned(min_blocks, max_duration)
+        })
+        .await
+    }
+
+    pub fn delete_orphaned(
+        &self,
+        min_blocks: usize,
+        max_duration: Duration,
+    ) -> AsyncResult<OrphanStats> {
+        self.unblock(move |store| store.delete_orphaned(min_blocks, max_duration))
+    }
+
+    pub fn delete_blocks(&self, cids: Vec<Cid>) -> AsyncResult<u64> {
+        self.unblock(move |store| store.delete_blocks(cids))
+    }
+
+    pub fn delete_orphaned_cids(&self) -> AsyncResult<u64> {
+        self.unblock(|store| store.delete_orphaned_cids())
+    }
+
+    pub fn checkpoint(&self, mode: CheckpointMode) -> AsyncResult<CheckpointStats> {
+        self.unblock(move |store| store.checkpoint(mode))
+    }
+
+    pub fn flush(&self) -> AsyncResult<()> {
+        self.unblock(|store| store.flush())
+    }
+
+    pub fn heartbeat(&self) -> AsyncResult<()> {
+        self.unblock(|store| store.heartbeat())
+    }
+
+    pub async fn idle_maintenance(&self, budget: Duration) -> crate::Result<MaintenanceStats> {
+        self.unblock_throttled(move |store| store.idle_maintenance(budget))
+            .await
+    }
+
+    pub fn orphan_count(&self) -> AsyncResult<u64> {
+        self.unblock(|store| store.orphan_count())
     }
 
     pub fn get_block(&self, cid: Cid) -> AsyncResult<Option<Vec<u8>>> {
-        self.unblock(move |store| store.get_block(&cid))
+        match self.reader.clone() {
+            Some(reader) => self.unblock_read(move || reader.get_block(&cid)),
+            None => self.unblock(move |store| store.get_block(&cid)),
+        }
     }
 
     pub fn get_blocks<I: IntoIterator<Item = Cid> + Send + 'static>(
         &self,
         cids: I,
     ) -> AsyncResult<impl Iterator<Item = (Cid, Option<Vec<u8>>)>> {
-        self.unblock(move |store| store.get_blocks(cids))
+        match self.reader.clone() {
+            Some(reader) => self.unblock_read(move || Ok(reader.get_blocks(cids)?.collect())),
+            None => self.unblock(move |store| Ok(store.get_blocks(cids)?.collect::<Vec<_>>())),
+        }
+        .map_ok(Vec::into_iter)
+        .boxed()
     }
 
     pub fn has_block(&self, cid: Cid) -> AsyncResult<bool> {
-        self.unblock(move |store| store.has_block(&cid))
+        match self.reader.clone() {
+            Some(reader) => self.unblock_read(move || reader.has_block(&cid)),
+            None => self.unblock(move |store| store.has_block(&cid)),
+        }
     }
 
     pub fn has_blocks<I, O>(&self, cids: I) -> AsyncResult<O>
@@ -148,17 +348,137 @@ impl<R: RuntimeAdapter> AsyncBlockStore<R> {
         self.unblock(move |store| store.get_missing_blocks(&cid))
     }
 
+    pub fn get_missing_blocks_limited<C: FromIterator<Cid> + Send + 'static>(
+        &self,
+        cid: Cid,
+        limit: usize,
+        max_depth: Option<u32>,
+    ) -> AsyncResult<C> {
+        self.unblock(move |store| store.get_missing_blocks_limited(&cid, limit, max_depth))
+    }
+
+    pub fn capabilities(&self) -> AsyncResult<Capabilities> {
+        self.unblock(|store| store.capabilities())
+    }
+
+    pub fn is_complete(&self, root: Cid) -> AsyncResult<bool> {
+        self.unblock(move |store| store.is_complete(&root))
+    }
+
+    pub fn is_pinned(&self, cid: Cid) -> AsyncResult<bool> {
+        self.unblock(move |store| store.is_pinned(&cid))
+    }
+
+    pub fn have_status(&self, cid: Cid) -> AsyncResult<Vec<(Cid, bool)>> {
+        self.unblock(move |store| store.have_status(&cid))
+    }
+
     pub fn get_descendants<C: FromIterator<Cid> + Send + 'static>(
         &self,
         cid: Cid,
     ) -> AsyncResult<C> {
-        self.unblock(move |store| store.get_descendants(&cid))
+        match self.reader.clone() {
+            Some(reader) => self.unblock_read(move || reader.get_descendants(&cid)),
+            None => self.unblock(move |store| store.get_descendants(&cid)),
+        }
+    }
+
+    pub fn get_parents(&self, cid: Cid) -> AsyncResult<Vec<Cid>> {
+        self.unblock(move |store| store.get_parents(&cid))
+    }
+
+    pub fn touch(&self, cids: Vec<Cid>) -> AsyncResult<()> {
+        self.unblock(move |store| store.touch(&cids))
+    }
+
+    pub fn manifest(&self, root: Cid) -> AsyncResult<Manifest> {
+        self.unblock(move |store| store.manifest(&root))
     }
 
-    pub fn reverse_alias(&self, cid: Cid) -> AsyncResult<Vec<Vec<u8>>> {
+    pub fn dag_diff(&self, old_root: Cid, new_root: Cid) -> AsyncResult<DagDiff> {
+        self.unblock(move |store| store.dag_diff(&old_root, &new_root))
+    }
+
+    pub fn dag_size(&self, root: Cid) -> AsyncResult<StoreStats> {
+        self.unblock(move |store| store.dag_size(&root))
+    }
+
+    pub fn get_ancestors(&self, cid: Cid) -> AsyncResult<Vec<Cid>> {
+        self.unblock(move |store| store.get_ancestors(&cid))
+    }
+
+    pub fn get_descendants_limited<C: FromIterator<Cid> + Send + 'static>(
+        &self,
+        cid: Cid,
+        max_depth: u32,
+    ) -> AsyncResult<C> {
+        self.unblock(move |store| store.get_descendants_limited(&cid, max_depth))
+    }
+
+    pub fn reverse_alias(&self, cid: Cid) -> AsyncResult<ReverseAlias> {
         self.unblock(move |store| store.reverse_alias(&cid))
     }
 
+    pub fn why_pinned(&self, cid: Cid) -> AsyncResult<Vec<PinReason>> {
+        self.unblock(move |store| store.why_pinned(&cid))
+    }
+
+    pub fn incomplete_pins(&self) -> AsyncResult<Vec<(Vec<u8>, Cid, u64)>> {
+        self.unblock(move |store| store.incomplete_pins())
+    }
+
+    pub fn dead_pins(&self) -> AsyncResult<Vec<(Vec<u8>, Cid)>> {
+        self.unblock(|store| store.dead_pins())
+    }
+
+    pub fn add_provider_hints(&self, cid: Cid, peers: Vec<Vec<u8>>) -> AsyncResult<()> {
+        self.unblock(move |store| store.add_provider_hints(&cid, peers))
+    }
+
+    pub fn delete_provider_hints(&self, cid: Cid) -> AsyncResult<usize> {
+        self.unblock(move |store| store.delete_provider_hints(&cid))
+    }
+
+    pub fn get_provider_hints(&self, cid: Cid) -> AsyncResult<Vec<Vec<u8>>> {
+        self.unblock(move |store| store.get_provider_hints(&cid))
+    }
+
+    pub fn get_metadata(&self, cid: Cid, key: String) -> AsyncResult<Option<Vec<u8>>> {
+        self.unblock(move |store| store.get_metadata(&cid, &key))
+    }
+
+    pub fn get_original_cid(&self, cid: Cid) -> AsyncResult<Cid> {
+        self.unblock(move |store| store.get_original_cid(&cid))
+    }
+
+    pub fn get_evictions(&self) -> AsyncResult<Vec<(Cid, SystemTime)>> {
+        self.unblock(|store| store.get_evictions())
+    }
+
+    pub fn get_trash(&self) -> AsyncResult<Vec<(Cid, SystemTime)>> {
+        self.unblock(|store| store.get_trash())
+    }
+
+    pub fn undelete(&self, root: Cid, alias: Option<&AsyncTempPin>) -> AsyncResult<u64> {
+        let alias = alias.cloned();
+        self.unblock(move |store| {
+            let alias = alias.as_ref().map(|x| x.0.as_ref());
+            store.undelete(&root, alias)
+        })
+    }
+
+    pub fn get_alias_history(&self) -> AsyncResult<Vec<AliasChange>> {
+        self.unblock(|store| store.get_alias_history())
+    }
+
+    pub fn put_stats(&self) -> AsyncResult<PutStats> {
+        self.unblock(|store| Ok(store.put_stats()))
+    }
+
+    pub fn temp_pin_stats(&self) -> AsyncResult<TempPinStats> {
+        self.unblock(|store| store.temp_pin_stats())
+    }
+
     pub fn get_known_cids<C: FromIterator<Cid> + Send + 'static>(&self) -> AsyncResult<C> {
         self.unblock(move |store| store.get_known_cids())
     }
@@ -171,6 +491,18 @@ impl<R: RuntimeAdapter> AsyncBlockStore<R> {
         self.unblock(move |store| store.get_store_stats())
     }
 
+    pub fn graph_stats(&self) -> AsyncResult<GraphStats> {
+        self.unblock(move |store| store.graph_stats())
+    }
+
+    pub fn alias_stats(&self) -> AsyncResult<AliasStats> {
+        self.unblock(|store| store.alias_stats())
+    }
+
+    pub fn approx_stats(&self) -> AsyncResult<ApproxStats> {
+        self.unblock(move |store| store.approx_stats())
+    }
+
     pub fn put_blocks<B: Block + Send + 'static>(
         &self,
         blocks: impl IntoIterator<Item = B> + Send + 'static,
@@ -196,38 +528,195 @@ impl<R: RuntimeAdapter> AsyncBlockStore<R> {
         })
     }
 
+    pub fn write_batch(&self, batch: Batch, pin: Option<&AsyncTempPin>) -> AsyncResult<()> {
+        let pin = pin.cloned();
+        self.unblock(move |store| {
+            let pin = pin.as_ref().map(|x| x.0.as_ref());
+            store.write_batch(batch, pin)
+        })
+    }
+
+    pub fn import_car(
+        &self,
+        reader: impl std::io::Read + Send + 'static,
+        alias: Option<&AsyncTempPin>,
+    ) -> AsyncResult<Vec<Cid>> {
+        let alias = alias.cloned();
+        self.unblock(move |store| {
+            let alias = alias.as_ref().map(|x| x.0.as_ref());
+            store.import_car(reader, alias)
+        })
+    }
+
+    pub fn export_car(
+        &self,
+        root: Cid,
+        writer: impl std::io::Write + Send + 'static,
+    ) -> AsyncResult<u64> {
+        self.unblock(move |store| store.export_car(&root, writer))
+    }
+
+    pub fn export_car_lenient(
+        &self,
+        root: Cid,
+        writer: impl std::io::Write + Send + 'static,
+    ) -> AsyncResult<ExportReport> {
+        self.unblock(move |store| store.export_car_lenient(&root, writer))
+    }
+
+    pub fn get_leaves_stream(
+        &self,
+        root: Cid,
+        f: impl FnMut(Cid, Vec<u8>) -> bool + Send + 'static,
+    ) -> AsyncResult<u64> {
+        self.unblock(move |store| {
+            let mut f = f;
+            let mut count = 0;
+            for leaf in store.get_leaves_stream(&root) {
+                let (cid, data) = leaf?;
+                count += 1;
+                if !f(cid, data) {
+                    break;
+                }
+            }
+            Ok(count)
+        })
+    }
+
     /// A gc loop that runs incremental gc in regular intervals
     ///
     /// Gc will run as long as this future is polled. GC is a two step process. First, the
     /// metadata of expendable non-pinned blocks will be deleted, then the actual data will
     /// be removed. This will run the first step and the second step interleaved to minimize
     /// gc interruptions.
+    ///
+    /// The pause after each step is at least `interval / 2`, but will be extended according to
+    /// the [Throttle] of the store if a step took long, see
+    /// [Config::with_throttle](crate::Config::with_throttle).
+    ///
+    /// If the store has an [adaptive budget](crate::Config::with_adaptive_gc), the duration of
+    /// each gc transaction is adjusted to how long other calls had to wait for the store.
     pub async fn gc_loop(self, config: GcConfig) -> crate::Result<()> {
+        let mut budget = config.initial_budget(self.gc_slice, self.adaptive_gc.as_ref());
         // initial delay so we don't start gc directly on startup
         self.runtime.sleep(config.interval / 2).await;
         // stop the loop as soon as we are the only thing left running
         while self.ref_count() > 1 {
-            debug!("gc_loop running incremental gc");
-            self.incremental_gc(config.min_blocks, config.target_duration)
-                .await?;
-            self.runtime.sleep(config.interval / 2).await;
-            debug!("gc_loop running incremental delete orphaned");
-            self.incremental_delete_orphaned(config.min_blocks, config.target_duration)
-                .await?;
-            self.runtime.sleep(config.interval / 2).await;
+            for (name, step) in GC_STEPS.iter() {
+                debug!("gc_loop running {}", name);
+                let t0 = Instant::now();
+                self.gc_step(&config, &mut budget, *step).await?;
+                self.runtime
+                    .sleep(config.pause_after(t0.elapsed(), &self.throttle))
+                    .await;
+            }
+        }
+        Ok(())
+    }
+
+    /// run a single step of the gc loop, either in one transaction or, if the store has a
+    /// [gc slice](crate::Config::with_gc_slice), in short transactions with pauses in between,
+    /// until `target_duration` is exceeded.
+    ///
+    /// `budget` is the duration of a single transaction, which is adjusted after each
+    /// transaction if the store has an [adaptive budget](crate::Config::with_adaptive_gc).
+    async fn gc_step(
+        &self,
+        config: &GcConfig,
+        budget: &mut Duration,
+        step: GcStep,
+    ) -> crate::Result<()> {
+        let (min_blocks, max_steps) = config.step_limits(self.gc_slice);
+        let t0 = Instant::now();
+        for _ in 0..max_steps {
+            let duration = *budget;
+            let (complete, wait) = match self
+                .unblock_gc(move |store| run_gc_step(store, step, min_blocks, duration))
+                .await?
+            {
+                (Some(complete), wait) => (complete, wait),
+                (None, _) => return Ok(()),
+            };
+            if let Some(adaptive) = &self.adaptive_gc {
+                *budget = adaptive.next_budget(*budget, wait);
+                debug!("gc_loop observed wait {:?}, budget {:?}", wait, budget);
+            }
+            if complete || t0.elapsed() >= config.target_duration {
+                break;
+            }
+            // give readers the opportunity to access the store
+            self.runtime.sleep(*budget).await;
         }
         Ok(())
     }
 
     /// helper to give a piece of code mutable, blocking access on the store
+    ///
+    /// The time from the call until the store is locked is recorded as wait for the gc loop.
     fn unblock<T: Send + 'static>(
         &self,
         f: impl FnOnce(&mut BlockStore) -> crate::Result<T> + Send + 'static,
+    ) -> AsyncResult<T> {
+        let t0 = Instant::now();
+        self.unblock_inner(move |inner| {
+            inner.wait = inner.wait.max(t0.elapsed());
+            f(&mut inner.store)
+        })
+    }
+
+    /// helper for manual maintenance, which pauses according to the [Throttle] after `f` is done
+    async fn unblock_throttled<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut BlockStore) -> crate::Result<T> + Send + 'static,
+    ) -> crate::Result<T> {
+        let (result, work) = self
+            .unblock(move |store| {
+                let t0 = Instant::now();
+                Ok((f(store)?, t0.elapsed()))
+            })
+            .await?;
+        let pause = self.throttle.pause_after(work);
+        if pause > Duration::default() {
+            self.runtime.sleep(pause).await;
+        }
+        Ok(result)
+    }
+
+    /// helper for gc steps, which also returns and resets the wait since the last gc step
+    fn unblock_gc<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut BlockStore) -> crate::Result<T> + Send + 'static,
+    ) -> AsyncResult<(T, Duration)> {
+        self.unblock_inner(move |inner| {
+            let wait = std::mem::take(&mut inner.wait);
+            Ok((f(&mut inner.store)?, wait))
+        })
+    }
+
+    /// helper for reads via the [BlockReader] of the store, which do not lock the store
+    fn unblock_read<T: Send + 'static>(
+        &self,
+        f: impl FnOnce() -> crate::Result<T> + Send + 'static,
+    ) -> AsyncResult<T> {
+        let span = Span::current();
+        self.runtime
+            .clone()
+            .unblock(move || span.in_scope(f))
+            .err_into()
+            .map(|x| x.and_then(|x| x))
+            .boxed()
+    }
+
+    fn unblock_inner<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Inner) -> crate::Result<T> + Send + 'static,
     ) -> AsyncResult<T> {
         if let Some(inner) = self.inner.clone() {
             let runtime = self.runtime.clone();
+            // carry the span of the caller over to the blocking thread, so logs can be correlated
+            let span = Span::current();
             runtime
-                .unblock(move || f(&mut inner.lock().unwrap().store))
+                .unblock(move || span.in_scope(|| f(&mut inner.lock().unwrap())))
                 .err_into()
                 .map(|x| x.and_then(|x| x))
                 .boxed()
@@ -277,6 +766,33 @@ impl GcConfig {
     pub fn with_interval(self, interval: Duration) -> Self {
         Self { interval, ..self }
     }
+
+    /// minimum number of blocks and maximum number of transactions of a gc step, given the slice
+    /// of the store
+    pub(crate) fn step_limits(&self, slice: Option<Duration>) -> (usize, usize) {
+        match slice {
+            Some(_) => (1, usize::max_value()),
+            None => (self.min_blocks, 1),
+        }
+    }
+
+    /// duration of a gc transaction before any adjustment, given the slice and adaptive budget
+    /// of the store
+    pub(crate) fn initial_budget(
+        &self,
+        slice: Option<Duration>,
+        adaptive: Option<&AdaptiveBudget>,
+    ) -> Duration {
+        match adaptive {
+            Some(adaptive) => adaptive.min_budget,
+            None => slice.unwrap_or(self.target_duration),
+        }
+    }
+
+    /// pause to make after a gc step that took `work`
+    pub(crate) fn pause_after(&self, work: Duration, throttle: &Throttle) -> Duration {
+        (self.interval / 2).max(throttle.pause_after(work))
+    }
 }
 
 impl Default for GcConfig {
@@ -288,3 +804,85 @@ impl Default for GcConfig {
         }
     }
 }
+
+/// A step of gc, which runs a single transaction of at most `max_duration` unless `min_blocks`
+/// takes longer, and returns true if there is nothing left to do.
+pub(crate) type GcStep = fn(&mut BlockStore, usize, Duration) -> crate::Result<bool>;
+
+/// The steps of gc, shared by the gc loop and the [GcScheduler](crate::gc_scheduler::GcScheduler).
+/// First the metadata of expendable blocks is deleted, then the orphaned data.
+pub(crate) const GC_STEPS: [(&str, GcStep); 2] = [
+    ("incremental gc", |store, min_blocks, max_duration| {
+        Ok(store
+            .incremental_gc(min_blocks, max_duration)?
+            .is_complete())
+    }),
+    (
+        "incremental delete orphaned",
+        BlockStore::incremental_delete_orphaned,
+    ),
+];
+
+/// run a single transaction of a gc step
+///
+/// Returns None if the step was skipped because gc is paused or the store is read only,
+/// otherwise whether the step is complete.
+pub(crate) fn run_gc_step(
+    store: &mut BlockStore,
+    step: GcStep,
+    min_blocks: usize,
+    max_duration: Duration,
+) -> crate::Result<Option<bool>> {
+    match step(store, min_blocks, max_duration) {
+        Err(BlockStoreError::GcPaused) => {
+            debug!("skipping gc step since gc is paused");
+            Ok(None)
+        }
+        Err(BlockStoreError::ReadOnly) => {
+            debug!("skipping gc step since the store is read only");
+            Ok(None)
+        }
+        result => result.map(Some),
+    }
+}
+
+/// Feedback controller for the duration of gc transactions.
+///
+/// Other calls on the store have to wait while a gc transaction is running. After each gc
+/// transaction, the gc loop looks at the maximum time the calls since the previous one had to
+/// wait for the store, without their own work. If it exceeds `target_wait`, the budget is
+/// halved, otherwise it is increased by half. So gc is aggressive when the store is idle and
+/// gentle under load, without tuning for a particular device.
+///
+/// Note that `min_blocks` still applies if gc is not sliced, so it should be set low when using
+/// this.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdaptiveBudget {
+    /// minimum duration of a gc transaction, and the initial duration
+    pub min_budget: Duration,
+    /// maximum duration of a gc transaction
+    pub max_budget: Duration,
+    /// maximum acceptable wait of other calls for the store
+    pub target_wait: Duration,
+}
+
+impl AdaptiveBudget {
+    pub fn new(min_budget: Duration, max_budget: Duration, target_wait: Duration) -> Self {
+        Self {
+            min_budget,
+            max_budget,
+            target_wait,
+        }
+    }
+
+    /// The budget for the next gc transaction, given the current budget and the wait observed
+    /// since the last gc transaction.
+    pub fn next_budget(&self, budget: Duration, wait: Duration) -> Duration {
+        let budget = if wait > self.target_wait {
+            budget / 2
+        } else {
+            budget.mul_f64(1.5)
+        };
+        budget.max(self.min_budget).min(self.max_budget)
+    }
+}
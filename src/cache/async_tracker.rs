@@ -1,8 +1,13 @@
 use super::{BlockInfo, CacheTracker};
+use fnv::{FnvHashMap, FnvHashSet};
 use std::{
     fmt::Debug,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
 };
+use tracing::Span;
 
 /// Wrapper around a spawn function
 pub trait Spawner: Send {
@@ -38,15 +43,17 @@ where
 {
     fn blocks_accessed(&mut self, blocks: Vec<BlockInfo>) {
         let inner = self.inner.clone();
+        let span = Span::current();
         self.spawner.spawn_blocking(move || {
-            inner.lock().unwrap().blocks_accessed(blocks);
+            span.in_scope(|| inner.lock().unwrap().blocks_accessed(blocks));
         });
     }
 
     fn blocks_written(&mut self, blocks: Vec<BlockInfo>) {
         let inner = self.inner.clone();
+        let span = Span::current();
         self.spawner.spawn_blocking(move || {
-            inner.lock().unwrap().blocks_written(blocks);
+            span.in_scope(|| inner.lock().unwrap().blocks_written(blocks));
         });
     }
 
@@ -62,3 +69,133 @@ where
         self.inner.lock().unwrap().sort_ids(ids);
     }
 }
+
+/// ranking of ids by importance, as computed by the last deferred sort
+#[derive(Debug, Default)]
+struct Ranking {
+    ranks: FnvHashMap<i64, usize>,
+    /// ids deleted while a sort is running, which must not be ranked by its result
+    deleted: FnvHashSet<i64>,
+}
+
+/// A wrapping cache tracker for trackers that are backed by slow or remote storage, such as a
+/// metrics service.
+///
+/// No call to the wrapped tracker is ever made from inside the gc transaction. Notifications are
+/// forwarded on another thread, like in [AsyncCacheTracker]. Sorting uses the ranking from the
+/// previous gc round, and at the same time takes a snapshot of the ids to sort and lets the
+/// wrapped tracker rank them on another thread for the next round.
+///
+/// So the order lags by one gc round. Ids that have not been ranked yet, which are mostly blocks
+/// that were written since the previous round, are considered the most important and are sorted
+/// by id. In the first round, no id is ranked, so all ids are sorted by id.
+pub struct DeferredSortCacheTracker<S, T> {
+    spawner: S,
+    inner: Arc<Mutex<T>>,
+    ranking: Arc<Mutex<Ranking>>,
+    /// true while a sort is running on another thread
+    sorting: Arc<AtomicBool>,
+}
+
+impl<S, T> Debug for DeferredSortCacheTracker<S, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DeferredSortCacheTracker")
+            .field("ranked", &self.ranking.lock().unwrap().ranks.len())
+            .finish()
+    }
+}
+
+impl<S: Spawner, T: CacheTracker> DeferredSortCacheTracker<S, T> {
+    /// Wrap a cache tracker, using `spawner` to run its methods on another thread
+    pub fn new(spawner: S, inner: T) -> Self {
+        Self {
+            spawner,
+            inner: Arc::new(Mutex::new(inner)),
+            ranking: Default::default(),
+            sorting: Default::default(),
+        }
+    }
+}
+
+impl<S, T> CacheTracker for DeferredSortCacheTracker<S, T>
+where
+    S: Spawner,
+    T: CacheTracker + Send + 'static,
+{
+    fn blocks_accessed(&mut self, blocks: Vec<BlockInfo>) {
+        let inner = self.inner.clone();
+        let span = Span::current();
+        self.spawner.spawn_blocking(move || {
+            span.in_scope(|| inner.lock().unwrap().blocks_accessed(blocks));
+        });
+    }
+
+    fn blocks_written(&mut self, blocks: Vec<BlockInfo>) {
+        let inner = self.inner.clone();
+        let span = Span::current();
+        self.spawner.spawn_blocking(move || {
+            span.in_scope(|| inner.lock().unwrap().blocks_written(blocks));
+        });
+    }
+
+    fn delete_ids(&mut self, ids: &[i64]) {
+        {
+            let mut ranking = self.ranking.lock().unwrap();
+            let sorting = self.sorting.load(Ordering::SeqCst);
+            for id in ids {
+                ranking.ranks.remove(id);
+                if sorting {
+                    ranking.deleted.insert(*id);
+                }
+            }
+        }
+        let inner = self.inner.clone();
+        let ids = ids.to_vec();
+        let span = Span::current();
+        self.spawner.spawn_blocking(move || {
+            span.in_scope(|| inner.lock().unwrap().delete_ids(&ids));
+        });
+    }
+
+    fn retain_ids(&mut self, ids: &[i64]) {
+        let inner = self.inner.clone();
+        let ids = ids.to_vec();
+        let span = Span::current();
+        self.spawner.spawn_blocking(move || {
+            span.in_scope(|| inner.lock().unwrap().retain_ids(&ids));
+        });
+    }
+
+    fn sort_ids(&self, ids: &mut [i64]) {
+        {
+            let mut ranking = self.ranking.lock().unwrap();
+            // unranked ids go last, so new blocks are not the first to be evicted
+            ids.sort_unstable_by_key(|id| {
+                let rank = ranking.ranks.get(id).copied();
+                (rank.is_none(), rank, *id)
+            });
+            // only one deferred sort at a time, a round that finds one running keeps the old ranking
+            if self.sorting.swap(true, Ordering::SeqCst) {
+                return;
+            }
+            ranking.deleted.clear();
+        }
+        let inner = self.inner.clone();
+        let ranking = self.ranking.clone();
+        let sorting = self.sorting.clone();
+        let mut snapshot = ids.to_vec();
+        let span = Span::current();
+        self.spawner.spawn_blocking(move || {
+            span.in_scope(|| inner.lock().unwrap().sort_ids(&mut snapshot));
+            let mut ranking = ranking.lock().unwrap();
+            let Ranking { ranks, deleted } = &mut *ranking;
+            *ranks = snapshot
+                .into_iter()
+                .filter(|id| !deleted.contains(id))
+                .enumerate()
+                .map(|(rank, id)| (id, rank))
+                .collect();
+            sorting.store(false, Ordering::SeqCst);
+        });
+    }
+}
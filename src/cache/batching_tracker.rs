@@ -0,0 +1,89 @@
+use super::{BlockInfo, CacheTracker};
+use std::{fmt::Debug, sync::Mutex};
+
+/// state shared between the notification and the gc methods
+#[derive(Debug)]
+struct Inner<T> {
+    tracker: T,
+    pending: Vec<BlockInfo>,
+}
+
+impl<T: CacheTracker> Inner<T> {
+    fn flush(&mut self) {
+        if !self.pending.is_empty() {
+            let blocks = std::mem::take(&mut self.pending);
+            self.tracker.blocks_accessed(blocks);
+        }
+    }
+}
+
+/// A wrapping cache tracker that buffers read notifications and forwards them in batches
+///
+/// Reads are much more frequent than writes, so a tracker that persists every access, like the
+/// [SqliteCacheTracker](super::SqliteCacheTracker), pays for a write transaction on every
+/// [get_block](crate::BlockStore::get_block). With this wrapper it only writes once per
+/// `batch_size` accessed blocks. Pending accesses are forwarded before the wrapped tracker is
+/// asked to sort or forget ids, so gc always sees all accesses. Accesses that are still pending
+/// when the tracker is dropped are forwarded as well.
+///
+/// Writes are forwarded immediately.
+#[derive(Debug)]
+pub struct BatchingCacheTracker<T: CacheTracker> {
+    inner: Mutex<Inner<T>>,
+    batch_size: usize,
+}
+
+impl<T: CacheTracker> BatchingCacheTracker<T> {
+    pub fn new(tracker: T, batch_size: usize) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                tracker,
+                pending: Vec::new(),
+            }),
+            batch_size,
+        }
+    }
+
+    /// Forward all pending accesses to the wrapped tracker
+    pub fn flush(&self) {
+        self.inner.lock().unwrap().flush()
+    }
+}
+
+impl<T: CacheTracker> Drop for BatchingCacheTracker<T> {
+    fn drop(&mut self) {
+        self.flush()
+    }
+}
+
+impl<T: CacheTracker> CacheTracker for BatchingCacheTracker<T> {
+    fn blocks_accessed(&mut self, blocks: Vec<BlockInfo>) {
+        let inner = self.inner.get_mut().unwrap();
+        inner.pending.extend(blocks);
+        if inner.pending.len() >= self.batch_size {
+            inner.flush();
+        }
+    }
+
+    fn blocks_written(&mut self, blocks: Vec<BlockInfo>) {
+        self.inner.get_mut().unwrap().tracker.blocks_written(blocks);
+    }
+
+    fn delete_ids(&mut self, ids: &[i64]) {
+        let inner = self.inner.get_mut().unwrap();
+        inner.flush();
+        inner.tracker.delete_ids(ids);
+    }
+
+    fn sort_ids(&self, ids: &mut [i64]) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.flush();
+        inner.tracker.sort_ids(ids);
+    }
+
+    fn retain_ids(&mut self, ids: &[i64]) {
+        let inner = self.inner.get_mut().unwrap();
+        inner.flush();
+        inner.tracker.retain_ids(ids);
+    }
+}
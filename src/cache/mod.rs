@@ -7,8 +7,12 @@ use std::{
     time::{Duration, Instant},
 };
 mod async_tracker;
+mod batching_tracker;
+mod score_tracker;
 mod sqlite_tracker;
-pub use async_tracker::{AsyncCacheTracker, Spawner};
+pub use async_tracker::{AsyncCacheTracker, DeferredSortCacheTracker, Spawner};
+pub use batching_tracker::BatchingCacheTracker;
+pub use score_tracker::{LfuCacheTracker, LruCacheTracker};
 pub use sqlite_tracker::SqliteCacheTracker;
 #[cfg(test)]
 mod tests;
@@ -34,6 +38,14 @@ impl BlockInfo {
             len: data.len(),
         }
     }
+    /// info for a block of which only the size is known
+    pub(crate) fn with_len(id: i64, cid: &Cid, len: usize) -> Self {
+        Self {
+            id,
+            codec: cid.codec(),
+            len,
+        }
+    }
     pub fn id(&self) -> i64 {
         self.id
     }
@@ -0,0 +1,218 @@
+use super::{BlockInfo, CacheTracker};
+use fnv::{FnvHashMap, FnvHashSet};
+use rusqlite::{Connection, NO_PARAMS};
+use std::{
+    fmt::Debug,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+const INIT: &str = r#"
+PRAGMA journal_mode = WAL;
+CREATE TABLE IF NOT EXISTS scores (
+    id INTEGER PRIMARY KEY,
+    score INTEGER NOT NULL
+);
+"#;
+
+#[derive(Debug, Clone, Copy)]
+enum Policy {
+    /// the score is a logical time of the last access
+    Lru,
+    /// the score is the number of accesses
+    Lfu,
+}
+
+/// scores of blocks, higher scores are more important
+struct Scores {
+    policy: Policy,
+    scores: FnvHashMap<i64, u64>,
+    /// logical clock for lru, incremented on every access
+    clock: u64,
+    /// sidecar database for snapshots, if any
+    sidecar: Option<Connection>,
+}
+
+impl Scores {
+    fn new(policy: Policy, sidecar: Option<Connection>) -> crate::Result<Self> {
+        let mut scores = FnvHashMap::default();
+        if let Some(conn) = &sidecar {
+            conn.execute_batch(INIT)?;
+            let mut stmt = conn.prepare("SELECT id, score FROM scores")?;
+            let rows = stmt.query_map(NO_PARAMS, |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)? as u64))
+            })?;
+            for row in rows {
+                let (id, score) = row?;
+                scores.insert(id, score);
+            }
+        }
+        let clock = scores.values().copied().max().unwrap_or_default();
+        Ok(Self {
+            policy,
+            scores,
+            clock,
+            sidecar,
+        })
+    }
+
+    fn accessed(&mut self, blocks: Vec<BlockInfo>) {
+        for block in blocks {
+            self.clock += 1;
+            let score = self.scores.entry(block.id()).or_default();
+            match self.policy {
+                Policy::Lru => *score = self.clock,
+                Policy::Lfu => *score += 1,
+            }
+        }
+    }
+
+    fn snapshot(&mut self) -> crate::Result<()> {
+        let Self {
+            sidecar, scores, ..
+        } = self;
+        if let Some(conn) = sidecar.as_mut() {
+            crate::in_txn(conn, crate::BusyRetry::default(), |txn| {
+                txn.execute("DELETE FROM scores", NO_PARAMS)?;
+                let mut stmt = txn.prepare("INSERT INTO scores (id, score) VALUES (?, ?)")?;
+                for (id, score) in scores.iter() {
+                    stmt.execute(&[*id, *score as i64])?;
+                }
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Scores {
+    fn drop(&mut self) {
+        if let Err(cause) = self.snapshot() {
+            tracing::warn!("Unable to snapshot cache tracker {}", cause);
+        }
+    }
+}
+
+/// shared implementation of the lru and lfu trackers
+#[derive(Clone)]
+struct ScoreTracker(Arc<Mutex<Scores>>);
+
+impl ScoreTracker {
+    fn new(policy: Policy, sidecar: Option<Connection>) -> crate::Result<Self> {
+        Ok(Self(Arc::new(Mutex::new(Scores::new(policy, sidecar)?))))
+    }
+
+    fn snapshot(&self) -> crate::Result<()> {
+        self.0.lock().unwrap().snapshot()
+    }
+
+    fn blocks_accessed(&mut self, blocks: Vec<BlockInfo>) {
+        self.0.lock().unwrap().accessed(blocks)
+    }
+
+    fn delete_ids(&mut self, ids: &[i64]) {
+        let mut scores = self.0.lock().unwrap();
+        for id in ids {
+            scores.scores.remove(id);
+        }
+    }
+
+    fn retain_ids(&mut self, ids: &[i64]) {
+        let ids = ids.iter().cloned().collect::<FnvHashSet<_>>();
+        self.0
+            .lock()
+            .unwrap()
+            .scores
+            .retain(|id, _| ids.contains(id));
+    }
+
+    fn sort_ids(&self, ids: &mut [i64]) {
+        let scores = self.0.lock().unwrap();
+        ids.sort_unstable_by_key(|id| (scores.scores.get(id).copied().unwrap_or_default(), *id));
+    }
+}
+
+impl Debug for ScoreTracker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let scores = self.0.lock().unwrap();
+        f.debug_struct("ScoreTracker")
+            .field("policy", &scores.policy)
+            .field("blocks", &scores.scores.len())
+            .finish()
+    }
+}
+
+macro_rules! score_tracker {
+    ($name:ident, $policy:expr, $doc:literal) => {
+        #[doc = $doc]
+        ///
+        /// Both reads and writes count as accesses. Blocks that were never accessed are collected
+        /// first. The scores are kept in memory. If the tracker is opened with a sidecar
+        /// database, they are loaded from it, and written back when [Self::snapshot] is called
+        /// and when the last clone of the tracker is dropped.
+        ///
+        /// Clones share the same scores, so a clone can be kept to take snapshots after the
+        /// tracker was passed to the [Config](crate::Config).
+        #[derive(Debug, Clone)]
+        pub struct $name(ScoreTracker);
+
+        impl $name {
+            /// A tracker without persistence
+            pub fn new() -> Self {
+                Self(ScoreTracker::new($policy, None).expect("no io without sidecar"))
+            }
+
+            /// A tracker that is persisted in a sidecar database at the given path
+            pub fn open(path: impl AsRef<Path>) -> crate::Result<Self> {
+                Ok(Self(ScoreTracker::new(
+                    $policy,
+                    Some(Connection::open(path)?),
+                )?))
+            }
+
+            /// Write the scores to the sidecar database, if any
+            pub fn snapshot(&self) -> crate::Result<()> {
+                self.0.snapshot()
+            }
+        }
+
+        impl Default for $name {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        impl CacheTracker for $name {
+            fn blocks_accessed(&mut self, blocks: Vec<BlockInfo>) {
+                self.0.blocks_accessed(blocks)
+            }
+
+            fn blocks_written(&mut self, blocks: Vec<BlockInfo>) {
+                self.0.blocks_accessed(blocks)
+            }
+
+            fn delete_ids(&mut self, ids: &[i64]) {
+                self.0.delete_ids(ids)
+            }
+
+            fn sort_ids(&self, ids: &mut [i64]) {
+                self.0.sort_ids(ids)
+            }
+
+            fn retain_ids(&mut self, ids: &[i64]) {
+                self.0.retain_ids(ids)
+            }
+        }
+    };
+}
+
+score_tracker!(
+    LruCacheTracker,
+    Policy::Lru,
+    "A cache tracker that collects the least recently used blocks first."
+);
+score_tracker!(
+    LfuCacheTracker,
+    Policy::Lfu,
+    "A cache tracker that collects the least frequently used blocks first."
+);
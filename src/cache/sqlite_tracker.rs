@@ -35,7 +35,7 @@ fn init_db(conn: &mut Connection) -> crate::Result<()> {
 }
 
 fn attempt_txn<T>(conn: &mut Connection, f: impl FnOnce(&Transaction) -> crate::Result<T>) {
-    let result = crate::in_txn(conn, f);
+    let result = crate::in_txn(conn, crate::BusyRetry::default(), f);
     if let Err(cause) = result {
         tracing::warn!("Unable to execute transaction {}", cause);
     }
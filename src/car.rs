@@ -0,0 +1,147 @@
+//! Reading and writing of [CARv1](https://ipld.io/specs/transport/car/carv1/) files
+//!
+//! A car file is a varint length prefixed dag-cbor header `{ roots: [cid], version: 1 }`,
+//! followed by sections consisting of a varint length prefix, a cid and the block data.
+use crate::{
+    hooks::{references, Codecs},
+    BlockStoreError, Result,
+};
+use libipld::{cbor::DagCborCodec, codec::Codec, store::DefaultParams, Cid, Ipld};
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+};
+
+/// Upper limit for the size of a header or section, to not allocate arbitrary amounts of memory
+/// for a corrupt file.
+const MAX_SECTION_SIZE: u64 = 1 << 24;
+
+/// A validated block read from a car file, with extracted links
+pub(crate) struct CarBlock {
+    block: libipld::Block<DefaultParams>,
+    links: Vec<Cid>,
+}
+
+impl crate::Block for CarBlock {
+    fn cid(&self) -> &Cid {
+        self.block.cid()
+    }
+
+    fn data(&self) -> &[u8] {
+        self.block.data()
+    }
+
+    fn links(&self) -> anyhow::Result<Vec<Cid>> {
+        Ok(self.links.clone())
+    }
+}
+
+fn invalid(text: impl Into<String>) -> BlockStoreError {
+    BlockStoreError::InvalidCar(text.into())
+}
+
+/// read an unsigned LEB128 varint. Returns None at the end of the input.
+fn read_varint(reader: &mut impl Read) -> Result<Option<u64>> {
+    let mut result = 0u64;
+    for i in 0..10 {
+        let mut byte = [0u8];
+        if reader.read(&mut byte)? == 0 {
+            return if i == 0 {
+                Ok(None)
+            } else {
+                Err(invalid("truncated varint"))
+            };
+        }
+        result |= u64::from(byte[0] & 0x7f) << (i * 7);
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(result));
+        }
+    }
+    Err(invalid("varint too long"))
+}
+
+fn write_varint(writer: &mut impl Write, mut value: u64) -> Result<()> {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            writer.write_all(&[byte])?;
+            return Ok(());
+        }
+        writer.write_all(&[byte | 0x80])?;
+    }
+}
+
+/// read a length prefixed section. Returns None at the end of the input.
+fn read_section(reader: &mut impl Read) -> Result<Option<Vec<u8>>> {
+    let len = match read_varint(reader)? {
+        Some(len) => len,
+        None => return Ok(None),
+    };
+    if len > MAX_SECTION_SIZE {
+        return Err(invalid(format!("section too large: {}", len)));
+    }
+    let mut data = vec![0u8; len as usize];
+    reader.read_exact(&mut data)?;
+    Ok(Some(data))
+}
+
+/// read the header and return the roots
+pub(crate) fn read_header(reader: &mut impl Read) -> Result<Vec<Cid>> {
+    let header = read_section(reader)?.ok_or_else(|| invalid("missing header"))?;
+    let header: Ipld = DagCborCodec.decode(&header)?;
+    let mut header = match header {
+        Ipld::Map(header) => header,
+        _ => return Err(invalid("header is not a map")),
+    };
+    if header.get("version") != Some(&Ipld::Integer(1)) {
+        return Err(invalid("unsupported version"));
+    }
+    match header.remove("roots") {
+        Some(Ipld::List(roots)) => roots
+            .into_iter()
+            .map(|root| match root {
+                Ipld::Link(cid) => Ok(cid),
+                _ => Err(invalid("root is not a link")),
+            })
+            .collect(),
+        _ => Err(invalid("missing roots")),
+    }
+}
+
+/// read the next block. Returns None at the end of the input.
+///
+/// The block is validated, so the data must match the hash of the cid.
+pub(crate) fn read_block(reader: &mut impl Read, codecs: &Codecs) -> Result<Option<CarBlock>> {
+    let section = match read_section(reader)? {
+        Some(section) => section,
+        None => return Ok(None),
+    };
+    let mut cursor = std::io::Cursor::new(&section);
+    let cid = Cid::read_bytes(&mut cursor)?;
+    let data = section[cursor.position() as usize..].to_vec();
+    let block = libipld::Block::new(cid, data)?;
+    let links = references(codecs, &block)?;
+    Ok(Some(CarBlock { block, links }))
+}
+
+pub(crate) fn write_header(writer: &mut impl Write, roots: &[Cid]) -> Result<()> {
+    let mut header = BTreeMap::new();
+    header.insert(
+        "roots".to_owned(),
+        Ipld::List(roots.iter().cloned().map(Ipld::Link).collect()),
+    );
+    header.insert("version".to_owned(), Ipld::Integer(1));
+    let header = DagCborCodec.encode(&Ipld::Map(header))?;
+    write_varint(writer, header.len() as u64)?;
+    writer.write_all(&header)?;
+    Ok(())
+}
+
+pub(crate) fn write_block(writer: &mut impl Write, cid: &Cid, data: &[u8]) -> Result<()> {
+    let cid = cid.to_bytes();
+    write_varint(writer, (cid.len() + data.len()) as u64)?;
+    writer.write_all(&cid)?;
+    writer.write_all(data)?;
+    Ok(())
+}
@@ -6,11 +6,13 @@ use rusqlite::{
 };
 use std::{convert::TryFrom, io::Cursor};
 
-/// This is sufficient for 32 byte hashes like sha2-256, but not for exotic hashes.
-const MAX_SIZE: usize = 39;
+/// The maximum size of a cid in the store.
+///
+/// This is sufficient for hashes of up to 48 bytes like sha2-384, but not for 64 byte hashes.
+pub(crate) const MAX_SIZE: usize = 64;
 
 /// a representation of a cid that implements AsRef<[u8]>
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub(crate) struct CidBytes {
     size: u8,
     data: [u8; MAX_SIZE],
@@ -60,7 +62,7 @@ impl TryFrom<&[u8]> for CidBytes {
 
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
         let mut res = CidBytes::default();
-        if value.len() < 64 {
+        if value.len() <= MAX_SIZE {
             res.size = value.len() as u8;
             res.data[0..value.len()].copy_from_slice(value);
             Ok(res)
@@ -6,35 +6,63 @@
 //! Tables:
 //! cids: mapping from cid (blob < 64 bytes) to id (u64)
 //! refs: m:n mapping from block ids to their children
-//! blocks: the actual data for blocks, keyed by block id
+//! blocks: the actual data for blocks, keyed by block id, with the time they were last written
 //!    cids can exist in the system without having data associated with them!
 //! alias: table that contains named pins for roots of graphs that should not be deleted by gc
 //!    you can alias incomplete or in fact non-existing data. It is not necessary for a pinned dag
 //!    to be complete.
+//! metadata: derived metadata for blocks, computed by a hook at put time
+//! provider_hints: peers that previously served a block, most recent last
+//! cid_originals: original form of cids that were normalized to CIDv1 when added
+//! evictions: log of cids of blocks that were deleted by gc, with the time of deletion
+//! alias_history: log of changes of aliases, with the previous and new cid
+//! checksums: fast checksums of block data, independent of the cid hash
+//! protected: cids that gc must never delete, without protecting their descendants
+//! accesses: time of the last read of blocks, only if access tracking is enabled
+//! refcounts: number of recursive pins and live parents of live blocks, only kept up to date in
+//! refcount gc mode
+//! extensions: schema versions of application owned tables in the same database
+//! commit_seq: a single row with a sequence number that is incremented on every change
+//! owners: stores of different processes that share the database, with their last heartbeat and
+//! their timeout
+//! temp_pin_owners: the owner of each temp pin, only if the database is shared by processes
+//!
+//! Small blocks are not stored inline in the cids table. The blocks table is a rowid table keyed
+//! by the cid id, so a block of up to a few kilobytes is stored entirely on a b-tree leaf page
+//! without any overflow pages. Inlining would not save pages, but would make the cids table and
+//! its unique index much less dense, and every query touching blocks would need two code paths.
+use fnv::{FnvHashMap, FnvHashSet};
 use libipld::{Cid, DefaultParams};
 use rusqlite::{
-    config::DbConfig, params, types::FromSql, Connection, OptionalExtension, ToSql, Transaction,
-    NO_PARAMS,
+    config::DbConfig, params, types::FromSql, types::Null, Connection, OptionalExtension, ToSql,
+    Transaction, TransactionBehavior, NO_PARAMS,
 };
 use std::{
-    collections::BTreeSet,
+    collections::{BTreeSet, VecDeque},
     convert::TryFrom,
+    hash::Hasher,
+    ops::Bound,
+    path::Path,
     sync::atomic::{AtomicI64, Ordering},
     time::Duration,
     time::Instant,
 };
 use tracing::*;
 
-use crate::{cache::CacheTracker, SizeTargets, StoreStats};
+use crate::{
+    cache::CacheTracker,
+    cidbytes::CidBytes,
+    hooks::{references, Codecs},
+    AliasStats, ApproxStats, BusyRetry, CheckpointMode, CheckpointStats, Config, GcStats,
+    GraphStats, ReverseAlias, SizeTargets, StoreStats, Synchronous, TempPinLimits, TempPinStats,
+    Visit,
+};
 
+/// pragmas the store relies on, the tunable ones are set by [set_pragmas]
 const PRAGMAS: &str = r#"
 -- this must be done before changing the database via the CLI!
 PRAGMA foreign_keys = ON;
 PRAGMA journal_mode = WAL;
--- this is the most conservative mode. This only works if we have few, large transactions
-PRAGMA synchronous = FULL;
--- I tried different even larger values for this. Did not make a difference.
-PRAGMA page_size = 4096;
 "#;
 
 const INIT: &str = r#"
@@ -64,12 +92,16 @@ ON refs (child_id);
 
 CREATE TABLE IF NOT EXISTS blocks (
     block_id INTEGER PRIMARY KEY,
-    block BLOB NOT NULL
+    block BLOB NOT NULL,
+    -- time of the last write of the block in milliseconds since the unix epoch, for the gc grace period
+    inserted INTEGER NOT NULL DEFAULT 0
 );
 
 CREATE TABLE IF NOT EXISTS aliases (
     name blob NOT NULL PRIMARY KEY,
     block_id INTEGER NOT NULL,
+    -- 1 if the alias protects the whole dag, 0 if it only protects the named block
+    recursive INTEGER NOT NULL DEFAULT 1,
     CONSTRAINT fk_block_id
       FOREIGN KEY (block_id)
       REFERENCES cids(id)
@@ -92,16 +124,143 @@ CREATE TABLE IF NOT EXISTS temp_pins (
 CREATE INDEX IF NOT EXISTS idx_temp_pins_block_id
 ON temp_pins (block_id);
 
--- delete temp aliases that were not dropped because of crash
-DELETE FROM temp_pins;
+CREATE TABLE IF NOT EXISTS metadata (
+    block_id INTEGER NOT NULL,
+    key TEXT NOT NULL,
+    value BLOB NOT NULL,
+    PRIMARY KEY(block_id,key)
+    CONSTRAINT fk_block_id
+      FOREIGN KEY (block_id)
+      REFERENCES cids(id)
+      ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS provider_hints (
+    block_id INTEGER NOT NULL,
+    peer BLOB NOT NULL,
+    UNIQUE(block_id,peer)
+    CONSTRAINT fk_block_id
+      FOREIGN KEY (block_id)
+      REFERENCES cids(id)
+      ON DELETE CASCADE
+);
+
+-- original form of cids that were normalized to CIDv1
+CREATE TABLE IF NOT EXISTS cid_originals (
+    id INTEGER NOT NULL PRIMARY KEY,
+    cid BLOB NOT NULL,
+    CONSTRAINT fk_id
+      FOREIGN KEY (id)
+      REFERENCES cids(id)
+      ON DELETE CASCADE
+);
+
+-- cids of blocks that were deleted by gc, if enabled
+CREATE TABLE IF NOT EXISTS evictions (
+    cid BLOB NOT NULL,
+    time INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_evictions_time
+ON evictions (time);
+
+-- changes of aliases, if enabled. A NULL cid means that the alias did not exist.
+CREATE TABLE IF NOT EXISTS alias_history (
+    name BLOB NOT NULL,
+    old_cid BLOB,
+    new_cid BLOB,
+    time INTEGER NOT NULL
+);
+
+-- versions of application owned extension tables
+CREATE TABLE IF NOT EXISTS extensions (
+    name TEXT NOT NULL PRIMARY KEY,
+    version INTEGER NOT NULL
+);
+
+-- commit sequence number, incremented on every commit that changes the block set or aliases
+CREATE TABLE IF NOT EXISTS commit_seq (
+    seq INTEGER NOT NULL
+);
+INSERT INTO commit_seq (seq) SELECT 0 WHERE NOT EXISTS (SELECT 1 FROM commit_seq);
+
+-- checksums of block data, if enabled, to detect corruption of the database
+CREATE TABLE IF NOT EXISTS checksums (
+    block_id INTEGER NOT NULL PRIMARY KEY,
+    checksum INTEGER NOT NULL,
+    CONSTRAINT fk_block_id
+      FOREIGN KEY (block_id)
+      REFERENCES cids(id)
+      ON DELETE CASCADE
+);
+
+-- cids that must never be deleted by gc, even if they are not pinned
+CREATE TABLE IF NOT EXISTS protected (
+    block_id INTEGER NOT NULL PRIMARY KEY,
+    CONSTRAINT fk_block_id
+      FOREIGN KEY (block_id)
+      REFERENCES cids(id)
+      ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS accesses (
+    block_id INTEGER NOT NULL PRIMARY KEY,
+    time INTEGER NOT NULL,
+    CONSTRAINT fk_block_id
+      FOREIGN KEY (block_id)
+      REFERENCES cids(id)
+      ON DELETE CASCADE
+);
 
 -- stats table to keep track of total number and size of blocks
 CREATE TABLE IF NOT EXISTS stats (
     count INTEGER NOT NULL,
     size INTEGER NOT NULL
 );
+INSERT INTO stats (count, size) SELECT 0, 0 WHERE NOT EXISTS (SELECT 1 FROM stats);
+
+-- ids are never reused, so a store that was considered dead can not take over another owner
+CREATE TABLE IF NOT EXISTS owners (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    pid INTEGER NOT NULL,
+    heartbeat INTEGER NOT NULL,
+    timeout INTEGER NOT NULL DEFAULT 0
+);
 
--- initialize stats from the real values at startup
+CREATE TABLE IF NOT EXISTS temp_pin_owners (
+    pin INTEGER PRIMARY KEY,
+    owner INTEGER NOT NULL
+);
+"#;
+
+/// Temporary objects of the connection of an owner, see [register_owner]. The trigger records
+/// the owner of a temp pin when its first block is added, so the owner does not have to be
+/// passed around when adding to temp pins.
+const OWNER_TRIGGER: &str = r#"
+CREATE TEMP TABLE IF NOT EXISTS current_owner (id INTEGER NOT NULL);
+
+CREATE TEMP TRIGGER IF NOT EXISTS record_temp_pin_owner
+AFTER INSERT ON main.temp_pins
+WHEN NOT EXISTS (SELECT 1 FROM temp_pins WHERE id = NEW.id AND block_id != NEW.block_id)
+BEGIN
+    INSERT OR REPLACE INTO temp_pin_owners (pin, owner) SELECT NEW.id, id FROM current_owner;
+END;
+"#;
+
+/// Temp pins that were not dropped because their owner is gone: pins without owner up to the
+/// given id, and pins of owners without a heartbeat since the given time
+const STALE_TEMP_PINS: &str = r#"
+SELECT DISTINCT id FROM temp_pins WHERE
+    (id <= ?1 AND id NOT IN (SELECT pin FROM temp_pin_owners)) OR
+    id IN (
+        SELECT pin FROM temp_pin_owners
+        WHERE owner NOT IN (SELECT id FROM owners WHERE heartbeat >= ?2)
+    )
+"#;
+
+/// Housekeeping that is done at startup, unless deferred
+const MAINTENANCE: &str = r#"
+-- initialize stats from the real values
 DELETE FROM stats;
 INSERT INTO stats (count, size) VALUES (
     (SELECT COUNT(id) FROM cids, blocks WHERE id = block_id),
@@ -109,7 +268,409 @@ INSERT INTO stats (count, size) VALUES (
 );
 "#;
 
-fn user_version(txn: &Transaction) -> rusqlite::Result<u32> {
+/// Statement to delete cids that nothing refers to: we don't have the block, it is not linked,
+/// aliased, temp pinned or protected, and there are no provider hints for it. These are left
+/// over e.g. from aliases of blocks that were never fetched.
+///
+/// At most ?1 cids are deleted, or all of them if it is negative.
+const DELETE_ORPHANED_CIDS: &str = r#"
+DELETE FROM cids WHERE id IN (
+    SELECT id FROM cids WHERE
+        id NOT IN (SELECT block_id FROM blocks) AND
+        id NOT IN (SELECT child_id FROM refs) AND
+        id NOT IN (SELECT parent_id FROM refs) AND
+        id NOT IN (SELECT block_id FROM aliases) AND
+        id NOT IN (SELECT block_id FROM temp_pins) AND
+        id NOT IN (SELECT block_id FROM protected) AND
+        id NOT IN (SELECT block_id FROM provider_hints)
+    LIMIT ?1
+);
+"#;
+
+/// Query for the ids of all blocks that are neither aliased nor temp pinned, nor descendants of
+/// a recursively aliased or temp pinned block. These are the candidates for gc.
+///
+/// Cids without a block are not candidates, they are left for
+/// [BlockStore::delete_orphaned_cids](crate::BlockStore::delete_orphaned_cids).
+pub const GC_CANDIDATES: &str = r#"
+WITH RECURSIVE
+    descendant_of(id) AS
+    (
+        SELECT block_id FROM aliases WHERE recursive UNION SELECT block_id FROM temp_pins
+        UNION SELECT block_id FROM blocks WHERE inserted > ?1
+        UNION ALL
+        SELECT DISTINCT child_id FROM refs JOIN descendant_of ON descendant_of.id=refs.parent_id
+    )
+SELECT id FROM
+    cids
+WHERE
+    id IN (SELECT block_id FROM blocks) AND
+    id NOT IN descendant_of AND
+    id NOT IN (SELECT block_id FROM aliases) AND
+    id NOT IN (SELECT block_id FROM protected);
+"#;
+
+/// Query for the gc candidates in refcount gc mode, where the ids of all blocks that are
+/// descendants of a recursively aliased or temp pinned block are in the refcounts table.
+///
+/// Only the descendants of blocks in the gc grace period have to be computed.
+pub const REFCOUNT_GC_CANDIDATES: &str = r#"
+WITH RECURSIVE
+    fresh(id) AS
+    (
+        SELECT block_id FROM blocks WHERE inserted > ?1
+        UNION
+        SELECT child_id FROM refs JOIN fresh ON fresh.id=refs.parent_id
+    )
+SELECT id FROM
+    cids
+WHERE
+    id IN (SELECT block_id FROM blocks) AND
+    id NOT IN (SELECT block_id FROM refcounts) AND
+    id NOT IN (SELECT block_id FROM aliases) AND
+    id NOT IN (SELECT block_id FROM protected) AND
+    id NOT IN fresh;
+"#;
+
+/// Query for the cids of all descendants of a cid, including the cid itself.
+pub const DESCENDANTS: &str = r#"
+WITH RECURSIVE
+    descendant_of(id) AS
+    (
+        SELECT id FROM cids WHERE cid = ?
+        UNION ALL
+        SELECT DISTINCT child_id FROM refs JOIN descendant_of ON descendant_of.id=refs.parent_id
+    ),
+    descendant_ids as (
+        SELECT DISTINCT id FROM descendant_of
+    )
+    -- retrieve corresponding cids - this is a set because of select distinct
+    SELECT cid from cids JOIN descendant_ids ON cids.id = descendant_ids.id;
+"#;
+
+/// Query for the cids of all descendants of an id, including the id itself, for which we do not
+/// have the data.
+pub const MISSING_BLOCKS: &str = r#"
+WITH RECURSIVE
+    -- find descendants of cid, including the id of the cid itself
+    descendant_of(id) AS (
+        SELECT ?
+        UNION ALL
+        SELECT DISTINCT child_id FROM refs JOIN descendant_of ON descendant_of.id=refs.parent_id
+    ),
+    -- find orphaned ids
+    orphaned_ids as (
+      SELECT DISTINCT id FROM descendant_of LEFT JOIN blocks ON descendant_of.id = blocks.block_id WHERE blocks.block_id IS NULL
+    )
+    -- retrieve corresponding cids - this is a set because of select distinct
+SELECT cid from cids JOIN orphaned_ids ON cids.id = orphaned_ids.id
+"#;
+
+/// Query for the names of all aliases that protect an id, which are aliases of the id itself and
+/// recursive aliases of its ancestors.
+pub const ANCESTOR_ALIASES: &str = r#"
+WITH RECURSIVE
+    ancestor_of(id) AS
+    (
+        SELECT ?1
+        UNION
+        SELECT parent_id FROM refs JOIN ancestor_of ON ancestor_of.id=refs.child_id
+    )
+SELECT DISTINCT name FROM ancestor_of JOIN aliases ON ancestor_of.id = block_id
+WHERE recursive OR block_id = ?1
+ORDER BY name;
+"#;
+
+/// Query to check if we have the data for a cid.
+pub const HAS_BLOCK: &str =
+    "SELECT 1 FROM blocks, cids WHERE blocks.block_id = cids.id AND cids.cid = ?";
+
+/// Query for the ids of orphaned blocks, which are blocks without a corresponding cid.
+pub const ORPHANED_BLOCKS: &str =
+    "SELECT block_id FROM blocks WHERE block_id NOT IN (SELECT id FROM cids)";
+
+/// The core queries of the store, by name.
+///
+/// These can be used to check the query plans with `EXPLAIN QUERY PLAN`, e.g. after adding
+/// additional tables or indexes to the same database file.
+pub const CORE_QUERIES: &[(&str, &str)] = &[
+    ("gc_candidates", GC_CANDIDATES),
+    ("descendants", DESCENDANTS),
+    ("missing_blocks", MISSING_BLOCKS),
+    ("ancestor_aliases", ANCESTOR_ALIASES),
+    ("has_block", HAS_BLOCK),
+    ("orphaned_blocks", ORPHANED_BLOCKS),
+    ("refcount_gc_candidates", REFCOUNT_GC_CANDIDATES),
+];
+
+/// the file of the main database, or None for an in memory database
+pub(crate) fn database_file(conn: &Connection) -> crate::Result<Option<std::path::PathBuf>> {
+    let file: String = conn
+        .prepare("SELECT file FROM pragma_database_list WHERE name = 'main'")?
+        .query_row(NO_PARAMS, |row| row.get(0))?;
+    Ok(if file.is_empty() {
+        None
+    } else {
+        Some(file.into())
+    })
+}
+
+/// a number that changes whenever another connection commits a change to the database
+pub(crate) fn data_version(conn: &Connection) -> crate::Result<i64> {
+    Ok(conn.pragma_query_value(None, "data_version", |row| row.get(0))?)
+}
+
+/// create the refcounts table if needed, and recompute the counts if refcount gc is enabled.
+///
+/// The table is never dropped, since other handles on the same database might use it. But handles
+/// without refcount gc do not keep it up to date, so the counts are recomputed on every open with
+/// refcount gc.
+pub(crate) fn init_refcounts(txn: &Transaction, enabled: bool) -> crate::Result<()> {
+    txn.execute_batch(
+        r#"
+CREATE TABLE IF NOT EXISTS refcounts (
+    block_id INTEGER NOT NULL PRIMARY KEY,
+    refcount INTEGER NOT NULL,
+    CONSTRAINT fk_block_id
+      FOREIGN KEY (block_id)
+      REFERENCES cids(id)
+      ON DELETE CASCADE
+);
+"#,
+    )?;
+    if enabled {
+        info!("computing refcounts");
+        repair_refcounts(txn)?;
+    }
+    Ok(())
+}
+
+/// Tables and triggers of the trash, see [Config::with_trash].
+///
+/// Blocks are moved to the trash by triggers, so every way of deleting a block is covered. The
+/// time of deletion is set after the deletion by [stamp_trash].
+///
+/// [Config::with_trash]: crate::Config::with_trash
+const TRASH: &str = r#"
+-- blocks deleted by gc, with the time of deletion in milliseconds since the unix epoch
+CREATE TABLE IF NOT EXISTS trash (
+    cid BLOB NOT NULL PRIMARY KEY,
+    block BLOB NOT NULL,
+    time INTEGER
+);
+
+-- links of the blocks in the trash. Since gc deletes a dag in no particular order, the links
+-- from the parents of a deleted block are recorded as well, before they are lost.
+CREATE TABLE IF NOT EXISTS trash_refs (
+    parent BLOB NOT NULL,
+    child BLOB NOT NULL,
+    PRIMARY KEY(parent,child)
+);
+
+-- a block is deleted together with its cid
+CREATE TRIGGER IF NOT EXISTS trash_cid BEFORE DELETE ON cids
+WHEN OLD.id IN (SELECT block_id FROM blocks)
+BEGIN
+    REPLACE INTO trash (cid, block) SELECT OLD.cid, block FROM blocks WHERE block_id = OLD.id;
+    INSERT OR IGNORE INTO trash_refs (parent, child)
+        SELECT OLD.cid, cid FROM refs JOIN cids ON id = child_id WHERE parent_id = OLD.id;
+    INSERT OR IGNORE INTO trash_refs (parent, child)
+        SELECT cid, OLD.cid FROM refs JOIN cids ON id = parent_id WHERE child_id = OLD.id;
+END;
+
+-- the data of a block is deleted, but its cid is kept
+CREATE TRIGGER IF NOT EXISTS trash_block BEFORE DELETE ON blocks
+WHEN OLD.block_id IN (SELECT id FROM cids)
+BEGIN
+    REPLACE INTO trash (cid, block) SELECT cid, OLD.block FROM cids WHERE id = OLD.block_id;
+    INSERT OR IGNORE INTO trash_refs (parent, child)
+        SELECT parent.cid, child.cid FROM refs
+            JOIN cids AS parent ON parent.id = parent_id
+            JOIN cids AS child ON child.id = child_id
+        WHERE parent_id = OLD.block_id;
+END;
+"#;
+
+/// create the trash if it is enabled, or stop moving deleted blocks to it if it is disabled.
+///
+/// The tables are never dropped, since that would lose the blocks that can still be restored.
+pub(crate) fn init_trash(txn: &Transaction, enabled: bool) -> crate::Result<()> {
+    if enabled {
+        if !table_exists(txn, "trash")? {
+            info!("creating trash");
+        }
+        txn.execute_batch(TRASH)?;
+    } else {
+        txn.execute_batch(
+            r#"
+DROP TRIGGER IF EXISTS trash_cid;
+DROP TRIGGER IF EXISTS trash_block;
+"#,
+        )?;
+    }
+    Ok(())
+}
+
+/// set the time of blocks that were just moved to the trash, and delete blocks from the trash
+/// that were deleted before the retention time.
+pub(crate) fn stamp_trash(txn: &Transaction, now: i64, retention: Duration) -> crate::Result<()> {
+    txn.prepare_cached("UPDATE trash SET time = ? WHERE time IS NULL")?
+        .execute(&[now])?;
+    let cutoff = now.saturating_sub(i64::try_from(retention.as_millis())?);
+    txn.prepare_cached("DELETE FROM trash WHERE time < ?")?
+        .execute(&[cutoff])?;
+    // links of parents that are neither in the store nor in the trash are no longer needed
+    txn.prepare_cached(
+        r#"
+DELETE FROM trash_refs WHERE
+    parent NOT IN (SELECT cid FROM trash) AND
+    parent NOT IN (SELECT cid FROM cids)
+"#,
+    )?
+    .execute(NO_PARAMS)?;
+    Ok(())
+}
+
+/// get the cids and times of deletion of all blocks in the trash, most recently deleted first
+pub(crate) fn get_trash<C: FromSql>(txn: &Transaction) -> crate::Result<Vec<(C, i64)>> {
+    Ok(txn
+        .prepare_cached("SELECT cid, time FROM trash ORDER BY time DESC, cid")?
+        .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?)
+}
+
+/// a block in the trash, with its cid, data and links
+pub(crate) type TrashRow<C> = (C, Vec<u8>, Vec<C>);
+
+/// get a block and all its descendants from the trash. Descendants that are not in the trash
+/// are not followed.
+pub(crate) fn get_trash_dag<C: ToSql + FromSql>(
+    txn: &Transaction,
+    root: C,
+) -> crate::Result<Vec<TrashRow<C>>> {
+    let rows = txn
+        .prepare_cached(
+            r#"
+WITH RECURSIVE
+    restore(cid) AS
+    (
+        SELECT ?
+        UNION
+        SELECT child FROM trash_refs JOIN restore ON parent = restore.cid
+    )
+SELECT trash.cid, block FROM restore JOIN trash ON trash.cid = restore.cid
+"#,
+        )?
+        .query_map(&[root], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<(C, Vec<u8>)>>>()?;
+    let mut links = txn.prepare_cached("SELECT child FROM trash_refs WHERE parent = ?")?;
+    let mut result = Vec::with_capacity(rows.len());
+    for (cid, data) in rows {
+        let children = links
+            .query_map(&[&cid], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<C>>>()?;
+        result.push((cid, data, children));
+    }
+    Ok(result)
+}
+
+/// remove blocks from the trash
+pub(crate) fn delete_from_trash(txn: &Transaction, cids: &[impl ToSql]) -> crate::Result<()> {
+    let mut stmt = txn.prepare_cached("DELETE FROM trash WHERE cid = ?")?;
+    let mut refs = txn.prepare_cached("DELETE FROM trash_refs WHERE parent = ?")?;
+    for cid in cids {
+        stmt.execute(&[cid])?;
+        refs.execute(&[cid])?;
+    }
+    Ok(())
+}
+
+/// recompute the refcounts table from scratch, using the same reachability as [GC_CANDIDATES].
+pub(crate) fn repair_refcounts(txn: &Transaction) -> crate::Result<()> {
+    txn.execute_batch(
+        r#"
+DELETE FROM refcounts;
+WITH RECURSIVE
+    live(id) AS
+    (
+        SELECT block_id FROM aliases WHERE recursive UNION SELECT block_id FROM temp_pins
+        UNION
+        SELECT child_id FROM refs JOIN live ON live.id=refs.parent_id
+    )
+INSERT INTO refcounts (block_id, refcount)
+SELECT id,
+    (SELECT COUNT(*) FROM aliases WHERE recursive AND block_id = live.id) +
+    (SELECT COUNT(*) FROM temp_pins WHERE block_id = live.id) +
+    (SELECT COUNT(*) FROM refs WHERE child_id = live.id AND parent_id IN live)
+FROM live;
+"#,
+    )?;
+    Ok(())
+}
+
+/// the refcount of an id, which is 0 if the id is not live
+fn get_refcount(txn: &Transaction, id: i64) -> crate::Result<i64> {
+    Ok(txn
+        .prepare_cached("SELECT refcount FROM refcounts WHERE block_id = ?")?
+        .query_row(&[id], |row| row.get(0))
+        .optional()?
+        .unwrap_or_default())
+}
+
+fn get_child_ids(txn: &Transaction, id: i64) -> crate::Result<Vec<i64>> {
+    Ok(txn
+        .prepare_cached("SELECT child_id FROM refs WHERE parent_id = ?")?
+        .query_map(&[id], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?)
+}
+
+/// increment the refcount of an id. If the id becomes live, the children are incremented too.
+///
+/// This uses an explicit stack, since dags can be much deeper than the recursion limit.
+fn inc_refcount(txn: &Transaction, id: i64) -> crate::Result<()> {
+    let mut stack = vec![id];
+    while let Some(id) = stack.pop() {
+        let refcount = get_refcount(txn, id)?;
+        txn.prepare_cached("REPLACE INTO refcounts (block_id, refcount) VALUES (?, ?)")?
+            .execute(&[id, refcount + 1])?;
+        if refcount == 0 {
+            stack.extend(get_child_ids(txn, id)?);
+        }
+    }
+    Ok(())
+}
+
+/// decrement the refcount of an id. If the id is no longer live, the children are decremented too.
+fn dec_refcount(txn: &Transaction, id: i64) -> crate::Result<()> {
+    let mut stack = vec![id];
+    while let Some(id) = stack.pop() {
+        match get_refcount(txn, id)? {
+            0 => warn!("refcount of {} is already 0, refcounts need repair", id),
+            1 => {
+                txn.prepare_cached("DELETE FROM refcounts WHERE block_id = ?")?
+                    .execute(&[id])?;
+                stack.extend(get_child_ids(txn, id)?);
+            }
+            _ => {
+                txn.prepare_cached(
+                    "UPDATE refcounts SET refcount = refcount - 1 WHERE block_id = ?",
+                )?
+                .execute(&[id])?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// get the ids of a temp pin
+fn get_temp_pin_ids(txn: &Transaction, sql: &str, id: i64) -> crate::Result<Vec<i64>> {
+    Ok(txn
+        .prepare_cached(sql)?
+        .query_map(&[id], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?)
+}
+
+pub(crate) fn user_version(txn: &Transaction) -> rusqlite::Result<u32> {
     Ok(txn
         .pragma_query_value(None, "user_version", |row| row.get(0))
         .optional()?
@@ -123,7 +684,14 @@ fn table_exists(txn: &Transaction, table: &str) -> rusqlite::Result<bool> {
     Ok(num > 0)
 }
 
-fn migrate_v0_v1(txn: &Transaction) -> anyhow::Result<()> {
+fn column_exists(txn: &Transaction, table: &str, column: &str) -> rusqlite::Result<bool> {
+    let num: u32 = txn
+        .prepare_cached("SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2;")?
+        .query_row(params![table, column], |row| row.get(0))?;
+    Ok(num > 0)
+}
+
+fn migrate_v0_v1(txn: &Transaction, codecs: &Codecs) -> anyhow::Result<()> {
     info!("executing migration from v0 to v1");
     txn.execute_batch("ALTER TABLE blocks RENAME TO blocks_v0")?;
     // drop the old refs table, since the content can be extracted from blocks_v0
@@ -140,16 +708,18 @@ fn migrate_v0_v1(txn: &Transaction) -> anyhow::Result<()> {
         let (cid, data) = block?;
         let cid = Cid::try_from(cid)?;
         let block = libipld::Block::<DefaultParams>::new(cid, data)?;
-        let mut set = BTreeSet::new();
-        block.references(&mut set)?;
+        let links = references(codecs, &block)?;
         put_block(
             &txn,
             &block.cid().to_bytes(),
             block.data(),
-            set.into_iter()
+            links
+                .into_iter()
                 .map(|cid| cid.to_bytes())
                 .collect::<Vec<_>>(),
             None,
+            0,
+            false,
         )?;
     }
     info!("dropping table blocks_v0");
@@ -159,7 +729,7 @@ fn migrate_v0_v1(txn: &Transaction) -> anyhow::Result<()> {
     Ok(())
 }
 
-fn get_id(txn: &Transaction, cid: impl ToSql) -> rusqlite::Result<Option<i64>> {
+pub(crate) fn get_id(txn: &Transaction, cid: impl ToSql) -> rusqlite::Result<Option<i64>> {
     txn.prepare_cached("SELECT id FROM cids WHERE cid=?")?
         .query_row(&[cid], |row| row.get(0))
         .optional()
@@ -190,6 +760,96 @@ pub(crate) fn get_store_stats(txn: &Transaction) -> crate::Result<StoreStats> {
     Ok(result)
 }
 
+/// returns approximate statistics without scanning any tables
+///
+/// Counts are upper bounds derived from the max rowid, the average block size is computed from
+/// a random sample of `samples` blocks.
+pub(crate) fn get_approx_stats(txn: &Transaction, samples: usize) -> crate::Result<ApproxStats> {
+    let max_cid_id: i64 = txn
+        .prepare_cached("SELECT COALESCE(MAX(id), 0) FROM cids")?
+        .query_row(NO_PARAMS, |row| row.get(0))?;
+    let max_block_id: i64 = txn
+        .prepare_cached("SELECT COALESCE(MAX(block_id), 0) FROM blocks")?
+        .query_row(NO_PARAMS, |row| row.get(0))?;
+    let pragma = |name: &str| -> crate::Result<i64> {
+        Ok(txn.query_row(&format!("PRAGMA {}", name), NO_PARAMS, |row| row.get(0))?)
+    };
+    let used_pages = pragma("page_count")? - pragma("freelist_count")?;
+    let db_size = used_pages * pragma("page_size")?;
+    let mut sampled = 0i64;
+    let mut total = 0i64;
+    if max_block_id > 0 {
+        let mut stmt = txn.prepare_cached(
+            "SELECT LENGTH(block) FROM blocks WHERE block_id >= (ABS(RANDOM()) % ?) + 1 LIMIT 1",
+        )?;
+        for _ in 0..samples {
+            let len: Option<i64> = stmt
+                .query_row(&[max_block_id], |row| row.get(0))
+                .optional()?;
+            if let Some(len) = len {
+                sampled += 1;
+                total += len;
+            }
+        }
+    }
+    Ok(ApproxStats {
+        max_cids: u64::try_from(max_cid_id)?,
+        max_blocks: u64::try_from(max_block_id)?,
+        avg_block_size: if sampled > 0 {
+            u64::try_from(total / sampled)?
+        } else {
+            0
+        },
+        db_size: u64::try_from(db_size)?,
+    })
+}
+
+/// compute statistics about the shape of the graph in the refs table
+///
+/// this scans the refs table, so it is not cheap for large stores.
+pub(crate) fn get_graph_stats(txn: &Transaction) -> crate::Result<GraphStats> {
+    let mut stats = GraphStats::default();
+    let mut stmt = txn.prepare_cached(
+        "SELECT n, COUNT(*) FROM (SELECT COUNT(*) AS n FROM refs GROUP BY parent_id) GROUP BY n",
+    )?;
+    let mut rows = stmt.query(NO_PARAMS)?;
+    while let Some(row) = rows.next()? {
+        let fanout = u64::try_from(row.get::<_, i64>(0)?)?;
+        let count = u64::try_from(row.get::<_, i64>(1)?)?;
+        let bucket = (63 - fanout.leading_zeros()) as usize;
+        if stats.fanout_histogram.len() <= bucket {
+            stats.fanout_histogram.resize(bucket + 1, 0);
+        }
+        stats.fanout_histogram[bucket] += count;
+        stats.refs += fanout * count;
+        stats.parents += count;
+        stats.max_fanout = stats.max_fanout.max(fanout);
+    }
+    // follow the path along the first child from a number of aliased roots
+    let roots = txn
+        .prepare_cached("SELECT DISTINCT block_id FROM aliases LIMIT 100")?
+        .query_map(NO_PARAMS, |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<i64>>>()?;
+    let mut depth_stmt = txn.prepare_cached(
+        r#"
+WITH RECURSIVE
+    path(id, depth) AS
+    (
+        SELECT ?, 0
+        UNION ALL
+        SELECT (SELECT MIN(child_id) FROM refs WHERE parent_id = path.id), depth + 1 FROM path
+        WHERE depth < 100000 AND EXISTS (SELECT 1 FROM refs WHERE parent_id = path.id)
+    )
+SELECT MAX(depth) FROM path;
+"#,
+    )?;
+    for root in roots {
+        let depth: i64 = depth_stmt.query_row(&[root], |row| row.get(0))?;
+        stats.depth_estimate = stats.depth_estimate.max(u64::try_from(depth)?);
+    }
+    Ok(stats)
+}
+
 fn get_or_create_id(txn: &Transaction, cid: impl ToSql) -> rusqlite::Result<i64> {
     let id = get_id(&txn, cid.to_sql()?)?;
     Ok(if let Some(id) = id {
@@ -201,76 +861,312 @@ fn get_or_create_id(txn: &Transaction, cid: impl ToSql) -> rusqlite::Result<i64>
     })
 }
 
+/// the query for the gc candidates, depending on whether refcount gc is enabled
+fn gc_candidates(refcount: bool) -> &'static str {
+    if refcount {
+        REFCOUNT_GC_CANDIDATES
+    } else {
+        GC_CANDIDATES
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn incremental_gc(
     txn: &Transaction,
     min_blocks: usize,
     max_duration: Duration,
     size_targets: SizeTargets,
+    low_watermark: SizeTargets,
+    draining: bool,
+    grace_cutoff: i64,
+    refcount: bool,
+    order: GcOrder,
     cache_tracker: &mut impl CacheTracker,
-) -> crate::Result<bool> {
+    removed: &mut Vec<CidBytes>,
+) -> crate::Result<GcStats> {
     // get the store stats from the stats table
     let mut stats = get_store_stats(txn)?;
-    // if we don't exceed any of the size targets, there is nothing to do
-    if !size_targets.exceeded(&stats) {
-        return Ok(true);
+    let mut gc_stats = GcStats::default();
+    // if we don't exceed any of the size targets, there is nothing to do, unless a previous gc
+    // stopped before reaching the low watermark
+    let started = size_targets.exceeded(&stats) || (draining && low_watermark.exceeded(&stats));
+    if !started {
+        return Ok(gc_stats);
     }
     // find all ids that have neither a parent nor are aliased
-    let mut id_query = txn.prepare_cached(
-        r#"
-WITH RECURSIVE
-    descendant_of(id) AS
-    (
-        SELECT block_id FROM aliases UNION SELECT block_id FROM temp_pins
-        UNION ALL
-        SELECT DISTINCT child_id FROM refs JOIN descendant_of ON descendant_of.id=refs.parent_id
-    )
-SELECT id FROM
-    cids
-WHERE
-    id NOT IN descendant_of;
-        "#,
-    )?;
+    let mut id_query = txn.prepare_cached(gc_candidates(refcount))?;
     // measure the time from the start.
     // min_blocks will ensure that we get some work done even if the id query takes too long
     let t0 = Instant::now();
     // log execution time of the non-interruptible query that computes the set of ids to delete
     let mut ids = log_execution_time("gc_id_query", Duration::from_secs(1), || {
         id_query
-            .query_map(NO_PARAMS, |row| row.get(0))?
+            .query_map(&[grace_cutoff], |row| row.get(0))?
             .collect::<rusqlite::Result<Vec<i64>>>()
     })?;
     // give the cache tracker the opportunity to sort the non-pinned ids by value
     cache_tracker.sort_ids(&mut ids);
-    let mut block_size_stmt =
-        txn.prepare_cached("SELECT LENGTH(block) FROM blocks WHERE block_id = ?")?;
-    let mut update_stats_stmt =
-        txn.prepare_cached("UPDATE stats SET count = count - 1, size = size - ?")?;
-    let mut delete_stmt = txn.prepare_cached("DELETE FROM cids WHERE id = ?")?;
+    order.sort(txn, &mut ids)?;
     let mut n = 0;
     for id in ids.iter() {
         if n >= min_blocks && t0.elapsed() > max_duration {
             break;
         }
-        if !size_targets.exceeded(&stats) {
+        if !low_watermark.exceeded(&stats) {
             break;
         }
-        trace!("deleting id {}", id);
-        let block_size: Option<i64> = block_size_stmt
-            .query_row(&[id], |row| row.get(0))
-            .optional()?;
-        if let Some(block_size) = block_size {
-            update_stats_stmt.execute(&[block_size])?;
+        if let Some(block_size) = delete_gc_candidate(txn, *id, removed)? {
             stats.count -= 1;
-            stats.size -= block_size as u64;
+            stats.size -= block_size;
+            gc_stats.blocks_deleted += 1;
+            gc_stats.bytes_freed += block_size;
         }
-        delete_stmt.execute(&[id])?;
         n += 1;
     }
     cache_tracker.delete_ids(&ids[0..n]);
-    Ok(n == ids.len() || !size_targets.exceeded(&stats))
+    if low_watermark.exceeded(&stats) {
+        gc_stats.candidates_remaining = (ids.len() - n) as u64;
+    }
+    Ok(gc_stats)
+}
+
+/// gc that collects the largest unpinned blocks first, until at least `bytes` bytes are freed.
+///
+/// Blocks of the same size are collected in the order given by the cache tracker.
+///
+/// Returns the number of bytes freed, which is smaller than `bytes` if there are not enough
+/// unpinned blocks.
+pub(crate) fn gc_bytes(
+    txn: &Transaction,
+    bytes: u64,
+    grace_cutoff: i64,
+    refcount: bool,
+    order: GcOrder,
+    cache_tracker: &mut impl CacheTracker,
+    removed: &mut Vec<CidBytes>,
+) -> crate::Result<u64> {
+    let mut ids = log_execution_time("gc_id_query", Duration::from_secs(1), || {
+        txn.prepare_cached(gc_candidates(refcount))?
+            .query_map(&[grace_cutoff], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<i64>>>()
+    })?;
+    cache_tracker.sort_ids(&mut ids);
+    let order = GcOrder {
+        largest_first: true,
+        ..order
+    };
+    order.sort(txn, &mut ids)?;
+    let mut freed = 0u64;
+    let mut deleted = Vec::new();
+    for id in ids {
+        if freed >= bytes {
+            break;
+        }
+        freed += delete_gc_candidate(txn, id, removed)?.unwrap_or_default();
+        deleted.push(id);
+    }
+    cache_tracker.delete_ids(&deleted);
+    Ok(freed)
+}
+
+/// how gc candidates are sorted after the cache tracker has sorted them
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct GcOrder {
+    /// least recently accessed blocks first, see [Config::with_access_tracking]
+    ///
+    /// [Config::with_access_tracking]: crate::Config::with_access_tracking
+    pub least_recently_used: bool,
+    /// largest blocks first, see [Config::with_gc_largest_first]
+    ///
+    /// [Config::with_gc_largest_first]: crate::Config::with_gc_largest_first
+    pub largest_first: bool,
+}
+
+impl GcOrder {
+    /// sort gc candidates, keeping the previous order where the criteria are equal.
+    ///
+    /// If both criteria are enabled, the size takes precedence.
+    fn sort(&self, txn: &Transaction, ids: &mut Vec<i64>) -> crate::Result<()> {
+        if self.least_recently_used {
+            sort_by_access(txn, ids)?;
+        }
+        if self.largest_first {
+            sort_by_size(txn, ids)?;
+        }
+        Ok(())
+    }
+}
+
+/// sort ids so the least recently used blocks come first. Blocks that were never accessed count
+/// as used when they were written, and ids without a block come first.
+///
+/// The times are read with a single join over all blocks, which is about as expensive as the
+/// query for the gc candidates.
+fn sort_by_access(txn: &Transaction, ids: &mut [i64]) -> crate::Result<()> {
+    let mut last_used = ids
+        .iter()
+        .map(|id| (*id, 0))
+        .collect::<FnvHashMap<i64, i64>>();
+    let mut stmt = txn.prepare_cached(
+        "SELECT block_id, COALESCE(accesses.time, inserted) FROM blocks LEFT JOIN accesses USING (block_id)",
+    )?;
+    let mut rows = stmt.query(NO_PARAMS)?;
+    while let Some(row) = rows.next()? {
+        if let Some(time) = last_used.get_mut(&row.get(0)?) {
+            *time = row.get(1)?;
+        }
+    }
+    ids.sort_by_key(|id| last_used[id]);
+    Ok(())
+}
+
+/// number of accesses to write with a single statement in record_accesses
+const RECORD_ACCESSES_BATCH: usize = 64;
+
+/// record the time of the last access of blocks, with one statement per batch of accesses
+///
+/// Blocks that were deleted in the meantime are ignored.
+pub(crate) fn record_accesses(
+    txn: &Transaction,
+    accesses: &FnvHashMap<i64, i64>,
+) -> crate::Result<()> {
+    let placeholders = vec!["(?,?)"; RECORD_ACCESSES_BATCH].join(",");
+    let mut stmt = txn.prepare_cached(&format!(
+        r#"
+REPLACE INTO accesses (block_id, time)
+    SELECT column1, column2 FROM (VALUES {})
+    WHERE column1 IN (SELECT id FROM cids)"#,
+        placeholders
+    ))?;
+    let accesses = accesses.iter().collect::<Vec<_>>();
+    for chunk in accesses.chunks(RECORD_ACCESSES_BATCH) {
+        // pad the last chunk by repeating an access, so we can always use the same statement
+        let params = (0..RECORD_ACCESSES_BATCH)
+            .map(|i| chunk[i.min(chunk.len() - 1)])
+            .flat_map(|(id, time)| vec![id as &dyn ToSql, time as &dyn ToSql])
+            .collect::<Vec<_>>();
+        stmt.execute(params)?;
+    }
+    Ok(())
+}
+
+/// sort ids so the largest blocks come first.
+///
+/// The sort is stable, so the previous order is kept for blocks of the same size.
+fn sort_by_size(txn: &Transaction, ids: &mut Vec<i64>) -> crate::Result<()> {
+    let mut block_size_stmt =
+        txn.prepare_cached("SELECT LENGTH(block) FROM blocks WHERE block_id = ?")?;
+    let mut sized = Vec::with_capacity(ids.len());
+    for id in ids.drain(..) {
+        let block_size: Option<i64> = block_size_stmt
+            .query_row(&[id], |row| row.get(0))
+            .optional()?;
+        sized.push((id, block_size.unwrap_or_default()));
+    }
+    sized.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+    ids.extend(sized.into_iter().map(|(id, _)| id));
+    Ok(())
+}
+
+/// delete the cid of a gc candidate and update the stats.
+///
+/// Returns the size of the block, if we had it.
+fn delete_gc_candidate(
+    txn: &Transaction,
+    id: i64,
+    removed: &mut Vec<CidBytes>,
+) -> crate::Result<Option<u64>> {
+    trace!("deleting id {}", id);
+    // keep the cid of children of aliased or protected blocks, so the link from a block that is
+    // kept without its descendants is not lost, and the cid of blocks with provider hints, so
+    // the block can be fetched again
+    let keep_cid: bool = txn
+        .prepare_cached(
+            r#"
+SELECT
+    EXISTS(SELECT 1 FROM refs JOIN aliases ON parent_id = block_id WHERE child_id = ?1) OR
+    EXISTS(SELECT 1 FROM refs JOIN protected ON parent_id = block_id WHERE child_id = ?1) OR
+    EXISTS(SELECT 1 FROM provider_hints WHERE block_id = ?1)
+"#,
+        )?
+        .query_row(&[id], |row| row.get(0))?;
+    delete_block_rows(txn, id, keep_cid, removed)
+}
+
+/// delete an unpinned block right away, including its data and its links.
+///
+/// The cid is kept if the block is linked from other blocks, so these links are not lost, or if
+/// it has provider hints. Returns the size of the block, if we had it.
+pub(crate) fn delete_block(
+    txn: &Transaction,
+    id: i64,
+    removed: &mut Vec<CidBytes>,
+) -> crate::Result<Option<u64>> {
+    let have: bool = txn
+        .prepare_cached("SELECT EXISTS(SELECT 1 FROM blocks WHERE block_id = ?)")?
+        .query_row(&[id], |row| row.get(0))?;
+    if !have {
+        return Ok(None);
+    }
+    let keep_cid: bool = txn
+        .prepare_cached(
+            r#"
+SELECT
+    EXISTS(SELECT 1 FROM refs WHERE child_id = ?1) OR
+    EXISTS(SELECT 1 FROM provider_hints WHERE block_id = ?1)
+"#,
+        )?
+        .query_row(&[id], |row| row.get(0))?;
+    let block_size = delete_block_rows(txn, id, keep_cid, removed)?;
+    if !keep_cid {
+        // delete the orphaned data right away, instead of in incremental_delete_orphaned
+        txn.prepare_cached("DELETE FROM blocks WHERE block_id = ?")?
+            .execute(&[id])?;
+    }
+    Ok(block_size)
+}
+
+/// delete a block, update the stats and remember its cid in `removed` if we had the data.
+///
+/// If `keep_cid` is true, the data, the checksum and the links of the block are deleted, but its
+/// cid is kept, so links to it from blocks that are kept are not lost. Otherwise the cid is deleted together
+/// with the links, and the data is left for [incremental_delete_orphaned].
+///
+/// Returns the size of the block, if we had it.
+fn delete_block_rows(
+    txn: &Transaction,
+    id: i64,
+    keep_cid: bool,
+    removed: &mut Vec<CidBytes>,
+) -> crate::Result<Option<u64>> {
+    let block_size: Option<i64> = txn
+        .prepare_cached("SELECT LENGTH(block) FROM blocks WHERE block_id = ?")?
+        .query_row(&[id], |row| row.get(0))
+        .optional()?;
+    if let Some(block_size) = block_size {
+        txn.prepare_cached("UPDATE stats SET count = count - 1, size = size - ?")?
+            .execute(&[block_size])?;
+        removed.push(
+            txn.prepare_cached("SELECT cid FROM cids WHERE id = ?")?
+                .query_row(&[id], |row| row.get(0))?,
+        );
+    }
+    if keep_cid {
+        // the data has to be deleted before the links, so the trash gets both
+        txn.prepare_cached("DELETE FROM blocks WHERE block_id = ?")?
+            .execute(&[id])?;
+        txn.prepare_cached("DELETE FROM checksums WHERE block_id = ?")?
+            .execute(&[id])?;
+        txn.prepare_cached("DELETE FROM refs WHERE parent_id = ?")?
+            .execute(&[id])?;
+    } else {
+        txn.prepare_cached("DELETE FROM cids WHERE id = ?")?
+            .execute(&[id])?;
+    }
+    Ok(block_size.map(|size| size as u64))
 }
 
-/// deletes the orphaned blocks.
+/// deletes the orphaned blocks, see [ORPHANED_BLOCKS].
 ///
 /// orphaned blocks are blocks from the blocks table that do not have a corresponding id in the
 /// cid table and in the other metadata table. They are unreachable.
@@ -280,18 +1176,18 @@ WHERE
 ///
 /// note that the execution time limit is not entirely accurate, because in many cases the cost of
 /// deleting blocks will only be fully felt when doing the commit of the transaction.
+///
+/// Returns the number of deleted and remaining orphaned blocks.
 pub(crate) fn incremental_delete_orphaned(
     txn: &Transaction,
     min_blocks: usize,
     max_duration: Duration,
-) -> rusqlite::Result<bool> {
+) -> rusqlite::Result<(u64, u64)> {
     let t0 = Instant::now();
     let ids: Vec<i64> = log_execution_time("determine_orphaned", Duration::from_secs(1), || {
-        txn.prepare_cached(
-            "SELECT block_id FROM blocks WHERE block_id NOT IN (SELECT id FROM cids)",
-        )?
-        .query_map(NO_PARAMS, |row| row.get(0))?
-        .collect::<rusqlite::Result<_>>()
+        txn.prepare_cached(ORPHANED_BLOCKS)?
+            .query_map(NO_PARAMS, |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()
     })?;
     let mut delete_stmt = txn.prepare_cached("DELETE FROM blocks WHERE block_id = ?")?;
     let mut n = 0;
@@ -309,12 +1205,163 @@ pub(crate) fn incremental_delete_orphaned(
         delete_stmt.execute(&[id])?;
         n += 1;
     }
-    Ok(n == ids.len())
+    Ok((n as u64, (ids.len() - n) as u64))
+}
+
+/// get the number of orphaned blocks, see [ORPHANED_BLOCKS]
+pub(crate) fn count_orphaned(txn: &Transaction) -> crate::Result<u64> {
+    let n: i64 = txn
+        .prepare_cached(&format!("SELECT COUNT(*) FROM ({})", ORPHANED_BLOCKS))?
+        .query_row(NO_PARAMS, |row| row.get(0))?;
+    Ok(u64::try_from(n)?)
+}
+
+/// increment the commit sequence number and return the new value
+pub(crate) fn next_commit_seq(txn: &Transaction) -> crate::Result<u64> {
+    txn.prepare_cached("UPDATE commit_seq SET seq = seq + 1")?
+        .execute(NO_PARAMS)?;
+    get_commit_seq(txn)
+}
+
+/// get the current commit sequence number
+pub(crate) fn get_commit_seq(txn: &Transaction) -> crate::Result<u64> {
+    let seq: i64 = txn
+        .prepare_cached("SELECT seq FROM commit_seq LIMIT 1")?
+        .query_row(NO_PARAMS, |row| row.get(0))?;
+    Ok(u64::try_from(seq)?)
+}
+
+/// log the cids of blocks deleted by gc and prune log entries older than the retention time
+pub(crate) fn log_evictions(
+    txn: &Transaction,
+    removed: &[CidBytes],
+    now: i64,
+    retention: Duration,
+) -> crate::Result<()> {
+    let mut insert = txn.prepare_cached("INSERT INTO evictions (cid, time) VALUES (?, ?)")?;
+    for cid in removed {
+        insert.execute(params![cid, now])?;
+    }
+    let cutoff = now.saturating_sub(i64::try_from(retention.as_millis())?);
+    txn.prepare_cached("DELETE FROM evictions WHERE time < ?")?
+        .execute(&[cutoff])?;
+    Ok(())
+}
+
+/// get the logged evictions, oldest first
+pub(crate) fn get_evictions<C: FromSql>(txn: &Transaction) -> crate::Result<Vec<(C, i64)>> {
+    Ok(txn
+        .prepare_cached("SELECT cid, time FROM evictions ORDER BY time, rowid")?
+        .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?)
+}
+
+/// name, old cid, new cid and time of an alias change
+pub(crate) type AliasChangeRow<C> = (Vec<u8>, Option<C>, Option<C>, i64);
+
+/// log the change of an alias to `new`, if it is a change, and prune the log to `max_entries`.
+///
+/// This must be called before the alias is changed.
+pub(crate) fn log_alias_change(
+    txn: &Transaction,
+    name: &[u8],
+    new: Option<&CidBytes>,
+    now: i64,
+    max_entries: usize,
+) -> crate::Result<()> {
+    let old = resolve::<CidBytes>(txn, name)?;
+    if old.as_ref() == new {
+        return Ok(());
+    }
+    txn.prepare_cached(
+        "INSERT INTO alias_history (name, old_cid, new_cid, time) VALUES (?, ?, ?, ?)",
+    )?
+    .execute(params![name, old, new, now])?;
+    txn.prepare_cached(
+        "DELETE FROM alias_history WHERE rowid <= (SELECT MAX(rowid) FROM alias_history) - ?",
+    )?
+    .execute(&[i64::try_from(max_entries)?])?;
+    Ok(())
+}
+
+/// get the logged alias changes, oldest first
+pub(crate) fn get_alias_history<C: FromSql>(
+    txn: &Transaction,
+) -> crate::Result<Vec<AliasChangeRow<C>>> {
+    Ok(txn
+        .prepare_cached("SELECT name, old_cid, new_cid, time FROM alias_history ORDER BY rowid")?
+        .query_map(NO_PARAMS, |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?)
+}
+
+/// get statistics about temp pins
+pub(crate) fn get_temp_pin_stats(txn: &Transaction) -> crate::Result<TempPinStats> {
+    let (pins, blocks, max_blocks_per_pin): (i64, i64, i64) = txn
+        .prepare_cached(
+            r#"
+SELECT COUNT(*), COALESCE(SUM(n), 0), COALESCE(MAX(n), 0) FROM
+    (SELECT COUNT(*) AS n FROM temp_pins GROUP BY id)
+"#,
+        )?
+        .query_row(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    Ok(TempPinStats {
+        pins: u64::try_from(pins)?,
+        blocks: u64::try_from(blocks)?,
+        max_blocks_per_pin: u64::try_from(max_blocks_per_pin)?,
+    })
+}
+
+/// check that the number of temp pins and the size of the given temp pin are within the limits
+///
+/// `expired` are the ids of temp pins whose handles have been dropped, but that are not deleted
+/// yet. They do not count towards the limit.
+pub(crate) fn check_temp_pin_limits(
+    txn: &Transaction,
+    alias: i64,
+    limits: TempPinLimits,
+    expired: &[i64],
+) -> crate::Result<()> {
+    if limits == TempPinLimits::max_value() {
+        return Ok(());
+    }
+    let pins: i64 = txn
+        .prepare_cached("SELECT COUNT(DISTINCT id) FROM temp_pins")?
+        .query_row(NO_PARAMS, |row| row.get(0))?;
+    let mut pins = u64::try_from(pins)?;
+    for id in expired {
+        let exists: bool = txn
+            .prepare_cached("SELECT EXISTS(SELECT 1 FROM temp_pins WHERE id = ?)")?
+            .query_row(&[id], |row| row.get(0))?;
+        if exists {
+            pins -= 1;
+        }
+    }
+    if pins > limits.pins {
+        return Err(crate::BlockStoreError::TooManyTempPins(pins));
+    }
+    let blocks: i64 = txn
+        .prepare_cached("SELECT COUNT(*) FROM temp_pins WHERE id = ?")?
+        .query_row(&[alias], |row| row.get(0))?;
+    let blocks = u64::try_from(blocks)?;
+    if blocks > limits.blocks_per_pin {
+        return Err(crate::BlockStoreError::TempPinTooLarge(blocks));
+    }
+    Ok(())
 }
 
-pub(crate) fn delete_temp_pin(txn: &Transaction, alias: i64) -> rusqlite::Result<()> {
+pub(crate) fn delete_temp_pin(txn: &Transaction, alias: i64, refcount: bool) -> crate::Result<()> {
+    let ids = if refcount {
+        get_temp_pin_ids(txn, "SELECT block_id FROM temp_pins WHERE id = ?", alias)?
+    } else {
+        Vec::new()
+    };
     txn.prepare_cached("DELETE FROM temp_pins WHERE id = ?")?
         .execute(&[alias])?;
+    for id in ids {
+        dec_refcount(txn, id)?;
+    }
     Ok(())
 }
 
@@ -324,34 +1371,25 @@ pub(crate) fn put_block<C: ToSql>(
     data: &[u8],
     links: impl IntoIterator<Item = C>,
     alias: Option<&AtomicI64>,
-) -> crate::Result<i64> {
+    now: i64,
+    refcount: bool,
+) -> crate::Result<(i64, bool)> {
     let id = get_or_create_id(&txn, &key)?;
+    // writing an existing block again restarts its gc grace period
     let block_exists = txn
-        .prepare_cached("SELECT 1 FROM blocks WHERE block_id = ?")?
-        .query_row(&[id], |_| Ok(()))
-        .optional()?
-        .is_some();
+        .prepare_cached("UPDATE blocks SET inserted = ? WHERE block_id = ?")?
+        .execute(&[now, id])?
+        > 0;
     // create a temporary alias for the block, even if it already exists
     if let Some(alias) = alias {
-        let alias_id = alias.load(Ordering::SeqCst);
-        if alias_id > 0 {
-            txn.prepare_cached("INSERT OR IGNORE INTO temp_pins (id, block_id) VALUES (?, ?)")?
-                .execute(&[alias_id, id])?;
-        } else {
-            // since we are not using an autoincrement column, this will reuse ids.
-            // I think this is safe, but is it really? deserves some thought.
-            let alias_id: i64 = txn
-                .prepare_cached("SELECT COALESCE(MAX(id), 1) + 1 FROM temp_pins")?
-                .query_row(NO_PARAMS, |row| row.get(0))?;
-            txn.prepare_cached("INSERT INTO temp_pins (id, block_id) VALUES (?, ?)")?
-                .execute(&[alias_id, id])?;
-            alias.store(alias_id, Ordering::SeqCst);
-        }
+        add_to_temp_pin(txn, alias, id, refcount)?;
     }
     if !block_exists {
+        // the block might already be live, e.g. because it was aliased before we had it
+        let live = refcount && get_refcount(txn, id)? > 0;
         // add the block itself
-        txn.prepare_cached("INSERT INTO blocks (block_id, block) VALUES (?, ?)")?
-            .execute(params![id, &data])?;
+        txn.prepare_cached("INSERT INTO blocks (block_id, block, inserted) VALUES (?, ?, ?)")?
+            .execute(params![id, &data, now])?;
 
         // update the stats
         txn.prepare_cached("UPDATE stats SET count = count + 1, size = size + ?")?
@@ -363,139 +1401,1211 @@ pub(crate) fn put_block<C: ToSql>(
         for link in links {
             let child_id: i64 = get_or_create_id(&txn, link)?;
             insert_ref.execute(params![id, child_id])?;
+            if live {
+                inc_refcount(txn, child_id)?;
+            }
         }
     }
-    Ok(id)
+    Ok((id, !block_exists))
 }
 
-/// Get a block
-pub(crate) fn get_block(
+/// add an id to a temp pin, allocating an id for the temp pin if it does not have one yet
+fn add_to_temp_pin(
     txn: &Transaction,
-    cid: impl ToSql,
-) -> crate::Result<Option<(i64, Vec<u8>)>> {
-    let id = get_id(&txn, cid)?;
-    Ok(if let Some(id) = id {
-        txn.prepare_cached("SELECT block FROM blocks WHERE block_id = ?")?
-            .query_row(&[id], |row| row.get(0))
-            .optional()?
-            .map(|b| (id, b))
+    alias: &AtomicI64,
+    id: i64,
+    refcount: bool,
+) -> crate::Result<()> {
+    let alias_id = alias.load(Ordering::SeqCst);
+    let added = if alias_id > 0 {
+        txn.prepare_cached("INSERT OR IGNORE INTO temp_pins (id, block_id) VALUES (?, ?)")?
+            .execute(&[alias_id, id])?
+            > 0
     } else {
-        None
+        // since we are not using an autoincrement column, this will reuse ids.
+        // I think this is safe, but is it really? deserves some thought.
+        let alias_id: i64 = txn
+            .prepare_cached("SELECT COALESCE(MAX(id), 1) + 1 FROM temp_pins")?
+            .query_row(NO_PARAMS, |row| row.get(0))?;
+        txn.prepare_cached("INSERT INTO temp_pins (id, block_id) VALUES (?, ?)")?
+            .execute(&[alias_id, id])?;
+        alias.store(alias_id, Ordering::SeqCst);
+        true
+    };
+    if refcount && added {
+        inc_refcount(txn, id)?;
+    }
+    Ok(())
+}
+
+/// add cids to a temp pin, no matter if we have the blocks or not
+pub(crate) fn extend_temp_pin<C: ToSql>(
+    txn: &Transaction,
+    alias: &AtomicI64,
+    keys: impl IntoIterator<Item = C>,
+    refcount: bool,
+) -> crate::Result<()> {
+    for key in keys {
+        let id = get_or_create_id(txn, key)?;
+        add_to_temp_pin(txn, alias, id, refcount)?;
+    }
+    Ok(())
+}
+
+/// fast checksum of block data. This is only for detecting corruption, not for security.
+pub(crate) fn checksum(data: &[u8]) -> i64 {
+    let mut hasher = fnv::FnvHasher::default();
+    hasher.write(data);
+    hasher.finish() as i64
+}
+
+/// protect cids from gc, without protecting their descendants
+pub(crate) fn protect<C: ToSql>(
+    txn: &Transaction,
+    keys: impl IntoIterator<Item = C>,
+) -> crate::Result<()> {
+    for key in keys {
+        let id = get_or_create_id(txn, key)?;
+        txn.prepare_cached("INSERT OR IGNORE INTO protected (block_id) VALUES (?)")?
+            .execute(&[id])?;
+    }
+    Ok(())
+}
+
+/// remove the protection of cids, see [protect]
+pub(crate) fn unprotect<C: ToSql>(
+    txn: &Transaction,
+    keys: impl IntoIterator<Item = C>,
+) -> crate::Result<()> {
+    for key in keys {
+        txn.prepare_cached(
+            "DELETE FROM protected WHERE block_id = (SELECT id FROM cids WHERE cid = ?)",
+        )?
+        .execute(&[key])?;
+    }
+    Ok(())
+}
+
+/// get all protected cids
+pub(crate) fn get_protected<C: FromSql>(txn: &Transaction) -> crate::Result<Vec<C>> {
+    Ok(txn
+        .prepare_cached("SELECT cid FROM protected JOIN cids ON block_id = id ORDER BY id")?
+        .query_map(NO_PARAMS, |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<C>>>()?)
+}
+
+/// Store the checksum of the data of a block
+pub(crate) fn set_checksum(txn: &Transaction, id: i64, data: &[u8]) -> crate::Result<()> {
+    txn.prepare_cached("REPLACE INTO checksums (block_id, checksum) VALUES (?, ?)")?
+        .execute(&[id, checksum(data)])?;
+    Ok(())
+}
+
+/// Record the original form of a cid that was normalized
+pub(crate) fn set_original_cid(txn: &Transaction, id: i64, cid: &[u8]) -> crate::Result<()> {
+    txn.prepare_cached("REPLACE INTO cid_originals (id, cid) VALUES (?, ?)")?
+        .execute(params![id, cid])?;
+    Ok(())
+}
+
+/// Get the original form of a cid that was normalized
+pub(crate) fn get_original_cid<C: FromSql>(
+    txn: &Transaction,
+    cid: impl ToSql,
+) -> crate::Result<Option<C>> {
+    Ok(txn
+        .prepare_cached(
+            "SELECT cid_originals.cid FROM cid_originals, cids WHERE cid_originals.id = cids.id AND cids.cid = ?",
+        )?
+        .query_row(&[cid], |row| row.get(0))
+        .optional()?)
+}
+
+/// Set a metadata value for a block
+pub(crate) fn set_metadata(
+    txn: &Transaction,
+    id: i64,
+    key: &str,
+    value: &[u8],
+) -> crate::Result<()> {
+    txn.prepare_cached("REPLACE INTO metadata (block_id, key, value) VALUES (?, ?, ?)")?
+        .execute(params![id, key, value])?;
+    Ok(())
+}
+
+/// Get a metadata value for a block
+pub(crate) fn get_metadata(
+    txn: &Transaction,
+    cid: impl ToSql,
+    key: &str,
+) -> crate::Result<Option<Vec<u8>>> {
+    Ok(txn
+        .prepare_cached(
+            "SELECT value FROM metadata, cids WHERE metadata.block_id = cids.id AND cids.cid = ? AND metadata.key = ?",
+        )?
+        .query_row(params![cid, key], |row| row.get(0))
+        .optional()?)
+}
+
+/// Add provider hints for a cid, retaining only the `max` most recent hints
+pub(crate) fn add_provider_hints(
+    txn: &Transaction,
+    cid: impl ToSql,
+    peers: impl IntoIterator<Item = impl AsRef<[u8]>>,
+    max: usize,
+) -> crate::Result<()> {
+    let id = get_or_create_id(txn, cid)?;
+    let mut insert =
+        txn.prepare_cached("REPLACE INTO provider_hints (block_id, peer) VALUES (?, ?)")?;
+    for peer in peers {
+        insert.execute(params![id, peer.as_ref()])?;
+    }
+    txn.prepare_cached(
+        r#"
+DELETE FROM provider_hints WHERE block_id = ?1 AND rowid NOT IN
+    (SELECT rowid FROM provider_hints WHERE block_id = ?1 ORDER BY rowid DESC LIMIT ?2)
+"#,
+    )?
+    .execute(params![id, i64::try_from(max)?])?;
+    Ok(())
+}
+
+/// Delete all provider hints for a cid, returning the number of deleted hints
+pub(crate) fn delete_provider_hints(txn: &Transaction, cid: impl ToSql) -> crate::Result<usize> {
+    Ok(txn
+        .prepare_cached(
+            "DELETE FROM provider_hints WHERE block_id IN (SELECT id FROM cids WHERE cid = ?)",
+        )?
+        .execute(&[cid])?)
+}
+
+/// Get the provider hints for a cid, most recent first
+pub(crate) fn get_provider_hints(
+    txn: &Transaction,
+    cid: impl ToSql,
+) -> crate::Result<Vec<Vec<u8>>> {
+    Ok(txn
+        .prepare_cached(
+            r#"
+SELECT peer FROM provider_hints, cids WHERE provider_hints.block_id = cids.id AND cids.cid = ?
+ORDER BY provider_hints.rowid DESC
+"#,
+        )?
+        .query_map(&[cid], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<Vec<u8>>>>()?)
+}
+
+/// aliases that keep a cid alive, with the aliased root, which is the cid itself or an ancestor
+///
+/// direct aliases only keep the cid itself alive, not its descendants.
+pub(crate) fn get_pinning_aliases<C: ToSql + FromSql>(
+    txn: &Transaction,
+    cid: C,
+) -> crate::Result<Vec<(Vec<u8>, C)>> {
+    Ok(txn
+        .prepare_cached(
+            r#"
+WITH RECURSIVE
+    ancestor_of(id) AS
+    (
+        SELECT id FROM cids WHERE cid = ?1
+        UNION
+        SELECT parent_id FROM refs JOIN ancestor_of ON ancestor_of.id=refs.child_id
+    )
+SELECT name, cid FROM ancestor_of
+    JOIN aliases ON ancestor_of.id = aliases.block_id
+    JOIN cids ON ancestor_of.id = cids.id
+WHERE aliases.recursive OR cids.cid = ?1;
+"#,
+        )?
+        .query_map(&[cid], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?)
+}
+
+/// temp pins that keep a cid alive, with the pinned root, which is the cid itself or an ancestor
+pub(crate) fn get_pinning_temp_pins<C: ToSql + FromSql>(
+    txn: &Transaction,
+    cid: C,
+) -> crate::Result<Vec<(i64, C)>> {
+    Ok(txn
+        .prepare_cached(
+            r#"
+WITH RECURSIVE
+    ancestor_of(id) AS
+    (
+        SELECT id FROM cids WHERE cid = ?
+        UNION
+        SELECT parent_id FROM refs JOIN ancestor_of ON ancestor_of.id=refs.child_id
+    )
+SELECT temp_pins.id, cid FROM ancestor_of
+    JOIN temp_pins ON ancestor_of.id = temp_pins.block_id
+    JOIN cids ON ancestor_of.id = cids.id;
+"#,
+        )?
+        .query_map(&[cid], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?)
+}
+
+/// direct parents of a cid that are themselves kept alive by a recursive alias or temp pin
+pub(crate) fn get_live_parents<C: ToSql + FromSql>(
+    txn: &Transaction,
+    cid: C,
+) -> crate::Result<Vec<C>> {
+    Ok(txn
+        .prepare_cached(
+            r#"
+WITH RECURSIVE
+    parents(id) AS
+    (
+        SELECT parent_id FROM refs JOIN cids ON refs.child_id = cids.id WHERE cids.cid = ?
+    ),
+    ancestor_of(parent, id) AS
+    (
+        SELECT id, id FROM parents
+        UNION
+        SELECT ancestor_of.parent, refs.parent_id FROM refs
+            JOIN ancestor_of ON ancestor_of.id=refs.child_id
+    )
+SELECT DISTINCT cid FROM ancestor_of JOIN cids ON ancestor_of.parent = cids.id
+WHERE ancestor_of.id IN (
+    SELECT block_id FROM aliases WHERE recursive UNION SELECT block_id FROM temp_pins
+);
+"#,
+        )?
+        .query_map(&[cid], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?)
+}
+
+/// number of cids to look up with a single statement in get_blocks
+const GET_BLOCKS_BATCH: usize = 64;
+
+/// id, data and checksum, if any, of a block
+pub(crate) type BlockRow = (i64, Vec<u8>, Option<i64>);
+
+/// Get multiple blocks, with one statement per batch of cids
+///
+/// Returns the id, data and checksum, if any, of all blocks we have, keyed by cid.
+pub(crate) fn get_blocks(
+    txn: &Transaction,
+    cids: &[CidBytes],
+) -> crate::Result<FnvHashMap<CidBytes, BlockRow>> {
+    let mut result = FnvHashMap::default();
+    let placeholders = vec!["?"; GET_BLOCKS_BATCH].join(",");
+    let mut stmt = txn.prepare_cached(&format!(
+        r#"
+SELECT cid, id, block, checksum FROM cids
+    JOIN blocks ON id = blocks.block_id
+    LEFT JOIN checksums ON id = checksums.block_id
+WHERE cid IN ({})"#,
+        placeholders
+    ))?;
+    for chunk in cids.chunks(GET_BLOCKS_BATCH) {
+        // pad the last chunk by repeating a cid, so we can always use the same statement
+        let params = (0..GET_BLOCKS_BATCH)
+            .map(|i| &chunk[i.min(chunk.len() - 1)] as &dyn ToSql)
+            .collect::<Vec<_>>();
+        let mut rows = stmt.query(params)?;
+        while let Some(row) = rows.next()? {
+            result.insert(row.get(0)?, (row.get(1)?, row.get(2)?, row.get(3)?));
+        }
+    }
+    Ok(result)
+}
+
+/// get the id and size of a block, without reading the data, if we have it
+pub(crate) fn get_block_len(
+    txn: &Transaction,
+    cid: impl ToSql,
+) -> crate::Result<Option<(i64, usize)>> {
+    Ok(txn
+        .prepare_cached(
+            "SELECT id, LENGTH(block) FROM cids JOIN blocks ON id = block_id WHERE cid = ?",
+        )?
+        .query_row(&[cid], |row| {
+            Ok((row.get(0)?, row.get::<_, i64>(1)? as usize))
+        })
+        .optional()?)
+}
+
+/// Check if we have a block
+pub(crate) fn has_block(txn: &Transaction, cid: impl ToSql) -> crate::Result<bool> {
+    Ok(txn
+        .prepare_cached(HAS_BLOCK)?
+        .query_row(&[cid], |_| Ok(()))
+        .optional()?
+        .is_some())
+}
+
+/// Check if we have a cid
+pub(crate) fn has_cid(txn: &Transaction, cid: impl ToSql) -> crate::Result<bool> {
+    Ok(txn
+        .prepare_cached("SELECT 1 FROM cids WHERE cids.cid = ?")?
+        .query_row(&[cid], |_| Ok(()))
+        .optional()?
+        .is_some())
+}
+
+/// get the descendants of a cid.
+/// This just uses the refs table, so it does not ensure that we actually have data for each cid.
+/// The value itself is included.
+pub(crate) fn get_descendants<C: ToSql + FromSql>(
+    txn: &Transaction,
+    cid: C,
+) -> crate::Result<Vec<C>> {
+    let res = txn
+        .prepare_cached(DESCENDANTS)?
+        .query_map(&[cid], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<C>>>()?;
+    Ok(res)
+}
+
+/// get the descendants of a cid up to `max_depth` levels below it, including the cid itself.
+///
+/// A block that is reachable on paths of different lengths is visited once per depth.
+pub(crate) fn get_descendants_limited<C: ToSql + FromSql>(
+    txn: &Transaction,
+    cid: C,
+    max_depth: u32,
+) -> crate::Result<Vec<C>> {
+    let res = txn
+        .prepare_cached(
+            r#"
+WITH RECURSIVE
+    descendant_of(id, depth) AS
+    (
+        SELECT id, 0 FROM cids WHERE cid = ?
+        UNION
+        SELECT child_id, depth + 1 FROM refs JOIN descendant_of ON descendant_of.id=refs.parent_id
+        WHERE depth < ?
+    ),
+    descendant_ids as (
+        SELECT DISTINCT id FROM descendant_of
+    )
+SELECT cid from cids JOIN descendant_ids ON cids.id = descendant_ids.id;
+"#,
+        )?
+        .query_map(params![cid, max_depth], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<C>>>()?;
+    Ok(res)
+}
+
+/// cid, minimum depth and size of a block of a manifest
+pub(crate) type ManifestRow<C> = (C, u32, Option<i64>);
+
+/// get the descendants of a cid, including the cid itself, with their minimum depth below the
+/// cid and their size if we have them, ordered by depth and cid.
+pub(crate) fn get_manifest<C: ToSql + FromSql>(
+    txn: &Transaction,
+    cid: C,
+) -> crate::Result<Vec<ManifestRow<C>>> {
+    Ok(txn
+        .prepare_cached(
+            r#"
+WITH RECURSIVE
+    descendant_of(id, depth) AS
+    (
+        SELECT id, 0 FROM cids WHERE cid = ?
+        UNION
+        SELECT child_id, depth + 1 FROM refs JOIN descendant_of ON descendant_of.id=refs.parent_id
+    ),
+    min_depth(id, depth) AS (
+        SELECT id, MIN(depth) FROM descendant_of GROUP BY id
+    )
+SELECT cid, depth, LENGTH(block) FROM min_depth
+    JOIN cids ON cids.id = min_depth.id
+    LEFT JOIN blocks ON blocks.block_id = min_depth.id
+ORDER BY depth, cid;
+"#,
+        )?
+        .query_map(&[cid], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<rusqlite::Result<_>>()?)
+}
+
+/// get the cids that are reachable from the cid `from`, but not from the cid `except`
+pub(crate) fn get_dag_diff<C: ToSql + FromSql>(
+    txn: &Transaction,
+    from: &C,
+    except: &C,
+) -> crate::Result<Vec<C>> {
+    Ok(txn
+        .prepare_cached(
+            r#"
+WITH RECURSIVE
+    descendant_of(id) AS
+    (
+        SELECT id FROM cids WHERE cid = ?
+        UNION
+        SELECT child_id FROM refs JOIN descendant_of ON descendant_of.id=refs.parent_id
+    ),
+    except_descendant_of(id) AS
+    (
+        SELECT id FROM cids WHERE cid = ?
+        UNION
+        SELECT child_id FROM refs JOIN except_descendant_of ON except_descendant_of.id=refs.parent_id
+    ),
+    diff(id) AS
+    (
+        SELECT id FROM descendant_of EXCEPT SELECT id FROM except_descendant_of
+    )
+SELECT cid FROM diff JOIN cids ON cids.id = diff.id;
+"#,
+        )?
+        .query_map(&[from, except], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<C>>>()?)
+}
+
+/// get the number and total size of the blocks we have of the dag below a cid
+pub(crate) fn get_dag_size(txn: &Transaction, cid: impl ToSql) -> crate::Result<StoreStats> {
+    let (count, size): (i64, i64) = txn
+        .prepare_cached(
+            r#"
+WITH RECURSIVE
+    descendant_of(id) AS
+    (
+        SELECT id FROM cids WHERE cid = ?
+        UNION
+        SELECT child_id FROM refs JOIN descendant_of ON descendant_of.id=refs.parent_id
+    )
+SELECT COUNT(block_id), COALESCE(SUM(LENGTH(block)), 0) FROM descendant_of
+    JOIN blocks ON blocks.block_id = descendant_of.id;
+"#,
+        )?
+        .query_row(&[cid], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    Ok(StoreStats {
+        count: u64::try_from(count)?,
+        size: u64::try_from(size)?,
+    })
+}
+
+/// get statistics about aliases, with the aliases starting with one of `system_prefixes`
+/// reported separately.
+pub(crate) fn get_alias_stats(
+    txn: &Transaction,
+    system_prefixes: &[Vec<u8>],
+) -> crate::Result<AliasStats> {
+    let aliases: i64 = txn
+        .prepare_cached("SELECT COUNT(*) FROM aliases")?
+        .query_row(NO_PARAMS, |row| row.get(0))?;
+    let mut stats = AliasStats {
+        aliases: u64::try_from(aliases)?,
+        ..AliasStats::default()
+    };
+    if system_prefixes.is_empty() {
+        return Ok(stats);
+    }
+    let is_system = (1..=system_prefixes.len())
+        .map(|i| format!("substr(name, 1, length(?{0})) = ?{0}", i))
+        .collect::<Vec<_>>()
+        .join(" OR ");
+    let (system_aliases, count, size): (i64, i64, i64) = txn
+        .prepare_cached(&format!(
+            r#"
+WITH RECURSIVE
+    system_aliases(block_id, recursive) AS (
+        SELECT block_id, recursive FROM aliases WHERE {}
+    ),
+    -- only the descendants of recursive aliases are protected
+    descendant_of(id, recursive) AS (
+        SELECT block_id, recursive FROM system_aliases
+        UNION
+        SELECT child_id, 1 FROM refs JOIN descendant_of ON descendant_of.id=refs.parent_id
+        WHERE descendant_of.recursive
+    ),
+    descendant_ids(id) AS (
+        SELECT DISTINCT id FROM descendant_of
+    )
+SELECT
+    (SELECT COUNT(*) FROM system_aliases),
+    COUNT(block_id),
+    COALESCE(SUM(LENGTH(block)), 0)
+FROM descendant_ids JOIN blocks ON blocks.block_id = descendant_ids.id;
+"#,
+            is_system
+        ))?
+        .query_row(system_prefixes, |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?;
+    stats.system_aliases = u64::try_from(system_aliases)?;
+    stats.system_pinned = StoreStats {
+        count: u64::try_from(count)?,
+        size: u64::try_from(size)?,
+    };
+    Ok(stats)
+}
+
+/// get the set of descendants of an id for which we do not have the data yet.
+/// The value itself is included.
+/// It is safe to call this method for a cid we don't have yet.
+pub(crate) fn get_missing_blocks<C: ToSql + FromSql>(
+    txn: &Transaction,
+    cid: C,
+) -> crate::Result<Vec<C>> {
+    let id = get_or_create_id(&txn, cid)?;
+    let res = txn
+        .prepare_cached(MISSING_BLOCKS)?
+        .query_map(&[id], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<C>>>()?;
+    Ok(res)
+}
+
+/// get up to `limit` descendants of a cid, including the cid itself, for which we do not have
+/// the data, with a depth of at most `max_depth` below the cid.
+///
+/// The dag is walked breadth first and the walk stops as soon as `limit` cids are found.
+pub(crate) fn get_missing_blocks_limited<C: ToSql + FromSql>(
+    txn: &Transaction,
+    cid: C,
+    limit: usize,
+    max_depth: u32,
+) -> crate::Result<Vec<C>> {
+    let id = match get_id(txn, &cid)? {
+        Some(id) => id,
+        None => return Ok(if limit > 0 { vec![cid] } else { Vec::new() }),
+    };
+    let res = txn
+        .prepare_cached(
+            r#"
+WITH RECURSIVE
+    descendant_of(id, depth) AS (
+        SELECT ?1, 0
+        UNION
+        SELECT child_id, depth + 1 FROM refs JOIN descendant_of ON descendant_of.id=refs.parent_id
+        WHERE depth < ?2
+    )
+SELECT DISTINCT cid FROM descendant_of
+    JOIN cids ON cids.id = descendant_of.id
+    LEFT JOIN blocks ON descendant_of.id = blocks.block_id
+WHERE blocks.block_id IS NULL
+LIMIT ?3
+"#,
+        )?
+        .query_map(params![id, max_depth, limit as i64], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<C>>>()?;
+    Ok(res)
+}
+
+/// true if we have the data for a cid and all its descendants.
+///
+/// The recursive query is evaluated lazily, so this stops at the first missing block.
+pub(crate) fn is_complete(txn: &Transaction, cid: impl ToSql) -> crate::Result<bool> {
+    let id = match get_id(txn, cid)? {
+        Some(id) => id,
+        None => return Ok(false),
+    };
+    let missing = txn
+        .prepare_cached(
+            r#"
+WITH RECURSIVE
+    descendant_of(id) AS (
+        SELECT ?
+        UNION
+        SELECT child_id FROM refs JOIN descendant_of ON descendant_of.id=refs.parent_id
+    )
+SELECT 1 FROM descendant_of LEFT JOIN blocks ON descendant_of.id = blocks.block_id
+WHERE blocks.block_id IS NULL LIMIT 1
+"#,
+        )?
+        .query_row(&[id], |_| Ok(()))
+        .optional()?;
+    Ok(missing.is_none())
+}
+
+/// true if a cid is protected from gc, i.e. reachable from a recursive alias or a temp pin, or
+/// directly aliased or protected.
+///
+/// This is the reverse of the reachability in [GC_CANDIDATES]: instead of walking down from all
+/// roots, it walks up from the cid and stops at the first root. In refcount gc mode, the
+/// refcounts table is consulted instead.
+pub(crate) fn is_pinned(txn: &Transaction, cid: impl ToSql, refcount: bool) -> crate::Result<bool> {
+    let id = match get_id(txn, cid)? {
+        Some(id) => id,
+        None => return Ok(false),
+    };
+    if refcount {
+        let pinned = txn
+            .prepare_cached(
+                r#"
+SELECT 1 WHERE
+    ?1 IN (SELECT block_id FROM refcounts WHERE block_id = ?1) OR
+    ?1 IN (SELECT block_id FROM aliases WHERE block_id = ?1) OR
+    ?1 IN (SELECT block_id FROM protected WHERE block_id = ?1)
+"#,
+            )?
+            .query_row(&[id], |_| Ok(()))
+            .optional()?;
+        return Ok(pinned.is_some());
+    }
+    let root = txn
+        .prepare_cached(
+            r#"
+WITH RECURSIVE
+    ancestor_of(id) AS (
+        SELECT ?1
+        UNION
+        SELECT parent_id FROM refs JOIN ancestor_of ON ancestor_of.id=refs.child_id
+    )
+SELECT 1 FROM ancestor_of
+WHERE
+    id IN (SELECT block_id FROM aliases WHERE recursive OR block_id = ?1) OR
+    id IN (SELECT block_id FROM temp_pins) OR
+    id IN (SELECT block_id FROM protected WHERE block_id = ?1)
+LIMIT 1
+"#,
+        )?
+        .query_row(&[id], |_| Ok(()))
+        .optional()?;
+    Ok(root.is_some())
+}
+
+/// get the descendants of a cid, including the cid itself, together with whether we have the
+/// data for each of them.
+pub(crate) fn get_have_status<C: ToSql + FromSql>(
+    txn: &Transaction,
+    cid: C,
+) -> crate::Result<Vec<(C, bool)>> {
+    let res = txn
+        .prepare_cached(
+            r#"
+WITH RECURSIVE
+    descendant_of(id) AS
+    (
+        SELECT id FROM cids WHERE cid = ?
+        UNION
+        SELECT child_id FROM refs JOIN descendant_of ON descendant_of.id=refs.parent_id
+    )
+SELECT cid, blocks.block_id IS NOT NULL FROM descendant_of
+    JOIN cids ON cids.id = descendant_of.id
+    LEFT JOIN blocks ON blocks.block_id = descendant_of.id;
+"#,
+        )?
+        .query_map(&[cid], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<(C, bool)>>>()?;
+    Ok(res)
+}
+
+pub(crate) fn alias(
+    txn: &Transaction,
+    name: &[u8],
+    key: Option<&CidBytes>,
+    recursive: bool,
+    refcount: bool,
+) -> crate::Result<()> {
+    let old: Option<(i64, bool)> = if refcount {
+        txn.prepare_cached("SELECT block_id, recursive FROM aliases WHERE name = ?")?
+            .query_row(&[name], |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional()?
+    } else {
+        None
+    };
+    if let Some(key) = key {
+        let id = get_or_create_id(txn, key)?;
+        txn.prepare_cached("REPLACE INTO aliases (name, block_id, recursive) VALUES (?, ?, ?)")?
+            .execute(params![name, id, recursive])?;
+        // increment first, so blocks that are in both dags stay live
+        if refcount && recursive {
+            inc_refcount(txn, id)?;
+        }
+    } else {
+        txn.prepare_cached("DELETE FROM aliases WHERE name = ?")?
+            .execute(&[name])?;
+    }
+    if let Some((old_id, true)) = old {
+        dec_refcount(txn, old_id)?;
+    }
+    Ok(())
+}
+
+/// count the descendants of an id, including the id itself, for which we do not have the data.
+fn count_missing_blocks(txn: &Transaction, id: i64) -> crate::Result<i64> {
+    Ok(txn
+        .prepare_cached(
+            r#"
+WITH RECURSIVE
+    descendant_of(id) AS (
+        SELECT ?
+        UNION
+        SELECT child_id FROM refs JOIN descendant_of ON descendant_of.id=refs.parent_id
+    )
+SELECT COUNT(*) FROM descendant_of LEFT JOIN blocks ON descendant_of.id = blocks.block_id WHERE blocks.block_id IS NULL
+"#,
+        )?
+        .query_row(&[id], |row| row.get(0))?)
+}
+
+/// get all aliases that point to a dag with missing blocks, together with their root and the
+/// number of missing blocks. For direct aliases, only the root itself is considered.
+pub(crate) fn incomplete_pins<C: FromSql>(
+    txn: &Transaction,
+) -> crate::Result<Vec<(Vec<u8>, C, u64)>> {
+    let aliases = txn
+        .prepare_cached(
+            r#"
+SELECT name, id, cid, recursive, blocks.block_id IS NOT NULL FROM aliases
+    JOIN cids ON id = aliases.block_id
+    LEFT JOIN blocks ON id = blocks.block_id
+"#,
+        )?
+        .query_map(NO_PARAMS, |row| {
+            Ok((
+                row.get::<_, Vec<u8>>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, C>(2)?,
+                row.get::<_, bool>(3)?,
+                row.get::<_, bool>(4)?,
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut res = Vec::new();
+    for (name, id, cid, recursive, has_root) in aliases {
+        let missing = if recursive {
+            count_missing_blocks(txn, id)?
+        } else {
+            i64::from(!has_root)
+        };
+        if missing > 0 {
+            res.push((name, cid, u64::try_from(missing)?));
+        }
+    }
+    Ok(res)
+}
+
+/// get all aliases whose root we do not have, together with their root.
+///
+/// Since links are only known for blocks we have, such an alias can not protect any other block.
+pub(crate) fn dead_pins<C: FromSql>(txn: &Transaction) -> crate::Result<Vec<(Vec<u8>, C)>> {
+    Ok(txn
+        .prepare_cached(
+            r#"
+SELECT name, cid FROM aliases
+    JOIN cids ON id = aliases.block_id
+WHERE aliases.block_id NOT IN (SELECT block_id FROM blocks)
+ORDER BY name
+"#,
+        )?
+        .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?)
+}
+
+/// schema name under which snapshots are attached, see [attach_snapshot]
+const SNAPSHOT: &str = "block_store_snapshot";
+
+/// attach a snapshot of the database read only, so it can be compared with the live database.
+///
+/// This can not be done in a transaction, and must be undone with [detach_snapshot].
+pub(crate) fn attach_snapshot(conn: &Connection, path: &Path) -> crate::Result<()> {
+    let path = path
+        .to_str()
+        .ok_or_else(|| anyhow::anyhow!("snapshot path is not valid utf8"))?;
+    // in an sqlite uri, ? and # end the path, and % starts an escape
+    let path = path
+        .replace('%', "%25")
+        .replace('?', "%3f")
+        .replace('#', "%23");
+    conn.execute(
+        &format!("ATTACH DATABASE ? AS {}", SNAPSHOT),
+        &[format!("file:{}?mode=ro", path)],
+    )?;
+    let version: u32 = conn.query_row(
+        &format!("PRAGMA {}.user_version", SNAPSHOT),
+        NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    if version != 1 {
+        detach_snapshot(conn)?;
+        return Err(anyhow::anyhow!("unsupported snapshot version {}", version).into());
+    }
+    Ok(())
+}
+
+pub(crate) fn detach_snapshot(conn: &Connection) -> crate::Result<()> {
+    conn.execute(&format!("DETACH DATABASE {}", SNAPSHOT), NO_PARAMS)?;
+    Ok(())
+}
+
+/// changes of an alias between a snapshot and the live database
+pub(crate) type AliasDiffRow<C> = (Vec<u8>, Option<C>, Option<C>);
+
+/// added blocks, removed blocks and changed aliases, see [snapshot_diff]
+pub(crate) type SnapshotDiffRows<C> = (Vec<C>, Vec<C>, Vec<AliasDiffRow<C>>);
+
+/// compare the live database with an attached snapshot.
+///
+/// Returns the cids of blocks that were added and removed since the snapshot, and the aliases
+/// that were changed, with their root in the snapshot and their live root.
+pub(crate) fn snapshot_diff<C: FromSql>(txn: &Transaction) -> crate::Result<SnapshotDiffRows<C>> {
+    let blocks = |from: &str, to: &str| -> crate::Result<Vec<C>> {
+        Ok(txn
+            .prepare(&format!(
+                r#"
+SELECT cid FROM {from}.cids JOIN {from}.blocks ON id = block_id
+WHERE cid NOT IN (SELECT cid FROM {to}.cids JOIN {to}.blocks ON id = block_id)
+ORDER BY cid
+"#,
+                from = from,
+                to = to
+            ))?
+            .query_map(NO_PARAMS, |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?)
+    };
+    let added = blocks("main", SNAPSHOT)?;
+    let removed = blocks(SNAPSHOT, "main")?;
+    let aliases = txn
+        .prepare(&format!(
+            r#"
+SELECT names.name, old.cid, new.cid FROM
+    (SELECT name FROM main.aliases UNION SELECT name FROM {snapshot}.aliases) AS names
+    LEFT JOIN (
+        SELECT name, cid FROM {snapshot}.aliases JOIN {snapshot}.cids ON id = block_id
+    ) AS old ON old.name = names.name
+    LEFT JOIN (
+        SELECT name, cid FROM main.aliases JOIN main.cids ON id = block_id
+    ) AS new ON new.name = names.name
+WHERE old.cid IS NOT new.cid
+ORDER BY names.name
+"#,
+            snapshot = SNAPSHOT
+        ))?
+        .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok((added, removed, aliases))
+}
+
+pub(crate) fn reverse_alias(txn: &Transaction, cid: impl ToSql) -> crate::Result<ReverseAlias> {
+    let id = match get_id(txn, cid)? {
+        Some(id) => id,
+        None => return Ok(ReverseAlias::default()),
+    };
+    let aliases = txn
+        .prepare_cached(ANCESTOR_ALIASES)?
+        .query_map(&[id], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<Vec<u8>>>>()?;
+    let temp_pins: i64 = txn
+        .prepare_cached(
+            r#"
+WITH RECURSIVE
+    ancestor_of(id) AS
+    (
+        SELECT ?
+        UNION
+        SELECT parent_id FROM refs JOIN ancestor_of ON ancestor_of.id=refs.child_id
+    )
+SELECT COUNT(DISTINCT temp_pins.id) FROM ancestor_of JOIN temp_pins ON ancestor_of.id = block_id;
+"#,
+        )?
+        .query_row(&[id], |row| row.get(0))?;
+    Ok(ReverseAlias {
+        aliases,
+        temp_pins: u64::try_from(temp_pins)?,
     })
 }
 
-/// Check if we have a block
-pub(crate) fn has_block(txn: &Transaction, cid: impl ToSql) -> crate::Result<bool> {
+/// get the ids of all cids that are aliased, temp pinned or protected, or descendants of
+/// recursively aliased or temp pinned cids.
+pub(crate) fn get_pinned_ids(txn: &Transaction, refcount: bool) -> crate::Result<FnvHashSet<i64>> {
+    if refcount {
+        return Ok(txn
+            .prepare_cached(
+                r#"
+SELECT block_id FROM refcounts UNION SELECT block_id FROM aliases UNION SELECT block_id FROM protected
+"#,
+            )?
+            .query_map(NO_PARAMS, |row| row.get(0))?
+            .collect::<rusqlite::Result<FnvHashSet<i64>>>()?);
+    }
     Ok(txn
         .prepare_cached(
-            "SELECT 1 FROM blocks, cids WHERE blocks.block_id = cids.id AND cids.cid = ?",
+            r#"
+WITH RECURSIVE
+    descendant_of(id) AS
+    (
+        SELECT block_id FROM aliases WHERE recursive UNION SELECT block_id FROM temp_pins
+        UNION
+        SELECT child_id FROM refs JOIN descendant_of ON descendant_of.id=refs.parent_id
+    )
+SELECT id FROM descendant_of UNION SELECT block_id FROM aliases UNION SELECT block_id FROM protected
+"#,
         )?
-        .query_row(&[cid], |_| Ok(()))
-        .optional()?
-        .is_some())
+        .query_map(NO_PARAMS, |row| row.get(0))?
+        .collect::<rusqlite::Result<FnvHashSet<i64>>>()?)
 }
 
-/// Check if we have a cid
-pub(crate) fn has_cid(txn: &Transaction, cid: impl ToSql) -> crate::Result<bool> {
+/// get the cids of the direct children of an id
+pub(crate) fn get_links<C: FromSql>(txn: &Transaction, id: i64) -> crate::Result<Vec<C>> {
     Ok(txn
-        .prepare_cached("SELECT 1 FROM cids WHERE cids.cid = ?")?
-        .query_row(&[cid], |_| Ok(()))
-        .optional()?
-        .is_some())
+        .prepare_cached("SELECT cid FROM refs JOIN cids ON child_id = id WHERE parent_id = ?")?
+        .query_map(&[id], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<C>>>()?)
 }
 
-/// get the descendants of a cid.
-/// This just uses the refs table, so it does not ensure that we actually have data for each cid.
-/// The value itself is included.
-pub(crate) fn get_descendants<C: ToSql + FromSql>(
+/// get the cids of all transitive parents of a cid, not including the cid itself
+pub(crate) fn get_ancestors<C: ToSql + FromSql>(
     txn: &Transaction,
     cid: C,
 ) -> crate::Result<Vec<C>> {
-    let res = txn
+    Ok(txn
         .prepare_cached(
             r#"
 WITH RECURSIVE
-    descendant_of(id) AS
+    ancestor_of(id) AS
     (
-        SELECT id FROM cids WHERE cid = ?
-        UNION ALL
-        SELECT DISTINCT child_id FROM refs JOIN descendant_of ON descendant_of.id=refs.parent_id
-    ),
-    descendant_ids as (
-        SELECT DISTINCT id FROM descendant_of
+        SELECT parent_id FROM refs JOIN cids ON refs.child_id = cids.id WHERE cids.cid = ?
+        UNION
+        SELECT parent_id FROM refs JOIN ancestor_of ON ancestor_of.id=refs.child_id
     )
-    -- retrieve corresponding cids - this is a set because of select distinct
-    SELECT cid from cids JOIN descendant_ids ON cids.id = descendant_ids.id;
+SELECT cid FROM ancestor_of JOIN cids ON ancestor_of.id = cids.id;
 "#,
         )?
         .query_map(&[cid], |row| row.get(0))?
-        .collect::<rusqlite::Result<Vec<C>>>()?;
-    Ok(res)
+        .collect::<rusqlite::Result<Vec<C>>>()?)
 }
 
-/// get the set of descendants of an id for which we do not have the data yet.
-/// The value itself is included.
-/// It is safe to call this method for a cid we don't have yet.
-pub(crate) fn get_missing_blocks<C: ToSql + FromSql>(
+/// get the cids of the direct parents of a cid
+pub(crate) fn get_parents<C: ToSql + FromSql>(txn: &Transaction, cid: C) -> crate::Result<Vec<C>> {
+    Ok(txn
+        .prepare_cached(
+            r#"
+SELECT parent.cid FROM refs
+    JOIN cids AS child ON child.id = child_id
+    JOIN cids AS parent ON parent.id = parent_id
+WHERE child.cid = ?
+"#,
+        )?
+        .query_map(&[cid], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<C>>>()?)
+}
+
+/// get all aliases, the cids they point to, and whether they are recursive
+pub(crate) fn get_aliases<C: FromSql>(txn: &Transaction) -> crate::Result<Vec<(Vec<u8>, C, bool)>> {
+    Ok(txn
+        .prepare_cached("SELECT name, cid, recursive FROM aliases JOIN cids ON id = block_id")?
+        .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?)
+}
+
+/// get the aliases with names in a range, ordered by name.
+///
+/// names are blobs, which sqlite compares bytewise, so this is a range scan on the primary key.
+pub(crate) fn get_aliases_in_range<C: FromSql>(
+    txn: &Transaction,
+    start: Bound<&[u8]>,
+    end: Bound<&[u8]>,
+) -> crate::Result<Vec<(Vec<u8>, C)>> {
+    let mut conditions = Vec::new();
+    let mut params: Vec<&dyn ToSql> = Vec::new();
+    for (bound, inclusive, exclusive) in [(&start, ">=", ">"), (&end, "<=", "<")].iter() {
+        match bound {
+            Bound::Included(name) => {
+                conditions.push(format!("name {} ?", inclusive));
+                params.push(name);
+            }
+            Bound::Excluded(name) => {
+                conditions.push(format!("name {} ?", exclusive));
+                params.push(name);
+            }
+            Bound::Unbounded => {}
+        }
+    }
+    conditions.push("1".to_owned());
+    Ok(txn
+        .prepare_cached(&format!(
+            "SELECT name, cid FROM aliases JOIN cids ON id = block_id WHERE {} ORDER BY name",
+            conditions.join(" AND ")
+        ))?
+        .query_map(params, |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?)
+}
+
+/// get the cid an alias points to, if the alias exists
+pub(crate) fn resolve<C: FromSql>(txn: &Transaction, name: &[u8]) -> crate::Result<Option<C>> {
+    Ok(txn
+        .prepare_cached("SELECT cid FROM aliases JOIN cids ON id = block_id WHERE name = ?")?
+        .query_row(&[name], |row| row.get(0))
+        .optional()?)
+}
+
+/// call a function for every block in the store, without loading all blocks into memory
+pub(crate) fn for_each_block<C: FromSql>(
+    txn: &Transaction,
+    mut f: impl FnMut(i64, C, Vec<u8>) -> crate::Result<()>,
+) -> crate::Result<()> {
+    let mut stmt = txn.prepare("SELECT id, cid, block FROM cids JOIN blocks ON id = block_id")?;
+    let mut rows = stmt.query(NO_PARAMS)?;
+    while let Some(row) = rows.next()? {
+        f(row.get(0)?, row.get(1)?, row.get(2)?)?;
+    }
+    Ok(())
+}
+
+/// a row of a bulk read, with the cid and the data decoded separately, so that a corrupt row can
+/// be reported and skipped. The last element is the stored checksum of the data, if any.
+pub(crate) type BulkRow<C> = (
+    rusqlite::Result<C>,
+    rusqlite::Result<Vec<u8>>,
+    rusqlite::Result<Option<i64>>,
+);
+
+fn bulk_row<C: FromSql>(row: &rusqlite::Row, offset: usize) -> BulkRow<C> {
+    (row.get(offset), row.get(offset + 1), row.get(offset + 2))
+}
+
+/// call a function for the cid and data of all descendants of a cid, including the cid itself,
+/// for which we have the data. The blocks are streamed, not collected in memory.
+///
+/// rows that can not be decoded are passed to the function rather than aborting the traversal.
+pub(crate) fn for_each_descendant_block<C: ToSql + FromSql>(
     txn: &Transaction,
     cid: C,
-) -> crate::Result<Vec<C>> {
-    let id = get_or_create_id(&txn, cid)?;
-    let res = txn.prepare_cached(
+    mut f: impl FnMut(BulkRow<C>) -> crate::Result<()>,
+) -> crate::Result<()> {
+    let mut stmt = txn.prepare_cached(
         r#"
 WITH RECURSIVE
-    -- find descendants of cid, including the id of the cid itself
-    descendant_of(id) AS (
-        SELECT ?
-        UNION ALL
-        SELECT DISTINCT child_id FROM refs JOIN descendant_of ON descendant_of.id=refs.parent_id
-    ),
-    -- find orphaned ids
-    orphaned_ids as (
-      SELECT DISTINCT id FROM descendant_of LEFT JOIN blocks ON descendant_of.id = blocks.block_id WHERE blocks.block_id IS NULL
+    descendant_of(id) AS
+    (
+        SELECT id FROM cids WHERE cid = ?
+        UNION
+        SELECT child_id FROM refs JOIN descendant_of ON descendant_of.id=refs.parent_id
     )
-    -- retrieve corresponding cids - this is a set because of select distinct
-SELECT cid from cids JOIN orphaned_ids ON cids.id = orphaned_ids.id
+SELECT cid, block, checksum FROM descendant_of JOIN cids ON cids.id = descendant_of.id
+    JOIN blocks ON blocks.block_id = descendant_of.id
+    LEFT JOIN checksums ON checksums.block_id = descendant_of.id;
 "#,
-    )?
-        .query_map(&[id], |row| row.get(0))?
-        .collect::<rusqlite::Result<Vec<C>>>()?;
-    Ok(res)
+    )?;
+    let mut rows = stmt.query(&[cid])?;
+    while let Some(row) = rows.next()? {
+        f(bulk_row(row, 0))?;
+    }
+    Ok(())
 }
 
-pub(crate) fn alias<C: ToSql>(
+/// continue a breadth first walk over the descendants of a cid, to find the ones for which we do
+/// not have the data, see [crate::BlockStore::missing_blocks_iter].
+///
+/// Takes up to `limit` ids off `pending`, and adds the children of those we have to `pending`,
+/// unless they were already `visited`. Returns the cids of those we do not have.
+pub(crate) fn missing_blocks_step<C: FromSql>(
     txn: &Transaction,
-    name: &[u8],
-    key: Option<&C>,
+    pending: &mut VecDeque<i64>,
+    visited: &mut FnvHashSet<i64>,
+    limit: usize,
+) -> crate::Result<Vec<C>> {
+    let mut cid_stmt = txn.prepare_cached(
+        "SELECT cid, EXISTS(SELECT 1 FROM blocks WHERE block_id = id) FROM cids WHERE id = ?",
+    )?;
+    let mut links_stmt = txn.prepare_cached("SELECT child_id FROM refs WHERE parent_id = ?")?;
+    let mut missing = Vec::new();
+    for _ in 0..limit {
+        let id = match pending.pop_front() {
+            Some(id) => id,
+            None => break,
+        };
+        // the cid might have been deleted since it was found
+        let (cid, have): (C, bool) = match cid_stmt
+            .query_row(&[id], |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional()?
+        {
+            Some(row) => row,
+            None => continue,
+        };
+        if !have {
+            missing.push(cid);
+            continue;
+        }
+        let children = links_stmt
+            .query_map(&[id], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<i64>>>()?;
+        for child in children {
+            if visited.insert(child) {
+                pending.push_back(child);
+            }
+        }
+    }
+    Ok(missing)
+}
+
+/// walk the descendants of a cid depth first, calling a function with the cid and data of each,
+/// if we have it. Blocks that are reachable on several paths are only visited once.
+pub(crate) fn traverse<C: ToSql + FromSql>(
+    txn: &Transaction,
+    cid: C,
+    mut f: impl FnMut(&C, Option<&[u8]>) -> crate::Result<Visit>,
 ) -> crate::Result<()> {
-    if let Some(key) = key {
-        let id = get_or_create_id(txn, key)?;
-        txn.prepare_cached("REPLACE INTO aliases (name, block_id) VALUES (?, ?)")?
-            .execute(params![name, id])?;
-    } else {
-        txn.prepare_cached("DELETE FROM aliases WHERE name = ?")?
-            .execute(&[name])?;
+    let mut block_stmt = txn.prepare_cached("SELECT block FROM blocks WHERE block_id = ?")?;
+    let mut links_stmt = txn.prepare_cached(
+        "SELECT id, cid FROM refs JOIN cids ON child_id = id WHERE parent_id = ?",
+    )?;
+    let mut visited = FnvHashSet::default();
+    let mut stack = vec![(get_id(txn, &cid)?, cid)];
+    while let Some((id, cid)) = stack.pop() {
+        let id = match id {
+            Some(id) => id,
+            // a cid we know nothing about
+            None if f(&cid, None)? == Visit::Stop => break,
+            None => continue,
+        };
+        if !visited.insert(id) {
+            continue;
+        }
+        let data: Option<Vec<u8>> = block_stmt.query_row(&[id], |row| row.get(0)).optional()?;
+        match f(&cid, data.as_deref())? {
+            Visit::Continue => {
+                let children = links_stmt
+                    .query_map(&[id], |row| Ok((Some(row.get(0)?), row.get(1)?)))?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                // reversed, so children are visited in order
+                stack.extend(children.into_iter().rev());
+            }
+            Visit::SkipChildren => {}
+            Visit::Stop => break,
+        }
     }
     Ok(())
 }
 
-pub(crate) fn reverse_alias(txn: &Transaction, cid: impl ToSql) -> crate::Result<Vec<Vec<u8>>> {
-    let id = get_id(txn, cid)?;
+/// get up to `limit` blocks with an id greater than `after`, ordered by id
+pub(crate) fn get_blocks_after<C: FromSql>(
+    txn: &Transaction,
+    after: i64,
+    limit: usize,
+) -> crate::Result<Vec<(i64, BulkRow<C>)>> {
     Ok(txn
         .prepare_cached(
             r#"
-WITH RECURSIVE
-    ancestor_of(id) AS
-    (
-        SELECT ?
-        UNION ALL
-        SELECT DISTINCT parent_id FROM refs JOIN ancestor_of ON ancestor_of.id=refs.child_id
-    )
-SELECT DISTINCT name FROM ancestor_of LEFT JOIN aliases ON ancestor_of.id = block_id;
+SELECT id, cid, block, checksum FROM cids
+    JOIN blocks ON id = blocks.block_id
+    LEFT JOIN checksums ON id = checksums.block_id
+WHERE id > ? ORDER BY id LIMIT ?"#,
+        )?
+        .query_map(params![after, limit as i64], |row| {
+            Ok((row.get(0)?, bulk_row(row, 1)))
+        })?
+        .collect::<rusqlite::Result<_>>()?)
+}
+
+/// (parent id, child id) of a ref, which is the key for paging through refs
+pub(crate) type RefKey = (i64, i64);
+
+/// get up to `limit` refs with a (parent id, child id) larger than `after`, ordered by ids.
+pub(crate) fn get_refs_after<C: FromSql>(
+    txn: &Transaction,
+    after: RefKey,
+    limit: usize,
+) -> crate::Result<Vec<(RefKey, C, C)>> {
+    Ok(txn
+        .prepare_cached(
+            r#"
+SELECT parent_id, child_id, parent.cid, child.cid FROM refs
+    JOIN cids AS parent ON parent.id = parent_id
+    JOIN cids AS child ON child.id = child_id
+WHERE (parent_id, child_id) > (?, ?)
+ORDER BY parent_id, child_id
+LIMIT ?
 "#,
         )?
-        .query_map(params![id], |row| row.get(0))?
-        .collect::<rusqlite::Result<Vec<Vec<u8>>>>()?)
+        .query_map(params![after.0, after.1, limit as i64], |row| {
+            Ok(((row.get(0)?, row.get(1)?), row.get(2)?, row.get(3)?))
+        })?
+        .collect::<rusqlite::Result<_>>()?)
 }
 
 /// get all ids corresponding to cids that we have a block for
@@ -522,25 +2632,349 @@ pub(crate) fn get_known_cids<C: FromSql>(txn: &Transaction) -> crate::Result<Vec
         .collect::<rusqlite::Result<Vec<C>>>()?)
 }
 
-pub(crate) fn init_db(conn: &mut Connection, is_memory: bool) -> anyhow::Result<()> {
+pub(crate) fn init_db(
+    conn: &mut Connection,
+    is_memory: bool,
+    config: &Config,
+) -> anyhow::Result<()> {
+    // the page size can not be changed once the database is in wal mode
+    conn.pragma_update(None, "page_size", &config.pragmas.page_size)?;
     conn.execute_batch(PRAGMAS)?;
+    set_pragmas(conn, config)?;
     let foreign_keys: i64 = conn.pragma_query_value(None, "foreign_keys", |row| row.get(0))?;
     let journal_mode: String = conn.pragma_query_value(None, "journal_mode", |row| row.get(0))?;
     let expected_journal_mode = if is_memory { "memory" } else { "wal" };
     assert_eq!(foreign_keys, 1);
     assert_eq!(journal_mode, expected_journal_mode.to_owned());
     // use in_txn so we get the logging
-    in_txn(conn, |txn| {
+    in_txn(conn, config.busy_retry, |txn| {
         if user_version(&txn)? == 0 && table_exists(&txn, "blocks")? {
-            Ok(migrate_v0_v1(&txn)?)
+            migrate_v0_v1(&txn, &config.codecs)?;
         } else {
-            Ok(txn.execute_batch(INIT)?)
+            txn.execute_batch(INIT)?;
+            if !column_exists(&txn, "aliases", "recursive")? {
+                info!("adding recursive column to aliases");
+                txn.execute_batch(
+                    "ALTER TABLE aliases ADD COLUMN recursive INTEGER NOT NULL DEFAULT 1",
+                )?;
+            }
+            if !column_exists(&txn, "blocks", "inserted")? {
+                info!("adding inserted column to blocks");
+                txn.execute_batch(
+                    "ALTER TABLE blocks ADD COLUMN inserted INTEGER NOT NULL DEFAULT 0",
+                )?;
+            }
+        }
+        // this can only be created once the column exists
+        txn.execute_batch("CREATE INDEX IF NOT EXISTS idx_blocks_inserted ON blocks (inserted)")?;
+        if !column_exists(&txn, "owners", "timeout")? {
+            info!("recreating owners with autoincrement ids");
+            txn.execute_batch(
+                r#"
+CREATE TABLE owners_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    pid INTEGER NOT NULL,
+    heartbeat INTEGER NOT NULL,
+    timeout INTEGER NOT NULL DEFAULT 0
+);
+INSERT INTO owners_new (id, pid, heartbeat) SELECT id, pid, heartbeat FROM owners;
+DROP TABLE owners;
+ALTER TABLE owners_new RENAME TO owners;
+"#,
+            )?;
         }
+        init_refcounts(&txn, config.refcount_gc)?;
+        init_trash(&txn, config.trash.is_some())?;
+        Ok(())
     })?;
     assert!(conn.db_config(DbConfig::SQLITE_DBCONFIG_ENABLE_FKEY)?);
     Ok(())
 }
 
+/// the highest temp pin id. Temp pins up to this id at startup were not dropped because of a crash.
+pub(crate) fn max_temp_pin_id(txn: &Transaction) -> crate::Result<i64> {
+    Ok(txn
+        .prepare_cached("SELECT COALESCE(MAX(id), 0) FROM temp_pins")?
+        .query_row(NO_PARAMS, |row| row.get(0))?)
+}
+
+/// register a new owner of temp pins for this connection, returning its id
+pub(crate) fn register_owner(
+    txn: &Transaction,
+    pid: u32,
+    now: i64,
+    timeout: i64,
+) -> crate::Result<i64> {
+    txn.prepare_cached("INSERT INTO owners (pid, heartbeat, timeout) VALUES (?, ?, ?)")?
+        .execute(params![pid, now, timeout])?;
+    let id = txn.last_insert_rowid();
+    txn.execute_batch(OWNER_TRIGGER)?;
+    txn.execute("DELETE FROM current_owner", NO_PARAMS)?;
+    txn.execute("INSERT INTO current_owner (id) VALUES (?)", &[id])?;
+    Ok(id)
+}
+
+/// record that an owner is still alive
+///
+/// Fails if the owner was already considered dead by another process, since its temp pins may
+/// be gone by then.
+pub(crate) fn heartbeat(txn: &Transaction, owner: i64, now: i64) -> crate::Result<()> {
+    let updated = txn
+        .prepare_cached("UPDATE owners SET heartbeat = ? WHERE id = ?")?
+        .execute(&[now, owner])?;
+    if updated == 0 {
+        warn!("owner {} was considered dead by another process", owner);
+        return Err(crate::BlockStoreError::OwnerExpired(owner));
+    }
+    Ok(())
+}
+
+/// the number of owners that showed signs of life within their timeout
+pub(crate) fn live_owners(txn: &Transaction, now: i64) -> crate::Result<u64> {
+    let n: i64 = txn
+        .prepare_cached("SELECT COUNT(*) FROM owners WHERE heartbeat + timeout >= ?")?
+        .query_row(&[now], |row| row.get(0))?;
+    Ok(n as u64)
+}
+
+/// delete an owner together with its temp pins
+pub(crate) fn unregister_owner(txn: &Transaction, owner: i64, refcount: bool) -> crate::Result<()> {
+    let pins = txn
+        .prepare_cached("SELECT pin FROM temp_pin_owners WHERE owner = ?")?
+        .query_map(&[owner], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<i64>>>()?;
+    for pin in pins {
+        delete_temp_pin(txn, pin, refcount)?;
+    }
+    txn.prepare_cached("DELETE FROM temp_pin_owners WHERE owner = ?")?
+        .execute(&[owner])?;
+    txn.prepare_cached("DELETE FROM owners WHERE id = ?")?
+        .execute(&[owner])?;
+    Ok(())
+}
+
+/// delete temp pins that were not dropped because of a crash, and rebuild the stats table.
+///
+/// new temp pins always get a higher id, so live temp pins are not affected. If the database is
+/// shared by several processes, `owner_cutoff` is the time before which the last heartbeat of an
+/// owner must have been for its temp pins to be deleted, see [STALE_TEMP_PINS].
+pub(crate) fn maintenance(
+    txn: &Transaction,
+    max_stale_temp_pin_id: i64,
+    owner_cutoff: Option<i64>,
+    refcount: bool,
+) -> crate::Result<()> {
+    if let Some(cutoff) = owner_cutoff {
+        let pins = txn
+            .prepare_cached(STALE_TEMP_PINS)?
+            .query_map(&[max_stale_temp_pin_id, cutoff], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<i64>>>()?;
+        for pin in pins {
+            delete_temp_pin(txn, pin, refcount)?;
+        }
+        txn.prepare_cached("DELETE FROM owners WHERE heartbeat < ?")?
+            .execute(&[cutoff])?;
+    } else {
+        delete_stale_temp_pins(txn, max_stale_temp_pin_id, refcount)?;
+    }
+    txn.execute(
+        "DELETE FROM temp_pin_owners WHERE pin NOT IN (SELECT id FROM temp_pins)",
+        NO_PARAMS,
+    )?;
+    let cids = delete_orphaned_cids(txn, None)?;
+    if cids > 0 {
+        debug!("deleted {} orphaned cids", cids);
+    }
+    txn.execute_batch(MAINTENANCE)?;
+    Ok(())
+}
+
+/// delete all temp pins up to the given id
+fn delete_stale_temp_pins(
+    txn: &Transaction,
+    max_stale_temp_pin_id: i64,
+    refcount: bool,
+) -> crate::Result<()> {
+    let ids = if refcount {
+        get_temp_pin_ids(
+            txn,
+            "SELECT block_id FROM temp_pins WHERE id <= ?",
+            max_stale_temp_pin_id,
+        )?
+    } else {
+        Vec::new()
+    };
+    txn.prepare_cached("DELETE FROM temp_pins WHERE id <= ?")?
+        .execute(&[max_stale_temp_pin_id])?;
+    for id in ids {
+        dec_refcount(txn, id)?;
+    }
+    Ok(())
+}
+
+/// delete up to `limit` cids that nothing refers to, see [DELETE_ORPHANED_CIDS], returning their
+/// number
+pub(crate) fn delete_orphaned_cids(txn: &Transaction, limit: Option<u64>) -> crate::Result<u64> {
+    let limit = limit.map(|limit| limit as i64).unwrap_or(-1);
+    let n = txn
+        .prepare_cached(DELETE_ORPHANED_CIDS)?
+        .execute(&[limit])?;
+    Ok(n as u64)
+}
+
+/// set the busy timeout and the tunable pragmas, see [Pragmas]
+pub(crate) fn set_pragmas(conn: &Connection, config: &Config) -> crate::Result<()> {
+    conn.busy_timeout(config.busy_timeout)?;
+    let pragmas = &config.pragmas;
+    let synchronous = match pragmas.synchronous {
+        Synchronous::Full => "FULL",
+        Synchronous::Normal => "NORMAL",
+        Synchronous::Off => "OFF",
+    };
+    conn.pragma_update(None, "synchronous", &synchronous)?;
+    if let Some(cache_size) = pragmas.cache_size {
+        conn.pragma_update(None, "cache_size", &cache_size)?;
+    }
+    if let Some(mmap_size) = pragmas.mmap_size {
+        conn.pragma_update(None, "mmap_size", &i64::try_from(mmap_size)?)?;
+    }
+    if let Some(pages) = pragmas.wal_autocheckpoint {
+        conn.pragma_update(None, "wal_autocheckpoint", &pages)?;
+    }
+    for (name, value) in &config.custom_pragmas {
+        conn.pragma_update(None, name, value)?;
+    }
+    Ok(())
+}
+
+/// checkpoint the wal with the given mode
+///
+/// This is a noop if the database is not in wal mode.
+pub(crate) fn checkpoint(
+    conn: &Connection,
+    mode: CheckpointMode,
+) -> crate::Result<CheckpointStats> {
+    let mode = match mode {
+        CheckpointMode::Passive => "PASSIVE",
+        CheckpointMode::Full => "FULL",
+        CheckpointMode::Restart => "RESTART",
+        CheckpointMode::Truncate => "TRUNCATE",
+    };
+    let (busy, log, checkpointed): (i64, i64, i64) = conn.query_row(
+        &format!("PRAGMA wal_checkpoint({})", mode),
+        NO_PARAMS,
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+    // the page counts are -1 if the database is not in wal mode
+    Ok(CheckpointStats {
+        busy: busy != 0,
+        log_pages: u64::try_from(log.max(0))?,
+        checkpointed_pages: u64::try_from(checkpointed.max(0))?,
+    })
+}
+
+/// update the query planner statistics where they are likely to be outdated
+///
+/// the analysis limit keeps this cheap even for large tables.
+pub(crate) fn optimize(conn: &Connection) -> crate::Result<()> {
+    conn.execute_batch("PRAGMA analysis_limit = 1000; PRAGMA optimize;")?;
+    Ok(())
+}
+
+/// return up to `pages` free pages to the file system, returning the number of freed pages
+///
+/// This only does something if the database was created with `auto_vacuum = INCREMENTAL`.
+pub(crate) fn incremental_vacuum(conn: &Connection, pages: u32) -> crate::Result<u64> {
+    let auto_vacuum: i64 = conn.query_row("PRAGMA auto_vacuum", NO_PARAMS, |row| row.get(0))?;
+    if auto_vacuum != 2 {
+        return Ok(0);
+    }
+    let free_pages = || -> crate::Result<i64> {
+        Ok(conn.query_row("PRAGMA freelist_count", NO_PARAMS, |row| row.get(0))?)
+    };
+    let before = free_pages()?;
+    conn.execute_batch(&format!("PRAGMA incremental_vacuum({})", pages))?;
+    Ok(u64::try_from(before - free_pages()?)?)
+}
+
+/// number of foreign key violations, which should always be 0
+pub(crate) fn foreign_key_violations(txn: &Transaction) -> crate::Result<usize> {
+    let mut stmt = txn.prepare("PRAGMA foreign_key_check")?;
+    let mut rows = stmt.query(NO_PARAMS)?;
+    let mut count = 0;
+    while rows.next()?.is_some() {
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// names of all schema objects, excluding the ones that are created by sqlite itself
+fn schema_objects(txn: &Transaction) -> crate::Result<BTreeSet<String>> {
+    Ok(txn
+        .prepare_cached(
+            r#"
+SELECT name FROM sqlite_master
+WHERE type IN ('table', 'index', 'view', 'trigger') AND name NOT LIKE 'sqlite_%'
+"#,
+        )?
+        .query_map(NO_PARAMS, |row| row.get(0))?
+        .collect::<rusqlite::Result<BTreeSet<String>>>()?)
+}
+
+/// apply the migrations of an extension that have not been applied yet.
+///
+/// migration `i` brings the extension from version `i` to version `i + 1`. All schema objects
+/// created by the migrations must be prefixed with the extension name followed by an underscore.
+pub(crate) fn migrate_extension(
+    txn: &Transaction,
+    name: &str,
+    migrations: &[&str],
+) -> crate::Result<u32> {
+    let version: u32 = txn
+        .prepare_cached("SELECT version FROM extensions WHERE name = ?")?
+        .query_row(&[name], |row| row.get(0))
+        .optional()?
+        .unwrap_or_default();
+    let before = schema_objects(txn)?;
+    for (i, migration) in migrations.iter().enumerate().skip(version as usize) {
+        info!("executing migration {} of extension {}", i, name);
+        txn.execute_batch(migration)?;
+    }
+    let prefix = format!("{}_", name);
+    if let Some(object) = schema_objects(txn)?
+        .difference(&before)
+        .find(|object| !object.starts_with(&prefix))
+    {
+        return Err(anyhow::anyhow!(
+            "extension {} created {} which is not prefixed with {}",
+            name,
+            object,
+            prefix
+        )
+        .into());
+    }
+    let version = u32::try_from(migrations.len())?.max(version);
+    txn.prepare_cached("REPLACE INTO extensions (name, version) VALUES (?, ?)")?
+        .execute(params![name, version])?;
+    Ok(version)
+}
+
+/// get the query plans for the core queries
+pub(crate) fn query_plans(conn: &Connection) -> crate::Result<Vec<(&'static str, Vec<String>)>> {
+    CORE_QUERIES
+        .iter()
+        .map(|(name, sql)| {
+            let mut stmt = conn.prepare(&format!("EXPLAIN QUERY PLAN {}", sql))?;
+            // parameters do not matter for the plan, so just bind them all to null
+            let params = vec![Null; stmt.parameter_count()];
+            let plan = stmt
+                .query_map(&params, |row| row.get(3))?
+                .collect::<rusqlite::Result<Vec<String>>>()?;
+            Ok((*name, plan))
+        })
+        .collect()
+}
+
 pub(crate) fn integrity_check(conn: &Connection) -> crate::Result<Vec<String>> {
     let mut stmt = conn.prepare("SELECT integrity_check FROM pragma_integrity_check")?;
     let result = stmt
@@ -574,12 +3008,50 @@ pub(crate) fn log_execution_time<T, E>(
     result
 }
 
+/// true if the error means that another connection holds a lock on the database
+fn is_busy(cause: &rusqlite::Error) -> bool {
+    matches!(
+        cause,
+        rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error {
+                code: rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked,
+                ..
+            },
+            _,
+        )
+    )
+}
+
+/// begin a write transaction, retrying according to `retry` while the database is locked
+///
+/// the write lock is taken right away, so statements in the transaction can not fail because
+/// another connection wrote in the meantime.
+fn begin_write(conn: &mut Connection, retry: BusyRetry) -> crate::Result<Transaction<'_>> {
+    // a shared borrow can be retried in a loop. The connection is exclusively borrowed by the
+    // caller, so there can not be an open transaction
+    let conn: &Connection = conn;
+    let mut backoff = retry.backoff;
+    let mut attempt = 0;
+    loop {
+        match Transaction::new_unchecked(conn, TransactionBehavior::Immediate) {
+            Err(cause) if is_busy(&cause) && attempt < retry.retries => {
+                debug!("database is busy, retrying in {:?}", backoff);
+                std::thread::sleep(backoff);
+                backoff = (backoff * 2).min(retry.max_backoff);
+                attempt += 1;
+            }
+            result => return Ok(result?),
+        }
+    }
+}
+
 /// execute a statement in a write transaction
 pub(crate) fn in_txn<T>(
     conn: &mut Connection,
+    retry: BusyRetry,
     f: impl FnOnce(&Transaction) -> crate::Result<T>,
 ) -> crate::Result<T> {
-    let txn = conn.transaction()?;
+    let txn = begin_write(conn, retry)?;
     let result = f(&txn);
     match result {
         Ok(value) => {
@@ -597,6 +3069,26 @@ pub(crate) fn in_txn<T>(
     }
 }
 
+/// execute a statement in a write transaction that may add to a temp pin.
+///
+/// adding to a temp pin for the first time allocates an id for it. If the transaction fails, the
+/// id is reset, since otherwise the id could be allocated again for a different temp pin.
+pub(crate) fn in_pin_txn<T>(
+    conn: &mut Connection,
+    retry: BusyRetry,
+    pin: Option<&AtomicI64>,
+    f: impl FnOnce(&Transaction) -> crate::Result<T>,
+) -> crate::Result<T> {
+    let id = pin.map(|pin| pin.load(Ordering::SeqCst));
+    let result = in_txn(conn, retry, f);
+    if result.is_err() {
+        if let (Some(pin), Some(id)) = (pin, id) {
+            pin.store(id, Ordering::SeqCst);
+        }
+    }
+    result
+}
+
 /// execute a statement in a readonly transaction
 /// nested transactions are not allowed here.
 pub(crate) fn in_ro_txn<T>(
@@ -7,6 +7,71 @@ pub enum BlockStoreError {
     /// Error convering from a cid to a fixed sized representation.
     /// This can be caused by hashes with more than 32 bytes of size
     CidError(libipld::cid::Error),
+    /// A cid stored in the database could not be parsed.
+    /// This can be caused by legacy or corrupt rows in the cids table.
+    #[display(fmt = "corrupt cid in database: {:?}", _0)]
+    #[from(ignore)]
+    CorruptCid(Vec<u8>),
+    /// Gc was requested while gc is paused.
+    #[display(fmt = "gc is paused")]
+    #[from(ignore)]
+    GcPaused,
+    /// A mutating method was called while the store is read only.
+    #[display(fmt = "store is read only")]
+    #[from(ignore)]
+    ReadOnly,
+    /// Adding to a temp pin would exceed the configured maximum number of temp pins.
+    #[display(fmt = "too many temp pins: {}", _0)]
+    #[from(ignore)]
+    TooManyTempPins(u64),
+    /// Adding to a temp pin would exceed the configured maximum number of blocks per temp pin.
+    #[display(fmt = "temp pin too large: {} blocks", _0)]
+    #[from(ignore)]
+    TempPinTooLarge(u64),
+    /// An empty block was added while empty blocks are rejected.
+    #[display(fmt = "empty block {}", _0)]
+    #[from(ignore)]
+    EmptyBlock(libipld::Cid),
+    /// The links given for a block do not match the references in its data.
+    #[display(fmt = "links of block {} do not match its data", _0)]
+    #[from(ignore)]
+    LinkMismatch(libipld::Cid),
+    /// A cid to be added is larger than the configured maximum cid size.
+    #[display(fmt = "cid {} is too large", _0)]
+    #[from(ignore)]
+    CidTooLarge(libipld::Cid),
+    /// The data of a block does not match its stored checksum.
+    /// This indicates corruption of the database, as opposed to bad data from a peer.
+    #[display(fmt = "checksum mismatch for block {}", _0)]
+    #[from(ignore)]
+    ChecksumMismatch(libipld::Cid),
+    /// A block that is pinned was requested to be deleted.
+    #[display(fmt = "block {} is pinned", _0)]
+    #[from(ignore)]
+    Pinned(libipld::Cid),
+    /// The store was considered dead by another process that shares the database, so its temp
+    /// pins may be gone. The store has to be opened again.
+    #[display(fmt = "owner {} of temp pins was considered dead", _0)]
+    #[from(ignore)]
+    OwnerExpired(i64),
+    /// The database is in use by the given number of live stores that share it, so it can not
+    /// be opened without [Config::with_multi_process](crate::Config::with_multi_process).
+    #[display(fmt = "database is in use by {} other stores", _0)]
+    #[from(ignore)]
+    InUse(u64),
+    /// A batch with temp pin operations was written without a temp pin.
+    #[display(fmt = "batch has temp pin operations but no temp pin")]
+    #[from(ignore)]
+    MissingTempPin,
+    /// A wal checkpoint could not complete because of concurrent readers or writers.
+    #[display(fmt = "database is busy")]
+    Busy,
+    /// A car file could not be parsed.
+    #[display(fmt = "invalid car file: {}", _0)]
+    #[from(ignore)]
+    InvalidCar(String),
+    /// Error when reading or writing a car file
+    IoError(std::io::Error),
     /// Error when converting i64 from sqlite to u64.
     /// This is unlikely to ever happen.
     TryFromIntError(std::num::TryFromIntError),
@@ -19,6 +84,22 @@ impl std::error::Error for BlockStoreError {
         match self {
             BlockStoreError::SqliteError(e) => Some(e),
             BlockStoreError::CidError(e) => Some(e),
+            BlockStoreError::CorruptCid(_) => None,
+            BlockStoreError::GcPaused => None,
+            BlockStoreError::ReadOnly => None,
+            BlockStoreError::TooManyTempPins(_) => None,
+            BlockStoreError::TempPinTooLarge(_) => None,
+            BlockStoreError::EmptyBlock(_) => None,
+            BlockStoreError::LinkMismatch(_) => None,
+            BlockStoreError::CidTooLarge(_) => None,
+            BlockStoreError::ChecksumMismatch(_) => None,
+            BlockStoreError::Pinned(_) => None,
+            BlockStoreError::OwnerExpired(_) => None,
+            BlockStoreError::InUse(_) => None,
+            BlockStoreError::MissingTempPin => None,
+            BlockStoreError::Busy => None,
+            BlockStoreError::InvalidCar(_) => None,
+            BlockStoreError::IoError(e) => Some(e),
             BlockStoreError::TryFromIntError(e) => Some(e),
             BlockStoreError::Other(e) => Some(e.as_ref()),
         }
@@ -0,0 +1,153 @@
+//! A gc loop on a background thread, for applications that do not use an async runtime
+//!
+//! The scheduler shares the store with the foreground via a mutex. Gc only holds the lock for
+//! the duration of a single gc transaction, so foreground reads and writes are interleaved with
+//! gc according to the [GcConfig]. For applications that use an async runtime,
+//! [AsyncBlockStore::gc_loop](crate::async_block_store::AsyncBlockStore::gc_loop) does the same.
+//!
+//! The scheduler does not open a connection of its own. Gc has to delete the temp pins that were
+//! dropped in the foreground, write the accesses and notify the cache tracker of the foreground
+//! store, all of which only exist in memory. And since sqlite only allows one write transaction
+//! at a time, a separate connection would block foreground writes just the same.
+use crate::{
+    async_block_store::{run_gc_step, GcConfig, GcStep, GC_STEPS},
+    BlockStore, BlockStoreError, Result, Throttle,
+};
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+use tracing::*;
+
+/// Runs incremental gc and deletion of orphaned blocks in regular intervals on a background
+/// thread.
+///
+/// The thread is stopped when the scheduler is dropped, or by calling [GcScheduler::stop] to get
+/// the result. Errors of gc are logged, and gc is tried again after the next pause. The thread
+/// only fails if the mutex was poisoned by a panic of the foreground. Note that an
+/// [adaptive budget](crate::Config::with_adaptive_gc) is ignored, since the scheduler does not
+/// see the calls of the foreground.
+pub struct GcScheduler {
+    stopped: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<Result<()>>>,
+}
+
+impl GcScheduler {
+    /// Start gc for a store that is shared with the foreground.
+    pub fn spawn(store: Arc<Mutex<BlockStore>>, config: GcConfig) -> Self {
+        let stopped = Arc::new((Mutex::new(false), Condvar::new()));
+        let (throttle, slice) = {
+            // the config can still be read if the foreground panicked, the thread will fail
+            let store = store
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            (store.config.throttle, store.config.gc_slice)
+        };
+        let handle = {
+            let stopped = stopped.clone();
+            let span = Span::current();
+            std::thread::spawn(move || {
+                span.in_scope(|| gc_thread(&store, &config, &throttle, slice, &stopped))
+            })
+        };
+        Self {
+            stopped,
+            handle: Some(handle),
+        }
+    }
+
+    /// Stop the gc thread and wait until it is done.
+    ///
+    /// Returns an error if the gc thread stopped because the mutex was poisoned.
+    pub fn stop(mut self) -> Result<()> {
+        self.stop_and_join()
+    }
+
+    fn stop_and_join(&mut self) -> Result<()> {
+        let (lock, cvar) = &*self.stopped;
+        *lock.lock().unwrap() = true;
+        cvar.notify_all();
+        match self.handle.take() {
+            Some(handle) => handle
+                .join()
+                .map_err(|_| BlockStoreError::Other(anyhow::anyhow!("gc thread panicked")))?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for GcScheduler {
+    fn drop(&mut self) {
+        if let Err(cause) = self.stop_and_join() {
+            error!("gc thread failed {}", cause);
+        }
+    }
+}
+
+fn gc_thread(
+    store: &Mutex<BlockStore>,
+    config: &GcConfig,
+    throttle: &Throttle,
+    slice: Option<Duration>,
+    stopped: &(Mutex<bool>, Condvar),
+) -> Result<()> {
+    // initial delay so we don't start gc directly on startup
+    if sleep(stopped, config.interval / 2) {
+        return Ok(());
+    }
+    loop {
+        for (name, step) in GC_STEPS.iter() {
+            debug!("gc thread running {}", name);
+            let t0 = Instant::now();
+            gc_step(store, config, slice, stopped, *step)?;
+            if sleep(stopped, config.pause_after(t0.elapsed(), throttle)) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// run a single step of gc, either in one transaction or, if `slice` is set, in short
+/// transactions with pauses in between, until `target_duration` is exceeded.
+fn gc_step(
+    store: &Mutex<BlockStore>,
+    config: &GcConfig,
+    slice: Option<Duration>,
+    stopped: &(Mutex<bool>, Condvar),
+    step: GcStep,
+) -> Result<()> {
+    let (min_blocks, max_steps) = config.step_limits(slice);
+    let duration = config.initial_budget(slice, None);
+    let t0 = Instant::now();
+    for _ in 0..max_steps {
+        let mut guard = store.lock().map_err(|_| {
+            error!("gc thread stopped, the store was poisoned");
+            BlockStoreError::Other(anyhow::anyhow!("store mutex poisoned"))
+        })?;
+        let result = run_gc_step(&mut guard, step, min_blocks, duration);
+        drop(guard);
+        let complete = match result {
+            Ok(Some(complete)) => complete,
+            Ok(None) => return Ok(()),
+            Err(cause) => {
+                // try again after the pause
+                error!("gc step failed {}", cause);
+                return Ok(());
+            }
+        };
+        // give the foreground the opportunity to access the store
+        if complete || t0.elapsed() >= config.target_duration || sleep(stopped, duration) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// sleep for the given duration, returns true if the scheduler was stopped in the meantime
+fn sleep((lock, cvar): &(Mutex<bool>, Condvar), duration: Duration) -> bool {
+    let (stopped, _) = cvar
+        .wait_timeout_while(lock.lock().unwrap(), duration, |stopped| !*stopped)
+        .unwrap();
+    *stopped
+}
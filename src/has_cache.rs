@@ -0,0 +1,55 @@
+//! A small cache for the answers of has_block
+//!
+//! Bitswap tends to ask for the same few missing blocks over and over again. This cache absorbs
+//! these queries, including the negative answers.
+use crate::cidbytes::CidBytes;
+use fnv::FnvHashMap;
+use std::collections::VecDeque;
+
+#[derive(Debug, Default)]
+pub(crate) struct HasCache {
+    capacity: usize,
+    entries: FnvHashMap<CidBytes, bool>,
+    /// insertion order, for evicting the oldest entries
+    order: VecDeque<CidBytes>,
+}
+
+impl HasCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    pub fn get(&self, key: &CidBytes) -> Option<bool> {
+        self.entries.get(key).cloned()
+    }
+
+    pub fn insert(&mut self, key: CidBytes, value: bool) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.insert(key, value).is_none() {
+            self.order.push_back(key);
+        }
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+
+    /// Forget all answers, because another connection has changed the store
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    /// Forget the answer for a key, because the store has changed
+    pub fn invalidate(&mut self, key: &CidBytes) {
+        if self.entries.remove(key).is_some() {
+            self.order.retain(|x| x != key);
+        }
+    }
+}
@@ -0,0 +1,234 @@
+//! Hooks that are invoked by the store when blocks are added or removed.
+use fnv::FnvHashMap;
+use futures::channel::mpsc;
+use libipld::{store::DefaultParams, Block, Cid};
+use std::{collections::BTreeSet, fmt::Debug};
+
+/// Computes derived metadata for a block at put time.
+///
+/// The metadata is stored in the metadata table in the same transaction as the block itself,
+/// and is deleted together with the block by gc. So derived indexes can never drift from the
+/// block set.
+pub trait MetadataHook: Debug + Send {
+    /// compute metadata as key value pairs for a block that is being added.
+    ///
+    /// this will be called on every put, even for blocks that already exist. Existing values
+    /// for the same key will be overwritten.
+    fn metadata(&self, cid: &Cid, data: &[u8]) -> Vec<(String, Vec<u8>)>;
+}
+
+/// A committed change to the store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Commit {
+    /// commit sequence number of this change. A commit that only wrote blocks we already had is
+    /// not a change, and has the sequence number of the previous change.
+    pub seq: u64,
+    /// cids of blocks that were added. This can contain blocks we already had.
+    pub added: Vec<Cid>,
+    /// cids of blocks that were added and that we did not have before, a subset of `added`
+    pub inserted: Vec<Cid>,
+    /// cids of blocks that were removed by gc
+    pub removed: Vec<Cid>,
+    /// cids that are the new targets of aliases
+    pub aliased: Vec<Cid>,
+    /// names of aliases that were set or removed, with their new target
+    pub aliases: Vec<(Vec<u8>, Option<Cid>)>,
+}
+
+/// Notification about committed changes, to keep sidecar indexes in sync with the store.
+///
+/// The hook is called after the transaction has been committed, so a crash between the commit and
+/// the call is possible. A sidecar index should therefore persist the last [Commit::seq] it has
+/// processed, and resync if it is behind [BlockStore::commit_seq](crate::BlockStore::commit_seq)
+/// on startup.
+pub trait CommitHook: Debug + Send {
+    /// called after a change has been committed
+    fn after_commit(&mut self, commit: &Commit);
+
+    /// called when [BlockStore::has_changed](crate::BlockStore::has_changed) finds that another
+    /// connection has committed changes, up to the given commit sequence number. The details of
+    /// these changes are unknown.
+    fn after_external_commit(&mut self, _seq: u64) {}
+
+    /// whether the hook has become useless, e.g. because the receiving end of its channel was
+    /// dropped. Closed hooks are removed by the store.
+    fn is_closed(&self) -> bool {
+        false
+    }
+}
+
+/// A batch of newly inserted blocks, see [InsertChannel]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Inserted {
+    /// commit sequence number of the change that inserted the blocks
+    pub seq: u64,
+    /// cids of blocks that we did not have before
+    pub cids: Vec<Cid>,
+    /// number of batches that were dropped before this one because the channel was full
+    pub dropped: u64,
+}
+
+/// A commit hook that sends the cids of newly inserted blocks to a bounded channel, e.g. to
+/// announce them to a DHT or to push them to replication targets.
+///
+/// Blocks that we already had are not sent. Writers are never blocked by a slow receiver:
+/// if the channel is full, the batch is dropped, and the number of dropped batches is reported
+/// with the next batch that is sent. A receiver that sees dropped batches can resync, e.g. by
+/// announcing all blocks again.
+#[derive(Debug)]
+pub struct InsertChannel {
+    sender: mpsc::Sender<Inserted>,
+    dropped: u64,
+}
+
+impl InsertChannel {
+    /// Create a hook and the receiving end of its channel, which buffers up to `capacity`
+    /// batches.
+    pub fn new(capacity: usize) -> (Self, mpsc::Receiver<Inserted>) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        (Self { sender, dropped: 0 }, receiver)
+    }
+}
+
+impl CommitHook for InsertChannel {
+    fn after_commit(&mut self, commit: &Commit) {
+        if commit.inserted.is_empty() {
+            return;
+        }
+        let batch = Inserted {
+            seq: commit.seq,
+            cids: commit.inserted.clone(),
+            dropped: self.dropped,
+        };
+        match self.sender.try_send(batch) {
+            Ok(()) => self.dropped = 0,
+            Err(err) if err.is_full() => {
+                tracing::debug!("insert channel is full, dropping batch {}", commit.seq);
+                self.dropped += 1;
+            }
+            // nobody is listening anymore
+            Err(_) => {}
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
+
+/// A change of the store, see [EventChannel]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum StoreEvent {
+    /// a block that we did not have before was added
+    BlockAdded(Cid),
+    /// a block was deleted, e.g. by gc
+    BlockEvicted(Cid),
+    /// an alias was set to a new target, or removed if the target is `None`
+    AliasChanged { name: Vec<u8>, cid: Option<Cid> },
+    /// another connection has committed changes up to the given commit sequence number. The
+    /// details are unknown, so a receiver has to resync from the store.
+    ExternalChange(u64),
+}
+
+/// A commit hook that sends an event for every change of the store to an unbounded channel,
+/// e.g. for indexers that would otherwise have to poll the store for changes. See also
+/// [BlockStore::subscribe](crate::BlockStore::subscribe).
+///
+/// The events of a commit are sent in the order blocks added, blocks evicted, aliases changed.
+/// Blocks that we already had are not reported. Events are buffered until they are received,
+/// so a receiver that falls behind uses memory instead of slowing down writers.
+#[derive(Debug)]
+pub struct EventChannel {
+    sender: mpsc::UnboundedSender<StoreEvent>,
+}
+
+impl EventChannel {
+    /// Create a hook and the receiving end of its channel
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<StoreEvent>) {
+        let (sender, receiver) = mpsc::unbounded();
+        (Self { sender }, receiver)
+    }
+}
+
+impl CommitHook for EventChannel {
+    fn after_commit(&mut self, commit: &Commit) {
+        if self.sender.is_closed() {
+            return;
+        }
+        let events = commit
+            .inserted
+            .iter()
+            .map(|cid| StoreEvent::BlockAdded(*cid))
+            .chain(
+                commit
+                    .removed
+                    .iter()
+                    .map(|cid| StoreEvent::BlockEvicted(*cid)),
+            )
+            .chain(
+                commit
+                    .aliases
+                    .iter()
+                    .map(|(name, cid)| StoreEvent::AliasChanged {
+                        name: name.clone(),
+                        cid: *cid,
+                    }),
+            );
+        for event in events {
+            // the receiver was dropped
+            if self.sender.unbounded_send(event).is_err() {
+                break;
+            }
+        }
+    }
+
+    fn after_external_commit(&mut self, seq: u64) {
+        let _ = self.sender.unbounded_send(StoreEvent::ExternalChange(seq));
+    }
+
+    fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
+
+/// Extracts links from blocks of a codec that libipld does not support, such as dag-jose or
+/// application specific codecs.
+///
+/// Without an extractor, blocks of such codecs can not be migrated or imported.
+pub trait LinkExtractor: Debug + Send {
+    /// extract all links from the data of a block
+    fn links(&self, data: &[u8]) -> anyhow::Result<Vec<Cid>>;
+}
+
+/// registered link extractors, by codec
+pub(crate) type Codecs = FnvHashMap<u64, Box<dyn LinkExtractor>>;
+
+/// extract the links of a block, using a registered extractor if there is one for its codec and
+/// libipld otherwise.
+pub(crate) fn references(
+    codecs: &Codecs,
+    block: &Block<DefaultParams>,
+) -> anyhow::Result<Vec<Cid>> {
+    if let Some(extractor) = codecs.get(&block.cid().codec()) {
+        extractor.links(block.data())
+    } else {
+        let mut links = BTreeSet::new();
+        block.references(&mut links)?;
+        Ok(links.into_iter().collect())
+    }
+}
+
+/// extract the links of a block in the order in which they appear in the data, including
+/// duplicates.
+pub(crate) fn ordered_references(
+    codecs: &Codecs,
+    block: &Block<DefaultParams>,
+) -> anyhow::Result<Vec<Cid>> {
+    if let Some(extractor) = codecs.get(&block.cid().codec()) {
+        extractor.links(block.data())
+    } else {
+        let mut links = Vec::new();
+        block.references(&mut links)?;
+        Ok(links)
+    }
+}
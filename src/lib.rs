@@ -24,8 +24,9 @@
 //! targets are exceeded. [Size targets](SizeTargets) contain both the total size of the store
 //! and the number of blocks.
 //!
-//! GC will run incrementally, deleting blocks until the size targets are no longer exceeded. The
-//! order in which unpinned blocks will be deleted can be customized.
+//! GC will run incrementally, deleting blocks until the size targets are no longer exceeded, or
+//! until the [low watermark](Config::with_low_watermark) is reached. The order in which
+//! unpinned blocks will be deleted can be customized.
 //!
 //! ## Caching
 //!
@@ -33,12 +34,18 @@
 //! [CacheTracker](cache::CacheTracker). The default is to [do nothing](cache::NoopCacheTracker)
 //! and has no performance overhead.
 //!
+//! The [LruCacheTracker](cache::LruCacheTracker) and [LfuCacheTracker](cache::LfuCacheTracker)
+//! keep the recency or frequency of accesses in memory, and can be snapshotted to a sidecar
+//! database.
+//!
 //! The most elaborate implemented strategy is to keep track of access times in a separate database,
 //! via the [SqliteCacheTracker](cache::SqliteCacheTracker), which has a slight performance overhead.
 //!
 //! The performance overhead of writing to an access tracking database on each block read can be
 //! mitigated by using the [AsyncCacheTracker](cache::AsyncCacheTracker) wrapper to perform the database
-//! writes on a different thread.
+//! writes on a different thread, or by using the [BatchingCacheTracker](cache::BatchingCacheTracker)
+//! wrapper to forward reads in batches. Trackers backed by remote storage can be wrapped in a
+//! [DeferredSortCacheTracker](cache::DeferredSortCacheTracker), so gc never waits for them.
 //!
 //! # Usage
 //!
@@ -53,6 +60,46 @@
 //! [BlockStore], it provides a method [gc_loop](async_block_store::AsyncBlockStore::gc_loop) to
 //! run gc continuously.
 //!
+//! With [Config::with_read_connections], reads are done on a pool of separate connections, see
+//! [BlockReader], so they do not wait for long writes such as gc. The async
+//! wrapper uses the pool for [get_block](async_block_store::AsyncBlockStore::get_block) and
+//! similar reads.
+//!
+//! ## Multiple processes
+//!
+//! By default a store assumes that it is the only writer of its database. To open the same
+//! database from several processes at the same time, all of them must use
+//! [Config::with_multi_process], so they do not delete each other's temp pins.
+//!
+//! ## Multiple files
+//!
+//! For stores that outgrow a single sqlite file, [RoutedStore](routed_store::RoutedStore)
+//! distributes blocks over several stores based on their cid.
+//!
+//! To move to a store with a different config without downtime,
+//! [MigratingStore](migrating_store::MigratingStore) writes to both stores while the blocks are
+//! copied over.
+//!
+//! ## Tracing
+//!
+//! The store logs via [tracing](https://docs.rs/tracing), including reports about slow queries
+//! and gc runs. To attribute store activity to a specific request, call the store from inside a
+//! span that contains a correlation id, e.g. `info_span!("request", id = %request_id)`. All log
+//! events of the store will be emitted inside this span. [AsyncBlockStore](async_block_store::AsyncBlockStore)
+//! and [AsyncCacheTracker](cache::AsyncCacheTracker) carry the span of the caller over to the
+//! thread that does the actual work, and the gc loop can be attached to a span using
+//! `Instrument::instrument`.
+//!
+//! ## Consistency
+//!
+//! All reads observe all writes that have completed before the read was issued, no matter from
+//! which thread the write was done. Every read method runs in its own short read transaction,
+//! so the snapshot it sees is taken when the method is called. There are no long-lived read
+//! transactions that could pin an old snapshot of the database.
+//!
+//! To apply several writes atomically, e.g. adding a dag and pinning its root, collect them in
+//! a [Batch] and commit it with [BlockStore::write_batch].
+//!
 //! # Major differences to the go-ipfs pinning concept
 //!
 //! - Pinning/aliasing a root does not require that the dag is complete
@@ -60,29 +107,57 @@
 //! - Temporary pins as a mechanism to keep blocks safe from gc while a tree is being constructed
 pub mod async_block_store;
 pub mod cache;
+mod car;
 mod cidbytes;
 mod db;
 mod error;
+pub mod gc_scheduler;
+mod has_cache;
+pub mod hooks;
+pub mod migrating_store;
+pub mod reader;
+pub mod routed_store;
 #[cfg(test)]
 mod tests;
+#[cfg(feature = "bench")]
+pub mod workload;
 
 use crate::cidbytes::CidBytes;
 use cache::{BlockInfo, CacheTracker, NoopCacheTracker};
 use db::*;
+pub use db::{
+    ANCESTOR_ALIASES, CORE_QUERIES, DESCENDANTS, GC_CANDIDATES, HAS_BLOCK, MISSING_BLOCKS,
+    ORPHANED_BLOCKS, REFCOUNT_GC_CANDIDATES,
+};
 pub use error::{BlockStoreError, Result};
-use libipld::cid::{self, Cid};
-use rusqlite::{Connection, DatabaseName};
+use fnv::{FnvHashMap, FnvHashSet};
+use futures::channel::mpsc;
+use has_cache::HasCache;
+use hooks::{
+    ordered_references, references, Codecs, Commit, CommitHook, EventChannel, LinkExtractor,
+    MetadataHook, StoreEvent,
+};
+use libipld::{
+    cid::{self, Cid},
+    multihash::{Code, Multihash, MultihashDigest},
+    store::DefaultParams,
+};
+use reader::BlockReader;
+use rusqlite::{Connection, DatabaseName, OpenFlags, OptionalExtension, Transaction};
 use std::{
+    collections::{hash_map::RandomState, BTreeSet, VecDeque},
     convert::TryFrom,
     fmt,
+    hash::{BuildHasher, Hasher},
+    io::Write,
     iter::FromIterator,
-    ops::DerefMut,
+    ops::{Bound, DerefMut, RangeBounds},
     path::Path,
     sync::{
-        atomic::{AtomicI64, Ordering},
+        atomic::{AtomicI64, AtomicUsize, Ordering},
         Arc, Mutex,
     },
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
 use tracing::*;
 
@@ -126,274 +201,2446 @@ impl SizeTargets {
 
     /// Size targets that can not be reached. This can be used to disable gc.
     pub fn max_value() -> Self {
-        Self {
-            count: u64::max_value(),
-            size: u64::max_value(),
-        }
+        Self::new(u64::max_value(), u64::max_value())
     }
 }
 
-#[derive(Debug)]
-pub struct Config {
-    size_targets: SizeTargets,
-    cache_tracker: Box<dyn CacheTracker>,
+/// Throttle for background gc.
+///
+/// Gc is done in chunks. After each chunk, the throttle determines how long to pause so that gc
+/// does not starve foreground reads and writes, which is important on single-core embedded
+/// devices. See [Config::with_throttle] for what is throttled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Throttle {
+    /// maximum fraction of wall time to spend on gc, between 0.01 and 1.
+    ///
+    /// E.g. a value of 0.1 means that a chunk that took 100ms will be followed by a pause of
+    /// at least 900ms. A value of 1 disables this limit. Values outside of the range are
+    /// clamped, and NaN is treated as 1.
+    pub max_duty_cycle: f64,
+
+    /// minimum pause between two chunks of gc work.
+    pub min_pause: Duration,
 }
 
-impl Default for Config {
-    fn default() -> Self {
+impl Throttle {
+    pub fn new(max_duty_cycle: f64, min_pause: Duration) -> Self {
         Self {
-            size_targets: Default::default(),
-            cache_tracker: Box::new(NoopCacheTracker),
+            max_duty_cycle,
+            min_pause,
         }
     }
-}
 
-impl Config {
-    /// Set size targets for the store
-    pub fn with_size_targets(mut self, size_targets: SizeTargets) -> Self {
-        self.size_targets = size_targets;
-        self
+    /// A throttle that does not limit gc at all.
+    pub fn none() -> Self {
+        Self::new(1.0, Duration::default())
     }
-    /// Set strategy for which non-pinned blocks to keep in case one of the size targets is exceeded.
-    pub fn with_cache_tracker<T: CacheTracker + 'static>(mut self, cache_tracker: T) -> Self {
-        self.cache_tracker = Box::new(cache_tracker);
-        self
+
+    /// The pause to make after a chunk of gc work that took `work`.
+    pub fn pause_after(&self, work: Duration) -> Duration {
+        // the lower bound keeps the factor finite, so mul_f64 can not panic
+        let duty_cycle = if self.max_duty_cycle.is_nan() {
+            1.0
+        } else {
+            self.max_duty_cycle.max(0.01).min(1.0)
+        };
+        work.mul_f64(1.0 / duty_cycle - 1.0).max(self.min_pause)
     }
 }
 
-pub struct BlockStore {
-    conn: Connection,
-    expired_temp_pins: Arc<Mutex<Vec<i64>>>,
-    config: Config,
+impl Default for Throttle {
+    fn default() -> Self {
+        Self::none()
+    }
 }
 
-#[derive(Debug, Clone, Default, PartialEq, Eq)]
-pub struct StoreStats {
-    count: u64,
-    size: u64,
+/// Limits for the size of a single write transaction.
+///
+/// Batch operations like [BlockStore::put_blocks] will be split into multiple transactions once
+/// one of the limits is reached, so a giant import does not cause a huge WAL file or a very long
+/// commit. The limits are checked after each item, so a transaction can exceed them by one item,
+/// and every transaction contains at least one item, even with a limit of 0.
+///
+/// By default there are no limits, so batch operations are atomic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxnLimits {
+    /// maximum number of rows to insert in a single transaction, including refs
+    pub rows: usize,
+    /// maximum number of block bytes to insert in a single transaction
+    pub bytes: usize,
 }
 
-impl StoreStats {
-    /// Total number of blocks in the store
-    pub fn count(&self) -> u64 {
-        self.count
+impl TxnLimits {
+    pub fn new(rows: usize, bytes: usize) -> Self {
+        Self { rows, bytes }
     }
 
-    /// Total size of blocks in the store
-    pub fn size(&self) -> u64 {
-        self.size
+    /// Limits that can not be reached, so batch operations are never split.
+    pub fn max_value() -> Self {
+        Self::new(usize::max_value(), usize::max_value())
+    }
+
+    /// true if a transaction with `rows` rows and `bytes` bytes is full
+    ///
+    /// an empty transaction is never full, so batch operations always make progress.
+    fn exceeded(&self, rows: usize, bytes: usize) -> bool {
+        rows > 0 && (rows >= self.rows || bytes >= self.bytes)
     }
 }
 
-// do not implement Clone for this!
-/// a handle that contains a temporary pin
+impl Default for TxnLimits {
+    fn default() -> Self {
+        Self::max_value()
+    }
+}
+
+/// How to retry write transactions while the database is locked by another connection.
 ///
-/// dropping this handle enqueue the pin for dropping before the next gc.
-pub struct TempPin {
-    id: AtomicI64,
-    expired_temp_pins: Arc<Mutex<Vec<i64>>>,
+/// Another process, or a [cache tracker](cache::CacheTracker) with its own connection, can hold
+/// the write lock of the database. Sqlite waits for it for the
+/// [busy timeout](Config::with_busy_timeout), but some lock conflicts are reported right away.
+/// Starting a write transaction is retried with exponential backoff in both cases.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusyRetry {
+    /// maximum number of retries, 0 to fail right away
+    pub retries: u32,
+    /// time to wait before the first retry, doubled for every further retry
+    pub backoff: Duration,
+    /// maximum time to wait between retries
+    pub max_backoff: Duration,
 }
 
-/// dump the temp alias id so you can find it in the database
-impl fmt::Debug for TempPin {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let id = self.id.load(Ordering::SeqCst);
-        let mut builder = f.debug_struct("TempAlias");
-        if id > 0 {
-            builder.field("id", &id);
+impl BusyRetry {
+    /// Retry up to `retries` times, waiting `backoff` before the first retry and doubling it up
+    /// to `max_backoff` for every further retry.
+    ///
+    /// Only the `BEGIN IMMEDIATE` that starts a write transaction is retried. Once the
+    /// transaction has started, it holds the write lock, so a busy error from a statement or
+    /// from the commit is returned to the caller. Read transactions are not retried either.
+    pub fn new(retries: u32, backoff: Duration, max_backoff: Duration) -> Self {
+        Self {
+            retries,
+            backoff,
+            max_backoff,
         }
-        builder.finish()
     }
-}
 
-impl Drop for TempPin {
-    fn drop(&mut self) {
-        let id = self.id.get_mut();
-        let alias = *id;
-        if alias > 0 {
-            // not sure if we have to guard against double drop, but it certainly does not hurt.
-            *id = 0;
-            self.expired_temp_pins.lock().unwrap().push(alias);
-        }
+    /// Never retry.
+    pub fn none() -> Self {
+        Self::new(0, Duration::default(), Duration::default())
     }
 }
 
-/// An ipfs block
-pub trait Block {
-    fn cid(&self) -> &Cid;
-    fn data(&self) -> &[u8];
-    fn links(&self) -> anyhow::Result<Vec<Cid>>;
+impl Default for BusyRetry {
+    fn default() -> Self {
+        Self::new(5, Duration::from_millis(10), Duration::from_secs(1))
+    }
 }
 
-/// Block that owns its data
-pub struct OwnedBlock {
-    cid: Cid,
-    data: Vec<u8>,
-    links: Vec<Cid>,
+/// Limits for temp pins.
+///
+/// These protect against a buggy client accumulating a huge number of temp pin rows, which
+/// would silently disable gc. Exceeding a limit when adding blocks with a temp pin will fail the
+/// write with [BlockStoreError::TooManyTempPins] or [BlockStoreError::TempPinTooLarge].
+///
+/// By default there are no limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TempPinLimits {
+    /// maximum number of temp pins
+    pub pins: u64,
+    /// maximum number of blocks per temp pin
+    pub blocks_per_pin: u64,
 }
 
-impl OwnedBlock {
-    pub fn new(cid: Cid, data: Vec<u8>, links: Vec<Cid>) -> Self {
-        Self { cid, data, links }
+impl TempPinLimits {
+    pub fn new(pins: u64, blocks_per_pin: u64) -> Self {
+        Self {
+            pins,
+            blocks_per_pin,
+        }
     }
-}
 
-impl Block for OwnedBlock {
-    fn cid(&self) -> &Cid {
-        &self.cid
+    /// Limits that can not be reached.
+    pub fn max_value() -> Self {
+        Self::new(u64::max_value(), u64::max_value())
     }
+}
 
-    fn data(&self) -> &[u8] {
-        &self.data
+impl Default for TempPinLimits {
+    fn default() -> Self {
+        Self::max_value()
     }
+}
 
-    fn links(&self) -> anyhow::Result<Vec<Cid>> {
-        Ok(self.links.clone())
-    }
+/// Durability mode of the database, see [Pragmas::synchronous]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Synchronous {
+    /// sync the wal on every commit, so committed transactions survive a power loss
+    Full,
+    /// sync only on checkpoints. Committed transactions can be lost on power loss, but the
+    /// database stays consistent
+    Normal,
+    /// never sync. The database can get corrupted on power loss
+    Off,
 }
 
-struct BorrowedBlock<'a, F> {
-    cid: Cid,
-    data: &'a [u8],
-    links: F,
+/// Settings of the sqlite connection that trade durability and memory for speed.
+///
+/// Different deployments need very different trade-offs, e.g. a phone with slow flash storage
+/// and little memory or a server with a fast ssd. Settings that are `None` keep the sqlite
+/// default.
+///
+/// By default the database is synced on every commit and uses 4096 byte pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pragmas {
+    /// when to sync the database to disk
+    pub synchronous: Synchronous,
+    /// page size in bytes, a power of two between 512 and 65536
+    ///
+    /// This only has an effect when the database is created.
+    pub page_size: u32,
+    /// size of the page cache, in pages if positive or in KiB if negative
+    pub cache_size: Option<i64>,
+    /// maximum number of bytes of the database file to access via memory mapping
+    pub mmap_size: Option<u64>,
+    /// number of pages in the wal after which it is checkpointed automatically, 0 to disable
+    pub wal_autocheckpoint: Option<u32>,
 }
 
-impl<'a, F> BorrowedBlock<'a, F>
-where
-    F: Fn() -> anyhow::Result<Vec<Cid>>,
-{
-    fn new(cid: Cid, data: &'a [u8], links: F) -> Self {
-        Self { cid, data, links }
+impl Default for Pragmas {
+    fn default() -> Self {
+        Self {
+            synchronous: Synchronous::Full,
+            page_size: 4096,
+            cache_size: None,
+            mmap_size: None,
+            wal_autocheckpoint: None,
+        }
     }
 }
 
-impl<'a, F> Block for BorrowedBlock<'a, F>
-where
-    F: Fn() -> anyhow::Result<Vec<Cid>>,
-{
-    fn cid(&self) -> &Cid {
-        &self.cid
-    }
+/// The registration of a store as the owner of its temp pins in a database that is shared by
+/// several processes, see [Config::with_multi_process]
+///
+/// The registration is removed together with the temp pins of the store when the store is
+/// dropped, or by another process once the store has not shown signs of life for the timeout.
+/// In the latter case, the next heartbeat of the store fails with
+/// [BlockStoreError::OwnerExpired], and the store has to be opened again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessGuard {
+    id: i64,
+    timeout: Duration,
+}
 
-    fn data(&self) -> &[u8] {
-        self.data
+impl ProcessGuard {
+    /// id of the owner in the database
+    ///
+    /// Ids are never reused, not even after the owner was removed by another process, so the id
+    /// identifies this store for as long as the database exists.
+    pub fn id(&self) -> i64 {
+        self.id
     }
 
-    fn links(&self) -> anyhow::Result<Vec<Cid>> {
-        (self.links)()
+    /// time without signs of life after which the store is considered dead by other processes
+    pub fn timeout(&self) -> Duration {
+        self.timeout
     }
 }
 
-impl BlockStore {
-    /// Create an in memory block store with the given config
-    pub fn memory(config: Config) -> crate::Result<Self> {
-        let mut conn = Connection::open_in_memory()?;
-        init_db(&mut conn, true)?;
-        Ok(Self {
-            conn,
-            expired_temp_pins: Arc::new(Mutex::new(Vec::new())),
-            config,
-        })
-    }
-
-    /// Create a persistent block store with the given config
-    pub fn open(path: impl AsRef<Path>, mut config: Config) -> crate::Result<Self> {
-        let mut conn = Connection::open(path)?;
-        init_db(&mut conn, false)?;
-        let ids = in_txn(&mut conn, |txn| get_ids(txn))?;
-        config.cache_tracker.retain_ids(&ids);
-        Ok(Self {
-            conn,
-            expired_temp_pins: Arc::new(Mutex::new(Vec::new())),
-            config,
-        })
-    }
+/// Statistics about temp pins
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TempPinStats {
+    /// number of temp pins
+    pub pins: u64,
+    /// total number of blocks in all temp pins
+    pub blocks: u64,
+    /// number of blocks in the largest temp pin
+    pub max_blocks_per_pin: u64,
+}
 
-    /// Open the file at the given path for testing.
-    ///
-    /// This will create a writeable in-memory database that is initialized with the content
-    /// of the file at the given path.
-    pub fn open_test(path: impl AsRef<Path>, mut config: Config) -> crate::Result<Self> {
-        let mut conn = Connection::open_in_memory()?;
-        debug!(
-            "Restoring in memory database from {}",
-            path.as_ref().display()
-        );
-        conn.restore(
-            DatabaseName::Main,
-            path,
-            Some(|p: rusqlite::backup::Progress| {
-                let percent = (p.pagecount - p.remaining) * 100 / p.pagecount;
-                if percent % 10 == 0 {
-                    debug!("Restoring: {} %", percent);
-                }
-            }),
-        )?;
-        let ids = in_txn(&mut conn, |txn| get_ids(txn))?;
-        config.cache_tracker.retain_ids(&ids);
-        Ok(Self {
-            conn,
-            expired_temp_pins: Arc::new(Mutex::new(Vec::new())),
-            config,
-        })
-    }
+#[derive(Debug)]
+pub struct Config {
+    size_targets: SizeTargets,
+    low_watermark: Option<SizeTargets>,
+    cache_tracker: Box<dyn CacheTracker>,
+    metadata_hook: Option<Box<dyn MetadataHook>>,
+    max_provider_hints: usize,
+    txn_limits: TxnLimits,
+    throttle: Throttle,
+    gc_slice: Option<Duration>,
+    adaptive_gc: Option<async_block_store::AdaptiveBudget>,
+    commit_hooks: Vec<Box<dyn CommitHook>>,
+    normalize_cids: bool,
+    max_cid_size: usize,
+    gc_grace_period: Option<Duration>,
+    refcount_gc: bool,
+    gc_largest_first: bool,
+    access_tracking: bool,
+    eviction_log: Option<Duration>,
+    trash: Option<Duration>,
+    alias_history: Option<usize>,
+    system_alias_prefixes: Vec<Vec<u8>>,
+    temp_pin_limits: TempPinLimits,
+    has_cache_size: usize,
+    defer_maintenance: bool,
+    codecs: Codecs,
+    checksums: bool,
+    reject_empty_blocks: bool,
+    validate_links: bool,
+    pragmas: Pragmas,
+    custom_pragmas: Vec<(String, String)>,
+    busy_timeout: Duration,
+    busy_retry: BusyRetry,
+    read_connections: usize,
+    multi_process: Option<Duration>,
+}
 
-    pub fn integrity_check(&self) -> crate::Result<()> {
-        let result = integrity_check(&self.conn)?;
-        if result == vec!["ok".to_owned()] {
-            Ok(())
-        } else {
-            let error_text = result.join(";");
-            Err(crate::error::BlockStoreError::SqliteError(
-                rusqlite::Error::SqliteFailure(rusqlite::ffi::Error::new(11), Some(error_text)),
-            ))
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            size_targets: Default::default(),
+            low_watermark: None,
+            cache_tracker: Box::new(NoopCacheTracker),
+            metadata_hook: None,
+            max_provider_hints: 8,
+            txn_limits: TxnLimits::default(),
+            throttle: Throttle::default(),
+            gc_slice: None,
+            adaptive_gc: None,
+            commit_hooks: Vec::new(),
+            normalize_cids: false,
+            max_cid_size: cidbytes::MAX_SIZE,
+            gc_grace_period: None,
+            refcount_gc: false,
+            gc_largest_first: false,
+            access_tracking: false,
+            eviction_log: None,
+            trash: None,
+            alias_history: None,
+            system_alias_prefixes: Vec::new(),
+            temp_pin_limits: TempPinLimits::default(),
+            has_cache_size: 1024,
+            defer_maintenance: false,
+            codecs: Codecs::default(),
+            checksums: false,
+            reject_empty_blocks: false,
+            validate_links: false,
+            pragmas: Pragmas::default(),
+            custom_pragmas: Vec::new(),
+            busy_timeout: Duration::from_secs(5),
+            busy_retry: BusyRetry::default(),
+            read_connections: 0,
+            multi_process: None,
         }
     }
+}
 
-    /// Get a temporary alias for safely adding blocks to the store
-    pub fn temp_pin(&self) -> TempPin {
-        TempPin {
-            id: AtomicI64::new(0),
-            expired_temp_pins: self.expired_temp_pins.clone(),
-        }
+impl Config {
+    /// Set size targets for the store
+    pub fn with_size_targets(mut self, size_targets: SizeTargets) -> Self {
+        self.size_targets = size_targets;
+        self
     }
-
-    /// Add a permanent named alias/pin for a root
-    pub fn alias(&mut self, name: impl AsRef<[u8]>, link: Option<&Cid>) -> crate::Result<()> {
-        self.alias_many(std::iter::once((name, link.cloned())))
+    /// Set a low watermark, so gc keeps deleting until the store is at or below it once one of
+    /// the size targets is exceeded.
+    ///
+    /// Without a low watermark, gc stops as soon as the store is below the targets, so the next
+    /// write triggers gc again. The low watermark is capped at the size targets.
+    pub fn with_low_watermark(mut self, low_watermark: SizeTargets) -> Self {
+        self.low_watermark = Some(low_watermark);
+        self
     }
-
-    /// Add multiple permanent named aliases
-    pub fn alias_many(
-        &mut self,
-        aliases: impl IntoIterator<Item = (impl AsRef<[u8]>, Option<Cid>)>,
-    ) -> crate::Result<()> {
-        in_txn(&mut self.conn, |txn| {
-            for (name, link) in aliases.into_iter() {
-                let link: Option<CidBytes> = link.map(|x| CidBytes::try_from(&x)).transpose()?;
-                alias(txn, name.as_ref(), link.as_ref())?;
-            }
-            Ok(())
-        })
+    /// Set strategy for which non-pinned blocks to keep in case one of the size targets is exceeded.
+    pub fn with_cache_tracker<T: CacheTracker + 'static>(mut self, cache_tracker: T) -> Self {
+        self.cache_tracker = Box::new(cache_tracker);
+        self
     }
-
-    /// Returns the aliases referencing a block.
-    pub fn reverse_alias(&mut self, cid: &Cid) -> crate::Result<Vec<Vec<u8>>> {
-        let cid = CidBytes::try_from(cid)?;
-        in_txn(&mut self.conn, |txn| reverse_alias(txn, cid.as_ref()))
+    /// Set a hook to compute derived metadata for blocks when they are added.
+    pub fn with_metadata_hook<T: MetadataHook + 'static>(mut self, metadata_hook: T) -> Self {
+        self.metadata_hook = Some(Box::new(metadata_hook));
+        self
     }
-
-    /// Checks if the store knows about the cid.
+    /// Set the maximum number of provider hints to keep per block. The default is 8.
+    pub fn with_max_provider_hints(mut self, max_provider_hints: usize) -> Self {
+        self.max_provider_hints = max_provider_hints;
+        self
+    }
+    /// Set limits for the size of write transactions of batch operations
+    pub fn with_txn_limits(mut self, txn_limits: TxnLimits) -> Self {
+        self.txn_limits = txn_limits;
+        self
+    }
+    /// Set the throttle for gc in the background or via the
+    /// [async wrapper](async_block_store::AsyncBlockStore)
+    ///
+    /// This applies to the gc loop and the [GcScheduler](gc_scheduler::GcScheduler), and to the
+    /// gc, incremental gc, incremental delete orphaned and idle maintenance calls of the async
+    /// wrapper, which pause according to the throttle before they complete. Other maintenance
+    /// such as vacuum or checkpoints, and all calls on the [BlockStore] itself, are not
+    /// throttled. By default there is no limit.
+    pub fn with_throttle(mut self, throttle: Throttle) -> Self {
+        self.throttle = throttle;
+        self
+    }
+    /// Do background gc in short transactions of about `slice`, with pauses of the same
+    /// duration in between.
+    ///
+    /// This applies to the [gc loop](async_block_store::AsyncBlockStore::gc_loop) and the
+    /// [gc scheduler](gc_scheduler::GcScheduler), which continue until the target duration of
+    /// their [GcConfig](async_block_store::GcConfig) is exceeded or there is nothing left to do.
+    /// It bounds the time a concurrent read has to wait for gc, at the expense of gc throughput.
+    /// Each transaction collects at least one block, so `min_blocks` is not used. Note that each
+    /// transaction computes the set of gc candidates anew, which can take longer than the slice
+    /// for large stores.
+    pub fn with_gc_slice(mut self, slice: Duration) -> Self {
+        self.gc_slice = Some(slice);
+        self
+    }
+    /// Adjust the duration of each background gc transaction, i.e. the
+    /// [gc slice](Config::with_gc_slice) if set or otherwise the target duration, to how long
+    /// other calls had to wait for the store.
+    ///
+    /// This only applies to the [gc loop](async_block_store::AsyncBlockStore::gc_loop), see
+    /// [AdaptiveBudget](async_block_store::AdaptiveBudget).
+    pub fn with_adaptive_gc(mut self, adaptive: async_block_store::AdaptiveBudget) -> Self {
+        self.adaptive_gc = Some(adaptive);
+        self
+    }
+    /// Add a hook that is notified after changes have been committed
+    ///
+    /// Multiple hooks can be added, e.g. one for a sidecar index and an
+    /// [InsertChannel](hooks::InsertChannel) for replication. They are called in the order in
+    /// which they were added.
+    pub fn with_commit_hook<T: CommitHook + 'static>(mut self, commit_hook: T) -> Self {
+        self.commit_hooks.push(Box::new(commit_hook));
+        self
+    }
+    /// Normalize all cids to CIDv1 when storing and looking them up.
+    ///
+    /// With this enabled, a CIDv0 and the CIDv1 with the same multihash refer to the same block.
+    /// All cids returned by the store will be CIDv1. The original form of blocks added as CIDv0 is
+    /// recorded and can be retrieved with [BlockStore::get_original_cid].
+    ///
+    /// This should be set before adding any data, and never be changed for an existing store.
+    pub fn with_normalize_cids(mut self, normalize_cids: bool) -> Self {
+        self.normalize_cids = normalize_cids;
+        self
+    }
+    /// Set the maximum size of cids that can be added, in bytes.
+    ///
+    /// Adding a block, link, alias or temp pin with a larger cid fails with
+    /// [BlockStoreError::CidTooLarge], so malformed keys do not get into the store. The default
+    /// and the upper bound is 64 bytes.
+    pub fn with_max_cid_size(mut self, max_cid_size: usize) -> Self {
+        self.max_cid_size = max_cid_size.min(cidbytes::MAX_SIZE);
+        self
+    }
+    /// Protect blocks from gc for the given time after they were written.
+    ///
+    /// Blocks that are written shortly before they are aliased would otherwise be deleted if gc
+    /// runs in between and no temp pin is used. Blocks within the grace period protect their
+    /// descendants like a temp pin. Writing an existing block again restarts its grace period.
+    pub fn with_gc_grace_period(mut self, grace_period: Duration) -> Self {
+        self.gc_grace_period = Some(grace_period);
+        self
+    }
+    /// Keep a reference count of recursive pins and live parents for every live block.
+    ///
+    /// This makes gc cheap for stores with large pinned dags, since the set of live blocks does
+    /// not have to be recomputed on every gc. In exchange, writes get more expensive, since
+    /// pinning or unpinning a dag has to update the counts of all its blocks. This is disabled by
+    /// default, so gc walks the graph unless it is enabled. The counts are computed whenever the
+    /// store is opened with this option, since they are not updated by handles without it. See
+    /// [BlockStore::repair_refcounts].
+    pub fn with_refcount_gc(mut self, enabled: bool) -> Self {
+        self.refcount_gc = enabled;
+        self
+    }
+    /// Collect the largest unpinned blocks first in incremental gc.
+    ///
+    /// This frees the most space with the fewest deletions, so size targets are met quickly even
+    /// with a short time budget. The order of the cache tracker is only used to break ties
+    /// between blocks of the same size. See also [BlockStore::gc_bytes].
+    pub fn with_gc_largest_first(mut self, enabled: bool) -> Self {
+        self.gc_largest_first = enabled;
+        self
+    }
+    /// Record the time of the last read of each block in the database, and collect the least
+    /// recently used blocks first in gc.
+    ///
+    /// This is a persistent LRU order without having to implement a [CacheTracker]. Reads are
+    /// buffered in memory and written in batches, before the next gc, or when the store is
+    /// dropped. Blocks that were never read count as used when they were last written. The
+    /// order of the cache tracker is only used to break ties.
+    pub fn with_access_tracking(mut self, enabled: bool) -> Self {
+        self.access_tracking = enabled;
+        self
+    }
+    /// Keep a log of the cids of blocks that were deleted by gc for the given retention time.
+    ///
+    /// This gives applications a window to detect and undo accidental unpinning, by fetching
+    /// the blocks again. See [BlockStore::get_evictions].
+    pub fn with_eviction_log(mut self, retention: Duration) -> Self {
+        self.eviction_log = Some(retention);
+        self
+    }
+    /// Move blocks deleted by gc to a trash, and keep them there for the given retention time.
+    ///
+    /// In contrast to the [eviction log](Config::with_eviction_log), the trash keeps the data
+    /// and links of the blocks, so an accidental unpinning can be undone with
+    /// [BlockStore::undelete] without fetching the blocks again. Blocks in the trash take up
+    /// space, but are not counted in the store stats. They are deleted by gc once the retention
+    /// time has passed.
+    ///
+    /// Once created, the trash stays in the database. A store that is opened without this option
+    /// does not move deleted blocks to the trash, and neither expires nor restores the blocks
+    /// that are already in it, so they are kept until the store is opened with the trash again.
+    pub fn with_trash(mut self, retention: Duration) -> Self {
+        self.trash = Some(retention);
+        self
+    }
+    /// Treat aliases whose name starts with `prefix` as system pins. This can be called
+    /// multiple times to add several prefixes.
+    ///
+    /// System pins are for roots that are critical for the application, and must never be
+    /// affected by cache policies such as expiry or quotas of aliases. They are reported
+    /// separately in [BlockStore::alias_stats], see also [BlockStore::is_system_alias].
+    pub fn with_system_alias_prefix(mut self, prefix: impl Into<Vec<u8>>) -> Self {
+        self.system_alias_prefixes.push(prefix.into());
+        self
+    }
+    /// Keep a history of up to `max_entries` alias changes, see [BlockStore::get_alias_history].
+    ///
+    /// Every change of an alias is recorded with the previous and the new root, so applications
+    /// can implement undo and auditing of pin changes. By default no history is kept.
+    pub fn with_alias_history(mut self, max_entries: usize) -> Self {
+        self.alias_history = Some(max_entries);
+        self
+    }
+    /// Set limits for the number and size of temp pins
+    pub fn with_temp_pin_limits(mut self, temp_pin_limits: TempPinLimits) -> Self {
+        self.temp_pin_limits = temp_pin_limits;
+        self
+    }
+    /// Set the number of recent [BlockStore::has_block] answers to cache. The default is 1024.
+    ///
+    /// The cache is kept up to date when blocks are added or collected by this store. Changes by
+    /// other connections to the same database file are only seen when [BlockStore::has_changed]
+    /// finds them, which clears the cache, so the cache must be set to 0 if it is not polled.
+    pub fn with_has_cache_size(mut self, has_cache_size: usize) -> Self {
+        self.has_cache_size = has_cache_size;
+        self
+    }
+    /// Register a link extractor for a codec that libipld does not support.
+    ///
+    /// This is used to extract links when migrating old databases and when importing car files,
+    /// so blocks of this codec participate in reachability instead of appearing as leaves.
+    pub fn with_codec<T: LinkExtractor + 'static>(mut self, codec: u64, extractor: T) -> Self {
+        self.codecs.insert(codec, Box::new(extractor));
+        self
+    }
+    /// Store a fast checksum of the data of each added block, and verify it on read.
+    ///
+    /// A mismatch is reported as [BlockStoreError::ChecksumMismatch], which indicates corruption
+    /// of the database rather than bad data from a peer. Blocks that were added while checksums
+    /// were disabled are not verified.
+    pub fn with_checksums(mut self, checksums: bool) -> Self {
+        self.checksums = checksums;
+        self
+    }
+    /// Reject blocks with zero-length data with [BlockStoreError::EmptyBlock].
+    ///
+    /// Empty blocks are valid for some codecs, e.g. raw, so by default they are stored like any
+    /// other block.
+    pub fn with_reject_empty_blocks(mut self, reject_empty_blocks: bool) -> Self {
+        self.reject_empty_blocks = reject_empty_blocks;
+        self
+    }
+    /// Decode each added block and check that the given links exactly match its references.
+    ///
+    /// A mismatch fails the write with [BlockStoreError::LinkMismatch]. Wrong links silently
+    /// break gc reachability, so this is useful to catch integration bugs, at the cost of
+    /// decoding every block. Links are extracted with the [registered codecs](Config::with_codec).
+    pub fn with_validate_links(mut self, validate_links: bool) -> Self {
+        self.validate_links = validate_links;
+        self
+    }
+    /// Set the durability, page size and memory settings of the database
+    pub fn with_pragmas(mut self, pragmas: Pragmas) -> Self {
+        self.pragmas = pragmas;
+        self
+    }
+    /// Set an additional sqlite pragma when opening the database. This can be called multiple
+    /// times to set several pragmas.
+    ///
+    /// This is for tuning that is not covered by [Pragmas]. The pragmas are set in the order in
+    /// which they were added, after the ones of [Pragmas]. Pragmas that change the behavior of
+    /// the database, such as `foreign_keys` or `journal_mode`, will break the store.
+    pub fn with_pragma(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.custom_pragmas.push((name.into(), value.into()));
+        self
+    }
+    /// Set how long to wait for a lock held by another connection before failing. The default
+    /// is 5 seconds.
+    pub fn with_busy_timeout(mut self, busy_timeout: Duration) -> Self {
+        self.busy_timeout = busy_timeout;
+        self
+    }
+    /// Set how to retry write transactions while the database is locked, see [BusyRetry].
+    pub fn with_busy_retry(mut self, busy_retry: BusyRetry) -> Self {
+        self.busy_retry = busy_retry;
+        self
+    }
+    /// Open a pool of `n` read only connections for a [BlockReader], see [BlockStore::reader].
+    ///
+    /// This only has an effect for stores that are backed by a file. By default there are no
+    /// read connections.
+    pub fn with_read_connections(mut self, n: usize) -> Self {
+        self.read_connections = n;
+        self
+    }
+    /// Allow several processes to open the same database at the same time.
+    ///
+    /// By default, a store assumes that it is the only one that writes to the database, and
+    /// deletes all temp pins on open, since they must have been left over from a crash. With
+    /// this option, every store registers as the owner of its temp pins, see [ProcessGuard],
+    /// and only deletes temp pins of owners that did not show signs of life for `timeout`.
+    ///
+    /// A store shows signs of life on every gc and on [BlockStore::heartbeat], which must happen
+    /// more often than `timeout`, e.g. by running the
+    /// [gc loop](async_block_store::AsyncBlockStore::gc_loop). The has cache is disabled,
+    /// since it would not see the writes of other processes. Write transactions wait for each
+    /// other, see [Config::with_busy_timeout].
+    ///
+    /// All processes must use this option, and otherwise the same config. Opening the database
+    /// without this option fails with [BlockStoreError::InUse] while a store that uses it is
+    /// alive.
+    pub fn with_multi_process(mut self, timeout: Duration) -> Self {
+        self.multi_process = Some(timeout);
+        self
+    }
+    /// Defer housekeeping on open to an explicit call to [BlockStore::maintenance].
+    ///
+    /// This allows a store to start serving within milliseconds after a crash. Until maintenance
+    /// is done, temp pins that were not dropped because of the crash keep protecting their blocks,
+    /// and the cache tracker may track blocks that no longer exist.
+    pub fn with_defer_maintenance(mut self, defer_maintenance: bool) -> Self {
+        self.defer_maintenance = defer_maintenance;
+        self
+    }
+}
+
+pub struct BlockStore {
+    conn: Connection,
+    expired_temp_pins: Arc<Mutex<Vec<i64>>>,
+    gc_paused: usize,
+    /// true if the last incremental gc stopped before reaching the low watermark
+    gc_draining: bool,
+    /// see [BlockStore::set_read_only]
+    read_only: bool,
+    /// time of the last access of blocks that is not yet written, see
+    /// [Config::with_access_tracking]
+    accesses: FnvHashMap<i64, i64>,
+    has_cache: HasCache,
+    put_stats: PutStats,
+    /// data version at the last call of has_changed
+    data_version: i64,
+    /// temp pins up to this id are left over from a previous run
+    stale_temp_pins: i64,
+    /// see [Config::with_read_connections]
+    reader: Option<BlockReader>,
+    /// see [Config::with_multi_process]
+    process: Option<ProcessGuard>,
+    config: Config,
+}
+
+/// A reason why a block is kept alive, see [BlockStore::why_pinned]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PinReason {
+    /// an alias of the block itself or of one of its ancestors
+    Alias { name: Vec<u8>, root: Cid },
+    /// a temp pin of the block itself or of one of its ancestors
+    TempPin { id: i64, root: Cid },
+    /// a direct parent that is itself kept alive
+    Parent(Cid),
+}
+
+/// A change of an alias, see [BlockStore::get_alias_history]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AliasChange {
+    /// name of the alias
+    pub name: Vec<u8>,
+    /// the root before the change, or `None` if the alias was created
+    pub old: Option<Cid>,
+    /// the root after the change, or `None` if the alias was removed
+    pub new: Option<Cid>,
+    /// time of the change
+    pub time: SystemTime,
+}
+
+/// Differences between a snapshot of the store and the live store, see
+/// [BlockStore::diff_snapshot]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SnapshotDiff {
+    /// blocks that we have now, but did not have in the snapshot
+    pub added: Vec<Cid>,
+    /// blocks that we had in the snapshot, but no longer have
+    pub removed: Vec<Cid>,
+    /// aliases that were created, removed or changed since the snapshot, with their root in the
+    /// snapshot and their current root
+    pub aliases: Vec<(Vec<u8>, Option<Cid>, Option<Cid>)>,
+}
+
+/// A block of a [Manifest]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub cid: Cid,
+    /// size of the block, or `None` if we don't have it
+    pub size: Option<u64>,
+    /// length of the shortest path from the root to the block
+    pub depth: u32,
+}
+
+/// The blocks of a dag in a deterministic order, see [BlockStore::manifest]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    /// all blocks of the dag including the root, ordered by depth and then by the bytes of the cid
+    pub entries: Vec<ManifestEntry>,
+    /// sha2-256 of the entries.
+    ///
+    /// Each entry is encoded as the bytes of the cid, followed by the depth as a big endian u32,
+    /// followed by a 0 byte if the size is unknown or a 1 byte and the size as a big endian u64.
+    pub digest: Multihash,
+}
+
+impl Manifest {
+    fn new(entries: Vec<ManifestEntry>) -> Self {
+        let mut bytes = Vec::new();
+        for entry in &entries {
+            bytes.extend_from_slice(&entry.cid.to_bytes());
+            bytes.extend_from_slice(&entry.depth.to_be_bytes());
+            match entry.size {
+                Some(size) => {
+                    bytes.push(1);
+                    bytes.extend_from_slice(&size.to_be_bytes());
+                }
+                None => bytes.push(0),
+            }
+        }
+        let digest = Code::Sha2_256.digest(&bytes);
+        Self { entries, digest }
+    }
+}
+
+/// The result of [BlockStore::export_car_lenient]
+#[derive(Debug, Default)]
+pub struct ExportReport {
+    /// number of blocks that were written
+    pub blocks: u64,
+    /// blocks that could not be read, with their cid unless the cid itself is corrupt
+    pub errors: Vec<(Option<Cid>, BlockStoreError)>,
+}
+
+/// The difference between two dags, see [BlockStore::dag_diff]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DagDiff {
+    /// cids that are reachable from the new root, but not from the old root
+    pub added: Vec<Cid>,
+    /// cids that are reachable from the old root, but not from the new root
+    pub removed: Vec<Cid>,
+}
+
+/// What to do after visiting a block, see [BlockStore::traverse]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visit {
+    /// continue with the children of the block
+    Continue,
+    /// do not visit the children of the block, but continue with the rest
+    SkipChildren,
+    /// stop the traversal
+    Stop,
+}
+
+/// Optional features supported by a store, see [BlockStore::capabilities]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// version of the database schema
+    pub schema_version: u32,
+    /// whether block data is compressed. Not supported by this version.
+    pub compression: bool,
+    /// whether block data is encrypted. Not supported by this version.
+    pub encryption: bool,
+    /// whether blocks can be separated into namespaces. Not supported by this version.
+    pub namespaces: bool,
+    /// whether checksums of block data are stored and verified, see [Config::with_checksums]
+    pub checksums: bool,
+    /// whether cids are normalized, see [Config::with_normalize_cids]
+    pub normalize_cids: bool,
+}
+
+/// The pins that protect a block, see [BlockStore::reverse_alias]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReverseAlias {
+    /// names of the aliases of the block itself and the recursive aliases of its ancestors
+    pub aliases: Vec<Vec<u8>>,
+    /// number of temp pins of the block itself or of one of its ancestors
+    pub temp_pins: u64,
+}
+
+impl ReverseAlias {
+    /// true if the block is protected by at least one alias
+    pub fn is_aliased(&self) -> bool {
+        !self.aliases.is_empty()
+    }
+
+    /// true if the block is protected by at least one temp pin
+    pub fn is_temp_pinned(&self) -> bool {
+        self.temp_pins > 0
+    }
+}
+
+/// Statistics about the shape of the stored graph, see [BlockStore::graph_stats]
+///
+/// The cost of gc and other recursive queries grows with the number of links and the depth of
+/// the graph.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GraphStats {
+    /// total number of links
+    pub refs: u64,
+    /// number of blocks with at least one link
+    pub parents: u64,
+    /// maximum number of links of a single block
+    pub max_fanout: u64,
+    /// histogram of links per block. Entry `i` is the number of blocks with a number of links
+    /// in `2^i..2^(i+1)`.
+    pub fanout_histogram: Vec<u64>,
+    /// estimate of the maximum depth of the graph.
+    ///
+    /// This is the longest path along the first child of up to 100 aliased roots, so it is a
+    /// lower bound.
+    pub depth_estimate: u64,
+}
+
+/// Statistics about a single incremental gc, see [BlockStore::incremental_gc]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcStats {
+    /// number of blocks that were collected
+    pub blocks_deleted: u64,
+    /// total size of the collected blocks, which is freed by the next delete_orphaned
+    pub bytes_freed: u64,
+    /// number of unpinned blocks that still have to be collected to meet the size targets or
+    /// the low watermark, 0 if they are met
+    pub candidates_remaining: u64,
+    /// wall time of the gc, including the commit
+    pub elapsed: Duration,
+}
+
+impl GcStats {
+    /// true if either the size targets are met or there are no unpinned blocks left
+    pub fn is_complete(&self) -> bool {
+        self.candidates_remaining == 0
+    }
+}
+
+/// Statistics about a single deletion of orphaned blocks, see [BlockStore::delete_orphaned]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OrphanStats {
+    /// number of orphaned blocks that were deleted
+    pub deleted: u64,
+    /// number of orphaned blocks that are left to delete
+    pub remaining: u64,
+    /// wall time of the deletion, including the commit
+    pub elapsed: Duration,
+}
+
+impl OrphanStats {
+    /// true if all orphaned blocks are deleted
+    pub fn is_complete(&self) -> bool {
+        self.remaining == 0
+    }
+}
+
+/// How to checkpoint the wal, see [BlockStore::checkpoint]
+///
+/// These correspond to the modes of sqlite's `wal_checkpoint` pragma.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckpointMode {
+    /// checkpoint as much as possible without waiting for readers or writers
+    Passive,
+    /// wait for writers, then checkpoint everything that is not needed by readers
+    Full,
+    /// like [CheckpointMode::Full], then wait for readers so the wal starts over
+    Restart,
+    /// like [CheckpointMode::Restart], then truncate the wal file to zero bytes
+    Truncate,
+}
+
+/// The result of a wal checkpoint, see [BlockStore::checkpoint]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CheckpointStats {
+    /// true if the checkpoint could not complete because of concurrent readers or writers
+    pub busy: bool,
+    /// number of pages in the wal, 0 if the database is not in wal mode
+    pub log_pages: u64,
+    /// number of pages of the wal that have been written back to the database
+    pub checkpointed_pages: u64,
+}
+
+/// Statistics about a single run of [BlockStore::idle_maintenance]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MaintenanceStats {
+    /// number of orphaned blocks that were deleted
+    pub orphans_deleted: u64,
+    /// number of orphaned blocks that are left to delete
+    pub orphans_remaining: u64,
+    /// number of cids that nothing refers to that were deleted
+    pub cids_deleted: u64,
+    /// true if the wal was checkpointed
+    pub checkpointed: bool,
+    /// true if the query planner statistics were updated
+    pub analyzed: bool,
+    /// number of free pages that were returned to the file system
+    pub pages_vacuumed: u64,
+    /// true if all steps ran to completion, i.e. there is nothing left to do
+    pub complete: bool,
+    /// wall time of the maintenance
+    pub elapsed: Duration,
+}
+
+/// Cumulative statistics about block writes since the store was opened, see
+/// [BlockStore::put_stats]
+///
+/// The time of a write is split into stages, so it is possible to tell whether slow ingest is
+/// caused by link extraction and validation, by the inserts, or by the commits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PutStats {
+    /// number of write transactions
+    pub batches: u64,
+    /// number of blocks written
+    pub blocks: u64,
+    /// maximum number of blocks in a single transaction
+    pub max_batch_size: u64,
+    /// time spent extracting links, validating and computing keys
+    pub decode_time: Duration,
+    /// time spent inserting blocks, links and metadata
+    pub write_time: Duration,
+    /// time spent committing the transactions
+    pub commit_time: Duration,
+}
+
+/// Statistics about aliases, see [BlockStore::alias_stats]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AliasStats {
+    /// total number of aliases, including system aliases
+    pub aliases: u64,
+    /// number of [system aliases](Config::with_system_alias_prefix)
+    pub system_aliases: u64,
+    /// blocks protected by system aliases, some of which might also be protected by other aliases
+    pub system_pinned: StoreStats,
+}
+
+/// Approximate statistics for dashboards, see [BlockStore::approx_stats]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ApproxStats {
+    /// upper bound for the number of known cids
+    pub max_cids: u64,
+    /// upper bound for the number of blocks, including orphaned blocks
+    pub max_blocks: u64,
+    /// average size of a block, from a random sample
+    pub avg_block_size: u64,
+    /// size of the used pages of the database, in bytes
+    pub db_size: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    count: u64,
+    size: u64,
+}
+
+impl StoreStats {
+    /// Total number of blocks in the store
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Total size of blocks in the store
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+// do not implement Clone for this!
+/// a handle that contains a temporary pin
+///
+/// dropping this handle enqueue the pin for dropping before the next gc. So the lifetime of the
+/// pin is tied to the scope of the handle, and a pin can not be leaked by forgetting to delete it.
+/// Temp pins that were not dropped because of a crash are deleted on the next start.
+#[must_use = "the temp pin is released as soon as the handle is dropped"]
+pub struct TempPin {
+    id: AtomicI64,
+    expired_temp_pins: Arc<Mutex<Vec<i64>>>,
+}
+
+/// dump the temp alias id so you can find it in the database
+impl fmt::Debug for TempPin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let id = self.id.load(Ordering::SeqCst);
+        let mut builder = f.debug_struct("TempAlias");
+        if id > 0 {
+            builder.field("id", &id);
+        }
+        builder.finish()
+    }
+}
+
+impl Drop for TempPin {
+    fn drop(&mut self) {
+        let id = self.id.get_mut();
+        let alias = *id;
+        if alias > 0 {
+            // not sure if we have to guard against double drop, but it certainly does not hurt.
+            *id = 0;
+            self.expired_temp_pins.lock().unwrap().push(alias);
+        }
+    }
+}
+
+impl Drop for BlockStore {
+    fn drop(&mut self) {
+        // write pending accesses, so they are not lost
+        self.take_reader_accesses();
+        self.flush_accesses();
+        // release the temp pins of this store right away, instead of when it times out
+        if let Some(process) = self.process {
+            let refcount_gc = self.config.refcount_gc;
+            if let Err(cause) = in_txn(&mut self.conn, self.config.busy_retry, |txn| {
+                unregister_owner(txn, process.id, refcount_gc)
+            }) {
+                warn!("unable to unregister owner {} {}", process.id, cause);
+            }
+        }
+    }
+}
+
+/// An ipfs block
+pub trait Block {
+    fn cid(&self) -> &Cid;
+    fn data(&self) -> &[u8];
+    fn links(&self) -> anyhow::Result<Vec<Cid>>;
+}
+
+/// Block that owns its data
+#[derive(Debug)]
+pub struct OwnedBlock {
+    cid: Cid,
+    data: Vec<u8>,
+    links: Vec<Cid>,
+}
+
+impl OwnedBlock {
+    pub fn new(cid: Cid, data: Vec<u8>, links: Vec<Cid>) -> Self {
+        Self { cid, data, links }
+    }
+}
+
+impl Block for OwnedBlock {
+    fn cid(&self) -> &Cid {
+        &self.cid
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn links(&self) -> anyhow::Result<Vec<Cid>> {
+        Ok(self.links.clone())
+    }
+}
+
+struct BorrowedBlock<'a, F> {
+    cid: Cid,
+    data: &'a [u8],
+    links: F,
+}
+
+impl<'a, F> BorrowedBlock<'a, F>
+where
+    F: Fn() -> anyhow::Result<Vec<Cid>>,
+{
+    fn new(cid: Cid, data: &'a [u8], links: F) -> Self {
+        Self { cid, data, links }
+    }
+}
+
+impl<'a, F> Block for BorrowedBlock<'a, F>
+where
+    F: Fn() -> anyhow::Result<Vec<Cid>>,
+{
+    fn cid(&self) -> &Cid {
+        &self.cid
+    }
+
+    fn data(&self) -> &[u8] {
+        self.data
+    }
+
+    fn links(&self) -> anyhow::Result<Vec<Cid>> {
+        (self.links)()
+    }
+}
+
+/// A set of writes that are committed atomically, see [BlockStore::write_batch]
+///
+/// A batch only collects the operations, so it can be built without access to the store.
+/// Temp pin operations apply to the temp pin that is passed to [BlockStore::write_batch].
+#[derive(Debug, Default)]
+pub struct Batch {
+    blocks: Vec<OwnedBlock>,
+    aliases: Vec<(Vec<u8>, Option<Cid>, bool)>,
+    pinned: Vec<Cid>,
+    release_temp_pin: bool,
+}
+
+impl Batch {
+    /// Create an empty batch
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// true if the batch does not contain any operations
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+            && self.aliases.is_empty()
+            && self.pinned.is_empty()
+            && !self.release_temp_pin
+    }
+
+    /// Add a block, see [BlockStore::put_block]
+    pub fn put_block(&mut self, cid: &Cid, data: &[u8], links: impl IntoIterator<Item = Cid>) {
+        self.put(OwnedBlock::new(
+            *cid,
+            data.to_vec(),
+            links.into_iter().collect(),
+        ));
+    }
+
+    /// Add a block that is already owned
+    pub fn put(&mut self, block: OwnedBlock) {
+        self.blocks.push(block);
+    }
+
+    /// Set or remove an alias, see [BlockStore::alias]
+    pub fn alias(&mut self, name: impl AsRef<[u8]>, link: Option<&Cid>) {
+        self.aliases
+            .push((name.as_ref().to_vec(), link.cloned(), true));
+    }
+
+    /// Set or remove a direct alias, see [BlockStore::alias_direct]
+    pub fn alias_direct(&mut self, name: impl AsRef<[u8]>, link: Option<&Cid>) {
+        self.aliases
+            .push((name.as_ref().to_vec(), link.cloned(), false));
+    }
+
+    /// Add cids to the temp pin, see [BlockStore::extend_temp_pin]
+    pub fn extend_temp_pin(&mut self, cids: impl IntoIterator<Item = Cid>) {
+        self.pinned.extend(cids);
+    }
+
+    /// Delete the temp pin once all other operations of the batch are done
+    ///
+    /// Together with an alias for the root, this is like [BlockStore::assign_temp_pin_to_alias].
+    pub fn release_temp_pin(&mut self) {
+        self.release_temp_pin = true;
+    }
+}
+
+/// A write transaction for the tables of an extension, see [BlockStore::extension_txn]
+///
+/// This only allows to run statements. The transaction itself is committed or rolled back by
+/// the store, depending on the result of the function.
+pub struct ExtensionTxn<'a> {
+    txn: &'a Transaction<'a>,
+}
+
+impl<'a> ExtensionTxn<'a> {
+    /// Execute a statement, returning the number of changed rows
+    pub fn execute(&self, sql: &str, params: &[&dyn rusqlite::ToSql]) -> Result<usize> {
+        Ok(self.txn.prepare_cached(sql)?.execute(params)?)
+    }
+
+    /// Run a query that returns a single row, or None if it returns no rows
+    pub fn query_row<T>(
+        &self,
+        sql: &str,
+        params: &[&dyn rusqlite::ToSql],
+        f: impl FnOnce(&rusqlite::Row) -> rusqlite::Result<T>,
+    ) -> Result<Option<T>> {
+        Ok(self
+            .txn
+            .prepare_cached(sql)?
+            .query_row(params, f)
+            .optional()?)
+    }
+
+    /// Run a query, mapping all rows
+    pub fn query<T>(
+        &self,
+        sql: &str,
+        params: &[&dyn rusqlite::ToSql],
+        f: impl FnMut(&rusqlite::Row) -> rusqlite::Result<T>,
+    ) -> Result<Vec<T>> {
+        Ok(self
+            .txn
+            .prepare_cached(sql)?
+            .query_map(params, f)?
+            .collect::<rusqlite::Result<Vec<T>>>()?)
+    }
+}
+
+/// a file for staging a database, which is deleted on drop, also on errors
+///
+/// the file gets a random name and is created exclusively, so a file or symlink that another
+/// user placed in a shared directory is never opened. On unix, only the owner can read it.
+struct StagingFile(std::path::PathBuf);
+
+impl StagingFile {
+    /// create a new empty staging file in `dir`
+    fn create(dir: &Path) -> std::io::Result<(Self, std::fs::File)> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        loop {
+            // RandomState is seeded from the os, so the name can not be predicted
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u32(std::process::id());
+            hasher.write_usize(COUNTER.fetch_add(1, Ordering::SeqCst));
+            let path = dir.join(format!(
+                "ipfs-sqlite-block-store-{:016x}.sqlite",
+                hasher.finish()
+            ));
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            match options.open(&path) {
+                Ok(file) => return Ok((Self(path), file)),
+                Err(cause) if cause.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(cause) => return Err(cause),
+            }
+        }
+    }
+}
+
+impl Drop for StagingFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// write a single block within a transaction
+///
+/// returns the info for the cache tracker, the key of the block, whether it is new, and the
+/// number of links.
+fn write_block(
+    txn: &Transaction,
+    config: &Config,
+    block: &impl Block,
+    alias: Option<&AtomicI64>,
+    now: i64,
+    stats: &mut PutStats,
+) -> Result<(BlockInfo, CidBytes, bool, usize)> {
+    let normalize = config.normalize_cids;
+    let max_cid_size = config.max_cid_size;
+    let t0 = Instant::now();
+    if config.reject_empty_blocks && block.data().is_empty() {
+        return Err(BlockStoreError::EmptyBlock(*block.cid()));
+    }
+    if config.validate_links {
+        validate_links(&config.codecs, block)?;
+    }
+    let cid_bytes = insert_key(block.cid(), normalize, max_cid_size)?;
+    let links = block
+        .links()?
+        .iter()
+        .map(|link| insert_key(link, normalize, max_cid_size))
+        .collect::<Result<Vec<_>>>()?;
+    let n_links = links.len();
+    let t1 = Instant::now();
+    stats.decode_time += t1 - t0;
+    let (id, is_new) = put_block(
+        txn,
+        &cid_bytes,
+        &block.data(),
+        links,
+        alias,
+        now,
+        config.refcount_gc,
+    )?;
+    // the data of an existing block is not replaced, so neither is its checksum
+    if config.checksums && is_new {
+        set_checksum(txn, id, block.data())?;
+    }
+    if normalize && block.cid().version() == cid::Version::V0 {
+        set_original_cid(txn, id, &block.cid().to_bytes())?;
+    }
+    if let Some(hook) = &config.metadata_hook {
+        for (key, value) in hook.metadata(block.cid(), block.data()) {
+            set_metadata(txn, id, &key, &value)?;
+        }
+    }
+    stats.write_time += t1.elapsed();
+    stats.blocks += 1;
+    let info = BlockInfo::new(id, block.cid(), block.data());
+    Ok((info, cid_bytes, is_new, n_links))
+}
+
+/// set or remove a single alias within a transaction, logging the change if enabled
+fn write_alias(
+    txn: &Transaction,
+    config: &Config,
+    name: &[u8],
+    link: Option<&Cid>,
+    recursive: bool,
+) -> Result<()> {
+    let link_bytes: Option<CidBytes> = link
+        .map(|x| insert_key(x, config.normalize_cids, config.max_cid_size))
+        .transpose()?;
+    if let Some(max_entries) = config.alias_history {
+        let now = unix_millis(SystemTime::now());
+        log_alias_change(txn, name, link_bytes.as_ref(), now, max_entries)?;
+    }
+    alias(
+        txn,
+        name,
+        link_bytes.as_ref(),
+        recursive,
+        config.refcount_gc,
+    )
+}
+
+/// check that the links of a block match the references in its data
+fn validate_links(codecs: &Codecs, block: &impl Block) -> Result<()> {
+    let decoded = libipld::Block::<DefaultParams>::new(*block.cid(), block.data().to_vec())?;
+    let actual = references(codecs, &decoded)?
+        .into_iter()
+        .collect::<BTreeSet<_>>();
+    let given = block.links()?.into_iter().collect::<BTreeSet<_>>();
+    if actual != given {
+        return Err(BlockStoreError::LinkMismatch(*block.cid()));
+    }
+    Ok(())
+}
+
+/// parse a cid that was read from the database
+///
+/// this will give a typed error instead of a generic cid error, since a failure here means that
+/// there is a legacy or corrupt row in the cids table, not that the caller passed in a bad cid.
+fn cid_from_stored(bytes: &CidBytes) -> Result<Cid> {
+    Cid::try_from(bytes).map_err(|_| BlockStoreError::CorruptCid(bytes.as_ref().to_vec()))
+}
+
+/// an error of a single block in a bulk read, with the cid of the block unless it is corrupt
+type RowError = (Option<Cid>, BlockStoreError);
+
+/// decode a row of a bulk read, verifying its checksum if enabled
+#[allow(clippy::result_large_err)]
+fn decode_bulk_row(
+    (cid, data, expected): BulkRow<CidBytes>,
+    checksums: bool,
+) -> std::result::Result<(Cid, Vec<u8>), RowError> {
+    let cid = cid
+        .map_err(BlockStoreError::from)
+        .and_then(|cid| cid_from_stored(&cid))
+        .map_err(|cause| (None, cause))?;
+    let data = data.map_err(|cause| (Some(cid), cause.into()))?;
+    let expected = expected.map_err(|cause| (Some(cid), cause.into()))?;
+    if checksums && matches!(expected, Some(expected) if expected != checksum(&data)) {
+        return Err((Some(cid), BlockStoreError::ChecksumMismatch(cid)));
+    }
+    Ok((cid, data))
+}
+
+/// normalize a CIDv0 to the CIDv1 with the same multihash
+fn normalize_cid(cid: &Cid) -> Cid {
+    match cid.version() {
+        cid::Version::V0 => Cid::new_v1(cid.codec(), *cid.hash()),
+        cid::Version::V1 => *cid,
+    }
+}
+
+/// convert a cid to the key under which it is stored
+fn cid_key(cid: &Cid, normalize: bool) -> Result<CidBytes> {
+    Ok(if normalize {
+        CidBytes::try_from(&normalize_cid(cid))?
+    } else {
+        CidBytes::try_from(cid)?
+    })
+}
+
+/// look up blocks in a single read transaction, verifying their checksums if enabled
+fn read_blocks(
+    conn: &Connection,
+    cids: Vec<(Cid, CidBytes)>,
+    checksums: bool,
+) -> Result<Vec<(Cid, Option<BlockRow>)>> {
+    let keys = cids.iter().map(|(_, key)| *key).collect::<Vec<_>>();
+    let mut blocks = in_ro_txn(conn, |txn| get_blocks(txn, &keys))?;
+    if checksums {
+        for (cid, key) in &cids {
+            if let Some((_, data, Some(expected))) = blocks.get(key) {
+                if checksum(data) != *expected {
+                    return Err(BlockStoreError::ChecksumMismatch(*cid));
+                }
+            }
+        }
+    }
+    // a cid can be requested more than once, so only move the data out on its last occurrence
+    let last = keys
+        .iter()
+        .enumerate()
+        .map(|(i, key)| (*key, i))
+        .collect::<FnvHashMap<_, _>>();
+    Ok(cids
+        .into_iter()
+        .enumerate()
+        .map(|(i, (cid, key))| {
+            let block = if last[&key] == i {
+                blocks.remove(&key)
+            } else {
+                blocks.get(&key).cloned()
+            };
+            (cid, block)
+        })
+        .collect())
+}
+
+/// infos for the cache tracker about the blocks that were found by [read_blocks]
+fn block_infos(blocks: &[(Cid, Option<BlockRow>)]) -> Vec<BlockInfo> {
+    blocks
+        .iter()
+        .filter_map(|(cid, res)| {
+            res.as_ref()
+                .map(|(id, data, _)| BlockInfo::new(*id, cid, data))
+        })
+        .collect()
+}
+
+/// the key of a cid that is to be added to the store, checked against the maximum cid size
+fn insert_key(cid: &Cid, normalize: bool, max_cid_size: usize) -> Result<CidBytes> {
+    match cid_key(cid, normalize) {
+        Ok(key) if key.as_ref().len() <= max_cid_size => Ok(key),
+        Ok(_) => Err(BlockStoreError::CidTooLarge(*cid)),
+        // the key does not fit into CidBytes at all
+        Err(_) if stored_len(cid, normalize) > cidbytes::MAX_SIZE => {
+            Err(BlockStoreError::CidTooLarge(*cid))
+        }
+        Err(cause) => Err(cause),
+    }
+}
+
+/// length of the key under which a cid is stored, without the size limit of CidBytes
+fn stored_len(cid: &Cid, normalize: bool) -> usize {
+    if normalize {
+        normalize_cid(cid).to_bytes().len()
+    } else {
+        cid.to_bytes().len()
+    }
+}
+
+/// milliseconds since the unix epoch, as stored in the database
+fn unix_millis(time: SystemTime) -> i64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+/// number of blocks to read per transaction in iter_blocks
+const ITER_BATCH_SIZE: usize = 1000;
+
+/// number of blocks to import from a car file per call to put_blocks
+const CAR_BATCH_SIZE: usize = 1000;
+
+/// number of block accesses to buffer before writing them, see [Config::with_access_tracking]
+const ACCESS_BATCH_SIZE: usize = 1000;
+
+/// number of orphaned cids to delete per transaction in idle_maintenance
+const ORPHANED_CIDS_BATCH: u64 = 1000;
+
+/// max number of blocks to read ahead in get_leaves_stream
+const LEAVES_BATCH: usize = 256;
+
+/// read ahead the next blocks of `pending` and take them off in dag order, expanding the
+/// interior blocks, until a block is needed that was not read ahead. Returns the leaves.
+fn next_leaves(
+    txn: &Transaction,
+    pending: &mut VecDeque<CidBytes>,
+    config: &Config,
+) -> Result<Vec<(Cid, Vec<u8>)>> {
+    let batch = pending
+        .iter()
+        .copied()
+        .take(LEAVES_BATCH)
+        .collect::<Vec<_>>();
+    let fetched = get_blocks(txn, &batch)?;
+    let read = batch.into_iter().collect::<FnvHashSet<_>>();
+    let mut leaves = Vec::new();
+    while let Some(key) = pending.front().copied() {
+        if !read.contains(&key) {
+            break;
+        }
+        pending.pop_front();
+        // a missing block is skipped
+        let (id, data, expected) = match fetched.get(&key) {
+            Some(row) => row,
+            None => continue,
+        };
+        let cid = cid_from_stored(&key)?;
+        if config.checksums && matches!(expected, Some(expected) if *expected != checksum(data)) {
+            return Err(BlockStoreError::ChecksumMismatch(cid));
+        }
+        if get_links::<CidBytes>(txn, *id)?.is_empty() {
+            leaves.push((cid, data.clone()));
+        } else {
+            let block = libipld::Block::<DefaultParams>::new(cid, data.clone())?;
+            let links = ordered_references(&config.codecs, &block)?;
+            for link in links.iter().rev() {
+                pending.push_front(cid_key(link, config.normalize_cids)?);
+            }
+        }
+    }
+    Ok(leaves)
+}
+
+/// notify the commit hook, if any
+fn after_commit(hooks: &mut Vec<Box<dyn CommitHook>>, commit: Commit) {
+    hooks.retain(|hook| !hook.is_closed());
+    for hook in hooks {
+        hook.after_commit(&commit);
+    }
+}
+
+impl BlockStore {
+    /// a writable store on an initialized connection, without reader, process guard or stale
+    /// temp pins
+    fn from_conn(conn: Connection, config: Config) -> crate::Result<Self> {
+        let data_version = data_version(&conn)?;
+        Ok(Self {
+            conn,
+            expired_temp_pins: Arc::new(Mutex::new(Vec::new())),
+            process: None,
+            reader: None,
+            gc_paused: 0,
+            gc_draining: false,
+            accesses: FnvHashMap::default(),
+            read_only: false,
+            has_cache: HasCache::new(config.has_cache_size),
+            put_stats: PutStats::default(),
+            data_version,
+            stale_temp_pins: 0,
+            config,
+        })
+    }
+
+    /// Create an in memory block store with the given config
+    pub fn memory(config: Config) -> crate::Result<Self> {
+        let mut conn = Connection::open_in_memory()?;
+        init_db(&mut conn, true, &config)?;
+        Self::from_conn(conn, config)
+    }
+
+    /// Create an in memory block store from a serialized database, see [BlockStore::serialize].
+    ///
+    /// This is for small stores that are embedded in a binary or sent over the network, or for
+    /// loading a store into memory wholesale for read-mostly workloads. Changes are not written
+    /// back anywhere. The bytes are staged in a temporary file in [std::env::temp_dir] and
+    /// restored with the backup api, since rusqlite 0.24 does not expose the sqlite
+    /// deserialize api.
+    pub fn open_from_bytes(bytes: &[u8], config: Config) -> crate::Result<Self> {
+        let (file, mut handle) = StagingFile::create(&std::env::temp_dir())?;
+        handle.write_all(bytes)?;
+        drop(handle);
+        let mut conn = Connection::open_in_memory()?;
+        conn.restore(
+            DatabaseName::Main,
+            &file.0,
+            None::<fn(rusqlite::backup::Progress)>,
+        )?;
+        init_db(&mut conn, true, &config)?;
+        let stale_temp_pins = in_ro_txn(&conn, |txn| max_temp_pin_id(txn))?;
+        let mut store = Self::from_conn(conn, config)?;
+        store.stale_temp_pins = stale_temp_pins;
+        store.housekeeping()?;
+        Ok(store)
+    }
+
+    /// Serialize the whole database, e.g. for [BlockStore::open_from_bytes].
+    ///
+    /// This is a [backup](BlockStore::backup_to_writer) into memory, since rusqlite 0.24 does not
+    /// expose the sqlite serialize api, so it is only suitable for small stores.
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.backup_to_writer(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Create a persistent block store with the given config
+    ///
+    /// This does some housekeeping that can take a while for large stores, unless
+    /// [Config::with_defer_maintenance] is set.
+    pub fn open(path: impl AsRef<Path>, config: Config) -> crate::Result<Self> {
+        let mut conn = Connection::open(path.as_ref())?;
+        init_db(&mut conn, false, &config)?;
+        let reader = if config.read_connections > 0 {
+            Some(BlockReader::open(path.as_ref(), &config)?)
+        } else {
+            None
+        };
+        let stale_temp_pins = in_ro_txn(&conn, |txn| max_temp_pin_id(txn))?;
+        let now = unix_millis(SystemTime::now());
+        let process = if let Some(timeout) = config.multi_process {
+            let id = in_txn(&mut conn, config.busy_retry, |txn| {
+                register_owner(txn, std::process::id(), now, timeout.as_millis() as i64)
+            })?;
+            debug!("registered as owner {} of temp pins", id);
+            Some(ProcessGuard { id, timeout })
+        } else {
+            // housekeeping would delete the temp pins of the live stores
+            let live = in_ro_txn(&conn, |txn| live_owners(txn, now))?;
+            if live > 0 {
+                return Err(BlockStoreError::InUse(live));
+            }
+            None
+        };
+        let mut store = Self::from_conn(conn, config)?;
+        if process.is_some() {
+            store.has_cache = HasCache::new(0);
+        }
+        store.process = process;
+        store.reader = reader;
+        store.stale_temp_pins = stale_temp_pins;
+        if !store.config.defer_maintenance {
+            store.housekeeping()?;
+        }
+        Ok(store)
+    }
+
+    /// Open a store that is written by another process, for reading only. This is experimental.
+    ///
+    /// This allows a separate process to serve the blocks of a store, using
+    /// [BlockStore::has_changed] to find out when the writer has committed changes. All writes
+    /// fail, and no maintenance is done on open. The has cache is disabled, since it would not
+    /// see the changes of the writer.
+    ///
+    /// The database must have been created by [BlockStore::open], and must be in the same
+    /// directory as its `-wal` and `-shm` files, which the writer creates.
+    pub fn open_read_only(path: impl AsRef<Path>, config: Config) -> crate::Result<Self> {
+        let conn = Connection::open_with_flags(path.as_ref(), OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        set_pragmas(&conn, &config)?;
+        let reader = if config.read_connections > 0 {
+            Some(BlockReader::open(path.as_ref(), &config)?)
+        } else {
+            None
+        };
+        let mut store = Self::from_conn(conn, config)?;
+        store.reader = reader;
+        store.read_only = true;
+        store.has_cache = HasCache::new(0);
+        Ok(store)
+    }
+
+    /// The registration of this store as the owner of its temp pins, if the store was opened
+    /// with [Config::with_multi_process].
+    pub fn process_guard(&self) -> Option<&ProcessGuard> {
+        self.process.as_ref()
+    }
+
+    /// Show other processes that this store is still alive, see [Config::with_multi_process].
+    ///
+    /// This is done automatically on every gc. Does nothing if the store is not shared. Fails
+    /// with [BlockStoreError::OwnerExpired] if another process already considered the store
+    /// dead.
+    pub fn heartbeat(&mut self) -> Result<()> {
+        if let Some(process) = self.process {
+            let now = unix_millis(SystemTime::now());
+            in_txn(&mut self.conn, self.config.busy_retry, |txn| {
+                heartbeat(txn, process.id, now)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Get a handle for reading blocks on separate connections, if the store was opened with
+    /// [Config::with_read_connections].
+    ///
+    /// Reads via the handle do not wait for write transactions of the store, and see all writes
+    /// of the store that were done before them, see [reader].
+    pub fn reader(&self) -> Option<BlockReader> {
+        self.reader.clone()
+    }
+
+    /// Checks if another connection, e.g. the writer of a [read only](BlockStore::open_read_only)
+    /// store, has committed changes since the last call or since the store was opened.
+    ///
+    /// This is cheap, so it can be polled. Changes done through this store are not reported.
+    /// Changes are passed on to the [CommitHook]s, see [CommitHook::after_external_commit], and
+    /// clear the cache of [BlockStore::has_block] answers.
+    pub fn has_changed(&mut self) -> Result<bool> {
+        let data_version = data_version(&self.conn)?;
+        let changed = data_version != self.data_version;
+        self.data_version = data_version;
+        if changed {
+            self.has_cache.clear();
+        }
+        if changed && !self.config.commit_hooks.is_empty() {
+            let seq = in_ro_txn(&self.conn, get_commit_seq)?;
+            self.config.commit_hooks.retain(|hook| !hook.is_closed());
+            for hook in &mut self.config.commit_hooks {
+                hook.after_external_commit(seq);
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Do the housekeeping that was deferred on open, see [Config::with_defer_maintenance].
+    ///
+    /// This deletes temp pins that were left over from a crash and
+    /// [orphaned cids](BlockStore::delete_orphaned_cids), recomputes the store stats, updates the
+    /// cache tracker and checks foreign key constraints. It can be called at any time.
+    pub fn maintenance(&mut self) -> Result<()> {
+        self.check_writable()?;
+        self.housekeeping()?;
+        let violations = in_ro_txn(&self.conn, |txn| foreign_key_violations(txn))?;
+        if violations > 0 {
+            return Err(anyhow::anyhow!("{} foreign key violations", violations).into());
+        }
+        Ok(())
+    }
+
+    /// Write the content of the wal back to the database.
+    ///
+    /// The wal grows with every write transaction until it is checkpointed. Sqlite does this
+    /// automatically, but only in passive mode, so after large imports or while there are long
+    /// running readers the wal file can grow very large. [CheckpointMode::Truncate] also shrinks
+    /// the file, see [BlockStore::flush].
+    pub fn checkpoint(&mut self, mode: CheckpointMode) -> Result<CheckpointStats> {
+        let stats = checkpoint(&self.conn, mode)?;
+        debug!("checkpoint {:?} {:?}", mode, stats);
+        Ok(stats)
+    }
+
+    /// Write the entire wal back to the database and truncate the wal file.
+    ///
+    /// This waits for concurrent writers and readers of the database, e.g. other processes.
+    /// Fails with [BlockStoreError::Busy] if they did not finish in time.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.checkpoint(CheckpointMode::Truncate)?.busy {
+            return Err(BlockStoreError::Busy);
+        }
+        Ok(())
+    }
+
+    /// Do as much background maintenance as fits into `budget`.
+    ///
+    /// This is meant to be called periodically by applications that want to use idle time,
+    /// instead of scheduling the individual tasks themselves. In this order, and only while
+    /// there is time left, it
+    ///
+    /// - deletes orphaned blocks, see [BlockStore::delete_orphaned], unless gc is paused
+    /// - deletes cids that nothing refers to in batches of 1000, see
+    ///   [BlockStore::delete_orphaned_cids]
+    /// - checkpoints the wal without blocking readers or writers
+    /// - updates the query planner statistics
+    /// - returns free pages to the file system, if the database uses incremental auto vacuum,
+    ///   i.e. it was created with `Config::with_pragma("auto_vacuum", "incremental")`
+    ///
+    /// Individual steps are not interrupted, so the budget can be exceeded by the duration of a
+    /// single step. Unlike [BlockStore::maintenance], this does not check the database for
+    /// consistency.
+    pub fn idle_maintenance(&mut self, budget: Duration) -> Result<MaintenanceStats> {
+        self.check_writable()?;
+        let t0 = Instant::now();
+        let has_time = || t0.elapsed() < budget;
+        let mut stats = MaintenanceStats::default();
+        let mut orphans_done = false;
+        if !self.is_gc_paused() {
+            let orphans = self.delete_orphaned(0, budget)?;
+            stats.orphans_deleted = orphans.deleted;
+            stats.orphans_remaining = orphans.remaining;
+            orphans_done = orphans.is_complete();
+        }
+        let mut cids_done = false;
+        while !cids_done && has_time() {
+            let cids = in_txn(&mut self.conn, self.config.busy_retry, |txn| {
+                delete_orphaned_cids(txn, Some(ORPHANED_CIDS_BATCH))
+            })?;
+            stats.cids_deleted += cids;
+            cids_done = cids < ORPHANED_CIDS_BATCH;
+        }
+        if has_time() {
+            checkpoint(&self.conn, CheckpointMode::Passive)?;
+            stats.checkpointed = true;
+        }
+        if has_time() {
+            optimize(&self.conn)?;
+            stats.analyzed = true;
+        }
+        let mut vacuum_done = false;
+        while !vacuum_done && has_time() {
+            let pages = incremental_vacuum(&self.conn, 1000)?;
+            stats.pages_vacuumed += pages;
+            vacuum_done = pages < 1000;
+        }
+        stats.complete = orphans_done && cids_done && vacuum_done;
+        stats.elapsed = t0.elapsed();
+        debug!("idle maintenance {:?}", stats);
+        Ok(stats)
+    }
+
+    fn housekeeping(&mut self) -> Result<()> {
+        let stale_temp_pins = self.stale_temp_pins;
+        let refcount_gc = self.config.refcount_gc;
+        let process = self.process;
+        let now = unix_millis(SystemTime::now());
+        let ids = in_txn(&mut self.conn, self.config.busy_retry, |txn| {
+            let owner_cutoff = match process {
+                Some(process) => {
+                    heartbeat(txn, process.id, now)?;
+                    Some(now - process.timeout.as_millis() as i64)
+                }
+                None => None,
+            };
+            maintenance(txn, stale_temp_pins, owner_cutoff, refcount_gc)?;
+            get_ids(txn)
+        })?;
+        self.stale_temp_pins = 0;
+        self.config.cache_tracker.retain_ids(&ids);
+        Ok(())
+    }
+
+    /// Open the file at the given path for testing.
+    ///
+    /// This will create a writeable in-memory database that is initialized with the content
+    /// of the file at the given path.
+    pub fn open_test(path: impl AsRef<Path>, mut config: Config) -> crate::Result<Self> {
+        let mut conn = Connection::open_in_memory()?;
+        debug!(
+            "Restoring in memory database from {}",
+            path.as_ref().display()
+        );
+        conn.restore(
+            DatabaseName::Main,
+            path,
+            Some(|p: rusqlite::backup::Progress| {
+                let percent = (p.pagecount - p.remaining) * 100 / p.pagecount;
+                if percent % 10 == 0 {
+                    debug!("Restoring: {} %", percent);
+                }
+            }),
+        )?;
+        let refcount_gc = config.refcount_gc;
+        let trash = config.trash.is_some();
+        let ids = in_txn(&mut conn, config.busy_retry, |txn| {
+            init_refcounts(txn, refcount_gc)?;
+            init_trash(txn, trash)?;
+            get_ids(txn)
+        })?;
+        config.cache_tracker.retain_ids(&ids);
+        Self::from_conn(conn, config)
+    }
+
+    /// Compare the store with a snapshot of it, e.g. a backup from last night.
+    ///
+    /// The snapshot is a database file as written by [BlockStore::backup_to_writer] or `VACUUM
+    /// INTO`. It is attached read only for the duration of the call, so it is never modified.
+    /// Blocks are compared by cid, so the snapshot does not have to be a backup of this store.
+    pub fn diff_snapshot(&self, path: impl AsRef<Path>) -> Result<SnapshotDiff> {
+        attach_snapshot(&self.conn, path.as_ref())?;
+        let result = in_ro_txn(&self.conn, |txn| snapshot_diff::<CidBytes>(txn));
+        detach_snapshot(&self.conn)?;
+        let (added, removed, aliases) = result?;
+        let cids = |keys: Vec<CidBytes>| keys.iter().map(cid_from_stored).collect::<Result<_>>();
+        Ok(SnapshotDiff {
+            added: cids(added)?,
+            removed: cids(removed)?,
+            aliases: aliases
+                .into_iter()
+                .map(|(name, old, new)| {
+                    let old = old.as_ref().map(cid_from_stored).transpose()?;
+                    let new = new.as_ref().map(cid_from_stored).transpose()?;
+                    Ok((name, old, new))
+                })
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Write a consistent backup of the database to a writer, e.g. a socket or an uploader.
+    ///
+    /// This is not streaming: the sqlite backup api needs a database as the destination, so the
+    /// whole database is backed up to a staging file first, and only then written to the writer.
+    /// This needs as much free disk space as the database itself. The staging file is placed
+    /// next to the database file, or in [std::env::temp_dir] for in memory stores, and deleted
+    /// afterwards. The backup is done in steps of 1000 pages, so writes to the store are not
+    /// blocked for the whole time. The written bytes are a complete sqlite database file.
+    ///
+    /// Returns the number of bytes written.
+    pub fn backup_to_writer(&self, mut writer: impl std::io::Write) -> Result<u64> {
+        let dir = database_file(&self.conn)?
+            .and_then(|path| path.parent().map(Path::to_path_buf))
+            .unwrap_or_else(std::env::temp_dir);
+        let (file, _) = StagingFile::create(&dir)?;
+        {
+            let mut dst = Connection::open(&file.0)?;
+            let backup = rusqlite::backup::Backup::new(&self.conn, &mut dst)?;
+            backup.run_to_completion(1000, Duration::from_millis(10), None)?;
+        }
+        let written = std::io::copy(&mut std::fs::File::open(&file.0)?, &mut writer)?;
+        writer.flush()?;
+        Ok(written)
+    }
+
+    pub fn integrity_check(&self) -> crate::Result<()> {
+        let result = integrity_check(&self.conn)?;
+        if result == vec!["ok".to_owned()] {
+            Ok(())
+        } else {
+            let error_text = result.join(";");
+            Err(crate::error::BlockStoreError::SqliteError(
+                rusqlite::Error::SqliteFailure(rusqlite::ffi::Error::new(11), Some(error_text)),
+            ))
+        }
+    }
+
+    /// Get the query plans for the [core queries](CORE_QUERIES) of the store.
+    ///
+    /// Returns the name of each query together with the lines of its `EXPLAIN QUERY PLAN`
+    /// output, to validate index usage.
+    pub fn query_plans(&self) -> Result<Vec<(&'static str, Vec<String>)>> {
+        query_plans(&self.conn)
+    }
+
+    /// Create or migrate application owned tables in the same database.
+    ///
+    /// Extensions are versioned separately from the store schema. `migrations[i]` is a batch of
+    /// SQL statements that migrates the extension from version `i` to version `i + 1`, and only
+    /// the migrations that have not been applied yet are executed, in a single transaction.
+    ///
+    /// All tables, indexes, views and triggers created by an extension must be prefixed with the
+    /// extension name followed by an underscore, so they can never collide with the tables of the
+    /// store or of other extensions.
+    ///
+    /// Returns the current version of the extension.
+    pub fn migrate_extension(&mut self, name: &str, migrations: &[&str]) -> Result<u32> {
+        self.check_writable()?;
+        in_txn(&mut self.conn, self.config.busy_retry, |txn| {
+            migrate_extension(txn, name, migrations)
+        })
+    }
+
+    /// Run a function on the tables of an extension in a write transaction.
+    ///
+    /// The tables of the store itself should only be read from inside the function. The
+    /// transaction is committed if the function succeeds, and rolled back otherwise.
+    pub fn extension_txn<T>(&mut self, f: impl FnOnce(&ExtensionTxn) -> Result<T>) -> Result<T> {
+        self.check_writable()?;
+        in_txn(&mut self.conn, self.config.busy_retry, |txn| {
+            f(&ExtensionTxn { txn })
+        })
+    }
+
+    /// Subscribe to changes of the store.
+    ///
+    /// Every commit of this store sends events for the blocks that were added or evicted and
+    /// the aliases that were changed, see [EventChannel]. Changes by other connections are
+    /// reported as [StoreEvent::ExternalChange] once [BlockStore::has_changed] finds them.
+    ///
+    /// The subscription ends when the receiver is dropped.
+    pub fn subscribe(&mut self) -> mpsc::UnboundedReceiver<StoreEvent> {
+        self.config.commit_hooks.retain(|hook| !hook.is_closed());
+        let (hook, receiver) = EventChannel::new();
+        self.config.commit_hooks.push(Box::new(hook));
+        receiver
+    }
+
+    /// Get the current commit sequence number.
+    ///
+    /// This is incremented on every commit that changes the block set or the aliases, and is
+    /// passed to the [CommitHook].
+    pub fn commit_seq(&self) -> Result<u64> {
+        in_ro_txn(&self.conn, get_commit_seq)
+    }
+
+    /// Create a new store at `path` that contains only the blocks matching a predicate.
+    ///
+    /// The predicate is called with the cid and data of each block, and whether the block is
+    /// pinned by an alias or a temp pin. Links of the copied blocks and all aliases are copied as
+    /// well, so the copy is consistent but possibly incomplete. This can be used to create trimmed
+    /// copies to ship to constrained devices.
+    ///
+    /// Temp pins are not copied, since they belong to the [TempPin] handles of this store. Blocks
+    /// that are only protected by a temp pin are therefore unpinned in the copy, and will be
+    /// collected by its first gc unless the caller pins them.
+    ///
+    /// - `path` the path of the new store. This should not exist yet.
+    /// - `config` the config for the new store
+    /// - `predicate` decides for each block whether it should be copied
+    pub fn clone_filtered(
+        &self,
+        path: impl AsRef<Path>,
+        config: Config,
+        mut predicate: impl FnMut(&Cid, &[u8], bool) -> bool,
+    ) -> Result<BlockStore> {
+        let mut dst = BlockStore::open(path, config)?;
+        let refcount_gc = self.config.refcount_gc;
+        in_ro_txn(&self.conn, |txn| {
+            let pinned = get_pinned_ids(txn, refcount_gc)?;
+            let mut batch = Vec::new();
+            for_each_block(txn, |id, cid: CidBytes, data| {
+                let cid = cid_from_stored(&cid)?;
+                if predicate(&cid, &data, pinned.contains(&id)) {
+                    let links = get_links::<CidBytes>(txn, id)?
+                        .iter()
+                        .map(cid_from_stored)
+                        .collect::<Result<Vec<_>>>()?;
+                    batch.push(OwnedBlock::new(cid, data, links));
+                    if batch.len() >= 1000 {
+                        dst.put_blocks(batch.drain(..), None)?;
+                    }
+                }
+                Ok(())
+            })?;
+            dst.put_blocks(batch, None)?;
+            let aliases = get_aliases::<CidBytes>(txn)?
+                .into_iter()
+                .map(|(name, cid, recursive)| Ok((name, Some(cid_from_stored(&cid)?), recursive)))
+                .collect::<Result<Vec<_>>>()?;
+            dst.set_aliases(aliases)
+        })?;
+        Ok(dst)
+    }
+
+    /// convert a cid to the key under which it is stored
+    fn key(&self, cid: &Cid) -> Result<CidBytes> {
+        cid_key(cid, self.config.normalize_cids)
+    }
+
+    /// Get the cid in the form in which a block was originally added.
+    ///
+    /// This is only different from the given cid if [Config::with_normalize_cids] is enabled and
+    /// the block was added as a CIDv0.
+    pub fn get_original_cid(&self, cid: &Cid) -> Result<Cid> {
+        let key = self.key(cid)?;
+        let original = in_ro_txn(&self.conn, |txn| get_original_cid::<CidBytes>(txn, key))?;
+        match original {
+            Some(original) => cid_from_stored(&original),
+            None if self.config.normalize_cids => Ok(normalize_cid(cid)),
+            None => Ok(*cid),
+        }
+    }
+
+    /// Get the cids of blocks that were deleted by gc within the retention time of the
+    /// [eviction log](Config::with_eviction_log), together with the time of deletion.
+    pub fn get_evictions(&self) -> Result<Vec<(Cid, SystemTime)>> {
+        let res = in_ro_txn(&self.conn, get_evictions::<CidBytes>)?;
+        res.iter()
+            .map(|(cid, millis)| {
+                let time = SystemTime::UNIX_EPOCH + Duration::from_millis(u64::try_from(*millis)?);
+                Ok((cid_from_stored(cid)?, time))
+            })
+            .collect()
+    }
+
+    /// Get the cids of blocks in the [trash](Config::with_trash), together with the time of
+    /// deletion, most recently deleted first.
+    pub fn get_trash(&self) -> Result<Vec<(Cid, SystemTime)>> {
+        if self.config.trash.is_none() {
+            return Ok(Vec::new());
+        }
+        let res = in_ro_txn(&self.conn, get_trash::<CidBytes>)?;
+        res.iter()
+            .map(|(cid, millis)| {
+                let time = SystemTime::UNIX_EPOCH + Duration::from_millis(u64::try_from(*millis)?);
+                Ok((cid_from_stored(cid)?, time))
+            })
+            .collect()
+    }
+
+    /// Restore a block and its descendants from the [trash](Config::with_trash).
+    ///
+    /// The blocks are added again like with [BlockStore::put_blocks], so they should be pinned
+    /// with `alias`, or aliased right after, to keep gc from deleting them again. Descendants
+    /// that are not in the trash are not followed, so descendants of blocks that were not
+    /// deleted are not restored. Does nothing if the trash is disabled.
+    ///
+    /// The blocks are added and removed from the trash in a single transaction, regardless of the
+    /// configured [TxnLimits].
+    ///
+    /// Returns the number of restored blocks.
+    pub fn undelete(&mut self, root: &Cid, alias: Option<&TempPin>) -> Result<u64> {
+        if self.config.trash.is_none() {
+            return Ok(0);
+        }
+        let key = self.key(root)?;
+        let rows = in_ro_txn(&self.conn, |txn| get_trash_dag(txn, key))?;
+        let keys = rows.iter().map(|(cid, _, _)| *cid).collect::<Vec<_>>();
+        let mut batch = Batch::new();
+        for (cid, data, links) in rows {
+            let links = links
+                .iter()
+                .map(cid_from_stored)
+                .collect::<Result<Vec<_>>>()?;
+            batch.put(OwnedBlock::new(cid_from_stored(&cid)?, data, links));
+        }
+        self.write_batch_with(batch, alias, |txn| delete_from_trash(txn, &keys))?;
+        Ok(keys.len() as u64)
+    }
+
+    /// Get the changes of aliases recorded in the [alias history](Config::with_alias_history),
+    /// oldest first.
+    pub fn get_alias_history(&self) -> Result<Vec<AliasChange>> {
+        let res = in_ro_txn(&self.conn, get_alias_history::<CidBytes>)?;
+        res.iter()
+            .map(|(name, old, new, millis)| {
+                Ok(AliasChange {
+                    name: name.clone(),
+                    old: old.as_ref().map(cid_from_stored).transpose()?,
+                    new: new.as_ref().map(cid_from_stored).transpose()?,
+                    time: SystemTime::UNIX_EPOCH + Duration::from_millis(u64::try_from(*millis)?),
+                })
+            })
+            .collect()
+    }
+
+    /// Get statistics about the temp pins
+    pub fn temp_pin_stats(&self) -> Result<TempPinStats> {
+        in_ro_txn(&self.conn, get_temp_pin_stats)
+    }
+
+    /// Get a temporary alias for safely adding blocks to the store
+    ///
+    /// The pin is released when the returned handle is dropped.
+    pub fn temp_pin(&self) -> TempPin {
+        TempPin {
+            id: AtomicI64::new(0),
+            expired_temp_pins: self.expired_temp_pins.clone(),
+        }
+    }
+
+    /// Add cids to an existing temp pin, without adding any blocks.
+    ///
+    /// This can be used to protect blocks that are about to arrive, or blocks that we already
+    /// have, e.g. during a sync session. The cids do not have to be in the store yet.
+    pub fn extend_temp_pin(
+        &mut self,
+        pin: &TempPin,
+        cids: impl IntoIterator<Item = Cid>,
+    ) -> Result<()> {
+        self.check_writable()?;
+        let normalize = self.config.normalize_cids;
+        let max_cid_size = self.config.max_cid_size;
+        let temp_pin_limits = self.config.temp_pin_limits;
+        let refcount_gc = self.config.refcount_gc;
+        let keys = cids
+            .into_iter()
+            .map(|cid| insert_key(&cid, normalize, max_cid_size))
+            .collect::<Result<Vec<_>>>()?;
+        let expired = self.expired_temp_pin_ids();
+        in_pin_txn(
+            &mut self.conn,
+            self.config.busy_retry,
+            Some(&pin.id),
+            |txn| {
+                extend_temp_pin(txn, &pin.id, keys, refcount_gc)?;
+                check_temp_pin_limits(
+                    txn,
+                    pin.id.load(Ordering::SeqCst),
+                    temp_pin_limits,
+                    &expired,
+                )
+            },
+        )
+    }
+
+    /// ids of temp pins whose handles have been dropped, but that are not deleted yet
+    fn expired_temp_pin_ids(&self) -> Vec<i64> {
+        self.expired_temp_pins.lock().unwrap().clone()
+    }
+
+    /// Add a permanent named alias/pin for a root
+    ///
+    /// The name can be any byte string. Passing `None` as the link removes the alias.
+    pub fn alias(&mut self, name: impl AsRef<[u8]>, link: Option<&Cid>) -> crate::Result<()> {
+        self.alias_many(std::iter::once((name, link.cloned())))
+    }
+
+    /// Add a permanent named alias/pin that only protects the root itself, not its descendants.
+    ///
+    /// This is for roots such as manifests whose children are deliberately evictable. Setting
+    /// an alias with [BlockStore::alias] replaces a direct alias of the same name and vice versa.
+    pub fn alias_direct(&mut self, name: impl AsRef<[u8]>, link: Option<&Cid>) -> Result<()> {
+        self.set_aliases(std::iter::once((name, link.cloned(), false)))
+    }
+
+    /// Protect cids from gc, even if they are not pinned.
+    ///
+    /// This is for a few records that must always be kept, like the identity or provider records
+    /// of a node, without having to come up with an alias name for each of them. Like a
+    /// [direct alias](BlockStore::alias_direct), this does not protect descendants.
+    pub fn protect(&mut self, cids: impl IntoIterator<Item = Cid>) -> Result<()> {
+        self.check_writable()?;
+        let normalize = self.config.normalize_cids;
+        let max_cid_size = self.config.max_cid_size;
+        let keys = cids
+            .into_iter()
+            .map(|cid| insert_key(&cid, normalize, max_cid_size))
+            .collect::<Result<Vec<_>>>()?;
+        in_txn(&mut self.conn, self.config.busy_retry, |txn| {
+            protect(txn, keys)
+        })
+    }
+
+    /// Remove the protection of cids, see [BlockStore::protect].
+    pub fn unprotect(&mut self, cids: impl IntoIterator<Item = Cid>) -> Result<()> {
+        self.check_writable()?;
+        let keys = cids
+            .into_iter()
+            .map(|cid| self.key(&cid))
+            .collect::<Result<Vec<_>>>()?;
+        in_txn(&mut self.conn, self.config.busy_retry, |txn| {
+            unprotect(txn, keys)
+        })
+    }
+
+    /// Get all [protected](BlockStore::protect) cids.
+    pub fn protected_cids(&self) -> Result<Vec<Cid>> {
+        let res = in_ro_txn(&self.conn, get_protected::<CidBytes>)?;
+        res.iter().map(cid_from_stored).collect()
+    }
+
+    /// Add multiple permanent named aliases
+    ///
+    /// This is done in a single transaction, unless the configured [TxnLimits] are exceeded.
+    pub fn alias_many(
+        &mut self,
+        aliases: impl IntoIterator<Item = (impl AsRef<[u8]>, Option<Cid>)>,
+    ) -> crate::Result<()> {
+        self.set_aliases(aliases.into_iter().map(|(name, link)| (name, link, true)))
+    }
+
+    /// set or remove multiple aliases, each with a flag whether it is recursive
+    fn set_aliases(
+        &mut self,
+        aliases: impl IntoIterator<Item = (impl AsRef<[u8]>, Option<Cid>, bool)>,
+    ) -> Result<()> {
+        self.check_writable()?;
+        let txn_limits = self.config.txn_limits;
+        let mut aliases = aliases.into_iter().peekable();
+        while aliases.peek().is_some() {
+            let config = &self.config;
+            let (seq, changes) = in_txn(&mut self.conn, config.busy_retry, |txn| {
+                let mut rows = 0;
+                let mut changes = Vec::new();
+                while !txn_limits.exceeded(rows, 0) {
+                    let (name, link, recursive) = match aliases.next() {
+                        Some(alias) => alias,
+                        None => break,
+                    };
+                    write_alias(txn, config, name.as_ref(), link.as_ref(), recursive)?;
+                    changes.push((name.as_ref().to_vec(), link));
+                    rows += 1;
+                }
+                Ok((next_commit_seq(txn)?, changes))
+            })?;
+            after_commit(
+                &mut self.config.commit_hooks,
+                Commit {
+                    seq,
+                    added: Vec::new(),
+                    inserted: Vec::new(),
+                    removed: Vec::new(),
+                    aliased: changes.iter().filter_map(|(_, link)| *link).collect(),
+                    aliases: changes,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Convert a temp pin into a permanent named alias for a root, atomically.
+    ///
+    /// In a single transaction, the alias is set and the temp pin is deleted, so there is no
+    /// window in which the root is not protected, or both are present. Afterwards, the handle no
+    /// longer protects anything and can be dropped.
+    pub fn assign_temp_pin_to_alias(
+        &mut self,
+        pin: &TempPin,
+        name: impl AsRef<[u8]>,
+        root: &Cid,
+    ) -> Result<()> {
+        self.check_writable()?;
+        let id = pin.id.load(Ordering::SeqCst);
+        let config = &self.config;
+        let seq = in_txn(&mut self.conn, config.busy_retry, |txn| {
+            write_alias(txn, config, name.as_ref(), Some(root), true)?;
+            if id > 0 {
+                delete_temp_pin(txn, id, config.refcount_gc)?;
+            }
+            next_commit_seq(txn)
+        })?;
+        // the temp pin is already deleted, so it must not be enqueued for deletion again
+        let _ = pin
+            .id
+            .compare_exchange(id, 0, Ordering::SeqCst, Ordering::SeqCst);
+        after_commit(
+            &mut self.config.commit_hooks,
+            Commit {
+                seq,
+                added: Vec::new(),
+                inserted: Vec::new(),
+                removed: Vec::new(),
+                aliased: vec![*root],
+                aliases: vec![(name.as_ref().to_vec(), Some(*root))],
+            },
+        );
+        Ok(())
+    }
+
+    /// Get all aliases and the roots they point to
+    pub fn aliases(&self) -> Result<Vec<(Vec<u8>, Cid)>> {
+        let res = in_ro_txn(&self.conn, get_aliases::<CidBytes>)?;
+        res.iter()
+            .map(|(name, cid, _)| Ok((name.clone(), cid_from_stored(cid)?)))
+            .collect()
+    }
+
+    /// Get the aliases with names in a range, and the roots they point to, ordered by name
+    ///
+    /// Names are compared bytewise, and the range is scanned on the index of alias names. So
+    /// applications with structured alias names should encode them in a way that preserves
+    /// their order, e.g. integers as big endian, and can then list them efficiently.
+    pub fn aliases_in_range(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+    ) -> Result<Vec<(Vec<u8>, Cid)>> {
+        let start = range.start_bound().map(Vec::as_slice);
+        let end = range.end_bound().map(Vec::as_slice);
+        let res = in_ro_txn(&self.conn, |txn| {
+            get_aliases_in_range::<CidBytes>(txn, start, end)
+        })?;
+        res.into_iter()
+            .map(|(name, cid)| Ok((name, cid_from_stored(&cid)?)))
+            .collect()
+    }
+
+    /// Get the aliases with names that start with a prefix, and the roots they point to, ordered
+    /// by name. See [BlockStore::aliases_in_range].
+    pub fn aliases_with_prefix(&self, prefix: impl AsRef<[u8]>) -> Result<Vec<(Vec<u8>, Cid)>> {
+        let start = prefix.as_ref().to_vec();
+        // the smallest name that is larger than all names with the prefix
+        let mut end = start.clone();
+        while end.last() == Some(&u8::max_value()) {
+            end.pop();
+        }
+        let end = match end.last_mut() {
+            Some(last) => {
+                *last += 1;
+                Bound::Excluded(end)
+            }
+            None => Bound::Unbounded,
+        };
+        self.aliases_in_range((Bound::Included(start), end))
+    }
+
+    /// Get the root an alias points to, or `None` if there is no such alias
+    pub fn resolve(&self, name: impl AsRef<[u8]>) -> Result<Option<Cid>> {
+        let res = in_ro_txn(&self.conn, |txn| resolve::<CidBytes>(txn, name.as_ref()))?;
+        res.as_ref().map(cid_from_stored).transpose()
+    }
+
+    /// Returns the aliases and the number of temp pins protecting a block.
+    ///
+    /// E.g. if a block is aliased, or protected by more than one temp pin, it is safe to drop
+    /// a temp pin of the block.
+    pub fn reverse_alias(&mut self, cid: &Cid) -> crate::Result<ReverseAlias> {
+        let cid = self.key(cid)?;
+        in_txn(&mut self.conn, self.config.busy_retry, |txn| {
+            reverse_alias(txn, cid.as_ref())
+        })
+    }
+
+    /// Returns all reasons why a block is kept alive, for debugging.
+    ///
+    /// This lists all aliases and temp pins of the block or its ancestors, and all direct parents
+    /// that are kept alive themselves. If the result is empty, the block will be collected by gc.
+    pub fn why_pinned(&self, cid: &Cid) -> Result<Vec<PinReason>> {
+        let key = self.key(cid)?;
+        let (aliases, temp_pins, parents) = in_ro_txn(&self.conn, |txn| {
+            Ok((
+                get_pinning_aliases(txn, key)?,
+                get_pinning_temp_pins(txn, key)?,
+                get_live_parents(txn, key)?,
+            ))
+        })?;
+        let mut res = Vec::new();
+        for (name, root) in aliases {
+            res.push(PinReason::Alias {
+                name,
+                root: cid_from_stored(&root)?,
+            });
+        }
+        for (id, root) in temp_pins {
+            res.push(PinReason::TempPin {
+                id,
+                root: cid_from_stored(&root)?,
+            });
+        }
+        for parent in parents {
+            res.push(PinReason::Parent(cid_from_stored(&parent)?));
+        }
+        Ok(res)
+    }
+
+    /// Returns all aliases that point to a dag with missing blocks.
+    ///
+    /// For each incomplete alias, this returns the alias name, the root and the number of
+    /// blocks that are still missing. Aliases pointing to complete dags are not included.
+    pub fn incomplete_pins(&mut self) -> Result<Vec<(Vec<u8>, Cid, u64)>> {
+        let res = log_execution_time("incomplete_pins", Duration::from_millis(100), || {
+            in_ro_txn(&self.conn, |txn| incomplete_pins::<CidBytes>(txn))
+        })?;
+        res.into_iter()
+            .map(|(name, cid, missing)| Ok((name, cid_from_stored(&cid)?, missing)))
+            .collect()
+    }
+
+    /// Returns all aliases that protect nothing, because we have neither the root nor any of its
+    /// descendants.
+    ///
+    /// These are usually left over from pins that were never fetched, and can be pruned. For
+    /// each dead alias, this returns the alias name and the root.
+    pub fn dead_pins(&self) -> Result<Vec<(Vec<u8>, Cid)>> {
+        let res = in_ro_txn(&self.conn, |txn| dead_pins::<CidBytes>(txn))?;
+        res.into_iter()
+            .map(|(name, cid)| Ok((name, cid_from_stored(&cid)?)))
+            .collect()
+    }
+
+    /// Checks if the store knows about the cid.
     /// Note that this does not necessarily mean that the store has the data for the cid.
     pub fn has_cid(&self, cid: &Cid) -> Result<bool> {
-        let cid = CidBytes::try_from(cid)?;
+        let cid = self.key(cid)?;
         in_ro_txn(&self.conn, |txn| has_cid(txn, cid))
     }
 
     /// Checks if the store has the data for a cid
+    ///
+    /// Recent answers are cached, see [Config::with_has_cache_size].
     pub fn has_block(&mut self, cid: &Cid) -> Result<bool> {
-        let cid = CidBytes::try_from(cid)?;
-        in_ro_txn(&self.conn, |txn| has_block(txn, cid))
+        let cid = self.key(cid)?;
+        if let Some(result) = self.has_cache.get(&cid) {
+            return Ok(result);
+        }
+        let result = in_ro_txn(&self.conn, |txn| has_block(txn, cid))?;
+        self.has_cache.insert(cid, result);
+        Ok(result)
     }
 
     /// Look up multiple blocks in one read transaction
@@ -404,69 +2651,531 @@ impl BlockStore {
     {
         in_ro_txn(&self.conn, |txn| {
             cids.into_iter()
-                .map(|cid| -> Result<(Cid, bool)> {
-                    Ok((cid, has_block(txn, CidBytes::try_from(&cid)?)?))
-                })
+                .map(|cid| -> Result<(Cid, bool)> { Ok((cid, has_block(txn, self.key(&cid)?)?)) })
                 .collect::<crate::Result<O>>()
         })
     }
 
-    /// Get the stats for the store.
+    /// Get a metadata value for a block that was computed by the [MetadataHook]
+    pub fn get_metadata(&self, cid: &Cid, key: &str) -> Result<Option<Vec<u8>>> {
+        let cid = self.key(cid)?;
+        in_ro_txn(&self.conn, |txn| get_metadata(txn, cid, key))
+    }
+
+    /// Remember peers that served a block, so it can be fetched from them again later.
+    ///
+    /// Only the most recent hints are kept, up to the configured maximum. Hints survive the
+    /// deletion of the block, since that is when they are needed. Once they are no longer
+    /// wanted, they can be removed with [BlockStore::delete_provider_hints].
+    pub fn add_provider_hints(
+        &mut self,
+        cid: &Cid,
+        peers: impl IntoIterator<Item = impl AsRef<[u8]>>,
+    ) -> Result<()> {
+        self.check_writable()?;
+        let cid = self.key(cid)?;
+        let max = self.config.max_provider_hints;
+        in_txn(&mut self.conn, self.config.busy_retry, |txn| {
+            add_provider_hints(txn, cid, peers, max)
+        })
+    }
+
+    /// Delete all provider hints for a cid, returning the number of deleted hints.
+    ///
+    /// If we don't have the block, the cid is left for [BlockStore::delete_orphaned_cids].
+    pub fn delete_provider_hints(&mut self, cid: &Cid) -> Result<usize> {
+        self.check_writable()?;
+        let cid = self.key(cid)?;
+        in_txn(&mut self.conn, self.config.busy_retry, |txn| {
+            delete_provider_hints(txn, cid)
+        })
+    }
+
+    /// Get the peers that previously served a block, most recent first.
+    pub fn get_provider_hints(&self, cid: &Cid) -> Result<Vec<Vec<u8>>> {
+        let cid = self.key(cid)?;
+        in_ro_txn(&self.conn, |txn| get_provider_hints(txn, cid))
+    }
+
+    /// Get the stats for the store.
+    ///
+    /// The stats are kept up to date, so this is fast.
+    pub fn get_store_stats(&self) -> Result<StoreStats> {
+        in_ro_txn(&self.conn, get_store_stats)
+    }
+
+    /// Get statistics about the shape of the graph, such as the fanout distribution and depth.
+    ///
+    /// This scans all links, so it should not be called frequently on large stores.
+    pub fn graph_stats(&self) -> Result<GraphStats> {
+        log_execution_time("graph_stats", Duration::from_millis(100), || {
+            in_ro_txn(&self.conn, |txn| get_graph_stats(txn))
+        })
+    }
+
+    /// Get approximate statistics that are cheap to compute even for very large stores.
     ///
-    /// The stats are kept up to date, so this is fast.
-    pub fn get_store_stats(&self) -> Result<StoreStats> {
-        in_ro_txn(&self.conn, get_store_stats)
+    /// This does not scan any tables, so it is suitable to be called every few seconds.
+    /// Counts are upper bounds, since ids of deleted rows are not reused.
+    pub fn approx_stats(&self) -> Result<ApproxStats> {
+        in_ro_txn(&self.conn, |txn| get_approx_stats(txn, 16))
+    }
+
+    /// Get statistics about aliases, with the [system aliases](Config::with_system_alias_prefix)
+    /// reported separately.
+    ///
+    /// This computes the size of the dags of the system aliases, so it is about as expensive as
+    /// a gc.
+    pub fn alias_stats(&self) -> Result<AliasStats> {
+        let prefixes = self.config.system_alias_prefixes.clone();
+        in_ro_txn(&self.conn, move |txn| get_alias_stats(txn, &prefixes))
+    }
+
+    /// Checks if an alias is a [system alias](Config::with_system_alias_prefix).
+    pub fn is_system_alias(&self, name: impl AsRef<[u8]>) -> bool {
+        let name = name.as_ref();
+        self.config
+            .system_alias_prefixes
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
     }
 
     /// Get all cids that the store knows about
     pub fn get_known_cids<C: FromIterator<Cid>>(&mut self) -> Result<C> {
         let res = in_ro_txn(&self.conn, |txn| Ok(get_known_cids::<CidBytes>(txn)?))?;
-        let res = res.iter().map(Cid::try_from).collect::<cid::Result<C>>()?;
+        let res = res.iter().map(cid_from_stored).collect::<Result<C>>()?;
         Ok(res)
     }
 
+    /// Iterate over all blocks in the store, without loading them all into memory.
+    ///
+    /// The blocks are read lazily in batches of 1000, each in its own short read transaction, so
+    /// iterating over a large store does not keep a read transaction open for a long time. Blocks
+    /// that are added or removed while iterating may or may not be returned, but no block is
+    /// returned twice.
+    ///
+    /// A block that can not be read, e.g. because its row is corrupt or its checksum does not
+    /// match, is returned as an error, and iteration continues with the next block.
+    pub fn iter_blocks(&self) -> impl Iterator<Item = Result<(Cid, Vec<u8>)>> + '_ {
+        let mut last_id = 0;
+        let mut batch = Vec::new().into_iter();
+        let mut done = false;
+        std::iter::from_fn(move || loop {
+            if let Some((_, row)) = batch.next() {
+                return Some(decode_bulk_row(row, self.config.checksums).map_err(|(_, err)| err));
+            }
+            if done {
+                return None;
+            }
+            match in_ro_txn(&self.conn, |txn| {
+                get_blocks_after::<CidBytes>(txn, last_id, ITER_BATCH_SIZE)
+            }) {
+                Ok(blocks) => {
+                    done = blocks.len() < ITER_BATCH_SIZE;
+                    if let Some((id, _)) = blocks.last() {
+                        last_id = *id;
+                    }
+                    batch = blocks.into_iter();
+                }
+                Err(cause) => {
+                    done = true;
+                    return Some(Err(cause));
+                }
+            }
+        })
+    }
+
+    /// Iterate over all links in the store as `(parent, child)` pairs, without the block data.
+    ///
+    /// This is for exporting the graph structure, e.g. for visualization. Like
+    /// [BlockStore::iter_blocks], the links are read lazily in batches of 1000, each in its own
+    /// short read transaction. The child of a link does not have to be in the store.
+    pub fn iter_refs(&self) -> impl Iterator<Item = Result<(Cid, Cid)>> + '_ {
+        let mut last = (0, 0);
+        let mut batch = Vec::new().into_iter();
+        let mut done = false;
+        std::iter::from_fn(move || loop {
+            if let Some((_, parent, child)) = batch.next() {
+                return Some(
+                    cid_from_stored(&parent)
+                        .and_then(|parent| Ok((parent, cid_from_stored(&child)?))),
+                );
+            }
+            if done {
+                return None;
+            }
+            match in_ro_txn(&self.conn, |txn| {
+                get_refs_after::<CidBytes>(txn, last, ITER_BATCH_SIZE)
+            }) {
+                Ok(refs) => {
+                    done = refs.len() < ITER_BATCH_SIZE;
+                    if let Some((ids, _, _)) = refs.last() {
+                        last = *ids;
+                    }
+                    batch = refs.into_iter();
+                }
+                Err(cause) => {
+                    done = true;
+                    return Some(Err(cause));
+                }
+            }
+        })
+    }
+
     /// Get all cids for which the store has blocks
     pub fn get_block_cids<C: FromIterator<Cid>>(&mut self) -> Result<C> {
         let res = in_ro_txn(&self.conn, |txn| Ok(get_block_cids::<CidBytes>(txn)?))?;
-        let res = res.iter().map(Cid::try_from).collect::<cid::Result<C>>()?;
+        let res = res.iter().map(cid_from_stored).collect::<Result<C>>()?;
         Ok(res)
     }
 
     /// Get descendants of a cid
     pub fn get_descendants<C: FromIterator<Cid>>(&mut self, cid: &Cid) -> Result<C> {
-        let cid = CidBytes::try_from(cid)?;
+        let cid = self.key(cid)?;
         let res = in_ro_txn(&self.conn, move |txn| get_descendants(txn, cid))?;
-        let res = res.iter().map(Cid::try_from).collect::<cid::Result<C>>()?;
+        let res = res.iter().map(cid_from_stored).collect::<Result<C>>()?;
         Ok(res)
     }
 
+    /// Get the direct parents of a cid, which are all blocks that link to it
+    pub fn get_parents(&self, cid: &Cid) -> Result<Vec<Cid>> {
+        let cid = self.key(cid)?;
+        let res = in_ro_txn(&self.conn, move |txn| get_parents(txn, cid))?;
+        res.iter().map(cid_from_stored).collect()
+    }
+
+    /// Walk the dag below a root depth first, calling a visitor for each block.
+    ///
+    /// The visitor gets the cid and the data of the block, or `None` if we do not have the data.
+    /// It decides whether to continue with the children, skip them or stop. Blocks that are
+    /// reachable on several paths are only visited once. The whole traversal happens in a single
+    /// read transaction, without materializing lists of cids.
+    pub fn traverse(
+        &self,
+        root: &Cid,
+        mut visitor: impl FnMut(&Cid, Option<&[u8]>) -> Visit,
+    ) -> Result<()> {
+        let key = self.key(root)?;
+        in_ro_txn(&self.conn, move |txn| {
+            traverse(txn, key, |cid, data| {
+                Ok(visitor(&cid_from_stored(cid)?, data))
+            })
+        })
+    }
+
+    /// Report blocks as accessed to the cache tracker, without reading their data.
+    ///
+    /// Applications that know from their own indexes that blocks are still relevant can use this
+    /// to protect them from eviction by gc. Cids that we do not have the data for are ignored.
+    pub fn touch(&mut self, cids: &[Cid]) -> Result<()> {
+        let keys = cids
+            .iter()
+            .map(|cid| Ok((cid, self.key(cid)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let infos = in_ro_txn(&self.conn, |txn| {
+            let mut infos = Vec::with_capacity(keys.len());
+            for (cid, key) in &keys {
+                if let Some((id, len)) = get_block_len(txn, key)? {
+                    infos.push(BlockInfo::with_len(id, cid, len));
+                }
+            }
+            Ok(infos)
+        })?;
+        self.record_accesses(&infos);
+        self.config.cache_tracker.blocks_accessed(infos);
+        Ok(())
+    }
+
+    /// Get a manifest of all blocks of a dag, in a deterministic order and with a digest.
+    ///
+    /// Two parties can compare the digests of their manifests to cheaply agree on exactly which
+    /// blocks constitute a dataset before transferring anything. Only links that are known to
+    /// the store are followed, so blocks below missing blocks are not included.
+    pub fn manifest(&self, root: &Cid) -> Result<Manifest> {
+        let key = self.key(root)?;
+        let rows = in_ro_txn(&self.conn, move |txn| get_manifest(txn, key))?;
+        let entries = if rows.is_empty() {
+            // we don't know the root at all, so we don't have it
+            vec![ManifestEntry {
+                cid: *root,
+                size: None,
+                depth: 0,
+            }]
+        } else {
+            rows.iter()
+                .map(|(cid, depth, size)| {
+                    Ok(ManifestEntry {
+                        cid: cid_from_stored(cid)?,
+                        size: size.map(u64::try_from).transpose()?,
+                        depth: *depth,
+                    })
+                })
+                .collect::<Result<_>>()?
+        };
+        Ok(Manifest::new(entries))
+    }
+
+    /// Get the cids that are only reachable from one of two roots.
+    ///
+    /// This is useful for incremental replication of a dag that was updated from `old_root` to
+    /// `new_root`. Only links that are known to the store are followed, so the result might be
+    /// incomplete if parts of either dag are missing.
+    pub fn dag_diff(&self, old_root: &Cid, new_root: &Cid) -> Result<DagDiff> {
+        let old = self.key(old_root)?;
+        let new = self.key(new_root)?;
+        let (added, removed) = in_ro_txn(&self.conn, |txn| {
+            Ok((
+                get_dag_diff(txn, &new, &old)?,
+                get_dag_diff(txn, &old, &new)?,
+            ))
+        })?;
+        Ok(DagDiff {
+            added: added.iter().map(cid_from_stored).collect::<Result<_>>()?,
+            removed: removed.iter().map(cid_from_stored).collect::<Result<_>>()?,
+        })
+    }
+
+    /// Get the number and total size of the blocks of the dag below a root that we have
+    ///
+    /// Blocks that are reachable on several paths are only counted once.
+    pub fn dag_size(&self, root: &Cid) -> Result<StoreStats> {
+        let key = self.key(root)?;
+        in_ro_txn(&self.conn, move |txn| get_dag_size(txn, key))
+    }
+
+    /// Get all transitive parents of a cid, not including the cid itself
+    ///
+    /// These are all blocks whose dag contains the cid, e.g. the roots that are affected when the
+    /// cid changes.
+    pub fn get_ancestors(&self, cid: &Cid) -> Result<Vec<Cid>> {
+        let cid = self.key(cid)?;
+        let res = in_ro_txn(&self.conn, move |txn| get_ancestors(txn, cid))?;
+        res.iter().map(cid_from_stored).collect()
+    }
+
+    /// Get descendants of a cid up to `max_depth` levels below it
+    ///
+    /// A `max_depth` of 0 gives just the cid itself, 1 the cid and its direct children, and so on.
+    /// This avoids walking the whole dag if only the first levels are of interest, e.g. for
+    /// sharded directories.
+    pub fn get_descendants_limited<C: FromIterator<Cid>>(
+        &self,
+        cid: &Cid,
+        max_depth: u32,
+    ) -> Result<C> {
+        let cid = self.key(cid)?;
+        let res = in_ro_txn(&self.conn, move |txn| {
+            get_descendants_limited(txn, cid, max_depth)
+        })?;
+        res.iter().map(cid_from_stored).collect()
+    }
+
     /// Given a root of a dag, gives all cids which we do not have data for.
     pub fn get_missing_blocks<C: FromIterator<Cid>>(&mut self, cid: &Cid) -> Result<C> {
-        let cid = CidBytes::try_from(cid)?;
+        let cid = self.key(cid)?;
         let result = log_execution_time("get_missing_blocks", Duration::from_millis(10), || {
             in_ro_txn(&self.conn, move |txn| get_missing_blocks(txn, cid))
         })?;
-        let res = result
-            .iter()
-            .map(Cid::try_from)
-            .collect::<cid::Result<C>>()?;
+        let res = result.iter().map(cid_from_stored).collect::<Result<C>>()?;
         Ok(res)
     }
 
+    /// Given a root of a dag, gives up to `limit` cids which we do not have data for.
+    ///
+    /// Only descendants up to `max_depth` levels below the root are considered, or all
+    /// descendants if `max_depth` is `None`. The dag is walked breadth first, and the walk stops
+    /// as soon as `limit` cids are found, so this is suitable for fetching the next wanted cids
+    /// of a large dag.
+    pub fn get_missing_blocks_limited<C: FromIterator<Cid>>(
+        &self,
+        cid: &Cid,
+        limit: usize,
+        max_depth: Option<u32>,
+    ) -> Result<C> {
+        let key = self.key(cid)?;
+        let max_depth = max_depth.unwrap_or(u32::max_value());
+        let result = in_ro_txn(&self.conn, move |txn| {
+            get_missing_blocks_limited(txn, key, limit, max_depth)
+        })?;
+        result.iter().map(cid_from_stored).collect()
+    }
+
+    /// Iterate over the cids of a dag which we do not have data for, as they are found.
+    ///
+    /// In contrast to [BlockStore::get_missing_blocks], the dag is walked lazily, breadth first,
+    /// in steps of 1000 blocks, each in its own short read transaction. So the caller can start
+    /// requesting blocks before the traversal is finished, and no read transaction is kept open
+    /// while the caller handles the cids. Changes of the dag while iterating may or may not be
+    /// seen, but no cid is returned twice. The iteration ends after the first error.
+    pub fn missing_blocks_iter(&self, root: &Cid) -> impl Iterator<Item = Result<Cid>> + '_ {
+        let mut pending = VecDeque::new();
+        let mut visited = FnvHashSet::default();
+        let mut batch = Vec::new().into_iter();
+        // the root is looked up in the first step
+        let mut root = Some(*root);
+        std::iter::from_fn(move || loop {
+            if let Some(cid) = batch.next() {
+                return Some(cid_from_stored(&cid));
+            }
+            let result = if let Some(root) = root.take() {
+                self.key(&root).and_then(|key| {
+                    let id = in_ro_txn(&self.conn, |txn| Ok(get_id(txn, &key)?))?;
+                    match id {
+                        Some(id) => {
+                            visited.insert(id);
+                            pending.push_back(id);
+                            Ok(Vec::new())
+                        }
+                        // we don't even know the root
+                        None => Ok(vec![key]),
+                    }
+                })
+            } else if pending.is_empty() {
+                return None;
+            } else {
+                in_ro_txn(&self.conn, |txn| {
+                    missing_blocks_step::<CidBytes>(
+                        txn,
+                        &mut pending,
+                        &mut visited,
+                        ITER_BATCH_SIZE,
+                    )
+                })
+            };
+            match result {
+                Ok(missing) => batch = missing.into_iter(),
+                Err(cause) => {
+                    pending.clear();
+                    return Some(Err(cause));
+                }
+            }
+        })
+    }
+
+    /// Get the optional features supported by this store
+    pub fn capabilities(&self) -> Result<Capabilities> {
+        let schema_version = in_ro_txn(&self.conn, |txn| Ok(user_version(txn)?))?;
+        Ok(Capabilities {
+            schema_version,
+            compression: false,
+            encryption: false,
+            namespaces: false,
+            checksums: self.config.checksums,
+            normalize_cids: self.config.normalize_cids,
+        })
+    }
+
+    /// Checks if we have the data for a root and all its descendants.
+    ///
+    /// This is cheaper than [BlockStore::get_missing_blocks], since it stops at the first
+    /// missing block.
+    pub fn is_complete(&self, root: &Cid) -> Result<bool> {
+        let key = self.key(root)?;
+        in_ro_txn(&self.conn, move |txn| is_complete(txn, key))
+    }
+
+    /// Checks if a cid is protected from gc, because it is reachable from an alias or a temp pin,
+    /// or [protected](BlockStore::protect).
+    ///
+    /// Unlike [BlockStore::reverse_alias], this also takes temp pins into account, and stops at
+    /// the first root that protects the cid. With [Config::with_refcount_gc], this is a lookup
+    /// of the reference count instead of a walk up the graph.
+    pub fn is_pinned(&self, cid: &Cid) -> Result<bool> {
+        let key = self.key(cid)?;
+        let refcount_gc = self.config.refcount_gc;
+        in_ro_txn(&self.conn, move |txn| is_pinned(txn, key, refcount_gc))
+    }
+
+    /// Pause gc until the next call to [resume_gc](BlockStore::resume_gc).
+    ///
+    /// While gc is paused, all gc methods will return [BlockStoreError::GcPaused] without
+    /// deleting anything, so no eviction can happen during critical sections like serving a large
+    /// export. Calls can be nested, gc will be resumed once every pause has been resumed.
+    pub fn pause_gc(&mut self) {
+        self.gc_paused += 1;
+    }
+
+    /// Resume gc after a call to [pause_gc](BlockStore::pause_gc).
+    pub fn resume_gc(&mut self) {
+        self.gc_paused = self.gc_paused.saturating_sub(1);
+    }
+
+    /// true if gc is currently paused
+    pub fn is_gc_paused(&self) -> bool {
+        self.gc_paused > 0
+    }
+
+    fn check_gc_paused(&self) -> Result<()> {
+        if self.is_gc_paused() {
+            Err(BlockStoreError::GcPaused)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Make the store read only, or writable again.
+    ///
+    /// While the store is read only, all methods that modify it, including gc, return
+    /// [BlockStoreError::ReadOnly] without writing anything. This can be used during backups or
+    /// migrations, or when the disk is almost full, to guarantee that the store does not grow.
+    ///
+    /// Stores opened with [BlockStore::open_read_only] start out read only. Making them writable
+    /// does not make the underlying connection writable, so writes will fail with a sqlite error.
+    pub fn set_read_only(&mut self, read_only: bool) -> Result<()> {
+        // let sqlite reject any writes as well, in case we missed a code path
+        self.conn.pragma_update(None, "query_only", &read_only)?;
+        self.read_only = read_only;
+        Ok(())
+    }
+
+    /// true if the store is read only, see [BlockStore::set_read_only]
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            Err(BlockStoreError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Given a root of a dag, gives all known descendants together with whether we have their data.
+    ///
+    /// The root itself is included. This is done in a single traversal, so it is cheaper than
+    /// calling [get_descendants](BlockStore::get_descendants) and
+    /// [get_missing_blocks](BlockStore::get_missing_blocks).
+    pub fn have_status(&self, cid: &Cid) -> Result<Vec<(Cid, bool)>> {
+        let cid_bytes = self.key(cid)?;
+        let res = log_execution_time("have_status", Duration::from_millis(10), || {
+            in_ro_txn(&self.conn, move |txn| get_have_status(txn, cid_bytes))
+        })?;
+        if res.is_empty() {
+            // we don't know the root at all, so we don't have it
+            return Ok(vec![(*cid, false)]);
+        }
+        res.iter()
+            .map(|(cid, have)| Ok((cid_from_stored(cid)?, *have)))
+            .collect()
+    }
+
     /// do a full garbage collection
     ///
     /// for a large block store, this can take several seconds to minutes. If that is not acceptable,
     /// consider using incremental gc.
     pub fn gc(&mut self) -> Result<()> {
-        loop {
-            let complete = self.incremental_gc(20000, Duration::from_secs(1))?;
-            while !self.incremental_delete_orphaned(20000, Duration::from_secs(1))? {}
-            if complete {
-                break;
-            }
-        }
+        while !self.gc_chunk()? {}
         Ok(())
     }
+    /// one chunk of a full gc, returns true once gc is complete
+    fn gc_chunk(&mut self) -> Result<bool> {
+        let complete = self
+            .incremental_gc(20000, Duration::from_secs(1))?
+            .is_complete();
+        while !self.incremental_delete_orphaned(20000, Duration::from_secs(1))? {}
+        Ok(complete)
+    }
     /// Perform an incremental garbage collection.
     ///
     /// Will collect unpinned blocks until either the size targets are met again, or at minimum
@@ -477,11 +3186,150 @@ impl BlockStore {
     /// when doing incremental gc, the actual blocks are not yet deleted. So a call to this method
     /// should usually be followed by a call to incremental_delete_orphaned.
     ///
+    /// By default, finding the unpinned blocks walks the graph from all aliases and temp pins, so
+    /// it takes time proportional to the size of the store. With [Config::with_refcount_gc], the
+    /// set of pinned blocks is kept up to date on every write instead.
+    ///
     /// - `min_blocks` the minium number of blocks to collect in any case
     /// - `max_duration` the maximum duration that should be spent on gc
     ///
-    /// Returns true if either size targets are met or there are no unpinned blocks left.
-    pub fn incremental_gc(&mut self, min_blocks: usize, max_duration: Duration) -> Result<bool> {
+    /// Returns statistics about the gc. If [GcStats::is_complete] is true, either size targets
+    /// are met or there are no unpinned blocks left.
+    pub fn incremental_gc(&mut self, min_blocks: usize, max_duration: Duration) -> Result<GcStats> {
+        let size_targets = self.config.size_targets;
+        let low_watermark = self.low_watermark();
+        let grace_cutoff = self.grace_cutoff();
+        let refcount_gc = self.config.refcount_gc;
+        let order = self.gc_order();
+        let draining = self.gc_draining;
+        let t0 = Instant::now();
+        let mut stats = self.gc_txn(move |txn, cache_tracker, removed| {
+            incremental_gc(
+                txn,
+                min_blocks,
+                max_duration,
+                size_targets,
+                low_watermark,
+                draining,
+                grace_cutoff,
+                refcount_gc,
+                order,
+                cache_tracker,
+                removed,
+            )
+        })?;
+        self.gc_draining = !stats.is_complete();
+        stats.elapsed = t0.elapsed();
+        debug!("incremental gc {:?}", stats);
+        Ok(stats)
+    }
+    /// Perform a gc that frees at least `bytes` bytes, regardless of the size targets.
+    ///
+    /// In contrast to [BlockStore::incremental_gc], this collects the largest unpinned blocks
+    /// first, so it is useful if size pressure is caused by a few large blocks rather than by
+    /// the number of blocks. As with incremental gc, the data is only deleted by
+    /// incremental_delete_orphaned.
+    ///
+    /// Returns the number of bytes freed, which is less than `bytes` if there are not enough
+    /// unpinned blocks.
+    pub fn gc_bytes(&mut self, bytes: u64) -> Result<u64> {
+        let grace_cutoff = self.grace_cutoff();
+        let refcount_gc = self.config.refcount_gc;
+        let order = self.gc_order();
+        self.gc_txn(move |txn, cache_tracker, removed| {
+            gc_bytes(
+                txn,
+                bytes,
+                grace_cutoff,
+                refcount_gc,
+                order,
+                cache_tracker,
+                removed,
+            )
+        })
+    }
+    fn gc_order(&self) -> GcOrder {
+        GcOrder {
+            least_recently_used: self.config.access_tracking,
+            largest_first: self.config.gc_largest_first,
+        }
+    }
+    /// pass the block accesses of the reader on to the cache tracker and access tracking
+    fn take_reader_accesses(&mut self) {
+        if let Some(reader) = &self.reader {
+            let infos = reader.take_accesses();
+            if !infos.is_empty() {
+                self.record_accesses(&infos);
+                self.config.cache_tracker.blocks_accessed(infos);
+            }
+        }
+    }
+    /// remember that blocks were accessed, see [Config::with_access_tracking]
+    fn record_accesses(&mut self, infos: &[BlockInfo]) {
+        if !self.config.access_tracking || self.read_only {
+            return;
+        }
+        let now = unix_millis(SystemTime::now());
+        self.accesses
+            .extend(infos.iter().map(|info| (info.id(), now)));
+        if self.accesses.len() >= ACCESS_BATCH_SIZE {
+            self.flush_accesses();
+        }
+    }
+    /// write the buffered accesses, see [Config::with_access_tracking]
+    fn flush_accesses(&mut self) {
+        if self.accesses.is_empty() {
+            return;
+        }
+        let accesses = std::mem::take(&mut self.accesses);
+        if let Err(cause) = in_txn(&mut self.conn, self.config.busy_retry, |txn| {
+            record_accesses(txn, &accesses)
+        }) {
+            warn!("unable to record block accesses {}", cause);
+        }
+    }
+    /// Recompute the reference counts of [Config::with_refcount_gc] from scratch.
+    ///
+    /// The counts are kept up to date by all writes with refcount gc and recomputed on open, so
+    /// this is only needed if they were corrupted, e.g. by writes of a concurrent handle without
+    /// refcount gc. Does nothing if refcount gc is disabled.
+    pub fn repair_refcounts(&mut self) -> Result<()> {
+        self.check_writable()?;
+        if !self.config.refcount_gc {
+            return Ok(());
+        }
+        in_txn(&mut self.conn, self.config.busy_retry, |txn| {
+            repair_refcounts(txn)
+        })
+    }
+    /// the size at which incremental gc stops, capped at the size targets
+    fn low_watermark(&self) -> SizeTargets {
+        let targets = self.config.size_targets;
+        match self.config.low_watermark {
+            Some(low) => SizeTargets::new(low.count.min(targets.count), low.size.min(targets.size)),
+            None => targets,
+        }
+    }
+    /// blocks written after this time are protected by the gc grace period
+    fn grace_cutoff(&self) -> i64 {
+        match self.config.gc_grace_period {
+            Some(grace_period) => unix_millis(
+                SystemTime::now()
+                    .checked_sub(grace_period)
+                    .unwrap_or(SystemTime::UNIX_EPOCH),
+            ),
+            None => i64::max_value(),
+        }
+    }
+    /// Run a gc transaction that collects the cids of removed blocks.
+    ///
+    /// This takes care of expired temp pins, the eviction log and notifications.
+    fn gc_txn<T>(
+        &mut self,
+        f: impl FnOnce(&Transaction, &mut Box<dyn CacheTracker>, &mut Vec<CidBytes>) -> Result<T>,
+    ) -> Result<T> {
+        self.check_writable()?;
+        self.check_gc_paused()?;
         // atomically grab the expired_temp_pins until now
         let expired_temp_pins = {
             let mut result = Vec::new();
@@ -491,23 +3339,70 @@ impl BlockStore {
             );
             result
         };
-        Ok(log_execution_time("gc", Duration::from_secs(1), || {
-            let size_targets = self.config.size_targets;
+        self.take_reader_accesses();
+        // write pending accesses first, so gc sees them
+        let accesses = std::mem::take(&mut self.accesses);
+        let result = log_execution_time("gc", Duration::from_secs(1), || {
+            let accesses = &accesses;
+            let eviction_log = self.config.eviction_log;
+            let trash = self.config.trash;
+            let refcount_gc = self.config.refcount_gc;
+            let process = self.process;
             let cache_tracker = &mut self.config.cache_tracker;
-            in_txn(&mut self.conn, move |txn| {
+            in_txn(&mut self.conn, self.config.busy_retry, move |txn| {
+                if let Some(process) = process {
+                    heartbeat(txn, process.id, unix_millis(SystemTime::now()))?;
+                }
                 // get rid of dropped temp aliases, this should be fast
                 for id in expired_temp_pins {
-                    delete_temp_pin(txn, id)?;
+                    delete_temp_pin(txn, id, refcount_gc)?;
+                }
+                record_accesses(txn, accesses)?;
+                let mut removed = Vec::new();
+                let result = f(txn, cache_tracker, &mut removed)?;
+                if let Some(retention) = eviction_log {
+                    log_evictions(txn, &removed, unix_millis(SystemTime::now()), retention)?;
                 }
-                Ok(incremental_gc(
-                    &txn,
-                    min_blocks,
-                    max_duration,
-                    size_targets,
-                    cache_tracker,
-                )?)
+                if let Some(retention) = trash {
+                    stamp_trash(txn, unix_millis(SystemTime::now()), retention)?;
+                }
+                let seq = if removed.is_empty() {
+                    None
+                } else {
+                    Some(next_commit_seq(txn)?)
+                };
+                Ok((result, removed, seq))
             })
-        })?)
+        });
+        let (result, removed, seq) = match result {
+            Ok(result) => result,
+            Err(cause) => {
+                // keep the accesses for the next attempt
+                self.accesses = accesses;
+                return Err(cause);
+            }
+        };
+        for key in &removed {
+            self.has_cache.invalidate(key);
+        }
+        if let Some(seq) = seq {
+            let removed = removed
+                .iter()
+                .map(cid_from_stored)
+                .collect::<Result<Vec<_>>>()?;
+            after_commit(
+                &mut self.config.commit_hooks,
+                Commit {
+                    seq,
+                    added: Vec::new(),
+                    inserted: Vec::new(),
+                    removed,
+                    aliased: Vec::new(),
+                    aliases: Vec::new(),
+                },
+            );
+        }
+        Ok(result)
     }
     /// Incrementally delete orphaned blocks
     ///
@@ -522,21 +3417,99 @@ impl BlockStore {
     /// - `min_blocks` the minium number of blocks to delete in any case
     /// - `max_duration` the maximum duration that should be spent on gc
     ///
-    /// Returns true if all orphaned blocks are deleted
+    /// Returns true if all orphaned blocks are deleted. Use [BlockStore::delete_orphaned] to get
+    /// the number of deleted and remaining blocks.
     pub fn incremental_delete_orphaned(
         &mut self,
         min_blocks: usize,
         max_duration: Duration,
     ) -> Result<bool> {
-        Ok(log_execution_time(
-            "delete_orphaned",
-            Duration::from_millis(100),
-            || {
-                in_txn(&mut self.conn, move |txn| {
+        Ok(self
+            .delete_orphaned(min_blocks, max_duration)?
+            .is_complete())
+    }
+    /// Delete orphaned blocks, like [BlockStore::incremental_delete_orphaned], and return
+    /// statistics about the deletion.
+    ///
+    /// This allows to schedule the deletion of orphaned blocks, e.g. during idle periods, and
+    /// to tell how much work is left.
+    pub fn delete_orphaned(
+        &mut self,
+        min_blocks: usize,
+        max_duration: Duration,
+    ) -> Result<OrphanStats> {
+        self.check_writable()?;
+        self.check_gc_paused()?;
+        let t0 = Instant::now();
+        let (deleted, remaining) =
+            log_execution_time("delete_orphaned", Duration::from_millis(100), || {
+                in_txn(&mut self.conn, self.config.busy_retry, move |txn| {
                     Ok(incremental_delete_orphaned(txn, min_blocks, max_duration)?)
                 })
-            },
-        )?)
+            })?;
+        let stats = OrphanStats {
+            deleted,
+            remaining,
+            elapsed: t0.elapsed(),
+        };
+        debug!("delete orphaned {:?}", stats);
+        Ok(stats)
+    }
+    /// Delete cids for which we don't have the block and that are not linked, aliased, temp
+    /// pinned, protected or have provider hints.
+    ///
+    /// Such cids are left over e.g. from aliases and temp pins of blocks that were never fetched,
+    /// and would otherwise accumulate when gc does not run because the size targets are met. This is also done by
+    /// [BlockStore::maintenance].
+    ///
+    /// Returns the number of deleted cids.
+    pub fn delete_orphaned_cids(&mut self) -> Result<u64> {
+        self.check_writable()?;
+        in_txn(&mut self.conn, self.config.busy_retry, |txn| {
+            delete_orphaned_cids(txn, None)
+        })
+    }
+    /// Get the number of orphaned blocks, which are deleted by
+    /// [BlockStore::incremental_delete_orphaned].
+    pub fn orphan_count(&self) -> Result<u64> {
+        in_ro_txn(&self.conn, count_orphaned)
+    }
+    /// Delete specific blocks right away, without waiting for gc.
+    ///
+    /// This is for blocks that the application knows to be garbage. The data and the links of
+    /// the blocks are deleted immediately, regardless of the size targets and the gc grace
+    /// period. Blocks we don't have are ignored. Like gc, this fails with
+    /// [BlockStoreError::GcPaused] while gc is paused.
+    ///
+    /// Fails with [BlockStoreError::Pinned] without deleting anything if any of the blocks is
+    /// pinned, see [BlockStore::is_pinned].
+    ///
+    /// Returns the number of deleted blocks.
+    pub fn delete_blocks(&mut self, cids: impl IntoIterator<Item = Cid>) -> Result<u64> {
+        let cids = cids
+            .into_iter()
+            .map(|cid| Ok((cid, self.key(&cid)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let refcount_gc = self.config.refcount_gc;
+        self.gc_txn(move |txn, cache_tracker, removed| {
+            let mut ids = Vec::new();
+            for (cid, key) in cids {
+                if is_pinned(txn, key, refcount_gc)? {
+                    return Err(BlockStoreError::Pinned(cid));
+                }
+                if let Some(id) = get_id(txn, key)? {
+                    ids.push(id);
+                }
+            }
+            let mut deleted = Vec::new();
+            for id in ids {
+                if delete_block(txn, id, removed)?.is_some() {
+                    deleted.push(id);
+                }
+            }
+            cache_tracker.delete_ids(&deleted);
+            Ok(deleted.len() as u64)
+        })
     }
     /// Add a number of blocks to the store
     ///
@@ -551,30 +3524,100 @@ impl BlockStore {
     /// - `alias` an optional temporary alias.
     ///   This can be used to incrementally add blocks without having to worry about them being garbage
     ///   collected before they can be pinned with a permanent alias.
+    ///
+    /// If adding the blocks fails, the transaction is rolled back, so no cids, blocks or links of
+    /// the failed transaction remain in the store.
+    ///
+    /// The blocks are added in a single transaction, unless the configured [TxnLimits] are
+    /// exceeded. In that case they are split into multiple transactions, so use a temp pin to
+    /// protect the blocks that are already committed.
     pub fn put_blocks<B: Block>(
         &mut self,
         blocks: impl IntoIterator<Item = B>,
         alias: Option<&TempPin>,
     ) -> Result<()> {
-        let infos = in_txn(&mut self.conn, |txn| {
-            let alias = alias.map(|alias| &alias.id);
-            Ok(blocks
-                .into_iter()
-                .map(|block| {
-                    let cid_bytes = CidBytes::try_from(block.cid())?;
-                    let links = block
-                        .links()?
-                        .iter()
-                        .map(CidBytes::try_from)
-                        .collect::<std::result::Result<Vec<_>, cid::Error>>()?;
-                    let id = put_block(txn, &cid_bytes, &block.data(), links, alias)?;
-                    Ok(BlockInfo::new(id, block.cid(), block.data()))
-                })
-                .collect::<Result<Vec<_>>>()?)
-        })?;
-        self.config.cache_tracker.blocks_written(infos);
+        self.check_writable()?;
+        let txn_limits = self.config.txn_limits;
+        let temp_pin_limits = self.config.temp_pin_limits;
+        let alias = alias.map(|alias| &alias.id);
+        let mut blocks = blocks.into_iter().peekable();
+        while blocks.peek().is_some() {
+            let expired = self.expired_temp_pin_ids();
+            let config = &self.config;
+            let now = unix_millis(SystemTime::now());
+            let mut stats = PutStats {
+                batches: 1,
+                ..PutStats::default()
+            };
+            let (seq, infos, added, inserted, keys, t_done) =
+                in_pin_txn(&mut self.conn, config.busy_retry, alias, |txn| {
+                    let mut infos = Vec::new();
+                    let mut added = Vec::new();
+                    let mut inserted = Vec::new();
+                    let mut keys = Vec::new();
+                    let mut rows = 0;
+                    let mut bytes = 0;
+                    while !txn_limits.exceeded(rows, bytes) {
+                        let block = match blocks.next() {
+                            Some(block) => block,
+                            None => break,
+                        };
+                        let (info, cid_bytes, is_new, n_links) =
+                            write_block(txn, config, &block, alias, now, &mut stats)?;
+                        rows += 1 + n_links;
+                        bytes += block.data().len();
+                        infos.push(info);
+                        keys.push(cid_bytes);
+                        added.push(*block.cid());
+                        if is_new {
+                            inserted.push(*block.cid());
+                        }
+                    }
+                    if let Some(alias) = alias {
+                        check_temp_pin_limits(
+                            txn,
+                            alias.load(Ordering::SeqCst),
+                            temp_pin_limits,
+                            &expired,
+                        )?;
+                    }
+                    // writing blocks we already have only refreshes them
+                    let seq = if inserted.is_empty() {
+                        get_commit_seq(txn)?
+                    } else {
+                        next_commit_seq(txn)?
+                    };
+                    Ok((seq, infos, added, inserted, keys, Instant::now()))
+                })?;
+            stats.commit_time = t_done.elapsed();
+            self.put_stats.batches += stats.batches;
+            self.put_stats.blocks += stats.blocks;
+            self.put_stats.max_batch_size = self.put_stats.max_batch_size.max(stats.blocks);
+            self.put_stats.decode_time += stats.decode_time;
+            self.put_stats.write_time += stats.write_time;
+            self.put_stats.commit_time += stats.commit_time;
+            for key in &keys {
+                self.has_cache.invalidate(key);
+            }
+            self.config.cache_tracker.blocks_written(infos);
+            after_commit(
+                &mut self.config.commit_hooks,
+                Commit {
+                    seq,
+                    added,
+                    inserted,
+                    removed: Vec::new(),
+                    aliased: Vec::new(),
+                    aliases: Vec::new(),
+                },
+            );
+        }
         Ok(())
     }
+    /// Get cumulative statistics about block writes since the store was opened
+    pub fn put_stats(&self) -> PutStats {
+        self.put_stats
+    }
     /// Add a single block
     ///
     /// this is just a convenience method that calls put_blocks internally.
@@ -598,27 +3641,271 @@ impl BlockStore {
         self.put_blocks(Some(block), alias)?;
         Ok(())
     }
+    /// Commit all operations of a [Batch] atomically
+    ///
+    /// Unlike [BlockStore::put_blocks], this always uses a single transaction, regardless of the
+    /// configured [TxnLimits]. If any operation fails, none of them are applied.
+    ///
+    /// - `pin` the temp pin for the blocks and the temp pin operations of the batch. Fails with
+    ///   [BlockStoreError::MissingTempPin] if the batch has temp pin operations but no pin is
+    ///   given.
+    ///
+    /// Within the batch, blocks are written first, then the temp pin is extended, then the
+    /// aliases are set, and finally the temp pin is released if requested.
+    pub fn write_batch(&mut self, batch: Batch, pin: Option<&TempPin>) -> Result<()> {
+        self.write_batch_with(batch, pin, |_| Ok(()))
+    }
+    /// write a batch, and run `f` at the end of the same transaction
+    fn write_batch_with(
+        &mut self,
+        batch: Batch,
+        pin: Option<&TempPin>,
+        f: impl FnOnce(&Transaction) -> Result<()>,
+    ) -> Result<()> {
+        self.check_writable()?;
+        if batch.is_empty() {
+            return Ok(());
+        }
+        if pin.is_none() && (!batch.pinned.is_empty() || batch.release_temp_pin) {
+            return Err(BlockStoreError::MissingTempPin);
+        }
+        let Batch {
+            blocks,
+            aliases,
+            pinned,
+            release_temp_pin,
+        } = batch;
+        let config = &self.config;
+        let pin_id = pin.map(|pin| &pin.id);
+        let expired = self.expired_temp_pin_ids();
+        let now = unix_millis(SystemTime::now());
+        let mut stats = PutStats {
+            batches: 1,
+            ..PutStats::default()
+        };
+        let (seq, infos, added, inserted, keys, released) =
+            in_pin_txn(&mut self.conn, config.busy_retry, pin_id, |txn| {
+                let mut infos = Vec::new();
+                let mut added = Vec::new();
+                let mut inserted = Vec::new();
+                let mut keys = Vec::new();
+                for block in &blocks {
+                    let (info, cid_bytes, is_new, _) =
+                        write_block(txn, config, block, pin_id, now, &mut stats)?;
+                    infos.push(info);
+                    keys.push(cid_bytes);
+                    added.push(*block.cid());
+                    if is_new {
+                        inserted.push(*block.cid());
+                    }
+                }
+                if let Some(pin_id) = pin_id {
+                    if !pinned.is_empty() {
+                        let keys = pinned
+                            .iter()
+                            .map(|cid| insert_key(cid, config.normalize_cids, config.max_cid_size))
+                            .collect::<Result<Vec<_>>>()?;
+                        extend_temp_pin(txn, pin_id, keys, config.refcount_gc)?;
+                    }
+                    check_temp_pin_limits(
+                        txn,
+                        pin_id.load(Ordering::SeqCst),
+                        config.temp_pin_limits,
+                        &expired,
+                    )?;
+                }
+                for (name, link, recursive) in &aliases {
+                    write_alias(txn, config, name, link.as_ref(), *recursive)?;
+                }
+                let mut released = None;
+                if let (Some(pin_id), true) = (pin_id, release_temp_pin) {
+                    let id = pin_id.load(Ordering::SeqCst);
+                    if id > 0 {
+                        delete_temp_pin(txn, id, config.refcount_gc)?;
+                        released = Some(id);
+                    }
+                }
+                f(txn)?;
+                // temp pin operations and blocks we already have do not count as changes
+                let seq = if inserted.is_empty() && aliases.is_empty() {
+                    get_commit_seq(txn)?
+                } else {
+                    next_commit_seq(txn)?
+                };
+                Ok((seq, infos, added, inserted, keys, released))
+            })?;
+        if let (Some(pin_id), Some(id)) = (pin_id, released) {
+            // the temp pin is already deleted, so it must not be enqueued for deletion again
+            let _ = pin_id.compare_exchange(id, 0, Ordering::SeqCst, Ordering::SeqCst);
+        }
+        self.put_stats.batches += stats.batches;
+        self.put_stats.blocks += stats.blocks;
+        self.put_stats.max_batch_size = self.put_stats.max_batch_size.max(stats.blocks);
+        self.put_stats.decode_time += stats.decode_time;
+        self.put_stats.write_time += stats.write_time;
+        for key in &keys {
+            self.has_cache.invalidate(key);
+        }
+        self.config.cache_tracker.blocks_written(infos);
+        after_commit(
+            &mut self.config.commit_hooks,
+            Commit {
+                seq,
+                added,
+                inserted,
+                removed: Vec::new(),
+                aliased: aliases.iter().filter_map(|(_, link, _)| *link).collect(),
+                aliases: aliases
+                    .into_iter()
+                    .map(|(name, link, _)| (name, link))
+                    .collect(),
+            },
+        );
+        Ok(())
+    }
+    /// Import all blocks from a CARv1 file
+    ///
+    /// The file is streamed through a buffer, so the reader does not need to be buffered, and
+    /// blocks are added in batches of up to 1000 blocks. Each block is validated against its cid, and links are extracted using libipld.
+    ///
+    /// - `reader` the car file
+    /// - `alias` an optional temporary alias for all imported blocks.
+    ///   Use this to protect the roots and their descendants from gc until they are pinned.
+    ///
+    /// Returns the roots from the header of the car file.
+    pub fn import_car(
+        &mut self,
+        reader: impl std::io::Read,
+        alias: Option<&TempPin>,
+    ) -> Result<Vec<Cid>> {
+        // varints are read byte by byte
+        let mut reader = std::io::BufReader::new(reader);
+        let roots = car::read_header(&mut reader)?;
+        loop {
+            let mut batch = Vec::new();
+            while batch.len() < CAR_BATCH_SIZE {
+                match car::read_block(&mut reader, &self.config.codecs)? {
+                    Some(block) => batch.push(block),
+                    None => break,
+                }
+            }
+            if batch.is_empty() {
+                break;
+            }
+            self.put_blocks(batch, alias)?;
+        }
+        Ok(roots)
+    }
+    /// Export a dag to a CARv1 file
+    ///
+    /// Writes all blocks reachable from `root` that are in the store, in a single read
+    /// transaction. Blocks are streamed from the database, so this works for dags that do not fit
+    /// into memory. Missing blocks are skipped, use [BlockStore::get_missing_blocks] to check
+    /// that the dag is complete.
+    ///
+    /// Returns the number of blocks written.
+    pub fn export_car(&self, root: &Cid, writer: impl std::io::Write) -> Result<u64> {
+        let report = self.export_car_impl(root, writer, false)?;
+        Ok(report.blocks)
+    }
+    /// Export a dag to a CARv1 file, skipping blocks that can not be read
+    ///
+    /// Like [BlockStore::export_car], but a block whose row is corrupt or whose checksum does not
+    /// match is left out of the file and reported, instead of failing the whole export. Errors
+    /// of the database structure itself and errors of the writer still abort the export.
+    pub fn export_car_lenient(
+        &self,
+        root: &Cid,
+        writer: impl std::io::Write,
+    ) -> Result<ExportReport> {
+        self.export_car_impl(root, writer, true)
+    }
+    fn export_car_impl(
+        &self,
+        root: &Cid,
+        mut writer: impl std::io::Write,
+        lenient: bool,
+    ) -> Result<ExportReport> {
+        let key = self.key(root)?;
+        let checksums = self.config.checksums;
+        car::write_header(&mut writer, &[*root])?;
+        let mut report = ExportReport::default();
+        in_ro_txn(&self.conn, |txn| {
+            for_each_descendant_block(txn, key, |row| {
+                match decode_bulk_row(row, checksums) {
+                    Ok((cid, data)) => {
+                        car::write_block(&mut writer, &cid, &data)?;
+                        report.blocks += 1;
+                    }
+                    Err(error) if lenient => report.errors.push(error),
+                    Err((_, cause)) => return Err(cause),
+                }
+                Ok(())
+            })
+        })?;
+        writer.flush()?;
+        Ok(report)
+    }
+    /// Iterate over the data of the leaves of a dag, in dag order.
+    ///
+    /// This is meant for reconstructing files or playing media, where the leaves have to be
+    /// read in order. The dag is traversed depth first, and blocks are read ahead in batches of
+    /// up to 256, each in its own short read transaction, so this is much faster than calling
+    /// [BlockStore::get_block] for each block, and does not keep a read transaction open while
+    /// the caller consumes the leaves. Leaves that are linked multiple times are returned
+    /// multiple times. The links of interior blocks are decoded to get their order, so their
+    /// codec must be supported. Missing blocks are skipped, and the iteration ends after the
+    /// first error.
+    pub fn get_leaves_stream(
+        &self,
+        root: &Cid,
+    ) -> impl Iterator<Item = Result<(Cid, Vec<u8>)>> + '_ {
+        let mut pending = VecDeque::new();
+        let mut error = None;
+        match self.key(root) {
+            Ok(root) => pending.push_back(root),
+            Err(cause) => error = Some(cause),
+        }
+        let mut batch = Vec::new().into_iter();
+        std::iter::from_fn(move || loop {
+            if let Some(leaf) = batch.next() {
+                return Some(Ok(leaf));
+            }
+            if let Some(cause) = error.take() {
+                return Some(Err(cause));
+            }
+            if pending.is_empty() {
+                return None;
+            }
+            let config = &self.config;
+            match in_ro_txn(&self.conn, |txn| next_leaves(txn, &mut pending, config)) {
+                Ok(leaves) => batch = leaves.into_iter(),
+                Err(cause) => {
+                    pending.clear();
+                    return Some(Err(cause));
+                }
+            }
+        })
+    }
     /// Get multiple blocks in a single read transaction
+    ///
+    /// The blocks are looked up in batches, with a single statement per batch, so this is much
+    /// faster than getting the blocks one by one.
     pub fn get_blocks<I>(&mut self, cids: I) -> Result<impl Iterator<Item = (Cid, Option<Vec<u8>>)>>
     where
         I: IntoIterator<Item = Cid>,
     {
-        let res = in_ro_txn(&self.conn, |txn| {
-            cids.into_iter()
-                .map(|cid| Ok((cid, get_block(txn, &CidBytes::try_from(&cid)?)?)))
-                .collect::<crate::Result<Vec<_>>>()
-        })?;
-        let infos = res
-            .iter()
-            .filter_map(|(cid, res)| {
-                res.as_ref()
-                    .map(|(id, data)| BlockInfo::new(*id, cid, data))
-            })
-            .collect::<Vec<_>>();
+        let cids = cids
+            .into_iter()
+            .map(|cid| Ok((cid, self.key(&cid)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let res = read_blocks(&self.conn, cids, self.config.checksums)?;
+        let infos = block_infos(&res);
+        self.record_accesses(&infos);
         self.config.cache_tracker.blocks_accessed(infos);
         Ok(res
             .into_iter()
-            .map(|(cid, res)| (cid, res.map(|(_, data)| data))))
+            .map(|(cid, res)| (cid, res.map(|(_, data, _)| data))))
     }
     /// Get data for a block
     ///
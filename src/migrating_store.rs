@@ -0,0 +1,217 @@
+//! A combinator for migrating to a new store without downtime
+//!
+//! This is for long running services that want to move their blocks to a store with a different
+//! config, e.g. with checksums or refcount gc enabled, or to a different file system. All writes
+//! go to both stores, while reads are served from the old store. In the background, the blocks of
+//! the old store are copied to the new store with [MigratingStore::copy_step]. Once the copy has
+//! converged, [MigratingStore::cutover] returns the new store.
+//!
+//! Gc of the new store is paused until the cutover, since temp pins of the old store are not
+//! carried over. Deletions in the old store are not mirrored: blocks that are collected in the
+//! old store after they have been copied remain in the new store until its first gc after the
+//! cutover, which collects them unless they are pinned in the new store.
+use crate::{
+    cid_from_stored, cidbytes::CidBytes, db::*, decode_bulk_row, Block, BlockStore, OwnedBlock,
+    Result, StoreStats, TempPin,
+};
+use fnv::FnvHashSet;
+use libipld::Cid;
+use std::fmt;
+
+/// A block store that mirrors all writes to a new store while the blocks are copied over
+pub struct MigratingStore {
+    old: BlockStore,
+    new: BlockStore,
+    /// id of the last block of the old store that has been copied
+    copied: i64,
+    /// true once all blocks of the old store have been copied
+    converged: bool,
+}
+
+impl fmt::Debug for MigratingStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MigratingStore")
+            .field("copied", &self.copied)
+            .field("converged", &self.converged)
+            .finish()
+    }
+}
+
+/// A temp pin for a [MigratingStore], consisting of a temp pin for each store
+#[derive(Debug)]
+pub struct MigratingTempPin {
+    old: TempPin,
+    new: TempPin,
+}
+
+impl MigratingStore {
+    /// Start a migration from `old` to `new`
+    ///
+    /// This copies all aliases of the old store to the new store, so copied blocks are pinned
+    /// in the new store just like in the old store.
+    pub fn new(old: BlockStore, mut new: BlockStore) -> Result<Self> {
+        new.pause_gc();
+        let mut this = Self {
+            old,
+            new,
+            copied: 0,
+            converged: false,
+        };
+        this.sync_aliases()?;
+        Ok(this)
+    }
+
+    /// The old and the new store, e.g. for maintenance
+    ///
+    /// Writes to either store directly are not mirrored to the other one.
+    pub fn stores(&mut self) -> (&mut BlockStore, &mut BlockStore) {
+        (&mut self.old, &mut self.new)
+    }
+
+    /// true if all blocks of the old store have been copied to the new store
+    pub fn is_converged(&self) -> bool {
+        self.converged
+    }
+
+    /// Copy up to `max_blocks` blocks from the old store to the new store
+    ///
+    /// Blocks are copied in the order in which they were added to the old store, together
+    /// with their links. Returns true once all blocks have been copied, i.e. the copy has
+    /// converged. Blocks that are added after that are written to both stores anyway. A
+    /// `max_blocks` of 0 copies nothing and never converges.
+    pub fn copy_step(&mut self, max_blocks: usize) -> Result<bool> {
+        if self.converged {
+            return Ok(true);
+        }
+        let checksums = self.old.config.checksums;
+        let after = self.copied;
+        let (last, blocks) = in_ro_txn(&self.old.conn, |txn| {
+            let rows = get_blocks_after::<CidBytes>(txn, after, max_blocks)?;
+            let mut last = None;
+            let mut blocks = Vec::with_capacity(rows.len());
+            for (id, row) in rows {
+                let (cid, data) = decode_bulk_row(row, checksums).map_err(|(_, cause)| cause)?;
+                let links = get_links::<CidBytes>(txn, id)?
+                    .iter()
+                    .map(cid_from_stored)
+                    .collect::<Result<Vec<_>>>()?;
+                blocks.push(OwnedBlock::new(cid, data, links));
+                last = Some(id);
+            }
+            Ok((last, blocks))
+        })?;
+        let n = blocks.len();
+        self.new.put_blocks(blocks, None)?;
+        if let Some(last) = last {
+            self.copied = last;
+        }
+        self.converged = max_blocks > 0 && n < max_blocks;
+        Ok(self.converged)
+    }
+
+    /// Finish the migration and return the new store
+    ///
+    /// This copies the remaining blocks, which is quick if [MigratingStore::copy_step] has
+    /// converged, makes the aliases of the new store match the old store, and resumes gc of the
+    /// new store.
+    pub fn cutover(mut self) -> Result<BlockStore> {
+        while !self.copy_step(1000)? {}
+        self.sync_aliases()?;
+        let Self { mut new, .. } = self;
+        new.resume_gc();
+        Ok(new)
+    }
+
+    /// make the aliases of the new store match the aliases of the old store
+    fn sync_aliases(&mut self) -> Result<()> {
+        let aliases = in_ro_txn(&self.old.conn, get_aliases::<CidBytes>)?;
+        let names = aliases
+            .iter()
+            .map(|(name, _, _)| name.clone())
+            .collect::<FnvHashSet<_>>();
+        let removed = in_ro_txn(&self.new.conn, get_aliases::<CidBytes>)?
+            .into_iter()
+            .filter(|(name, _, _)| !names.contains(name))
+            .map(|(name, _, _)| Ok((name, None, true)));
+        let aliases = aliases
+            .into_iter()
+            .map(|(name, cid, recursive)| Ok((name, Some(cid_from_stored(&cid)?), recursive)))
+            .chain(removed)
+            .collect::<Result<Vec<_>>>()?;
+        self.new.set_aliases(aliases)
+    }
+
+    /// Get a temporary pin that works for both stores
+    pub fn temp_pin(&self) -> MigratingTempPin {
+        MigratingTempPin {
+            old: self.old.temp_pin(),
+            new: self.new.temp_pin(),
+        }
+    }
+
+    /// Add a permanent named alias/pin for a root in both stores
+    pub fn alias(&mut self, name: impl AsRef<[u8]>, link: Option<&Cid>) -> Result<()> {
+        self.old.alias(name.as_ref(), link)?;
+        self.new.alias(name.as_ref(), link)
+    }
+
+    /// Add a permanent named alias/pin for just a block in both stores, see
+    /// [BlockStore::alias_direct]
+    pub fn alias_direct(&mut self, name: impl AsRef<[u8]>, link: Option<&Cid>) -> Result<()> {
+        self.old.alias_direct(name.as_ref(), link)?;
+        self.new.alias_direct(name.as_ref(), link)
+    }
+
+    /// Add a number of blocks to both stores
+    ///
+    /// This is not atomic across stores.
+    pub fn put_blocks<B: Block>(
+        &mut self,
+        blocks: impl IntoIterator<Item = B>,
+        alias: Option<&MigratingTempPin>,
+    ) -> Result<()> {
+        let blocks = blocks
+            .into_iter()
+            .map(|block| Ok((*block.cid(), block.data().to_vec(), block.links()?)))
+            .collect::<Result<Vec<_>>>()?;
+        let owned = |blocks: &[(Cid, Vec<u8>, Vec<Cid>)]| {
+            blocks
+                .iter()
+                .map(|(cid, data, links)| OwnedBlock::new(*cid, data.clone(), links.clone()))
+                .collect::<Vec<_>>()
+        };
+        self.old
+            .put_blocks(owned(&blocks), alias.map(|alias| &alias.old))?;
+        self.new
+            .put_blocks(owned(&blocks), alias.map(|alias| &alias.new))
+    }
+
+    /// Add a single block to both stores
+    pub fn put_block(
+        &mut self,
+        cid: &Cid,
+        data: &[u8],
+        links: impl IntoIterator<Item = Cid> + Clone,
+        alias: Option<&MigratingTempPin>,
+    ) -> Result<()> {
+        self.old
+            .put_block(cid, data, links.clone(), alias.map(|alias| &alias.old))?;
+        self.new
+            .put_block(cid, data, links, alias.map(|alias| &alias.new))
+    }
+
+    /// Get data for a block from the old store
+    pub fn get_block(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        self.old.get_block(cid)
+    }
+
+    /// Checks if the old store has the data for a cid
+    pub fn has_block(&mut self, cid: &Cid) -> Result<bool> {
+        self.old.has_block(cid)
+    }
+
+    /// Number and size of blocks of the old store
+    pub fn get_store_stats(&self) -> Result<StoreStats> {
+        self.old.get_store_stats()
+    }
+}
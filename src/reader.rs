@@ -0,0 +1,142 @@
+//! Reading blocks on separate connections
+//!
+//! A [BlockStore] has a single connection, so reads have to wait for long write transactions
+//! such as gc or large imports, even though readers and writers do not block each other in wal
+//! mode. A [BlockReader] has a pool of read only connections to the same database, see
+//! [Config::with_read_connections], and can be used from other threads while the store writes.
+//!
+//! Every read of a reader is done in a new read transaction, so it sees everything that was
+//! committed when it started. All writes of the store are committed before they return, so a
+//! read always sees the writes that were done before it, no matter which connection it uses.
+//!
+//! Block accesses via a reader are passed on to the cache tracker of the store, and recorded
+//! for [access tracking](Config::with_access_tracking), before the next gc.
+//!
+//! [BlockStore]: crate::BlockStore
+//! [Config::with_read_connections]: crate::Config::with_read_connections
+//! [Config::with_access_tracking]: crate::Config::with_access_tracking
+use crate::{
+    block_infos, cache::BlockInfo, cid_from_stored, cid_key, db::*, read_blocks, Config, Result,
+};
+use libipld::Cid;
+use rusqlite::{Connection, OpenFlags};
+use std::{
+    fmt,
+    iter::FromIterator,
+    path::Path,
+    sync::{Arc, Condvar, Mutex},
+};
+
+/// maximum number of block accesses to keep until the next gc. Further accesses are dropped.
+const MAX_PENDING_ACCESSES: usize = 100_000;
+
+/// A handle for reading blocks of a [BlockStore](crate::BlockStore) on a pool of separate
+/// connections
+///
+/// This can be cloned and shared between threads.
+#[derive(Clone)]
+pub struct BlockReader {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    pool: Mutex<Vec<Connection>>,
+    available: Condvar,
+    normalize_cids: bool,
+    checksums: bool,
+    /// block accesses that have not been passed to the store yet
+    accesses: Mutex<Vec<BlockInfo>>,
+}
+
+impl fmt::Debug for BlockReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockReader")
+            .field("idle", &self.inner.pool.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl BlockReader {
+    /// open the read connections of a store, after the database has been initialized
+    pub(crate) fn open(path: &Path, config: &Config) -> Result<Self> {
+        let pool = (0..config.read_connections)
+            .map(|_| {
+                let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+                set_pragmas(&conn, config)?;
+                Ok(conn)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            inner: Arc::new(Inner {
+                pool: Mutex::new(pool),
+                available: Condvar::new(),
+                normalize_cids: config.normalize_cids,
+                checksums: config.checksums,
+                accesses: Mutex::new(Vec::new()),
+            }),
+        })
+    }
+
+    /// run a function with a connection of the pool, waiting until one is available
+    fn with_conn<T>(&self, f: impl FnOnce(&Connection) -> Result<T>) -> Result<T> {
+        let conn = {
+            let mut pool = self.inner.pool.lock().unwrap();
+            loop {
+                if let Some(conn) = pool.pop() {
+                    break conn;
+                }
+                pool = self.inner.available.wait(pool).unwrap();
+            }
+        };
+        let result = f(&conn);
+        self.inner.pool.lock().unwrap().push(conn);
+        self.inner.available.notify_one();
+        result
+    }
+
+    /// take the block accesses since the last call, to pass them on to the store
+    pub(crate) fn take_accesses(&self) -> Vec<BlockInfo> {
+        std::mem::take(&mut self.inner.accesses.lock().unwrap())
+    }
+
+    /// Get multiple blocks in a single read transaction, see
+    /// [BlockStore::get_blocks](crate::BlockStore::get_blocks)
+    pub fn get_blocks<I>(&self, cids: I) -> Result<impl Iterator<Item = (Cid, Option<Vec<u8>>)>>
+    where
+        I: IntoIterator<Item = Cid>,
+    {
+        let cids = cids
+            .into_iter()
+            .map(|cid| Ok((cid, cid_key(&cid, self.inner.normalize_cids)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let checksums = self.inner.checksums;
+        let res = self.with_conn(|conn| read_blocks(conn, cids, checksums))?;
+        let mut accesses = self.inner.accesses.lock().unwrap();
+        if accesses.len() < MAX_PENDING_ACCESSES {
+            accesses.extend(block_infos(&res));
+        }
+        Ok(res
+            .into_iter()
+            .map(|(cid, res)| (cid, res.map(|(_, data, _)| data))))
+    }
+
+    /// Get data for a block
+    ///
+    /// Will return None if we don't have the data
+    pub fn get_block(&self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_blocks(std::iter::once(*cid))?.next().unwrap().1)
+    }
+
+    /// Checks if the store has the data for a cid
+    pub fn has_block(&self, cid: &Cid) -> Result<bool> {
+        let key = cid_key(cid, self.inner.normalize_cids)?;
+        self.with_conn(|conn| in_ro_txn(conn, |txn| has_block(txn, key)))
+    }
+
+    /// Get descendants of a cid
+    pub fn get_descendants<C: FromIterator<Cid>>(&self, cid: &Cid) -> Result<C> {
+        let key = cid_key(cid, self.inner.normalize_cids)?;
+        let res = self.with_conn(|conn| in_ro_txn(conn, |txn| get_descendants(txn, key)))?;
+        res.iter().map(cid_from_stored).collect()
+    }
+}
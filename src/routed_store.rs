@@ -0,0 +1,157 @@
+//! A combinator that distributes blocks over several stores
+//!
+//! This is for stores that outgrow a single sqlite file. Each block is put into and read from the
+//! store chosen by a routing function of its cid, e.g. a byte of the hash.
+//!
+//! Since every store does its own gc, pinning only protects the part of a dag that is in the
+//! same store as the pinned root. Links from a block to blocks in other stores are recorded, but
+//! do not keep these blocks alive. So either disable gc by using [SizeTargets::max_value] for all
+//! stores, or keep the blocks of a dag in one store by routing on something other than the hash.
+//!
+//! [SizeTargets::max_value]: crate::SizeTargets::max_value
+use crate::{Block, BlockStore, Result, StoreStats, TempPin};
+use libipld::Cid;
+use std::{fmt, iter::FromIterator};
+
+/// A block store that routes each cid to one of several underlying stores
+pub struct RoutedStore<F> {
+    stores: Vec<BlockStore>,
+    route: F,
+}
+
+impl<F> fmt::Debug for RoutedStore<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RoutedStore")
+            .field("stores", &self.stores.len())
+            .finish()
+    }
+}
+
+/// A temp pin for a [RoutedStore], consisting of one temp pin per underlying store
+#[derive(Debug)]
+pub struct RoutedTempPin(Vec<TempPin>);
+
+impl<F: Fn(&Cid) -> usize> RoutedStore<F> {
+    /// Create a routed store
+    ///
+    /// - `stores` the underlying stores. Must not be empty.
+    /// - `route` function that returns the index of the store for a cid. The result is taken
+    ///   modulo the number of stores. This must never change for a given set of stores.
+    pub fn new(stores: Vec<BlockStore>, route: F) -> Self {
+        assert!(
+            !stores.is_empty(),
+            "a routed store needs at least one store"
+        );
+        Self { stores, route }
+    }
+
+    /// The underlying stores, e.g. for maintenance
+    pub fn stores(&mut self) -> &mut [BlockStore] {
+        &mut self.stores
+    }
+
+    /// Return the underlying stores
+    pub fn into_inner(self) -> Vec<BlockStore> {
+        self.stores
+    }
+
+    fn index(&self, cid: &Cid) -> usize {
+        (self.route)(cid) % self.stores.len()
+    }
+
+    fn store(&mut self, cid: &Cid) -> &mut BlockStore {
+        let index = self.index(cid);
+        &mut self.stores[index]
+    }
+
+    /// Get a temporary pin that works for all underlying stores
+    pub fn temp_pin(&self) -> RoutedTempPin {
+        RoutedTempPin(self.stores.iter().map(|store| store.temp_pin()).collect())
+    }
+
+    /// Add a permanent named alias/pin for a root
+    ///
+    /// The alias is set in the store of the root, and removed from all other stores.
+    pub fn alias(&mut self, name: impl AsRef<[u8]>, link: Option<&Cid>) -> Result<()> {
+        let index = link.map(|cid| self.index(cid));
+        for (i, store) in self.stores.iter_mut().enumerate() {
+            if Some(i) == index {
+                store.alias(name.as_ref(), link)?;
+            } else {
+                store.alias(name.as_ref(), None)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Add a number of blocks
+    ///
+    /// The blocks are grouped by store, and each group is added using [BlockStore::put_blocks].
+    /// This is not atomic across stores.
+    pub fn put_blocks<B: Block>(
+        &mut self,
+        blocks: impl IntoIterator<Item = B>,
+        alias: Option<&RoutedTempPin>,
+    ) -> Result<()> {
+        let mut groups = self.stores.iter().map(|_| Vec::new()).collect::<Vec<_>>();
+        for block in blocks {
+            groups[self.index(block.cid())].push(block);
+        }
+        for (i, (store, group)) in self.stores.iter_mut().zip(groups).enumerate() {
+            if !group.is_empty() {
+                store.put_blocks(group, alias.map(|alias| &alias.0[i]))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Add a single block
+    pub fn put_block(
+        &mut self,
+        cid: &Cid,
+        data: &[u8],
+        links: impl IntoIterator<Item = Cid> + Clone,
+        alias: Option<&RoutedTempPin>,
+    ) -> Result<()> {
+        let index = self.index(cid);
+        self.stores[index].put_block(cid, data, links, alias.map(|alias| &alias.0[index]))
+    }
+
+    /// Get data for a block
+    pub fn get_block(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        self.store(cid).get_block(cid)
+    }
+
+    /// Checks if the store has the data for a cid
+    pub fn has_block(&mut self, cid: &Cid) -> Result<bool> {
+        self.store(cid).has_block(cid)
+    }
+
+    /// Get all cids for which any of the stores has blocks
+    pub fn get_block_cids<C: FromIterator<Cid>>(&mut self) -> Result<C> {
+        let mut result = Vec::new();
+        for store in &mut self.stores {
+            result.extend(store.get_block_cids::<Vec<_>>()?);
+        }
+        Ok(result.into_iter().collect())
+    }
+
+    /// Combined number and size of blocks of all stores
+    pub fn get_store_stats(&self) -> Result<StoreStats> {
+        let mut result = StoreStats::default();
+        for store in &self.stores {
+            let stats = store.get_store_stats()?;
+            result.count += stats.count;
+            result.size += stats.size;
+        }
+        Ok(result)
+    }
+
+    /// Do a full gc of all stores
+    pub fn gc(&mut self) -> Result<()> {
+        for store in &mut self.stores {
+            store.gc()?;
+        }
+        Ok(())
+    }
+}
@@ -1,19 +1,30 @@
 #![allow(clippy::many_single_char_names)]
 use crate::{
-    async_block_store::{AsyncBlockStore, GcConfig, RuntimeAdapter},
+    async_block_store::{AdaptiveBudget, AsyncBlockStore, GcConfig, RuntimeAdapter},
     cache::CacheTracker,
     cache::InMemCacheTracker,
-    cache::{SortByIdCacheTracker, SqliteCacheTracker},
-    BlockStore, Config, SizeTargets,
+    cache::{BatchingCacheTracker, BlockInfo, DeferredSortCacheTracker, Spawner},
+    cache::{LfuCacheTracker, LruCacheTracker, SortByIdCacheTracker, SqliteCacheTracker},
+    gc_scheduler::GcScheduler,
+    hooks::{Commit, CommitHook, InsertChannel, Inserted, LinkExtractor, MetadataHook, StoreEvent},
+    migrating_store::MigratingStore,
+    routed_store::RoutedStore,
+    AliasStats, ApproxStats, Batch, BlockStore, BlockStoreError, BusyRetry, CheckpointMode,
+    CheckpointStats, Config, DagDiff, GraphStats, ManifestEntry, OwnedBlock, PinReason, Pragmas,
+    PutStats, ReverseAlias, SizeTargets, SnapshotDiff, StoreStats, Synchronous, TempPinLimits,
+    TempPinStats, Throttle, TxnLimits, Visit, GC_CANDIDATES,
 };
 use fnv::FnvHashSet;
 use futures::prelude::*;
 use libipld::{
     cid::Cid,
-    multihash::{Code, MultihashDigest},
+    multihash::{Code, Multihash, MultihashDigest},
+};
+use rusqlite::{params, Connection, NO_PARAMS};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
 };
-use rusqlite::{params, Connection};
-use std::time::Duration;
 use tempdir::TempDir;
 
 fn cid(name: &str) -> Cid {
@@ -150,7 +161,9 @@ fn size_targets() -> anyhow::Result<()> {
     // check that gc does nothing
     assert_eq!(store.get_store_stats()?.count, 10);
     assert_eq!(store.get_store_stats()?.size, 10000);
-    store.incremental_gc(5, Duration::from_secs(100000))?;
+    let stats = store.incremental_gc(5, Duration::from_secs(100000))?;
+    assert_eq!(stats.blocks_deleted, 0);
+    assert!(stats.is_complete());
     assert_eq!(store.get_store_stats()?.count, 10);
     assert_eq!(store.get_store_stats()?.size, 10000);
 
@@ -162,7 +175,10 @@ fn size_targets() -> anyhow::Result<()> {
     }
 
     // check that gc gets triggered and removes min_blocks
-    store.incremental_gc(10, Duration::from_secs(100000))?;
+    let stats = store.incremental_gc(10, Duration::from_secs(100000))?;
+    assert_eq!(stats.blocks_deleted, 5);
+    assert_eq!(stats.bytes_freed, 5000);
+    assert!(stats.is_complete());
     assert_eq!(store.get_store_stats()?.count, 10);
     assert_eq!(store.get_store_stats()?.size, 10000);
 
@@ -177,6 +193,115 @@ fn size_targets() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test]
+fn gc_largest_first() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(
+        Config::default()
+            .with_size_targets(SizeTargets::new(100, 10000))
+            .with_cache_tracker(SortByIdCacheTracker)
+            .with_gc_largest_first(true),
+    )?;
+    for i in 0..8 {
+        let cid = unpinned(i);
+        store.put_block(&cid, &data(&cid, 1000), vec![], None)?;
+    }
+    let large = unpinned(8);
+    store.put_block(&large, &data(&large, 5000), vec![], None)?;
+    // the cache tracker would collect the oldest blocks first
+    let stats = store.incremental_gc(1, Duration::from_secs(1))?;
+    assert_eq!(stats.blocks_deleted, 1);
+    assert_eq!(stats.bytes_freed, 5000);
+    assert!(!store.has_block(&large)?);
+    Ok(())
+}
+
+#[test]
+fn access_tracking() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(
+        Config::default()
+            .with_size_targets(SizeTargets::new(2, 100000))
+            .with_cache_tracker(SortByIdCacheTracker)
+            .with_access_tracking(true),
+    )?;
+    let cids = (0..4).map(unpinned).collect::<Vec<_>>();
+    for cid in &cids[..3] {
+        store.put_block(cid, &data(cid, 1000), vec![], None)?;
+    }
+    std::thread::sleep(Duration::from_millis(2));
+    for i in [2, 0, 1].iter() {
+        store.get_block(&cids[*i])?;
+        std::thread::sleep(Duration::from_millis(2));
+    }
+    store.put_block(&cids[3], &data(&cids[3], 1000), vec![], None)?;
+    store.gc()?;
+    // the least recently read blocks go first, and a block that was written after them but
+    // never read counts as used when it was written
+    assert!(!store.has_block(&cids[2])?);
+    assert!(!store.has_block(&cids[0])?);
+    assert!(store.has_block(&cids[1])?);
+    assert!(store.has_block(&cids[3])?);
+    Ok(())
+}
+
+#[test]
+fn access_tracking_drop() -> anyhow::Result<()> {
+    let tmp = TempDir::new("access_tracking_drop")?;
+    let path = tmp.path().join("db.sqlite");
+    let config = || Config::default().with_access_tracking(true);
+    let mut store = BlockStore::open(&path, config())?;
+    // more accesses than are written with a single statement
+    let cids = (0..100).map(unpinned).collect::<Vec<_>>();
+    for cid in &cids {
+        store.put_block(cid, &data(cid, 10), vec![], None)?;
+        store.get_block(cid)?;
+    }
+    // buffered accesses are written when the store is dropped
+    drop(store);
+    let store = BlockStore::open(&path, config())?;
+    let accesses: i64 =
+        store
+            .conn
+            .query_row("SELECT count(*) FROM accesses", params![], |row| row.get(0))?;
+    assert_eq!(accesses, 100);
+    Ok(())
+}
+
+#[test]
+fn size_targets_low_watermark() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(
+        Config::default()
+            .with_size_targets(SizeTargets::new(10, 100000))
+            .with_low_watermark(SizeTargets::new(5, 100000)),
+    )?;
+    for i in 0..11 {
+        let cid = unpinned(i);
+        store.put_block(&cid, &data(&cid, 1000), vec![], None)?;
+    }
+    // collect a single block per call, so gc has to continue below the targets
+    let mut calls = 0;
+    while !store
+        .incremental_gc(1, Duration::from_secs(0))?
+        .is_complete()
+    {
+        calls += 1;
+        assert!(calls < 11, "gc did not reach the low watermark");
+    }
+    assert_eq!(store.get_store_stats()?.count, 5);
+    // below the targets, gc does nothing until they are exceeded again
+    for i in 11..16 {
+        let cid = unpinned(i);
+        store.put_block(&cid, &data(&cid, 1000), vec![], None)?;
+    }
+    assert_eq!(
+        store
+            .incremental_gc(1, Duration::from_secs(0))?
+            .blocks_deleted,
+        0
+    );
+    assert_eq!(store.get_store_stats()?.count, 10);
+    Ok(())
+}
+
 #[test]
 fn in_mem_cache_tracker() -> anyhow::Result<()> {
     cache_test(InMemCacheTracker::new(|access, _| Some(access)))
@@ -187,6 +312,134 @@ fn sqlite_cache_tracker() -> anyhow::Result<()> {
     cache_test(SqliteCacheTracker::memory(|access, _| Some(access))?)
 }
 
+#[test]
+fn lru_cache_tracker() -> anyhow::Result<()> {
+    cache_test(LruCacheTracker::new())
+}
+
+#[test]
+fn lfu_cache_tracker() -> anyhow::Result<()> {
+    let tmp = TempDir::new("lfu_cache_tracker")?;
+    let path = tmp.path().join("scores.sqlite");
+    let tracker = LfuCacheTracker::open(&path)?;
+    cache_test(tracker.clone())?;
+    tracker.snapshot()?;
+    // the scores survive a restart
+    let restored = LfuCacheTracker::open(&path)?;
+    let mut ids = vec![9, 3, 1];
+    restored.sort_ids(&mut ids);
+    assert_eq!(ids, vec![1, 9, 3]);
+    Ok(())
+}
+
+/// records the ids passed to blocks_accessed, one vec per call
+#[derive(Debug, Clone, Default)]
+struct RecordingCacheTracker(Arc<Mutex<Vec<Vec<i64>>>>);
+
+impl CacheTracker for RecordingCacheTracker {
+    fn blocks_accessed(&mut self, blocks: Vec<BlockInfo>) {
+        let ids = blocks.iter().map(|block| block.id()).collect();
+        self.0.lock().unwrap().push(ids);
+    }
+}
+
+#[test]
+fn batching_cache_tracker() -> anyhow::Result<()> {
+    let recorder = RecordingCacheTracker::default();
+    let mut store = BlockStore::memory(
+        Config::default().with_cache_tracker(BatchingCacheTracker::new(recorder.clone(), 3)),
+    )?;
+    let a = cid("a");
+    store.put_block(&a, b"a", vec![], None)?;
+    store.get_block(&a)?;
+    store.get_block(&a)?;
+    assert!(recorder.0.lock().unwrap().is_empty());
+    store.get_block(&a)?;
+    store.get_block(&a)?;
+    assert_eq!(*recorder.0.lock().unwrap(), vec![vec![1, 1, 1]]);
+    // gc sees the pending access
+    store.gc()?;
+    assert_eq!(recorder.0.lock().unwrap().len(), 2);
+    Ok(())
+}
+
+type Task = Box<dyn FnOnce() + Send>;
+
+/// a spawner that queues tasks until they are run explicitly
+#[derive(Clone, Default)]
+struct QueueSpawner(Arc<Mutex<Vec<Task>>>);
+
+impl QueueSpawner {
+    fn run(&self) -> usize {
+        let tasks = std::mem::take(&mut *self.0.lock().unwrap());
+        let n = tasks.len();
+        for task in tasks {
+            task();
+        }
+        n
+    }
+}
+
+impl Spawner for QueueSpawner {
+    fn spawn_blocking(&self, f: impl FnOnce() + Send + 'static) {
+        self.0.lock().unwrap().push(Box::new(f));
+    }
+}
+
+/// a cache tracker that considers small ids the most important
+#[derive(Debug)]
+struct ReverseCacheTracker;
+
+impl CacheTracker for ReverseCacheTracker {
+    fn sort_ids(&self, ids: &mut [i64]) {
+        ids.sort_unstable_by(|a, b| b.cmp(a));
+    }
+}
+
+#[test]
+fn deferred_sort_cache_tracker() {
+    let spawner = QueueSpawner::default();
+    let mut tracker = DeferredSortCacheTracker::new(spawner.clone(), ReverseCacheTracker);
+    // nothing is ranked yet
+    let mut ids = vec![3, 1, 2];
+    tracker.sort_ids(&mut ids);
+    assert_eq!(ids, vec![1, 2, 3]);
+    // a second round while the first sort is still pending does not queue another one
+    tracker.sort_ids(&mut ids);
+    assert_eq!(spawner.run(), 1);
+    // the next round uses the ranking of the wrapped tracker, and new ids go last
+    let mut ids = vec![1, 4, 2, 3];
+    tracker.sort_ids(&mut ids);
+    assert_eq!(ids, vec![3, 2, 1, 4]);
+    // notifications are forwarded on the spawner, and deleted ids lose their rank, even if they
+    // were deleted while a sort was pending
+    tracker.delete_ids(&[3]);
+    assert_eq!(spawner.run(), 2);
+    let mut ids = vec![1, 2, 3, 4];
+    tracker.sort_ids(&mut ids);
+    assert_eq!(ids, vec![4, 2, 1, 3]);
+}
+
+#[test]
+fn deferred_sort_cache_tracker_lru() {
+    let spawner = QueueSpawner::default();
+    let tracker = InMemCacheTracker::new(|access, _| Some(access));
+    let mut tracker = DeferredSortCacheTracker::new(spawner.clone(), tracker);
+    let a = cid("a");
+    tracker.blocks_accessed(vec![BlockInfo::new(2, &a, b"a")]);
+    spawner.run();
+    std::thread::sleep(Duration::from_millis(1));
+    tracker.blocks_accessed(vec![BlockInfo::new(1, &a, b"a")]);
+    spawner.run();
+    let mut ids = vec![1, 2];
+    tracker.sort_ids(&mut ids);
+    spawner.run();
+    // a block written after the last ranking is not the first to be evicted
+    let mut ids = vec![3, 1, 2];
+    tracker.sort_ids(&mut ids);
+    assert_eq!(ids, vec![2, 1, 3]);
+}
+
 fn cache_test(tracker: impl CacheTracker + 'static) -> anyhow::Result<()> {
     // let tracker = ;
 
@@ -249,6 +502,25 @@ fn cache_test(tracker: impl CacheTracker + 'static) -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test]
+fn touch() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(
+        Config::default()
+            .with_size_targets(SizeTargets::new(3, 10000))
+            .with_cache_tracker(InMemCacheTracker::new(|access, _| Some(access))),
+    )?;
+    for i in 0..4 {
+        let cid = unpinned(i);
+        store.put_block(&cid, &data(&cid, 100), vec![], None)?;
+    }
+    // the oldest block is refreshed, and unknown cids are ignored
+    store.touch(&[unpinned(0), unpinned(100)])?;
+    store.incremental_gc(1, Duration::from_secs(100000))?;
+    let cids = store.get_block_cids::<FnvHashSet<_>>()?;
+    assert_eq!(cids, vec![0, 2, 3].into_iter().map(unpinned).collect());
+    Ok(())
+}
+
 const OLD_INIT: &str = r#"
 CREATE TABLE IF NOT EXISTS blocks (
     key BLOB PRIMARY KEY,
@@ -286,14 +558,28 @@ fn test_reverse_alias() -> anyhow::Result<()> {
     let data = data(&cid, 1);
     store.put_block(&cid, &data, vec![], None)?;
     store.alias(&b"leaf"[..], Some(&cid))?;
-    assert_eq!(store.reverse_alias(&cid)?, vec![b"leaf".to_vec()]);
+    assert_eq!(store.reverse_alias(&cid)?.aliases, vec![b"leaf".to_vec()]);
     let cid2 = pinned(1);
     store.put_block(&cid2, &data, vec![cid], None)?;
     store.alias(&b"root"[..], Some(&cid2))?;
     assert_eq!(
-        store.reverse_alias(&cid)?,
+        store.reverse_alias(&cid)?.aliases,
         vec![b"leaf".to_vec(), b"root".to_vec()]
     );
+    // a block that is only protected via an ancestor
+    let cid3 = pinned(2);
+    let pin = store.temp_pin();
+    store.put_block(&cid3, &data, vec![cid2], Some(&pin))?;
+    store.alias(&b"leaf"[..], None)?;
+    let reverse = store.reverse_alias(&cid)?;
+    assert_eq!(reverse.aliases, vec![b"root".to_vec()]);
+    assert_eq!(reverse.temp_pins, 1);
+    assert!(reverse.is_aliased() && reverse.is_temp_pinned());
+    store.alias(&b"root"[..], None)?;
+    let reverse = store.reverse_alias(&cid)?;
+    assert!(!reverse.is_aliased() && reverse.is_temp_pinned());
+    // unknown cids are not protected at all
+    assert_eq!(store.reverse_alias(&unpinned(0))?, ReverseAlias::default());
     Ok(())
 }
 
@@ -369,6 +655,32 @@ async fn temp_pin_async() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn block_reader_async() -> anyhow::Result<()> {
+    let tmp = TempDir::new("block_reader_async")?;
+    let store = BlockStore::open(
+        tmp.path().join("db"),
+        Config::default().with_read_connections(2),
+    )?;
+    let (store, _completed) = AsyncBlockStore::new(TokioRuntime, store);
+    let a = cid("a");
+    let b = cid("b");
+    store.put_block(b, b"b".to_vec(), vec![], None).await?;
+    store.put_block(a, b"a".to_vec(), vec![b], None).await?;
+    // reads via the reader see the writes that completed before
+    assert_eq!(store.get_block(a).await?, Some(b"a".to_vec()));
+    assert!(store.has_block(b).await?);
+    let blocks = store.get_blocks(vec![a, b]).await?.collect::<Vec<_>>();
+    assert_eq!(blocks.len(), 2);
+    let descendants: FnvHashSet<Cid> = store.get_descendants(a).await?;
+    assert_eq!(descendants, [a, b].iter().copied().collect());
+    store.alias(b"a".to_vec(), Some(a)).await?;
+    store.alias(b"a".to_vec(), None).await?;
+    store.gc().await?;
+    assert!(!store.has_block(a).await?);
+    Ok(())
+}
+
 #[tokio::test]
 async fn gc_loop() -> anyhow::Result<()> {
     let store = BlockStore::memory(Config::default())?;
@@ -401,6 +713,140 @@ async fn gc_loop() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[tokio::test(flavor = "multi_thread")]
+async fn gc_loop_sliced() -> anyhow::Result<()> {
+    let hook = RecordingCommitHook::default();
+    let mut store = BlockStore::memory(
+        Config::default()
+            .with_gc_slice(Duration::from_millis(1))
+            .with_commit_hook(hook.clone()),
+    )?;
+    let pinned = cid("pinned");
+    store.put_block(&pinned, b"pinned", vec![], None)?;
+    store.alias(b"pinned", Some(&pinned))?;
+    store.put_blocks(
+        (0..500).map(|i| {
+            let cid = unpinned(i);
+            OwnedBlock::new(cid, data(&cid, 100), vec![])
+        }),
+        None,
+    )?;
+    let (store, _completed) = AsyncBlockStore::new(TokioRuntime, store);
+    let handle = tokio::spawn(store.clone().gc_loop(GcConfig {
+        interval: Duration::from_millis(10),
+        target_duration: Duration::from_secs(10),
+        ..GcConfig::default()
+    }));
+    // commit sequence numbers observed by reads while gc is running
+    let mut observed = Vec::new();
+    let t0 = std::time::Instant::now();
+    while store.get_store_stats().await?.count > 1 {
+        assert!(t0.elapsed() < Duration::from_secs(30), "gc did not finish");
+        assert!(store.get_block(pinned).await?.is_some());
+        observed.push(store.commit_seq().await?);
+    }
+    handle.abort();
+    let gc_commits = hook
+        .0
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|commit| !commit.removed.is_empty())
+        .map(|commit| commit.seq)
+        .collect::<Vec<_>>();
+    // gc was split into several transactions, and reads were done in between
+    assert!(gc_commits.len() > 1);
+    let (first, last) = (gc_commits[0], gc_commits[gc_commits.len() - 1]);
+    assert!(observed.iter().any(|seq| *seq >= first && *seq < last));
+    Ok(())
+}
+
+#[test]
+fn gc_scheduler() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let a = cid("a");
+    let b = cid("b");
+    store.put_block(&a, b"a", vec![], None)?;
+    store.alias(b"a", Some(&a))?;
+    store.put_block(&b, b"b", vec![], None)?;
+    let store = Arc::new(Mutex::new(store));
+    let scheduler = GcScheduler::spawn(
+        store.clone(),
+        GcConfig::default().with_interval(Duration::from_millis(10)),
+    );
+    let t0 = std::time::Instant::now();
+    while store.lock().unwrap().has_block(&b)? {
+        assert!(t0.elapsed() < Duration::from_secs(10), "gc did not run");
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    assert!(store.lock().unwrap().has_block(&a)?);
+    scheduler.stop()?;
+    Ok(())
+}
+
+#[test]
+fn gc_scheduler_errors() -> anyhow::Result<()> {
+    let tmp = TempDir::new("gc_scheduler_errors")?;
+    let path = tmp.path().join("db");
+    let config = Config::default()
+        .with_busy_timeout(Duration::default())
+        .with_busy_retry(BusyRetry::none());
+    let mut store = BlockStore::open(&path, config)?;
+    let a = cid("a");
+    store.put_block(&a, b"a", vec![], None)?;
+    let store = Arc::new(Mutex::new(store));
+    // gc fails while another connection holds the write lock
+    let lock = Connection::open(&path)?;
+    lock.execute_batch("BEGIN IMMEDIATE")?;
+    let scheduler = GcScheduler::spawn(
+        store.clone(),
+        GcConfig::default().with_interval(Duration::from_millis(10)),
+    );
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(store.lock().unwrap().has_block(&a)?);
+    // and continues once the lock is released
+    lock.execute_batch("COMMIT")?;
+    let t0 = std::time::Instant::now();
+    while store.lock().unwrap().has_block(&a)? {
+        assert!(
+            t0.elapsed() < Duration::from_secs(10),
+            "gc did not continue"
+        );
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    // a panic of the foreground stops the thread with an error
+    let poisoner = store.clone();
+    let _ = std::thread::spawn(move || {
+        let _guard = poisoner.lock().unwrap();
+        panic!("poison the store");
+    })
+    .join();
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(scheduler.stop().is_err());
+    Ok(())
+}
+
+#[test]
+fn read_only_follower() -> anyhow::Result<()> {
+    let tmp = TempDir::new("read_only_follower")?;
+    let path = tmp.path().join("db.sqlite");
+    let mut writer = BlockStore::open(&path, Config::default())?;
+    let mut reader = BlockStore::open_read_only(&path, Config::default())?;
+    let a = cid("a");
+    assert!(!reader.has_changed()?);
+    assert!(!reader.has_block(&a)?);
+    writer.put_block(&a, b"abcd", vec![], None)?;
+    assert!(reader.has_changed()?);
+    assert!(!reader.has_changed()?);
+    assert!(reader.has_block(&a)?);
+    assert_eq!(reader.get_block(&a)?, Some(b"abcd".to_vec()));
+    // the reader can not write
+    assert!(reader.put_block(&cid("b"), b"b", vec![], None).is_err());
+    // the writer does not see its own changes
+    assert!(!writer.has_changed()?);
+    Ok(())
+}
+
 #[test]
 fn broken_db() -> anyhow::Result<()> {
     let store = BlockStore::open("test-data/mini.sqlite", Config::default())?;
@@ -410,3 +856,2489 @@ fn broken_db() -> anyhow::Result<()> {
     assert!(store.integrity_check().is_err());
     Ok(())
 }
+
+#[test]
+fn corrupt_cid_row() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let a = cid("a");
+    store.put_block(&a, b"abcd", vec![], None)?;
+    store.alias(b"alias1", Some(&a))?;
+    // simulate a legacy or corrupt row that is not a valid cid
+    store
+        .conn
+        .execute("INSERT INTO cids (cid) VALUES (x'0102')", params![])?;
+    let res = store.get_known_cids::<Vec<_>>();
+    assert!(matches!(res, Err(BlockStoreError::CorruptCid(bytes)) if bytes == vec![1, 2]));
+    // valid rows are unaffected
+    assert_eq!(store.get_block_cids::<Vec<_>>()?, vec![a]);
+    Ok(())
+}
+
+#[test]
+fn incomplete_pins() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let a = cid("a");
+    let b = cid("b");
+    let c = cid("c");
+    store.put_block(&a, b"abcd", vec![b, c], None)?;
+    store.put_block(&b, b"fubar", vec![], None)?;
+    store.alias(b"complete", Some(&b))?;
+    store.alias(b"incomplete", Some(&a))?;
+    assert_eq!(
+        store.incomplete_pins()?,
+        vec![(b"incomplete".to_vec(), a, 1)]
+    );
+    store.put_block(&c, b"fubar", vec![], None)?;
+    assert!(store.incomplete_pins()?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn dead_pins() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let a = cid("a");
+    let b = cid("b");
+    let c = cid("c");
+    store.put_block(&a, b"a", vec![b], None)?;
+    store.alias(b"a", Some(&a))?;
+    store.alias(b"b", Some(&b))?;
+    store.alias_direct(b"c", Some(&c))?;
+    assert_eq!(
+        store.dead_pins()?,
+        vec![(b"b".to_vec(), b), (b"c".to_vec(), c)]
+    );
+    store.put_block(&b, b"b", vec![], None)?;
+    assert_eq!(store.dead_pins()?, vec![(b"c".to_vec(), c)]);
+    Ok(())
+}
+
+#[test]
+fn throttle_pause() {
+    let throttle = Throttle::new(0.5, Duration::from_millis(10));
+    assert_eq!(
+        throttle.pause_after(Duration::from_millis(100)),
+        Duration::from_millis(100)
+    );
+    assert_eq!(
+        throttle.pause_after(Duration::from_millis(1)),
+        Duration::from_millis(10)
+    );
+    assert_eq!(
+        Throttle::none().pause_after(Duration::from_secs(1)),
+        Duration::default()
+    );
+    // out of range duty cycles are clamped instead of panicking
+    for duty_cycle in &[0.0, -1.0, 1e-300, f64::NAN, f64::INFINITY] {
+        Throttle::new(*duty_cycle, Duration::default()).pause_after(Duration::from_secs(1));
+    }
+}
+
+#[tokio::test]
+async fn throttle_manual_gc() -> anyhow::Result<()> {
+    let min_pause = Duration::from_millis(50);
+    let store = BlockStore::memory(Config::default().with_throttle(Throttle::new(1.0, min_pause)))?;
+    let (store, _completed) = AsyncBlockStore::new(TokioRuntime, store);
+    store
+        .put_block(cid("a"), b"a".to_vec(), vec![], None)
+        .await?;
+    let t0 = std::time::Instant::now();
+    store.incremental_gc(1, Duration::from_secs(1)).await?;
+    assert!(t0.elapsed() >= min_pause);
+    let t0 = std::time::Instant::now();
+    store.gc().await?;
+    assert!(t0.elapsed() >= min_pause);
+    assert!(!store.has_block(cid("a")).await?);
+    Ok(())
+}
+
+#[test]
+fn gc_stats() -> anyhow::Result<()> {
+    let mut store =
+        BlockStore::memory(Config::default().with_size_targets(SizeTargets::new(2, 10000)))?;
+    for i in 0..10 {
+        let cid = unpinned(i);
+        store.put_block(&cid, &data(&cid, 100), vec![], None)?;
+    }
+    // collect only a single block, since the duration is exceeded immediately
+    let stats = store.incremental_gc(1, Duration::default())?;
+    assert_eq!(stats.blocks_deleted, 1);
+    assert_eq!(stats.bytes_freed, 100);
+    assert_eq!(stats.candidates_remaining, 9);
+    assert!(!stats.is_complete());
+    let stats = store.incremental_gc(10, Duration::default())?;
+    assert_eq!(stats.blocks_deleted, 7);
+    assert_eq!(stats.candidates_remaining, 0);
+    Ok(())
+}
+
+#[test]
+fn adaptive_budget() {
+    let ms = Duration::from_millis;
+    let adaptive = AdaptiveBudget::new(ms(10), ms(100), ms(50));
+    // grow while idle, up to the max
+    assert_eq!(adaptive.next_budget(ms(10), ms(0)), ms(15));
+    assert_eq!(adaptive.next_budget(ms(80), ms(50)), ms(100));
+    // back off under load, down to the min
+    assert_eq!(adaptive.next_budget(ms(100), ms(60)), ms(50));
+    assert_eq!(adaptive.next_budget(ms(15), ms(60)), ms(10));
+}
+
+/// runs the gc loop on 1000 unpinned blocks and returns the number of blocks removed by each gc
+/// transaction. Only the commit hook is watched, so the store is idle apart from gc.
+async fn gc_transactions(config: Config) -> anyhow::Result<Vec<usize>> {
+    let hook = RecordingCommitHook::default();
+    let mut store = BlockStore::memory(config.with_commit_hook(hook.clone()))?;
+    store.put_blocks(
+        (0..1000).map(|i| {
+            let cid = unpinned(i);
+            OwnedBlock::new(cid, data(&cid, 100), vec![])
+        }),
+        None,
+    )?;
+    let (store, _completed) = AsyncBlockStore::new(TokioRuntime, store);
+    let handle = tokio::spawn(
+        store
+            .clone()
+            .gc_loop(GcConfig::default().with_interval(Duration::from_millis(10))),
+    );
+    let removed = || {
+        hook.0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|commit| commit.removed.len())
+            .filter(|removed| *removed > 0)
+            .collect::<Vec<_>>()
+    };
+    let t0 = std::time::Instant::now();
+    while removed().iter().sum::<usize>() < 1000 {
+        assert!(t0.elapsed() < Duration::from_secs(30), "gc did not finish");
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    handle.abort();
+    Ok(removed())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn gc_loop_adaptive() -> anyhow::Result<()> {
+    let slice = Duration::from_millis(1);
+    let fixed = gc_transactions(Config::default().with_gc_slice(slice)).await?;
+    let adaptive = gc_transactions(Config::default().with_gc_slice(slice).with_adaptive_gc(
+        AdaptiveBudget::new(slice, Duration::from_millis(20), Duration::from_millis(10)),
+    ))
+    .await?;
+    // gc finishes with the adaptive budget as well
+    assert_eq!(adaptive.iter().sum::<usize>(), fixed.iter().sum::<usize>());
+    // the budget grows while nobody waits, and shrinks when calls waited too long
+    let budget = AdaptiveBudget::new(
+        Duration::from_millis(2),
+        Duration::from_millis(10),
+        Duration::from_millis(5),
+    );
+    let idle = Duration::from_millis(1);
+    let busy = Duration::from_millis(6);
+    let mut current = budget.min_budget;
+    let mut grown = Vec::new();
+    for _ in 0..5 {
+        current = budget.next_budget(current, idle);
+        grown.push(current.as_millis());
+    }
+    assert_eq!(grown, vec![3, 4, 6, 10, 10]);
+    assert_eq!(
+        budget.next_budget(Duration::from_millis(10), busy),
+        Duration::from_millis(5)
+    );
+    assert_eq!(
+        budget.next_budget(budget.min_budget, busy),
+        budget.min_budget
+    );
+    Ok(())
+}
+
+#[derive(Debug)]
+struct SizeClassHook;
+
+impl MetadataHook for SizeClassHook {
+    fn metadata(&self, _cid: &Cid, data: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let class = if data.len() > 4 { "large" } else { "small" };
+        vec![("size_class".into(), class.as_bytes().to_vec())]
+    }
+}
+
+#[test]
+fn metadata_hook() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default().with_metadata_hook(SizeClassHook))?;
+    let a = cid("a");
+    let b = cid("b");
+    store.put_block(&a, b"abcd", vec![], None)?;
+    store.put_block(&b, b"fubar", vec![], None)?;
+    assert_eq!(
+        store.get_metadata(&a, "size_class")?,
+        Some(b"small".to_vec())
+    );
+    assert_eq!(
+        store.get_metadata(&b, "size_class")?,
+        Some(b"large".to_vec())
+    );
+    assert_eq!(store.get_metadata(&a, "mime")?, None);
+    // metadata is removed together with the block
+    store.gc()?;
+    assert_eq!(store.get_metadata(&a, "size_class")?, None);
+    Ok(())
+}
+
+#[tokio::test]
+async fn read_your_writes() -> anyhow::Result<()> {
+    let store = BlockStore::memory(Config::default())?;
+    let (store, _completed) = AsyncBlockStore::new(TokioRuntime, store);
+    let tasks = (0..10usize).map(|i| {
+        let store = store.clone();
+        tokio::spawn(async move {
+            let cid = unpinned(i);
+            let data = data(&cid, 100);
+            store.put_block(cid, data.clone(), vec![], None).await?;
+            // a read after a completed write must observe the write
+            let read = store.get_block(cid).await?;
+            anyhow::ensure!(read == Some(data), "write not observed");
+            Ok::<_, anyhow::Error>(cid)
+        })
+    });
+    let mut cids = Vec::new();
+    for task in tasks {
+        cids.push(task.await??);
+    }
+    // and also from another task
+    for cid in cids {
+        assert!(store.has_block(cid).await?);
+    }
+    Ok(())
+}
+
+#[test]
+fn provider_hints() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default().with_max_provider_hints(2))?;
+    let a = cid("a");
+    store.put_block(&a, b"abcd", vec![], None)?;
+    store.add_provider_hints(&a, vec![b"peer1", b"peer2"])?;
+    assert_eq!(
+        store.get_provider_hints(&a)?,
+        vec![b"peer2".to_vec(), b"peer1".to_vec()]
+    );
+    // only the most recent hints are kept
+    store.add_provider_hints(&a, vec![b"peer3"])?;
+    assert_eq!(
+        store.get_provider_hints(&a)?,
+        vec![b"peer3".to_vec(), b"peer2".to_vec()]
+    );
+    // hints survive the block, so it can be fetched again
+    store.gc()?;
+    assert!(!store.has_block(&a)?);
+    assert_eq!(
+        store.get_provider_hints(&a)?,
+        vec![b"peer3".to_vec(), b"peer2".to_vec()]
+    );
+    store.put_block(&a, b"abcd", vec![], None)?;
+    assert_eq!(store.delete_blocks(vec![a])?, 1);
+    assert_eq!(store.get_provider_hints(&a)?.len(), 2);
+    // once the hints are deleted, the cid is orphaned
+    assert_eq!(store.delete_provider_hints(&a)?, 2);
+    assert!(store.get_provider_hints(&a)?.is_empty());
+    assert_eq!(store.delete_orphaned_cids()?, 1);
+    assert!(!store.has_cid(&a)?);
+    Ok(())
+}
+
+#[test]
+fn txn_limits() -> anyhow::Result<()> {
+    let mut store =
+        BlockStore::memory(Config::default().with_txn_limits(TxnLimits::new(1000, 2500)))?;
+    let blocks = (0..10)
+        .map(|i| {
+            let cid = unpinned(i);
+            OwnedBlock::new(cid, data(&cid, 1000), vec![])
+        })
+        .collect::<Vec<_>>();
+    let pin = store.temp_pin();
+    store.put_blocks(blocks, Some(&pin))?;
+    assert!(store.put_stats().batches > 1);
+    assert_eq!(store.get_store_stats()?.count(), 10);
+    store.gc()?;
+    assert_eq!(store.get_store_stats()?.count(), 10);
+    Ok(())
+}
+
+#[test]
+fn txn_limits_zero() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default().with_txn_limits(TxnLimits::new(0, 0)))?;
+    // every transaction takes at least one item, so this terminates
+    store.put_blocks(
+        (0..3).map(|i| {
+            let cid = unpinned(i);
+            OwnedBlock::new(cid, data(&cid, 10), vec![])
+        }),
+        None,
+    )?;
+    assert_eq!(store.put_stats().batches, 3);
+    store.alias_many((0..3).map(|i| (vec![i as u8], Some(unpinned(i)))))?;
+    assert_eq!(store.aliases()?.len(), 3);
+    Ok(())
+}
+
+#[test]
+fn put_stats() -> anyhow::Result<()> {
+    let mut store =
+        BlockStore::memory(Config::default().with_txn_limits(TxnLimits::new(1000, 2500)))?;
+    assert_eq!(store.put_stats(), PutStats::default());
+    store.put_blocks(
+        (0..10).map(|i| {
+            let cid = unpinned(i);
+            OwnedBlock::new(cid, data(&cid, 1000), vec![])
+        }),
+        None,
+    )?;
+    let stats = store.put_stats();
+    // 3 blocks per transaction because of the size limit
+    assert_eq!(stats.batches, 4);
+    assert_eq!(stats.blocks, 10);
+    assert_eq!(stats.max_batch_size, 3);
+    assert!(stats.write_time > Duration::default());
+    Ok(())
+}
+
+#[test]
+fn query_plans() -> anyhow::Result<()> {
+    let store = BlockStore::memory(Config::default())?;
+    let plans = store.query_plans()?;
+    assert_eq!(plans.len(), crate::CORE_QUERIES.len());
+    for (name, plan) in plans {
+        assert!(!plan.is_empty(), "no query plan for {}", name);
+    }
+    Ok(())
+}
+
+#[test]
+fn extension_tables() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let v1 = "CREATE TABLE app_index (key TEXT PRIMARY KEY, cid BLOB NOT NULL);";
+    let v2 = "CREATE INDEX app_index_cid ON app_index (cid);";
+    assert_eq!(store.migrate_extension("app", &[v1])?, 1);
+    // already applied migrations are not executed again
+    assert_eq!(store.migrate_extension("app", &[v1, v2])?, 2);
+    assert_eq!(store.migrate_extension("app", &[v1, v2])?, 2);
+    let a = cid("a");
+    store.extension_txn(|txn| {
+        txn.execute(
+            "INSERT INTO app_index (key, cid) VALUES (?, ?)",
+            params!["a", a.to_bytes()],
+        )?;
+        Ok(())
+    })?;
+    // a failing function rolls back its writes
+    assert!(store
+        .extension_txn(|txn| {
+            txn.execute("DELETE FROM app_index", NO_PARAMS)?;
+            Err::<(), _>(anyhow::anyhow!("failed").into())
+        })
+        .is_err());
+    let keys = store.extension_txn(|txn| {
+        txn.query("SELECT key FROM app_index", NO_PARAMS, |row| {
+            row.get::<_, String>(0)
+        })
+    })?;
+    assert_eq!(keys, vec!["a".to_string()]);
+    let cid = store.extension_txn(|txn| {
+        txn.query_row("SELECT cid FROM app_index WHERE key = ?", &[&"a"], |row| {
+            row.get::<_, Vec<u8>>(0)
+        })
+    })?;
+    assert_eq!(cid, Some(a.to_bytes()));
+    // tables that are not prefixed are rejected, and the migration is rolled back
+    assert!(store
+        .migrate_extension("other", &["CREATE TABLE blocks2 (id INTEGER);"])
+        .is_err());
+    assert_eq!(store.migrate_extension("other", &[])?, 0);
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default)]
+struct RecordingCommitHook(Arc<Mutex<Vec<Commit>>>);
+
+impl CommitHook for RecordingCommitHook {
+    fn after_commit(&mut self, commit: &Commit) {
+        self.0.lock().unwrap().push(commit.clone());
+    }
+}
+
+#[test]
+fn commit_hook() -> anyhow::Result<()> {
+    let hook = RecordingCommitHook::default();
+    let mut store = BlockStore::memory(Config::default().with_commit_hook(hook.clone()))?;
+    let a = cid("a");
+    let b = cid("b");
+    assert_eq!(store.commit_seq()?, 0);
+    store.put_block(&a, b"abcd", vec![], None)?;
+    store.put_block(&b, b"fubar", vec![], None)?;
+    store.alias(b"alias1", Some(&a))?;
+    store.gc()?;
+    assert_eq!(store.commit_seq()?, 4);
+    let commits = hook.0.lock().unwrap().clone();
+    assert_eq!(
+        commits.iter().map(|c| c.seq).collect::<Vec<_>>(),
+        vec![1, 2, 3, 4]
+    );
+    assert_eq!(commits[0].added, vec![a]);
+    assert_eq!(commits[2].aliased, vec![a]);
+    assert_eq!(commits[3].removed, vec![b]);
+    // writing a block we already have is not a change
+    store.put_block(&a, b"abcd", vec![], None)?;
+    assert_eq!(store.commit_seq()?, 4);
+    Ok(())
+}
+
+#[test]
+fn subscribe() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let mut events = store.subscribe();
+    let a = cid("a");
+    let b = cid("b");
+    store.put_block(&b, b"b", vec![], None)?;
+    store.put_block(&a, b"a", vec![b], None)?;
+    // a duplicate is not reported
+    store.put_block(&a, b"a", vec![b], None)?;
+    store.alias(b"root", Some(&a))?;
+    store.alias(b"root", None)?;
+    store.gc()?;
+    let mut received = Vec::new();
+    while let Ok(Some(event)) = events.try_next() {
+        received.push(event);
+    }
+    assert_eq!(received.len(), 6);
+    assert_eq!(
+        received[..4],
+        [
+            StoreEvent::BlockAdded(b),
+            StoreEvent::BlockAdded(a),
+            StoreEvent::AliasChanged {
+                name: b"root".to_vec(),
+                cid: Some(a),
+            },
+            StoreEvent::AliasChanged {
+                name: b"root".to_vec(),
+                cid: None,
+            },
+        ]
+    );
+    let evicted = received[4..].iter().cloned().collect::<FnvHashSet<_>>();
+    assert!(evicted.contains(&StoreEvent::BlockEvicted(a)));
+    assert!(evicted.contains(&StoreEvent::BlockEvicted(b)));
+    // a dropped receiver does not break writes, and its hook is removed
+    drop(events);
+    store.put_block(&a, b"a", vec![b], None)?;
+    assert!(store.config.commit_hooks.is_empty());
+    Ok(())
+}
+
+#[test]
+fn has_cache_external() -> anyhow::Result<()> {
+    let tmp = TempDir::new("has_cache_external")?;
+    let path = tmp.path().join("db");
+    let mut a = BlockStore::open(&path, Config::default())?;
+    let mut b = BlockStore::open(&path, Config::default())?;
+    let cid = cid("a");
+    a.put_block(&cid, b"a", vec![], None)?;
+    assert!(b.has_block(&cid)?);
+    a.delete_blocks(vec![cid])?;
+    // the cached answer is stale until the change is found
+    assert!(b.has_block(&cid)?);
+    assert!(b.has_changed()?);
+    assert!(!b.has_block(&cid)?);
+    Ok(())
+}
+
+#[test]
+fn subscribe_external() -> anyhow::Result<()> {
+    let tmp = TempDir::new("subscribe_external")?;
+    let path = tmp.path().join("db");
+    let mut writer = BlockStore::open(&path, Config::default())?;
+    let mut reader = BlockStore::open_read_only(&path, Config::default())?;
+    let mut events = reader.subscribe();
+    assert!(!reader.has_changed()?);
+    writer.put_block(&cid("a"), b"a", vec![], None)?;
+    assert!(reader.has_changed()?);
+    let seq = writer.commit_seq()?;
+    assert_eq!(events.try_next()?, Some(StoreEvent::ExternalChange(seq)));
+    assert!(events.try_next().is_err());
+    Ok(())
+}
+
+#[test]
+fn insert_channel() -> anyhow::Result<()> {
+    let (hook, mut receiver) = InsertChannel::new(0);
+    let recorder = RecordingCommitHook::default();
+    let mut store = BlockStore::memory(
+        Config::default()
+            .with_commit_hook(hook)
+            .with_commit_hook(recorder.clone()),
+    )?;
+    let a = cid("a");
+    let b = cid("b");
+    let c = cid("c");
+    store.put_blocks(
+        vec![
+            OwnedBlock::new(a, b"a".to_vec(), vec![]),
+            OwnedBlock::new(b, b"b".to_vec(), vec![]),
+        ],
+        None,
+    )?;
+    // a duplicate is not sent, and neither is an empty batch
+    store.put_block(&a, b"a", vec![], None)?;
+    // the channel is full, so this batch is dropped
+    store.put_block(&c, b"c", vec![], None)?;
+    assert_eq!(
+        receiver.try_next()?,
+        Some(Inserted {
+            seq: 1,
+            cids: vec![a, b],
+            dropped: 0,
+        })
+    );
+    assert!(receiver.try_next().is_err());
+    store.put_block(&cid("d"), b"d", vec![], None)?;
+    assert_eq!(
+        receiver.try_next()?.map(|inserted| inserted.dropped),
+        Some(1)
+    );
+    // all hooks are called
+    let commits = recorder.0.lock().unwrap().clone();
+    assert_eq!(commits.len(), 4);
+    assert_eq!(commits[1].added, vec![a]);
+    assert!(commits[1].inserted.is_empty());
+    assert_eq!(commits[1].seq, commits[0].seq);
+    Ok(())
+}
+
+#[test]
+fn clone_filtered() -> anyhow::Result<()> {
+    let tmp = TempDir::new("clone_filtered")?;
+    let mut store = BlockStore::memory(Config::default())?;
+    let a = cid("a");
+    let b = cid("b");
+    let c = cid("c");
+    store.put_block(&a, b"abcd", vec![b], None)?;
+    store.put_block(&b, b"fubar", vec![], None)?;
+    store.put_block(&c, b"unpinned", vec![], None)?;
+    store.alias(b"root", Some(&a))?;
+    // pinned only
+    let mut clone =
+        store.clone_filtered(tmp.path().join("db"), Config::default(), |_, _, pinned| {
+            pinned
+        })?;
+    assert_eq!(
+        clone.get_block_cids::<FnvHashSet<_>>()?,
+        vec![a, b].into_iter().collect()
+    );
+    assert_eq!(clone.get_descendants::<Vec<_>>(&a)?, vec![a, b]);
+    assert_eq!(clone.reverse_alias(&a)?.aliases, vec![b"root".to_vec()]);
+    Ok(())
+}
+
+#[test]
+fn read_only_switch() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let a = cid("a");
+    let b = cid("b");
+    store.put_block(&a, b"a", vec![], None)?;
+    store.set_read_only(true)?;
+    assert!(store.is_read_only());
+    assert!(matches!(
+        store.put_block(&b, b"b", vec![], None),
+        Err(BlockStoreError::ReadOnly)
+    ));
+    assert!(matches!(
+        store.alias(b"a", Some(&a)),
+        Err(BlockStoreError::ReadOnly)
+    ));
+    assert!(matches!(store.gc(), Err(BlockStoreError::ReadOnly)));
+    // reads still work
+    assert!(store.has_block(&a)?);
+    assert!(!store.has_block(&b)?);
+    store.set_read_only(false)?;
+    store.put_block(&b, b"b", vec![], None)?;
+    assert!(store.has_block(&b)?);
+    Ok(())
+}
+
+#[test]
+fn pause_gc() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let a = cid("a");
+    store.put_block(&a, b"abcd", vec![], None)?;
+    store.pause_gc();
+    store.pause_gc();
+    assert!(matches!(store.gc(), Err(BlockStoreError::GcPaused)));
+    store.resume_gc();
+    assert!(matches!(store.gc(), Err(BlockStoreError::GcPaused)));
+    assert!(store.has_block(&a)?);
+    store.resume_gc();
+    store.gc()?;
+    assert!(!store.has_block(&a)?);
+    Ok(())
+}
+
+#[test]
+fn have_status() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let a = cid("a");
+    let b = cid("b");
+    let c = cid("c");
+    store.put_block(&a, b"abcd", vec![b, c], None)?;
+    store.put_block(&c, b"fubar", vec![], None)?;
+    let status = store
+        .have_status(&a)?
+        .into_iter()
+        .collect::<FnvHashSet<_>>();
+    assert_eq!(
+        status,
+        vec![(a, true), (b, false), (c, true)].into_iter().collect()
+    );
+    assert_eq!(store.have_status(&cid("d"))?, vec![(cid("d"), false)]);
+    Ok(())
+}
+
+#[test]
+fn normalize_cids() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default().with_normalize_cids(true))?;
+    let hash = Code::Sha2_256.digest(b"abcd");
+    let v0 = Cid::new_v0(hash)?;
+    let v1 = Cid::new_v1(0x70, hash);
+    store.put_block(&v0, b"abcd", vec![], None)?;
+    // both representations refer to the same block
+    assert_eq!(store.get_block(&v1)?, Some(b"abcd".to_vec()));
+    assert!(store.has_block(&v0)?);
+    assert_eq!(store.get_block_cids::<Vec<_>>()?, vec![v1]);
+    // the original form is recorded
+    assert_eq!(store.get_original_cid(&v1)?, v0);
+    Ok(())
+}
+
+#[test]
+fn eviction_log() -> anyhow::Result<()> {
+    let mut store =
+        BlockStore::memory(Config::default().with_eviction_log(Duration::from_secs(3600)))?;
+    let a = cid("a");
+    let b = cid("b");
+    store.put_block(&a, b"abcd", vec![], None)?;
+    store.put_block(&b, b"fubar", vec![], None)?;
+    store.alias(b"alias1", Some(&b))?;
+    store.gc()?;
+    let evictions = store.get_evictions()?;
+    assert_eq!(
+        evictions.iter().map(|(cid, _)| *cid).collect::<Vec<_>>(),
+        vec![a]
+    );
+    Ok(())
+}
+
+#[test]
+fn manifest() -> anyhow::Result<()> {
+    let a = cid("a");
+    let b = cid("b");
+    let c = cid("c");
+    let d = cid("d");
+    let mut store1 = BlockStore::memory(Config::default())?;
+    store1.put_block(&a, b"a", vec![b, c], None)?;
+    store1.put_block(&b, b"bb", vec![c], None)?;
+    store1.put_block(&c, b"ccc", vec![], None)?;
+    // same dag, added in a different order
+    let mut store2 = BlockStore::memory(Config::default())?;
+    store2.put_block(&c, b"ccc", vec![], None)?;
+    store2.put_block(&b, b"bb", vec![c], None)?;
+    store2.put_block(&a, b"a", vec![b, c], None)?;
+    let manifest = store1.manifest(&a)?;
+    assert_eq!(manifest, store2.manifest(&a)?);
+    // c is at depth 1, even though it can also be reached via b
+    let mut depth1 = vec![(b, Some(2)), (c, Some(3))];
+    depth1.sort_by_key(|(cid, _)| cid.to_bytes());
+    let mut expected = vec![ManifestEntry {
+        cid: a,
+        size: Some(1),
+        depth: 0,
+    }];
+    expected.extend(depth1.into_iter().map(|(cid, size)| ManifestEntry {
+        cid,
+        size,
+        depth: 1,
+    }));
+    assert_eq!(manifest.entries, expected);
+    // a missing block changes the digest
+    let mut store3 = BlockStore::memory(Config::default())?;
+    store3.put_block(&a, b"a", vec![b, c], None)?;
+    store3.put_block(&b, b"bb", vec![c], None)?;
+    assert_ne!(store3.manifest(&a)?.digest, manifest.digest);
+    assert_eq!(store3.manifest(&d)?.entries.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn protected_cids() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let a = cid("a");
+    let b = cid("b");
+    store.put_block(&a, b"a", vec![b], None)?;
+    store.put_block(&b, b"b", vec![], None)?;
+    store.protect(vec![a])?;
+    assert_eq!(store.protected_cids()?, vec![a]);
+    assert!(store.is_pinned(&a)?);
+    store.gc()?;
+    // only the protected block itself is kept, but its link is not lost
+    assert!(store.has_block(&a)?);
+    assert!(!store.has_block(&b)?);
+    assert_eq!(store.get_missing_blocks::<Vec<_>>(&a)?, vec![b]);
+    store.unprotect(vec![a])?;
+    assert!(store.protected_cids()?.is_empty());
+    store.gc()?;
+    assert!(!store.has_block(&a)?);
+    Ok(())
+}
+
+#[test]
+fn system_aliases() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(
+        Config::default()
+            .with_system_alias_prefix("sys/")
+            .with_system_alias_prefix("identity"),
+    )?;
+    let a = cid("a");
+    let b = cid("b");
+    let c = cid("c");
+    store.put_block(&a, b"a", vec![b], None)?;
+    store.put_block(&b, b"bb", vec![], None)?;
+    store.put_block(&c, b"ccc", vec![], None)?;
+    store.alias(b"sys/root", Some(&a))?;
+    store.alias_direct(b"identity", Some(&c))?;
+    store.alias(b"user/root", Some(&c))?;
+    assert!(store.is_system_alias(b"sys/root"));
+    assert!(!store.is_system_alias(b"user/root"));
+    let stats = store.alias_stats()?;
+    assert_eq!(stats.aliases, 3);
+    assert_eq!(stats.system_aliases, 2);
+    assert_eq!(stats.system_pinned.count(), 3);
+    assert_eq!(stats.system_pinned.size(), 6);
+    // without system prefixes, nothing is a system alias
+    let mut store = BlockStore::memory(Config::default())?;
+    store.alias(b"sys/root", Some(&a))?;
+    assert_eq!(
+        store.alias_stats()?,
+        AliasStats {
+            aliases: 1,
+            ..AliasStats::default()
+        }
+    );
+    Ok(())
+}
+
+#[test]
+fn alias_history() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default().with_alias_history(2))?;
+    let a = cid("a");
+    let b = cid("b");
+    store.alias(b"alias1", Some(&a))?;
+    // setting an alias to its current value is not a change
+    store.alias(b"alias1", Some(&a))?;
+    store.alias(b"alias1", Some(&b))?;
+    store.alias(b"alias1", None)?;
+    let history = store
+        .get_alias_history()?
+        .into_iter()
+        .map(|change| (change.name, change.old, change.new))
+        .collect::<Vec<_>>();
+    // the oldest change was pruned
+    assert_eq!(
+        history,
+        vec![
+            (b"alias1".to_vec(), Some(a), Some(b)),
+            (b"alias1".to_vec(), Some(b), None),
+        ]
+    );
+    // no history by default
+    let mut store = BlockStore::memory(Config::default())?;
+    store.alias(b"alias1", Some(&a))?;
+    assert!(store.get_alias_history()?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn temp_pin_limits() -> anyhow::Result<()> {
+    let mut store =
+        BlockStore::memory(Config::default().with_temp_pin_limits(TempPinLimits::new(1, 2)))?;
+    let pin1 = store.temp_pin();
+    store.put_block(&unpinned(0), b"abcd", vec![], Some(&pin1))?;
+    store.put_block(&unpinned(1), b"abcd", vec![], Some(&pin1))?;
+    assert!(matches!(
+        store.put_block(&unpinned(2), b"abcd", vec![], Some(&pin1)),
+        Err(BlockStoreError::TempPinTooLarge(3))
+    ));
+    // the failed write was rolled back
+    assert!(!store.has_block(&unpinned(2))?);
+    let pin2 = store.temp_pin();
+    assert!(matches!(
+        store.put_block(&unpinned(2), b"abcd", vec![], Some(&pin2)),
+        Err(BlockStoreError::TooManyTempPins(2))
+    ));
+    assert_eq!(
+        store.temp_pin_stats()?,
+        TempPinStats {
+            pins: 1,
+            blocks: 2,
+            max_blocks_per_pin: 2,
+        }
+    );
+    // dropped temp pins do not count, even before gc has deleted them
+    drop(pin1);
+    store.put_block(&unpinned(2), b"abcd", vec![], Some(&pin2))?;
+    Ok(())
+}
+
+#[test]
+fn has_cache() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default().with_has_cache_size(1))?;
+    let a = cid("a");
+    let b = cid("b");
+    // negative answers are invalidated when the block is added
+    assert!(!store.has_block(&a)?);
+    store.put_block(&a, b"abcd", vec![], None)?;
+    assert!(store.has_block(&a)?);
+    // positive answers are invalidated when the block is collected
+    store.gc()?;
+    assert!(!store.has_block(&a)?);
+    // the cache is bounded
+    assert!(!store.has_block(&b)?);
+    store.put_block(&a, b"abcd", vec![], None)?;
+    assert!(store.has_block(&a)?);
+    Ok(())
+}
+
+#[test]
+fn approx_stats() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    assert_eq!(store.approx_stats()?.max_blocks, 0);
+    for i in 0..10 {
+        store.put_block(&pinned(i), &[0u8; 100], vec![], None)?;
+    }
+    let ApproxStats {
+        max_cids,
+        max_blocks,
+        avg_block_size,
+        db_size,
+    } = store.approx_stats()?;
+    assert_eq!(max_cids, 10);
+    assert_eq!(max_blocks, 10);
+    assert_eq!(avg_block_size, 100);
+    assert!(db_size > 1000);
+    Ok(())
+}
+
+#[test]
+fn import_car() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let a = Cid::new_v1(0x55, Code::Sha2_256.digest(b"abcd"));
+    let b = Cid::new_v1(0x55, Code::Sha2_256.digest(b"fubar"));
+    let mut file = Vec::new();
+    crate::car::write_header(&mut file, &[a])?;
+    crate::car::write_block(&mut file, &a, b"abcd")?;
+    crate::car::write_block(&mut file, &b, b"fubar")?;
+    let pin = store.temp_pin();
+    let roots = store.import_car(file.as_slice(), Some(&pin))?;
+    assert_eq!(roots, vec![a]);
+    store.gc()?;
+    assert_eq!(store.get_block(&a)?, Some(b"abcd".to_vec()));
+    assert_eq!(store.get_block(&b)?, Some(b"fubar".to_vec()));
+    // a truncated file is an error
+    let res = store.import_car(&file[..file.len() - 1], None);
+    assert!(matches!(res, Err(BlockStoreError::IoError(_))));
+    Ok(())
+}
+
+#[test]
+fn gc_bytes() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    store.put_block(&unpinned(0), &[0u8; 10], vec![], None)?;
+    store.put_block(&unpinned(1), &[0u8; 1000], vec![], None)?;
+    store.put_block(&unpinned(2), &[0u8; 100], vec![], None)?;
+    store.put_block(&pinned(0), &[0u8; 10000], vec![], None)?;
+    store.alias(b"pin", Some(&pinned(0)))?;
+    // the largest unpinned block is collected first
+    assert_eq!(store.gc_bytes(500)?, 1000);
+    assert!(!store.has_block(&unpinned(1))?);
+    assert!(store.has_block(&unpinned(2))?);
+    // pinned blocks are never collected
+    assert_eq!(store.gc_bytes(100000)?, 110);
+    assert!(store.has_block(&pinned(0))?);
+    assert_eq!(store.get_store_stats()?.size(), 10000);
+    Ok(())
+}
+
+#[test]
+fn delete_blocks() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let a = cid("a");
+    let b = cid("b");
+    let c = cid("c");
+    let d = cid("d");
+    store.put_block(&c, b"c", vec![], None)?;
+    store.put_block(&b, b"b", vec![c], None)?;
+    store.put_block(&a, b"a", vec![b], None)?;
+    store.put_block(&d, b"d", vec![], None)?;
+    store.alias(b"d", Some(&d))?;
+    // pinned blocks can not be deleted, and nothing is deleted then
+    let res = store.delete_blocks(vec![a, d]);
+    assert!(matches!(res, Err(BlockStoreError::Pinned(cid)) if cid == d));
+    assert!(store.has_block(&a)?);
+    // the data is gone right away, and the link from a to b is kept
+    assert_eq!(store.delete_blocks(vec![b, cid("unknown")])?, 1);
+    assert!(!store.has_block(&b)?);
+    assert_eq!(store.get_store_stats()?.count(), 3);
+    assert_eq!(store.orphan_count()?, 0);
+    assert_eq!(store.get_missing_blocks::<Vec<_>>(&a)?, vec![b]);
+    // c is no longer linked from b
+    assert!(store.get_parents(&c)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn trash() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default().with_trash(Duration::from_secs(3600)))?;
+    let a = cid("a");
+    let b = cid("b");
+    let c = cid("c");
+    let d = cid("d");
+    store.put_block(&c, b"c", vec![], None)?;
+    store.put_block(&b, b"b", vec![c], None)?;
+    store.put_block(&a, b"a", vec![b], None)?;
+    store.put_block(&d, b"d", vec![], None)?;
+    store.alias(b"a", Some(&a))?;
+    // an accidental unalias
+    store.alias(b"a", None)?;
+    store.gc()?;
+    store.delete_blocks(vec![d])?;
+    assert_eq!(store.get_store_stats()?.count(), 0);
+    let mut trash = store
+        .get_trash()?
+        .into_iter()
+        .map(|(cid, _)| cid)
+        .collect::<Vec<_>>();
+    trash.sort();
+    let mut expected = vec![a, b, c, d];
+    expected.sort();
+    assert_eq!(trash, expected);
+    // restore the dag including its links
+    let pin = store.temp_pin();
+    assert_eq!(store.undelete(&a, Some(&pin))?, 3);
+    assert_eq!(store.get_block(&b)?, Some(b"b".to_vec()));
+    assert!(store.get_missing_blocks::<Vec<_>>(&a)?.is_empty());
+    assert_eq!(store.get_trash()?.len(), 1);
+    store.alias(b"a", Some(&a))?;
+    drop(pin);
+    store.gc()?;
+    assert_eq!(store.get_store_stats()?.count(), 3);
+    // opening the store without the trash keeps it
+    let tmp = TempDir::new("trash")?;
+    let path = tmp.path().join("db.sqlite");
+    let config = || Config::default().with_trash(Duration::from_secs(3600));
+    let mut store = BlockStore::open(&path, config())?;
+    store.put_block(&a, b"a", vec![], None)?;
+    store.gc()?;
+    drop(store);
+    // without the trash, deleted blocks do not go to the trash
+    let mut store = BlockStore::open(&path, Config::default())?;
+    let b = cid("b");
+    store.put_block(&b, b"b", vec![], None)?;
+    store.gc()?;
+    assert!(!store.has_block(&b)?);
+    let trashed: i64 = store
+        .conn
+        .query_row("SELECT COUNT(*) FROM trash", params![], |row| row.get(0))?;
+    assert_eq!(trashed, 1);
+    drop(store);
+    let mut store = BlockStore::open(&path, config())?;
+    assert_eq!(store.undelete(&b, None)?, 0);
+    assert_eq!(store.undelete(&a, None)?, 1);
+    assert!(store.get_trash()?.is_empty());
+    let mut store = BlockStore::memory(Config::default().with_trash(Duration::default()))?;
+    store.put_block(&a, b"a", vec![], None)?;
+    store.gc()?;
+    // blocks are deleted from the trash once the retention time has passed
+    std::thread::sleep(Duration::from_millis(2));
+    store.put_block(&b, b"b", vec![], None)?;
+    store.gc()?;
+    assert_eq!(
+        store
+            .get_trash()?
+            .into_iter()
+            .map(|(cid, _)| cid)
+            .collect::<Vec<_>>(),
+        vec![b]
+    );
+    Ok(())
+}
+
+#[test]
+fn delete_orphaned_cids() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let a = cid("a");
+    let b = cid("b");
+    let c = cid("c");
+    let d = cid("d");
+    store.put_block(&a, b"a", vec![b], None)?;
+    store.alias(b"a", Some(&a))?;
+    // aliasing a missing block creates a cid, which stays after the alias is removed
+    store.alias(b"c", Some(&c))?;
+    store.alias(b"c", None)?;
+    store.add_provider_hints(&d, vec![b"peer".to_vec()])?;
+    assert!(store.has_cid(&c)?);
+    assert_eq!(store.delete_orphaned_cids()?, 1);
+    assert!(!store.has_cid(&c)?);
+    // linked, aliased and hinted cids are kept
+    assert!(store.has_cid(&a)? && store.has_cid(&b)? && store.has_cid(&d)?);
+    assert_eq!(store.delete_orphaned_cids()?, 0);
+    Ok(())
+}
+
+#[test]
+fn delete_orphaned() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    for i in 0..3 {
+        store.put_block(&unpinned(i), &[0u8; 10], vec![], None)?;
+    }
+    assert_eq!(store.orphan_count()?, 0);
+    assert!(store
+        .incremental_gc(10, Duration::from_secs(1))?
+        .is_complete());
+    assert_eq!(store.orphan_count()?, 3);
+    // with no time budget, only the minimum number of blocks is deleted
+    let stats = store.delete_orphaned(1, Duration::default())?;
+    assert_eq!((stats.deleted, stats.remaining), (1, 2));
+    assert!(!stats.is_complete());
+    assert_eq!(store.orphan_count()?, 2);
+    let stats = store.delete_orphaned(0, Duration::from_secs(1))?;
+    assert_eq!((stats.deleted, stats.remaining), (2, 0));
+    assert_eq!(store.orphan_count()?, 0);
+    Ok(())
+}
+
+#[test]
+fn pragmas() -> anyhow::Result<()> {
+    let tmp = TempDir::new("pragmas")?;
+    let path = tmp.path().join("db");
+    let pragmas = Pragmas {
+        synchronous: Synchronous::Normal,
+        page_size: 8192,
+        cache_size: Some(-4096),
+        mmap_size: Some(1 << 20),
+        wal_autocheckpoint: Some(100),
+    };
+    let config = || {
+        Config::default()
+            .with_pragmas(pragmas)
+            .with_pragma("temp_store", "memory")
+    };
+    let pragma = |store: &BlockStore, name: &str| -> anyhow::Result<i64> {
+        Ok(store
+            .conn
+            .pragma_query_value(None, name, |row| row.get(0))?)
+    };
+    let mut store = BlockStore::open(&path, config())?;
+    store.put_block(&unpinned(0), b"a", vec![], None)?;
+    assert_eq!(pragma(&store, "synchronous")?, 1);
+    assert_eq!(pragma(&store, "page_size")?, 8192);
+    assert_eq!(pragma(&store, "cache_size")?, -4096);
+    assert_eq!(pragma(&store, "mmap_size")?, 1 << 20);
+    assert_eq!(pragma(&store, "wal_autocheckpoint")?, 100);
+    assert_eq!(pragma(&store, "temp_store")?, 2);
+    assert_eq!(pragma(&store, "foreign_keys")?, 1);
+    drop(store);
+    // the page size of an existing database is kept
+    let store = BlockStore::open(&path, Config::default())?;
+    assert_eq!(pragma(&store, "page_size")?, 8192);
+    assert_eq!(pragma(&store, "synchronous")?, 2);
+    let store = BlockStore::open_read_only(&path, config())?;
+    assert_eq!(pragma(&store, "cache_size")?, -4096);
+    Ok(())
+}
+
+#[test]
+fn block_reader() -> anyhow::Result<()> {
+    let tmp = TempDir::new("block_reader")?;
+    let path = tmp.path().join("db");
+    let recorder = RecordingCacheTracker::default();
+    let config = Config::default()
+        .with_read_connections(1)
+        .with_cache_tracker(recorder.clone());
+    let mut store = BlockStore::open(&path, config)?;
+    let reader = store.reader().unwrap();
+    let a = cid("a");
+    assert_eq!(reader.get_block(&a)?, None);
+    store.put_block(&a, b"a", vec![], None)?;
+    assert_eq!(reader.get_block(&a)?, Some(b"a".to_vec()));
+    // reads do not wait for a write transaction of the store
+    let reader2 = reader.clone();
+    store.extension_txn(move |_| {
+        let read = std::thread::spawn(move || reader2.has_block(&a));
+        assert!(read.join().unwrap()?);
+        Ok(())
+    })?;
+    // the accesses are passed to the cache tracker before gc
+    let accessed = recorder.0.lock().unwrap().len();
+    store.gc()?;
+    assert_eq!(recorder.0.lock().unwrap().len(), accessed + 1);
+    // memory stores have no reader
+    let store = BlockStore::memory(Config::default().with_read_connections(1))?;
+    assert!(store.reader().is_none());
+    Ok(())
+}
+
+#[test]
+fn multi_process() -> anyhow::Result<()> {
+    let tmp = TempDir::new("multi_process")?;
+    let path = tmp.path().join("db");
+    let config = || Config::default().with_multi_process(Duration::from_secs(60));
+    // concurrent opens of a new database
+    let openers = (0..4)
+        .map(|_| {
+            let path = path.clone();
+            std::thread::spawn(move || BlockStore::open(path, config()).map(drop))
+        })
+        .collect::<Vec<_>>();
+    for opener in openers {
+        opener.join().unwrap()?;
+    }
+    let mut a = BlockStore::open(&path, config())?;
+    let pin = a.temp_pin();
+    a.put_block(&unpinned(0), b"a", vec![], Some(&pin))?;
+    // opening the database again does not delete the temp pins of a live store
+    let mut b = BlockStore::open(&path, config())?;
+    assert_ne!(
+        a.process_guard().unwrap().id(),
+        b.process_guard().unwrap().id()
+    );
+    b.gc()?;
+    assert!(b.has_block(&unpinned(0))?);
+    drop(b);
+    // once a store does not show signs of life, its temp pins are deleted
+    a.conn.execute_batch("UPDATE owners SET heartbeat = 0")?;
+    let mut c = BlockStore::open(&path, config())?;
+    c.gc()?;
+    assert!(!c.has_block(&unpinned(0))?);
+    // the dead store finds out on its next heartbeat, and the id is not reused
+    assert!(matches!(
+        a.heartbeat(),
+        Err(BlockStoreError::OwnerExpired(id)) if id == a.process_guard().unwrap().id()
+    ));
+    assert!(matches!(a.gc(), Err(BlockStoreError::OwnerExpired(_))));
+    drop(a);
+    let mut d = BlockStore::open(&path, config())?;
+    assert!(d.process_guard().unwrap().id() > c.process_guard().unwrap().id());
+    let pin = d.temp_pin();
+    d.put_block(&unpinned(1), b"b", vec![], Some(&pin))?;
+    drop(c);
+    let mut c = BlockStore::open(&path, config())?;
+    c.gc()?;
+    assert!(c.has_block(&unpinned(1))?);
+    // a dropped store releases its temp pins right away
+    drop(d);
+    c.gc()?;
+    assert!(!c.has_block(&unpinned(1))?);
+    // a store without the option can not open the database while another store uses it
+    let pin = c.temp_pin();
+    c.put_block(&unpinned(2), b"c", vec![], Some(&pin))?;
+    assert!(matches!(
+        BlockStore::open(&path, Config::default()),
+        Err(BlockStoreError::InUse(1))
+    ));
+    assert!(c.has_block(&unpinned(2))?);
+    drop(c);
+    BlockStore::open(&path, Config::default())?;
+    Ok(())
+}
+
+#[test]
+fn busy_retry() -> anyhow::Result<()> {
+    let tmp = TempDir::new("busy_retry")?;
+    let path = tmp.path().join("db");
+    // hold the write lock from another connection for a while
+    let lock = |duration: Duration| -> anyhow::Result<std::thread::JoinHandle<()>> {
+        let conn = Connection::open(&path)?;
+        conn.execute_batch("BEGIN IMMEDIATE")?;
+        Ok(std::thread::spawn(move || {
+            std::thread::sleep(duration);
+            conn.execute_batch("COMMIT").unwrap();
+        }))
+    };
+    let config = Config::default().with_busy_timeout(Duration::default());
+    let mut store = BlockStore::open(&path, config.with_busy_retry(BusyRetry::none()))?;
+    let locked = lock(Duration::from_millis(100))?;
+    assert!(matches!(
+        store.put_block(&unpinned(0), b"a", vec![], None),
+        Err(BlockStoreError::SqliteError(_))
+    ));
+    locked.join().unwrap();
+    drop(store);
+    let retry = BusyRetry::new(10, Duration::from_millis(10), Duration::from_millis(50));
+    let config = Config::default().with_busy_timeout(Duration::default());
+    let mut store = BlockStore::open(&path, config.with_busy_retry(retry))?;
+    let locked = lock(Duration::from_millis(100))?;
+    store.put_block(&unpinned(0), b"a", vec![], None)?;
+    assert!(store.has_block(&unpinned(0))?);
+    locked.join().unwrap();
+    Ok(())
+}
+
+#[test]
+fn checkpoint() -> anyhow::Result<()> {
+    let tmp = TempDir::new("checkpoint")?;
+    let path = tmp.path().join("db");
+    let wal = tmp.path().join("db-wal");
+    let mut store = BlockStore::open(&path, Config::default())?;
+    for i in 0..100 {
+        store.put_block(&unpinned(i), &[0u8; 1000], vec![], None)?;
+    }
+    let stats = store.checkpoint(CheckpointMode::Passive)?;
+    assert!(!stats.busy);
+    assert!(stats.log_pages > 0);
+    assert_eq!(stats.checkpointed_pages, stats.log_pages);
+    // a passive checkpoint keeps the wal file around
+    assert!(std::fs::metadata(&wal)?.len() > 0);
+    store.flush()?;
+    assert_eq!(std::fs::metadata(&wal)?.len(), 0);
+    assert_eq!(store.get_block(&unpinned(0))?, Some(vec![0u8; 1000]));
+    // not in wal mode, a checkpoint does nothing
+    let mut store = BlockStore::memory(Config::default())?;
+    assert_eq!(
+        store.checkpoint(CheckpointMode::Truncate)?,
+        CheckpointStats::default()
+    );
+    Ok(())
+}
+
+#[test]
+fn idle_maintenance() -> anyhow::Result<()> {
+    let tmp = TempDir::new("idle_maintenance")?;
+    let mut store = BlockStore::open(tmp.path().join("db"), Config::default())?;
+    for i in 0..3 {
+        store.put_block(&unpinned(i), &[0u8; 10], vec![], None)?;
+    }
+    store.incremental_gc(10, Duration::from_secs(1))?;
+    assert_eq!(store.orphan_count()?, 3);
+    // with no budget, nothing is done
+    let stats = store.idle_maintenance(Duration::default())?;
+    assert_eq!((stats.orphans_deleted, stats.cids_deleted), (0, 0));
+    assert!(!stats.checkpointed && !stats.complete);
+    let stats = store.idle_maintenance(Duration::from_secs(10))?;
+    assert_eq!((stats.orphans_deleted, stats.orphans_remaining), (3, 0));
+    assert!(stats.checkpointed && stats.analyzed && stats.complete);
+    assert_eq!(store.orphan_count()?, 0);
+    // while gc is paused, orphans are kept
+    store.put_block(&unpinned(3), &[0u8; 10], vec![], None)?;
+    store.incremental_gc(10, Duration::from_secs(1))?;
+    store.pause_gc();
+    let stats = store.idle_maintenance(Duration::from_secs(10))?;
+    assert_eq!(stats.orphans_deleted, 0);
+    assert!(stats.checkpointed && !stats.complete);
+    assert_eq!(store.orphan_count()?, 1);
+    Ok(())
+}
+
+#[test]
+fn export_car() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let a = Cid::new_v1(0x55, Code::Sha2_256.digest(b"abcd"));
+    let b = Cid::new_v1(0x55, Code::Sha2_256.digest(b"fubar"));
+    let c = Cid::new_v1(0x55, Code::Sha2_256.digest(b"unrelated"));
+    store.put_block(&b, b"fubar", vec![], None)?;
+    store.put_block(&a, b"abcd", vec![b], None)?;
+    store.put_block(&c, b"unrelated", vec![], None)?;
+    let mut file = Vec::new();
+    assert_eq!(store.export_car(&a, &mut file)?, 2);
+    // round trip into a fresh store
+    let mut copy = BlockStore::memory(Config::default())?;
+    assert_eq!(copy.import_car(file.as_slice(), None)?, vec![a]);
+    assert_eq!(copy.get_block(&a)?, Some(b"abcd".to_vec()));
+    assert_eq!(copy.get_block(&b)?, Some(b"fubar".to_vec()));
+    assert!(!copy.has_block(&c)?);
+    Ok(())
+}
+
+#[test]
+fn export_car_lenient() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default().with_checksums(true))?;
+    let a = Cid::new_v1(0x55, Code::Sha2_256.digest(b"abcd"));
+    let b = Cid::new_v1(0x55, Code::Sha2_256.digest(b"fubar"));
+    let c = Cid::new_v1(0x55, Code::Sha2_256.digest(b"baz"));
+    store.put_block(&b, b"fubar", vec![], None)?;
+    store.put_block(&c, b"baz", vec![], None)?;
+    store.put_block(&a, b"abcd", vec![b, c], None)?;
+    // simulate corruption of two rows
+    store.conn.execute(
+        "UPDATE blocks SET block = 1 WHERE block = ?",
+        params![b"fubar".to_vec()],
+    )?;
+    store.conn.execute(
+        "UPDATE blocks SET block = ? WHERE block = ?",
+        params![b"bad".to_vec(), b"baz".to_vec()],
+    )?;
+    assert!(store.export_car(&a, Vec::new()).is_err());
+    let mut file = Vec::new();
+    let report = store.export_car_lenient(&a, &mut file)?;
+    assert_eq!(report.blocks, 1);
+    let mut errors = report.errors;
+    errors.sort_by_key(|(cid, _)| *cid);
+    let mut expected = vec![b, c];
+    expected.sort();
+    assert_eq!(
+        errors
+            .iter()
+            .map(|(cid, _)| cid.unwrap())
+            .collect::<Vec<_>>(),
+        expected
+    );
+    let mut copy = BlockStore::memory(Config::default())?;
+    copy.import_car(file.as_slice(), None)?;
+    assert_eq!(copy.get_block(&a)?, Some(b"abcd".to_vec()));
+    assert!(!copy.has_block(&b)?);
+    // iteration continues past corrupt rows
+    let blocks = store.iter_blocks().collect::<Vec<_>>();
+    assert_eq!(blocks.len(), 3);
+    assert_eq!(blocks.iter().filter(|block| block.is_err()).count(), 2);
+    Ok(())
+}
+
+#[test]
+fn routed_store() -> anyhow::Result<()> {
+    let stores = vec![
+        BlockStore::memory(Config::default())?,
+        BlockStore::memory(Config::default())?,
+    ];
+    let mut store = RoutedStore::new(stores, |cid: &Cid| cid.hash().digest()[0] as usize);
+    let pin = store.temp_pin();
+    let cids = (0..10).map(unpinned).collect::<Vec<_>>();
+    store.put_blocks(
+        cids.iter()
+            .map(|cid| OwnedBlock::new(*cid, b"abcd".to_vec(), vec![])),
+        Some(&pin),
+    )?;
+    for cid in &cids {
+        assert_eq!(store.get_block(cid)?, Some(b"abcd".to_vec()));
+    }
+    assert_eq!(store.get_store_stats()?.count(), 10);
+    // the blocks are distributed over both stores
+    for inner in store.stores() {
+        let count = inner.get_store_stats()?.count();
+        assert!(count > 0 && count < 10);
+    }
+    drop(pin);
+    store.alias(b"pin", Some(&cids[0]))?;
+    store.gc()?;
+    assert_eq!(store.get_block_cids::<Vec<_>>()?, vec![cids[0]]);
+    Ok(())
+}
+
+#[test]
+fn migrating_store() -> anyhow::Result<()> {
+    let mut old = BlockStore::memory(Config::default())?;
+    let cids = (0..10).map(unpinned).collect::<Vec<_>>();
+    for cid in &cids[..5] {
+        old.put_block(cid, b"abcd", vec![], None)?;
+    }
+    old.alias(b"pin", Some(&cids[0]))?;
+    let new = BlockStore::memory(Config::default().with_checksums(true))?;
+    let mut store = MigratingStore::new(old, new)?;
+    // writes go to both stores while the copy is in progress
+    let pin = store.temp_pin();
+    store.put_blocks(
+        cids[5..]
+            .iter()
+            .map(|cid| OwnedBlock::new(*cid, b"abcd".to_vec(), vec![])),
+        Some(&pin),
+    )?;
+    store.alias(b"pin2", Some(&cids[5]))?;
+    // an empty step does not converge
+    assert!(!store.copy_step(0)?);
+    assert!(!store.copy_step(4)?);
+    assert!(!store.copy_step(4)?);
+    assert!(store.copy_step(4)?);
+    assert!(store.is_converged());
+    {
+        let (old, new) = store.stores();
+        assert_eq!(
+            old.get_block_cids::<FnvHashSet<_>>()?,
+            new.get_block_cids::<FnvHashSet<_>>()?
+        );
+        // gc of the new store is paused until the cutover
+        assert!(new.is_gc_paused());
+        // aliases set directly are synced on cutover
+        old.alias(b"pin2", None)?;
+    }
+    drop(pin);
+    let mut new = store.cutover()?;
+    assert_eq!(new.aliases()?, vec![(b"pin".to_vec(), cids[0])]);
+    new.gc()?;
+    assert_eq!(new.get_block_cids::<Vec<_>>()?, vec![cids[0]]);
+    Ok(())
+}
+
+#[test]
+fn put_blocks_single_txn() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let seq0 = store.commit_seq()?;
+    store.put_blocks(
+        (0..1000).map(|i| OwnedBlock::new(unpinned(i), data(&unpinned(i), 100), vec![])),
+        None,
+    )?;
+    // all blocks were added in a single commit
+    assert_eq!(store.commit_seq()?, seq0 + 1);
+    assert_eq!(store.get_store_stats()?.count(), 1000);
+    Ok(())
+}
+
+#[test]
+fn defer_maintenance() -> anyhow::Result<()> {
+    let tmp = TempDir::new("defer_maintenance")?;
+    let path = tmp.path().join("db");
+    let mut store = BlockStore::open(&path, Config::default())?;
+    let pin = store.temp_pin();
+    store.put_block(&unpinned(0), b"abcd", vec![], Some(&pin))?;
+    // simulate a crash, so the temp pin is never dropped
+    std::mem::forget(pin);
+    drop(store);
+    let mut store = BlockStore::open(&path, Config::default().with_defer_maintenance(true))?;
+    let pin = store.temp_pin();
+    store.put_block(&unpinned(1), b"abcd", vec![], Some(&pin))?;
+    // the stale temp pin still protects its block until maintenance
+    store.gc()?;
+    assert!(store.has_block(&unpinned(0))?);
+    store.maintenance()?;
+    store.gc()?;
+    assert!(!store.has_block(&unpinned(0))?);
+    // live temp pins are not affected by maintenance
+    assert!(store.has_block(&unpinned(1))?);
+    Ok(())
+}
+
+#[test]
+fn get_blocks() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    for i in 0..100 {
+        store.put_block(&pinned(i), &data(&pinned(i), 10), vec![], None)?;
+    }
+    // more than one batch, with missing and duplicate cids
+    let cids = (0..150)
+        .map(pinned)
+        .chain(vec![pinned(1), pinned(1000)])
+        .collect::<Vec<_>>();
+    let res = store.get_blocks(cids.clone())?.collect::<Vec<_>>();
+    assert_eq!(res.len(), cids.len());
+    for ((cid, data), expected) in res.into_iter().zip(cids) {
+        assert_eq!(cid, expected);
+        assert_eq!(data.is_some(), store.has_block(&cid)?);
+    }
+    Ok(())
+}
+
+#[test]
+fn why_pinned() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let a = cid("a");
+    let b = cid("b");
+    let c = cid("c");
+    let pin = store.temp_pin();
+    store.put_block(&c, b"c", vec![], None)?;
+    store.put_block(&b, b"b", vec![c], None)?;
+    store.put_block(&a, b"a", vec![b], Some(&pin))?;
+    store.alias(b"alias", Some(&b))?;
+    let reasons = store.why_pinned(&c)?;
+    assert_eq!(reasons.len(), 3);
+    assert!(reasons.contains(&PinReason::Alias {
+        name: b"alias".to_vec(),
+        root: b
+    }));
+    assert!(reasons
+        .iter()
+        .any(|r| matches!(r, PinReason::TempPin { root, .. } if *root == a)));
+    assert!(reasons.contains(&PinReason::Parent(b)));
+    drop(pin);
+    store.alias(b"alias", None)?;
+    store.gc()?;
+    // a block that is not pinned has no reasons
+    store.put_block(&c, b"c", vec![], None)?;
+    assert!(store.why_pinned(&c)?.is_empty());
+    Ok(())
+}
+
+/// a codec where the data is just a concatenation of binary cids
+#[derive(Debug)]
+struct CidListCodec;
+
+impl LinkExtractor for CidListCodec {
+    fn links(&self, mut data: &[u8]) -> anyhow::Result<Vec<Cid>> {
+        let mut links = Vec::new();
+        while !data.is_empty() {
+            let cid = Cid::read_bytes(&mut data)?;
+            links.push(cid);
+        }
+        Ok(links)
+    }
+}
+
+#[test]
+fn custom_codec() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default().with_codec(0x300001, CidListCodec))?;
+    let child = Cid::new_v1(0x55, Code::Sha2_256.digest(b"child"));
+    let root_data = child.to_bytes();
+    let root = Cid::new_v1(0x300001, Code::Sha2_256.digest(&root_data));
+    let mut file = Vec::new();
+    crate::car::write_header(&mut file, &[root])?;
+    crate::car::write_block(&mut file, &root, &root_data)?;
+    crate::car::write_block(&mut file, &child, b"child")?;
+    store.import_car(file.as_slice(), None)?;
+    store.alias(b"root", Some(&root))?;
+    store.gc()?;
+    // the child is reachable via the link extracted by the registered codec
+    assert!(store.has_block(&child)?);
+    Ok(())
+}
+
+#[test]
+fn get_leaves_stream() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default().with_codec(0x300001, CidListCodec))?;
+    let leaf = |name: &str| Cid::new_v1(0x55, Code::Sha2_256.digest(name.as_bytes()));
+    let node = |links: &[Cid]| {
+        let data = links
+            .iter()
+            .flat_map(|cid| cid.to_bytes())
+            .collect::<Vec<_>>();
+        (Cid::new_v1(0x300001, Code::Sha2_256.digest(&data)), data)
+    };
+    let (l1, l2, l3, missing) = (leaf("l1"), leaf("l2"), leaf("l3"), leaf("missing"));
+    // the links are deliberately not in the order of the cids
+    let (n1, n1_data) = node(&[l2, l1, l2]);
+    let (root, root_data) = node(&[n1, missing, l3]);
+    store.put_block(&root, &root_data, vec![n1, missing, l3], None)?;
+    store.put_block(&n1, &n1_data, vec![l1, l2], None)?;
+    for cid in [l1, l2, l3].iter() {
+        store.put_block(cid, &cid.to_bytes(), vec![], None)?;
+    }
+    let leaves = store
+        .get_leaves_stream(&root)
+        .map(|leaf| {
+            let (cid, data) = leaf?;
+            assert_eq!(data, cid.to_bytes());
+            Ok(cid)
+        })
+        .collect::<crate::Result<Vec<_>>>()?;
+    assert_eq!(leaves, vec![l2, l1, l2, l3]);
+    // no read transaction is kept open while the caller holds a leaf
+    let mut leaves = store.get_leaves_stream(&root);
+    assert_eq!(leaves.next().transpose()?.map(|(cid, _)| cid), Some(l2));
+    assert!(store.conn.is_autocommit());
+    drop(leaves);
+    // more leaves than are read ahead at once
+    let many = (0..600).map(|i| leaf(&i.to_string())).collect::<Vec<_>>();
+    let (big, big_data) = node(&many);
+    store.put_block(&big, &big_data, many.clone(), None)?;
+    for cid in &many {
+        store.put_block(cid, &cid.to_bytes(), vec![], None)?;
+    }
+    let leaves = store
+        .get_leaves_stream(&big)
+        .map(|leaf| Ok(leaf?.0))
+        .collect::<crate::Result<Vec<_>>>()?;
+    assert_eq!(leaves, many);
+    Ok(())
+}
+
+#[test]
+fn validate_links() -> anyhow::Result<()> {
+    let config = Config::default()
+        .with_codec(0x300001, CidListCodec)
+        .with_validate_links(true);
+    let mut store = BlockStore::memory(config)?;
+    let child = Cid::new_v1(0x55, Code::Sha2_256.digest(b"child"));
+    let root_data = child.to_bytes();
+    let root = Cid::new_v1(0x300001, Code::Sha2_256.digest(&root_data));
+    assert!(matches!(
+        store.put_block(&root, &root_data, vec![], None),
+        Err(BlockStoreError::LinkMismatch(cid)) if cid == root
+    ));
+    assert!(!store.has_cid(&root)?);
+    store.put_block(&root, &root_data, vec![child], None)?;
+    assert!(store.has_block(&root)?);
+    Ok(())
+}
+
+#[test]
+fn iter_blocks() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let cids = (0..2500).map(pinned).collect::<Vec<_>>();
+    store.put_blocks(
+        cids.iter()
+            .map(|cid| OwnedBlock::new(*cid, data(cid, 10), vec![])),
+        None,
+    )?;
+    let mut count = 0;
+    for res in store.iter_blocks() {
+        let (cid, block) = res?;
+        assert_eq!(block, data(&cid, 10));
+        count += 1;
+    }
+    assert_eq!(count, cids.len());
+    Ok(())
+}
+
+#[test]
+fn is_complete() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let a = cid("a");
+    let b = cid("b");
+    let c = cid("c");
+    assert!(!store.is_complete(&a)?);
+    store.put_block(&a, b"a", vec![b, c], None)?;
+    assert!(!store.is_complete(&a)?);
+    store.put_block(&b, b"b", vec![], None)?;
+    assert!(!store.is_complete(&a)?);
+    store.put_block(&c, b"c", vec![], None)?;
+    assert!(store.is_complete(&a)?);
+    assert!(store.is_complete(&b)?);
+    Ok(())
+}
+
+#[test]
+fn is_pinned() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let a = cid("a");
+    let b = cid("b");
+    let c = cid("c");
+    let d = cid("d");
+    assert!(!store.is_pinned(&a)?);
+    store.put_block(&a, b"a", vec![b], None)?;
+    store.put_block(&b, b"b", vec![c], None)?;
+    assert!(!store.is_pinned(&a)?);
+    // a direct alias only protects the root itself
+    store.alias_direct(b"direct", Some(&a))?;
+    assert!(store.is_pinned(&a)?);
+    assert!(!store.is_pinned(&b)?);
+    // a recursive alias protects all descendants, even missing ones
+    store.alias(b"recursive", Some(&b))?;
+    assert!(store.is_pinned(&b)?);
+    assert!(store.is_pinned(&c)?);
+    store.alias(b"recursive", None)?;
+    assert!(!store.is_pinned(&c)?);
+    // temp pins protect as well
+    let pin = store.temp_pin();
+    store.put_block(&d, b"d", vec![c], Some(&pin))?;
+    assert!(store.is_pinned(&d)?);
+    assert!(store.is_pinned(&c)?);
+    Ok(())
+}
+
+#[test]
+fn is_pinned_refcount_gc() -> anyhow::Result<()> {
+    // the refcounts table must give the same answers as walking the graph
+    let mut store = BlockStore::memory(Config::default().with_refcount_gc(true))?;
+    let a = cid("a");
+    let b = cid("b");
+    let c = cid("c");
+    store.put_block(&a, b"a", vec![b], None)?;
+    store.put_block(&b, b"b", vec![c], None)?;
+    store.alias_direct(b"direct", Some(&a))?;
+    assert!(store.is_pinned(&a)?);
+    assert!(!store.is_pinned(&b)?);
+    store.alias(b"recursive", Some(&a))?;
+    assert!(store.is_pinned(&b)?);
+    assert!(store.is_pinned(&c)?);
+    store.alias(b"recursive", None)?;
+    assert!(!store.is_pinned(&c)?);
+    let pin = store.temp_pin();
+    store.extend_temp_pin(&pin, vec![b])?;
+    assert!(store.is_pinned(&c)?);
+    Ok(())
+}
+
+#[test]
+fn backup_to_writer() -> anyhow::Result<()> {
+    let tmp = TempDir::new("backup_to_writer")?;
+    let mut store = BlockStore::memory(Config::default())?;
+    let a = cid("a");
+    store.put_block(&a, b"abcd", vec![], None)?;
+    store.alias(b"a", Some(&a))?;
+    let mut backup = Vec::new();
+    let written = store.backup_to_writer(&mut backup)?;
+    assert_eq!(written, backup.len() as u64);
+    let path = tmp.path().join("restored.sqlite");
+    std::fs::write(&path, &backup)?;
+    let mut restored = BlockStore::open(&path, Config::default())?;
+    assert_eq!(restored.get_block(&a)?, Some(b"abcd".to_vec()));
+    assert_eq!(restored.resolve(b"a")?, Some(a));
+    // the backup of a persistent store is staged next to it, and the staging file is removed
+    let written = restored.backup_to_writer(std::io::sink())?;
+    assert_eq!(written, backup.len() as u64);
+    assert!(std::fs::read_dir(tmp.path())?.all(|entry| !entry
+        .unwrap()
+        .file_name()
+        .to_string_lossy()
+        .starts_with("ipfs-sqlite-block-store-")));
+    Ok(())
+}
+
+#[test]
+fn staging_file() -> anyhow::Result<()> {
+    let tmp = TempDir::new("staging_file")?;
+    let (a, _) = crate::StagingFile::create(tmp.path())?;
+    let (b, _) = crate::StagingFile::create(tmp.path())?;
+    assert_ne!(a.0, b.0);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&a.0)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    let path = a.0.clone();
+    drop(a);
+    assert!(!path.exists());
+    Ok(())
+}
+
+#[test]
+fn diff_snapshot() -> anyhow::Result<()> {
+    let tmp = TempDir::new("diff_snapshot")?;
+    let mut store = BlockStore::memory(Config::default())?;
+    let a = cid("a");
+    let b = cid("b");
+    let c = cid("c");
+    store.put_block(&a, b"a", vec![], None)?;
+    store.put_block(&b, b"b", vec![], None)?;
+    store.alias(b"changed", Some(&a))?;
+    store.alias(b"removed", Some(&b))?;
+    store.alias(b"unchanged", Some(&a))?;
+    let path = tmp.path().join("snapshot?#%.sqlite");
+    store.backup_to_writer(std::fs::File::create(&path)?)?;
+    store.put_block(&c, b"c", vec![], None)?;
+    store.alias(b"changed", Some(&c))?;
+    store.alias(b"removed", None)?;
+    store.alias(b"created", Some(&a))?;
+    store.gc()?;
+    assert_eq!(
+        store.diff_snapshot(&path)?,
+        SnapshotDiff {
+            added: vec![c],
+            removed: vec![b],
+            aliases: vec![
+                (b"changed".to_vec(), Some(a), Some(c)),
+                (b"created".to_vec(), None, Some(a)),
+                (b"removed".to_vec(), Some(b), None),
+            ],
+        }
+    );
+    // the snapshot is detached again
+    assert_eq!(store.diff_snapshot(&path)?.removed, vec![b]);
+    Ok(())
+}
+
+#[test]
+fn serialize() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let a = cid("a");
+    store.put_block(&a, b"abcd", vec![], None)?;
+    store.alias(b"a", Some(&a))?;
+    let bytes = store.serialize()?;
+    let mut copy = BlockStore::open_from_bytes(&bytes, Config::default())?;
+    assert_eq!(copy.get_block(&a)?, Some(b"abcd".to_vec()));
+    assert_eq!(copy.get_store_stats()?, store.get_store_stats()?);
+    // the copy is writeable and independent of the original
+    let b = cid("b");
+    copy.put_block(&b, b"b", vec![], None)?;
+    assert!(!store.has_block(&b)?);
+    // also works for a persistent store in wal mode
+    let tmp = TempDir::new("serialize")?;
+    let mut store = BlockStore::open(tmp.path().join("db"), Config::default())?;
+    store.put_block(&a, b"abcd", vec![], None)?;
+    let mut copy = BlockStore::open_from_bytes(&store.serialize()?, Config::default())?;
+    assert_eq!(copy.get_block(&a)?, Some(b"abcd".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn get_parents() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let a = cid("a");
+    let b = cid("b");
+    let c = cid("c");
+    store.put_block(&a, b"a", vec![c], None)?;
+    store.put_block(&b, b"b", vec![c], None)?;
+    let mut parents = store.get_parents(&c)?;
+    parents.sort();
+    let mut expected = vec![a, b];
+    expected.sort();
+    assert_eq!(parents, expected);
+    assert!(store.get_parents(&a)?.is_empty());
+    assert!(store.get_parents(&cid("unknown"))?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn get_ancestors() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let a = cid("a");
+    let b = cid("b");
+    let c = cid("c");
+    let d = cid("d");
+    store.put_block(&a, b"a", vec![b, c], None)?;
+    store.put_block(&b, b"b", vec![d], None)?;
+    store.put_block(&c, b"c", vec![d], None)?;
+    let ancestors = |cid| -> anyhow::Result<FnvHashSet<Cid>> {
+        Ok(store.get_ancestors(cid)?.into_iter().collect())
+    };
+    assert_eq!(ancestors(&d)?, vec![a, b, c].into_iter().collect());
+    assert_eq!(ancestors(&b)?, vec![a].into_iter().collect());
+    assert!(ancestors(&a)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn dag_size() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let a = cid("a");
+    let b = cid("b");
+    let c = cid("c");
+    let d = cid("d");
+    store.put_block(&a, b"a", vec![b, c], None)?;
+    store.put_block(&b, b"bb", vec![d], None)?;
+    store.put_block(&c, b"ccc", vec![d], None)?;
+    store.put_block(&d, b"dddd", vec![], None)?;
+    // d is only counted once
+    let size = store.dag_size(&a)?;
+    assert_eq!((size.count(), size.size()), (4, 10));
+    let size = store.dag_size(&b)?;
+    assert_eq!((size.count(), size.size()), (2, 6));
+    assert_eq!(store.dag_size(&cid("unknown"))?, StoreStats::default());
+    Ok(())
+}
+
+#[test]
+fn dag_diff() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let old = cid("old");
+    let new = cid("new");
+    let shared = cid("shared");
+    let a = cid("a");
+    let b = cid("b");
+    store.put_block(&shared, b"shared", vec![], None)?;
+    store.put_block(&a, b"a", vec![], None)?;
+    store.put_block(&b, b"b", vec![], None)?;
+    store.put_block(&old, b"old", vec![shared, a], None)?;
+    store.put_block(&new, b"new", vec![shared, b], None)?;
+    let diff = store.dag_diff(&old, &new)?;
+    let set = |cids: Vec<Cid>| cids.into_iter().collect::<FnvHashSet<_>>();
+    assert_eq!(set(diff.added), set(vec![new, b]));
+    assert_eq!(set(diff.removed), set(vec![old, a]));
+    assert_eq!(store.dag_diff(&old, &old)?, DagDiff::default());
+    Ok(())
+}
+
+#[test]
+fn traverse() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let a = cid("a");
+    let b = cid("b");
+    let c = cid("c");
+    let d = cid("d");
+    store.put_block(&a, b"a", vec![b, c], None)?;
+    store.put_block(&b, b"b", vec![d], None)?;
+    store.put_block(&c, b"c", vec![d], None)?;
+    let visit = |store: &BlockStore, f: &dyn Fn(&Cid) -> Visit| {
+        let mut visited = Vec::new();
+        store.traverse(&a, |cid, data| {
+            visited.push((*cid, data.map(|data| data.to_vec())));
+            f(cid)
+        })?;
+        anyhow::Result::<_>::Ok(visited)
+    };
+    // d is missing, and only visited once
+    let visited = visit(&store, &|_| Visit::Continue)?;
+    assert_eq!(visited.len(), 4);
+    assert_eq!(visited[0], (a, Some(b"a".to_vec())));
+    assert!(visited.contains(&(d, None)));
+    // skip the children of b and c, so d is not reached
+    let visited = visit(&store, &|cid| {
+        if *cid == b || *cid == c {
+            Visit::SkipChildren
+        } else {
+            Visit::Continue
+        }
+    })?;
+    assert_eq!(visited.len(), 3);
+    assert!(visited.iter().all(|(cid, _)| *cid != d));
+    // stop at the first block
+    let visited = visit(&store, &|_| Visit::Stop)?;
+    assert_eq!(visited, vec![(a, Some(b"a".to_vec()))]);
+    Ok(())
+}
+
+#[test]
+fn get_descendants_limited() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let a = cid("a");
+    let b = cid("b");
+    let c = cid("c");
+    let d = cid("d");
+    store.put_block(&a, b"a", vec![b, c], None)?;
+    store.put_block(&b, b"b", vec![d], None)?;
+    let descendants = |depth| store.get_descendants_limited::<FnvHashSet<_>>(&a, depth);
+    assert_eq!(descendants(0)?, vec![a].into_iter().collect());
+    assert_eq!(descendants(1)?, vec![a, b, c].into_iter().collect());
+    assert_eq!(descendants(2)?, vec![a, b, c, d].into_iter().collect());
+    assert_eq!(descendants(100)?, descendants(2)?);
+    Ok(())
+}
+
+#[test]
+fn get_missing_blocks_limited() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let root = cid("root");
+    let child = cid("child");
+    let leaves = (0..10).map(unpinned).collect::<Vec<_>>();
+    let mut links = leaves[0..5].to_vec();
+    links.push(child);
+    let missing = |store: &BlockStore, limit, depth| {
+        store.get_missing_blocks_limited::<FnvHashSet<_>>(&root, limit, depth)
+    };
+    assert_eq!(missing(&store, 10, None)?, vec![root].into_iter().collect());
+    store.put_block(&root, b"root", links, None)?;
+    store.put_block(&child, b"child", leaves[5..].to_vec(), None)?;
+    assert_eq!(missing(&store, 100, None)?.len(), 10);
+    assert_eq!(missing(&store, 3, None)?.len(), 3);
+    assert_eq!(
+        missing(&store, 100, Some(1))?,
+        leaves[0..5].iter().cloned().collect()
+    );
+    assert!(missing(&store, 100, Some(0))?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn missing_blocks_iter() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let root = cid("root");
+    let leaves = (0..2000).map(unpinned).collect::<Vec<_>>();
+    let missing = store
+        .missing_blocks_iter(&root)
+        .collect::<crate::Result<Vec<_>>>()?;
+    assert_eq!(missing, vec![root]);
+    // more blocks than are walked in one step, with some of the leaves linked twice
+    let (a, b) = (cid("a"), cid("b"));
+    store.put_block(&a, b"a", leaves[..1500].to_vec(), None)?;
+    store.put_block(&b, b"b", leaves[1000..].to_vec(), None)?;
+    store.put_block(&root, b"root", vec![a, b], None)?;
+    store.put_block(&leaves[0], b"0", vec![], None)?;
+    let missing = store
+        .missing_blocks_iter(&root)
+        .collect::<crate::Result<Vec<_>>>()?;
+    assert_eq!(missing.len(), leaves.len() - 1);
+    assert_eq!(
+        missing.into_iter().collect::<FnvHashSet<_>>(),
+        leaves[1..].iter().cloned().collect()
+    );
+    // the first cids are returned before the traversal is finished, without an open transaction
+    let mut missing = store.missing_blocks_iter(&root);
+    assert!(missing.next().is_some());
+    assert!(store.conn.is_autocommit());
+    Ok(())
+}
+
+#[test]
+fn capabilities() -> anyhow::Result<()> {
+    let store = BlockStore::memory(Config::default().with_checksums(true))?;
+    let capabilities = store.capabilities()?;
+    assert_eq!(capabilities.schema_version, 1);
+    assert!(capabilities.checksums);
+    assert!(!capabilities.normalize_cids);
+    assert!(!capabilities.compression);
+    Ok(())
+}
+
+#[test]
+fn empty_block() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default().with_checksums(true))?;
+    let empty = Cid::new_v1(0x55, Code::Sha2_256.digest(b""));
+    store.put_block(&empty, b"", vec![], None)?;
+    assert!(store.has_block(&empty)?);
+    assert_eq!(store.get_block(&empty)?, Some(vec![]));
+    assert_eq!(store.get_store_stats()?.count(), 1);
+    assert_eq!(store.get_store_stats()?.size(), 0);
+    assert_eq!(
+        store.iter_blocks().next().transpose()?,
+        Some((empty, vec![]))
+    );
+    store.gc()?;
+    assert!(!store.has_block(&empty)?);
+    assert_eq!(store.get_store_stats()?.count(), 0);
+
+    let mut store = BlockStore::memory(Config::default().with_reject_empty_blocks(true))?;
+    assert!(matches!(
+        store.put_block(&empty, b"", vec![], None),
+        Err(BlockStoreError::EmptyBlock(cid)) if cid == empty
+    ));
+    assert!(!store.has_cid(&empty)?);
+    Ok(())
+}
+
+#[test]
+fn iter_refs() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let root = cid("root");
+    let children = (0..1500).map(unpinned).collect::<Vec<_>>();
+    store.put_block(&root, b"root", children.clone(), None)?;
+    store.put_block(&children[0], b"child", vec![children[1]], None)?;
+    let refs = store.iter_refs().collect::<Result<FnvHashSet<_>, _>>()?;
+    let mut expected = children
+        .iter()
+        .map(|child| (root, *child))
+        .collect::<FnvHashSet<_>>();
+    expected.insert((children[0], children[1]));
+    assert_eq!(refs, expected);
+    Ok(())
+}
+
+#[cfg(feature = "bench")]
+#[test]
+fn workload() -> anyhow::Result<()> {
+    use crate::workload::{apply, churn, unixfs_file, Rng};
+    let (root, blocks) = unixfs_file(&mut Rng::new(0), 1000, 100, 4);
+    // 10 leaves, 3 intermediate nodes, 1 root
+    assert_eq!(blocks.len(), 14);
+    let mut store = BlockStore::memory(Config::default())?;
+    store.put_blocks(blocks, None)?;
+    store.alias(b"file", Some(&root))?;
+    assert_eq!(store.get_missing_blocks::<Vec<_>>(&root)?, vec![]);
+    apply(&mut store, churn(&mut Rng::new(0), 10, 10, 2, 5, 5))?;
+    // no seed leaves the generator stuck at 0
+    assert_ne!(Rng::new(0x9e37_79b9_7f4a_7c15).next_u64(), 0);
+    assert_ne!(Rng::new(0x61c8_8646_80b5_83eb).next_u64(), 0);
+    Ok(())
+}
+
+#[test]
+fn extend_temp_pin() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let pin = store.temp_pin();
+    // pin a block before it arrives, and a block we already have
+    store.put_block(&unpinned(0), b"abcd", vec![], None)?;
+    store.extend_temp_pin(&pin, vec![unpinned(0), unpinned(1)])?;
+    store.put_block(&unpinned(1), b"abcd", vec![], None)?;
+    store.put_block(&unpinned(2), b"abcd", vec![], None)?;
+    store.gc()?;
+    assert!(store.has_block(&unpinned(0))?);
+    assert!(store.has_block(&unpinned(1))?);
+    assert!(!store.has_block(&unpinned(2))?);
+    drop(pin);
+    store.gc()?;
+    assert!(!store.has_block(&unpinned(0))?);
+    Ok(())
+}
+
+#[test]
+fn graph_stats() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    // a chain of 3 blocks, where the root also has 4 leaves
+    let leaves = (0..4).map(unpinned).collect::<Vec<_>>();
+    store.put_block(&cid("c"), b"c", vec![], None)?;
+    store.put_block(&cid("b"), b"b", vec![cid("c")], None)?;
+    let mut links = vec![cid("b")];
+    links.extend(leaves);
+    store.put_block(&cid("a"), b"a", links, None)?;
+    store.alias(b"root", Some(&cid("a")))?;
+    assert_eq!(
+        store.graph_stats()?,
+        GraphStats {
+            refs: 6,
+            parents: 2,
+            max_fanout: 5,
+            fanout_histogram: vec![1, 0, 1],
+            depth_estimate: 2,
+        }
+    );
+    Ok(())
+}
+
+#[test]
+fn max_cid_size() -> anyhow::Result<()> {
+    // a cid with a 48 byte hash fits into the store
+    let large = Cid::new_v1(0x71, Multihash::wrap(0x15, &[1; 48])?);
+    // a cid with a 64 byte hash does not
+    let too_large = Cid::new_v1(0x71, Code::Sha2_512.digest(b"abcd"));
+    let mut store = BlockStore::memory(Config::default())?;
+    store.put_block(&large, b"abcd", vec![], None)?;
+    assert_eq!(store.get_block(&large)?, Some(b"abcd".to_vec()));
+    assert!(matches!(
+        store.put_block(&unpinned(0), b"abcd", vec![too_large], None),
+        Err(BlockStoreError::CidTooLarge(cid)) if cid == too_large
+    ));
+    assert!(matches!(
+        store.alias(b"a", Some(&too_large)),
+        Err(BlockStoreError::CidTooLarge(_))
+    ));
+    // the maximum can be lowered
+    let mut store = BlockStore::memory(Config::default().with_max_cid_size(40))?;
+    store.put_block(&unpinned(0), b"abcd", vec![], None)?;
+    assert!(matches!(
+        store.put_block(&large, b"abcd", vec![], None),
+        Err(BlockStoreError::CidTooLarge(_))
+    ));
+    assert_eq!(store.get_known_cids::<Vec<_>>()?.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn gc_grace_period() -> anyhow::Result<()> {
+    let mut store =
+        BlockStore::memory(Config::default().with_gc_grace_period(Duration::from_secs(3600)))?;
+    let a = cid("a");
+    let b = cid("b");
+    store.put_block(&b, b"b", vec![], None)?;
+    // simulate that b was written long ago
+    store
+        .conn
+        .execute("UPDATE blocks SET inserted = 0", params![])?;
+    store.put_block(&a, b"a", vec![b], None)?;
+    store.gc()?;
+    // a is fresh, and protects its child b
+    assert!(store.has_block(&a)?);
+    assert!(store.has_block(&b)?);
+    store
+        .conn
+        .execute("UPDATE blocks SET inserted = 0", params![])?;
+    store.gc()?;
+    assert!(!store.has_block(&a)?);
+    assert!(!store.has_block(&b)?);
+    // without a grace period, fresh blocks are collected
+    let mut store = BlockStore::memory(Config::default())?;
+    store.put_block(&a, b"a", vec![], None)?;
+    store.gc()?;
+    assert!(!store.has_block(&a)?);
+    Ok(())
+}
+
+#[test]
+fn refcount_gc() -> anyhow::Result<()> {
+    fn refcounts(store: &BlockStore) -> anyhow::Result<Vec<(i64, i64)>> {
+        Ok(store
+            .conn
+            .prepare("SELECT block_id, refcount FROM refcounts ORDER BY block_id")?
+            .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?)
+    }
+    let mut store = BlockStore::memory(Config::default().with_refcount_gc(true))?;
+    let a = cid("a");
+    let b = cid("b");
+    let c = cid("c");
+    let d = cid("d");
+    let e = cid("e");
+    let f = cid("f");
+    // a and d share the child b
+    store.put_block(&a, b"a", vec![b], None)?;
+    store.put_block(&b, b"b", vec![c], None)?;
+    store.put_block(&c, b"c", vec![], None)?;
+    store.put_block(&d, b"d", vec![b], None)?;
+    store.alias(b"a", Some(&a))?;
+    store.alias(b"d", Some(&d))?;
+    // e is aliased before we have it, and gets a child later
+    store.alias(b"e", Some(&e))?;
+    store.put_block(&e, b"e", vec![f], None)?;
+    store.put_block(&f, b"f", vec![], None)?;
+    let pin = store.temp_pin();
+    store.put_block(&cid("tmp"), b"tmp", vec![], Some(&pin))?;
+    store.put_block(&cid("unpinned"), b"unpinned", vec![], None)?;
+    let before = refcounts(&store)?;
+    store.repair_refcounts()?;
+    assert_eq!(refcounts(&store)?, before);
+    store.gc()?;
+    for x in [a, b, c, d, e, f, cid("tmp")].iter() {
+        assert!(store.has_block(x)?);
+    }
+    assert!(!store.has_block(&cid("unpinned"))?);
+    // b stays alive via d
+    store.alias(b"a", None)?;
+    drop(pin);
+    store.gc()?;
+    assert!(!store.has_block(&a)?);
+    assert!(!store.has_block(&cid("tmp"))?);
+    assert!(store.has_block(&b)?);
+    assert!(store.has_block(&c)?);
+    store.alias(b"d", None)?;
+    store.gc()?;
+    assert!(!store.has_block(&b)?);
+    assert!(!store.has_block(&c)?);
+    assert!(store.has_block(&f)?);
+    let before = refcounts(&store)?;
+    store.repair_refcounts()?;
+    assert_eq!(refcounts(&store)?, before);
+    Ok(())
+}
+
+#[test]
+fn refcount_gc_reopen() -> anyhow::Result<()> {
+    let tmp = TempDir::new("refcount_gc_reopen")?;
+    let path = tmp.path().join("db.sqlite");
+    let a = cid("a");
+    let b = cid("b");
+    let mut store = BlockStore::open(&path, Config::default().with_refcount_gc(true))?;
+    store.put_block(&a, b"a", vec![], None)?;
+    store.alias(b"a", Some(&a))?;
+    drop(store);
+    // a handle without refcount gc keeps the table, but does not update it
+    let mut store = BlockStore::open(&path, Config::default())?;
+    store.put_block(&b, b"b", vec![], None)?;
+    store.alias(b"b", Some(&b))?;
+    let refcounts: i64 =
+        store
+            .conn
+            .query_row("SELECT count(*) FROM refcounts", params![], |row| {
+                row.get(0)
+            })?;
+    assert_eq!(refcounts, 1);
+    drop(store);
+    // the counts are recomputed on open, so b is not collected
+    let mut store = BlockStore::open(&path, Config::default().with_refcount_gc(true))?;
+    store.gc()?;
+    assert!(store.has_block(&a)?);
+    assert!(store.has_block(&b)?);
+    Ok(())
+}
+
+#[test]
+fn failed_put_leaves_nothing() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    // a cid with a 64 byte hash is too large for the store
+    let too_large = Cid::new_v1(0x71, Code::Sha2_512.digest(b"abcd"));
+    let pin = store.temp_pin();
+    let res = store.put_blocks(
+        vec![
+            OwnedBlock::new(unpinned(0), b"abcd".to_vec(), vec![unpinned(1)]),
+            OwnedBlock::new(unpinned(2), b"abcd".to_vec(), vec![too_large]),
+        ],
+        Some(&pin),
+    );
+    assert!(res.is_err());
+    assert!(store.get_known_cids::<Vec<_>>()?.is_empty());
+    assert_eq!(store.get_store_stats()?.count(), 0);
+    assert_eq!(store.temp_pin_stats()?, TempPinStats::default());
+    // the temp pin did not keep the id of the failed transaction, so it is not shared
+    let pin2 = store.temp_pin();
+    store.put_block(&unpinned(3), b"abcd", vec![], Some(&pin2))?;
+    store.put_block(&unpinned(4), b"abcd", vec![], Some(&pin))?;
+    drop(pin);
+    store.gc()?;
+    assert!(store.has_block(&unpinned(3))?);
+    assert!(!store.has_block(&unpinned(4))?);
+    Ok(())
+}
+
+#[test]
+fn assign_temp_pin_to_alias() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let pin = store.temp_pin();
+    store.put_block(&cid("b"), b"b", vec![], Some(&pin))?;
+    store.put_block(&cid("a"), b"a", vec![cid("b")], Some(&pin))?;
+    store.assign_temp_pin_to_alias(&pin, b"root", &cid("a"))?;
+    assert_eq!(store.temp_pin_stats()?, TempPinStats::default());
+    drop(pin);
+    store.gc()?;
+    assert!(store.has_block(&cid("a"))?);
+    assert!(store.has_block(&cid("b"))?);
+    assert_eq!(
+        store.reverse_alias(&cid("a"))?.aliases,
+        vec![b"root".to_vec()]
+    );
+    Ok(())
+}
+
+#[test]
+fn write_batch() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default().with_reject_empty_blocks(true))?;
+    let a = cid("a");
+    let b = cid("b");
+    let c = cid("c");
+    let pin = store.temp_pin();
+    store.put_block(&c, b"c", vec![], Some(&pin))?;
+    // a failing operation rolls back the entire batch
+    let mut batch = Batch::new();
+    batch.put_block(&b, b"b", vec![]);
+    batch.alias(b"root", Some(&b));
+    batch.put_block(&a, b"", vec![b]);
+    assert!(matches!(
+        store.write_batch(batch, Some(&pin)),
+        Err(BlockStoreError::EmptyBlock(cid)) if cid == a
+    ));
+    assert!(!store.has_block(&b)?);
+    assert!(store.aliases()?.is_empty());
+    assert_eq!(store.temp_pin_stats()?.blocks, 1);
+    // temp pin operations need a temp pin
+    let mut batch = Batch::new();
+    batch.put_block(&b, b"b", vec![]);
+    batch.extend_temp_pin(vec![b]);
+    assert!(matches!(
+        store.write_batch(batch, None),
+        Err(BlockStoreError::MissingTempPin)
+    ));
+    let mut batch = Batch::new();
+    batch.release_temp_pin();
+    assert!(matches!(
+        store.write_batch(batch, None),
+        Err(BlockStoreError::MissingTempPin)
+    ));
+    assert!(!store.has_block(&b)?);
+    // blocks, aliases and the release of the temp pin are committed together
+    let mut batch = Batch::new();
+    batch.put_block(&b, b"b", vec![]);
+    batch.put_block(&a, b"a", vec![b]);
+    batch.alias(b"root", Some(&a));
+    batch.release_temp_pin();
+    store.write_batch(batch, Some(&pin))?;
+    assert_eq!(store.temp_pin_stats()?, TempPinStats::default());
+    assert_eq!(store.aliases()?, vec![(b"root".to_vec(), a)]);
+    drop(pin);
+    store.gc()?;
+    assert!(store.has_block(&a)?);
+    assert!(store.has_block(&b)?);
+    assert!(!store.has_block(&c)?);
+    Ok(())
+}
+
+#[test]
+fn checksums() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default().with_checksums(true))?;
+    let a = cid("a");
+    store.put_block(&a, b"abcd", vec![], None)?;
+    assert_eq!(store.get_block(&a)?, Some(b"abcd".to_vec()));
+    // writing the cid again with other data keeps the stored data and its checksum
+    store.put_block(&a, b"efgh", vec![], None)?;
+    assert_eq!(store.get_block(&a)?, Some(b"abcd".to_vec()));
+    // simulate corruption of the database
+    store
+        .conn
+        .execute("UPDATE blocks SET block = ?", params![b"abce".to_vec()])?;
+    assert!(matches!(
+        store.get_block(&a),
+        Err(BlockStoreError::ChecksumMismatch(cid)) if cid == a
+    ));
+    // a checksum that can not be read is reported in bulk reads, instead of skipping the check
+    store.put_block(&cid("b"), b"b", vec![], None)?;
+    store
+        .conn
+        .execute("UPDATE checksums SET checksum = 'bad'", params![])?;
+    let blocks = store.iter_blocks().collect::<Vec<_>>();
+    assert_eq!(blocks.len(), 2);
+    assert!(blocks.iter().all(|block| block.is_err()));
+    Ok(())
+}
+
+#[test]
+fn alias_direct() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default().with_checksums(true))?;
+    let a = cid("a");
+    let b = cid("b");
+    let c = cid("c");
+    store.put_block(&c, b"c", vec![], None)?;
+    store.put_block(&b, b"b", vec![c], None)?;
+    store.put_block(&a, b"a", vec![b], None)?;
+    store.alias_direct(b"manifest", Some(&a))?;
+    assert_eq!(store.why_pinned(&a)?.len(), 1);
+    assert!(store.why_pinned(&b)?.is_empty());
+    store.gc()?;
+    assert!(store.has_block(&a)?);
+    assert!(!store.has_block(&b)?);
+    assert!(!store.has_block(&c)?);
+    // the cid of b is kept for the link from a, but neither its checksum nor as a gc candidate
+    let checksums: i64 =
+        store
+            .conn
+            .query_row("SELECT COUNT(*) FROM checksums", params![], |row| {
+                row.get(0)
+            })?;
+    assert_eq!(checksums, 1);
+    let candidates = store
+        .conn
+        .prepare(GC_CANDIDATES)?
+        .query_map(params![0], |row| row.get::<_, i64>(0))?
+        .count();
+    assert_eq!(candidates, 0);
+    // only the root itself counts for a direct alias
+    assert!(store.incomplete_pins()?.is_empty());
+    // a recursive alias of the same name replaces the direct alias
+    store.alias(b"manifest", Some(&a))?;
+    assert_eq!(store.incomplete_pins()?, vec![(b"manifest".to_vec(), a, 1)]);
+    store.put_block(&c, b"c", vec![], None)?;
+    store.put_block(&b, b"b", vec![c], None)?;
+    store.gc()?;
+    assert!(store.has_block(&c)?);
+    Ok(())
+}
+
+#[test]
+fn aliases() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let a = cid("a");
+    let b = cid("b");
+    assert!(store.aliases()?.is_empty());
+    store.alias(b"a", Some(&a))?;
+    store.alias_direct(b"b", Some(&b))?;
+    let mut aliases = store.aliases()?;
+    aliases.sort();
+    assert_eq!(aliases, vec![(b"a".to_vec(), a), (b"b".to_vec(), b)]);
+    assert_eq!(store.resolve(b"a")?, Some(a));
+    assert_eq!(store.resolve(b"c")?, None);
+    store.alias(b"a", None)?;
+    assert_eq!(store.resolve(b"a")?, None);
+    Ok(())
+}
+
+#[test]
+fn aliases_in_range() -> anyhow::Result<()> {
+    let mut store = BlockStore::memory(Config::default())?;
+    let a = cid("a");
+    let names: Vec<Vec<u8>> = vec![
+        b"user/1".to_vec(),
+        b"user/2".to_vec(),
+        b"user/3".to_vec(),
+        b"users".to_vec(),
+        vec![0xff],
+        vec![0xff, 0xff, 1],
+    ];
+    for name in &names {
+        store.alias(name, Some(&a))?;
+    }
+    let names_of = |aliases: Vec<(Vec<u8>, Cid)>| {
+        aliases
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(names_of(store.aliases_in_range(..)?), names);
+    assert_eq!(
+        names_of(store.aliases_in_range(b"user/2".to_vec()..b"users".to_vec())?),
+        names[1..3].to_vec()
+    );
+    assert_eq!(
+        names_of(store.aliases_in_range(b"user/2".to_vec()..=b"users".to_vec())?),
+        names[1..4].to_vec()
+    );
+    assert_eq!(
+        names_of(store.aliases_with_prefix(b"user/")?),
+        names[0..3].to_vec()
+    );
+    assert_eq!(
+        names_of(store.aliases_with_prefix([0xff])?),
+        names[4..].to_vec()
+    );
+    assert_eq!(names_of(store.aliases_with_prefix([])?), names);
+    Ok(())
+}
@@ -0,0 +1,193 @@
+//! Reusable workload generators for benchmarks
+//!
+//! All generators are deterministic for a given seed, so results are comparable across runs,
+//! machines and configs. This module is only available with the `bench` feature.
+use crate::{Block, BlockStore, OwnedBlock, Result};
+use libipld::{
+    cid::Cid,
+    multihash::{Code, MultihashDigest},
+};
+
+/// A small deterministic pseudo random number generator (xorshift64*)
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // mix the seed with splitmix64, so similar seeds give unrelated sequences
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        // xorshift gets stuck at 0, and splitmix64 maps exactly one seed there
+        Self(if z == 0 { 0x9e37_79b9_7f4a_7c15 } else { z })
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// a number in `0..n`
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % (n.max(1) as u64)) as usize
+    }
+
+    /// `n` pseudo random bytes
+    pub fn bytes(&mut self, n: usize) -> Vec<u8> {
+        let mut res = Vec::with_capacity(n + 8);
+        while res.len() < n {
+            res.extend_from_slice(&self.next_u64().to_le_bytes());
+        }
+        res.truncate(n);
+        res
+    }
+}
+
+fn block(codec: u64, data: Vec<u8>, links: Vec<Cid>) -> OwnedBlock {
+    let cid = Cid::new_v1(codec, Code::Sha2_256.digest(&data));
+    OwnedBlock::new(cid, data, links)
+}
+
+/// A random dag of `n` blocks, where each block links to up to `max_links` earlier blocks.
+///
+/// Blocks are returned in insertion order, so every block comes after its children. The last
+/// block links to all blocks that are not linked otherwise, so it is the single root.
+pub fn random_dag(
+    rng: &mut Rng,
+    n: usize,
+    max_links: usize,
+    block_size: usize,
+) -> (Cid, Vec<OwnedBlock>) {
+    let mut blocks: Vec<OwnedBlock> = Vec::with_capacity(n + 1);
+    let mut linked = vec![false; n];
+    for i in 0..n {
+        let mut links = Vec::new();
+        if i > 0 {
+            for _ in 0..rng.below(max_links + 1) {
+                let j = rng.below(i);
+                linked[j] = true;
+                links.push(*blocks[j].cid());
+            }
+        }
+        links.sort();
+        links.dedup();
+        blocks.push(block(0x71, rng.bytes(block_size), links));
+    }
+    let unlinked = (0..n)
+        .filter(|i| !linked[*i])
+        .map(|i| *blocks[i].cid())
+        .collect();
+    let root = block(0x71, rng.bytes(block_size), unlinked);
+    let cid = *root.cid();
+    blocks.push(root);
+    (cid, blocks)
+}
+
+/// A tree that looks like a file in UnixFS with the balanced layout.
+///
+/// The file of `file_size` bytes is split into raw leaves of `chunk_size` bytes, which are
+/// combined by intermediate nodes with up to `fanout` links, until there is a single root.
+/// go-ipfs uses a chunk size of 262144 and a fanout of 174.
+pub fn unixfs_file(
+    rng: &mut Rng,
+    file_size: usize,
+    chunk_size: usize,
+    fanout: usize,
+) -> (Cid, Vec<OwnedBlock>) {
+    assert!(chunk_size > 0 && fanout > 1);
+    let mut blocks = Vec::new();
+    let mut layer = Vec::new();
+    let mut remaining = file_size;
+    loop {
+        let size = remaining.min(chunk_size);
+        let leaf = block(0x55, rng.bytes(size), Vec::new());
+        layer.push(*leaf.cid());
+        blocks.push(leaf);
+        remaining -= size;
+        if remaining == 0 {
+            break;
+        }
+    }
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(fanout)
+            .map(|links| {
+                // roughly the size of a dag-pb node with the given links
+                let node = block(0x70, rng.bytes(links.len() * 48), links.to_vec());
+                let cid = *node.cid();
+                blocks.push(node);
+                cid
+            })
+            .collect();
+    }
+    (layer[0], blocks)
+}
+
+/// A single step of a churn workload
+#[derive(Debug)]
+pub enum Op {
+    /// add blocks
+    Put(Vec<OwnedBlock>),
+    /// set or remove an alias
+    Alias(Vec<u8>, Option<Cid>),
+    /// read a block
+    Get(Cid),
+    /// run a full gc
+    Gc,
+}
+
+/// A churn workload that continuously adds new dags and unpins old ones.
+///
+/// Each round adds a random dag of `dag_size` blocks and pins it. Only the last `retained` dags
+/// stay pinned, so older ones become garbage. Each round also reads `reads` random blocks of the
+/// retained dags, and every `gc_interval` rounds a gc is done.
+///
+/// Panics if `retained` is 0, since reads need at least one retained dag.
+pub fn churn(
+    rng: &mut Rng,
+    rounds: usize,
+    dag_size: usize,
+    retained: usize,
+    reads: usize,
+    gc_interval: usize,
+) -> Vec<Op> {
+    assert!(retained > 0, "churn needs at least one retained dag");
+    let mut ops = Vec::new();
+    let mut live: Vec<Vec<Cid>> = Vec::new();
+    for round in 0..rounds {
+        let (root, blocks) = random_dag(rng, dag_size, 3, 1024);
+        live.push(blocks.iter().map(|block| *block.cid()).collect());
+        ops.push(Op::Put(blocks));
+        let name = format!("churn-{}", round % retained).into_bytes();
+        ops.push(Op::Alias(name, Some(root)));
+        if live.len() > retained {
+            live.remove(0);
+        }
+        for _ in 0..reads {
+            let dag = &live[rng.below(live.len())];
+            ops.push(Op::Get(dag[rng.below(dag.len())]));
+        }
+        if gc_interval > 0 && (round + 1) % gc_interval == 0 {
+            ops.push(Op::Gc);
+        }
+    }
+    ops
+}
+
+/// Apply a workload to a store
+pub fn apply(store: &mut BlockStore, ops: impl IntoIterator<Item = Op>) -> Result<()> {
+    for op in ops {
+        match op {
+            Op::Put(blocks) => store.put_blocks(blocks, None)?,
+            Op::Alias(name, link) => store.alias(name, link.as_ref())?,
+            Op::Get(cid) => {
+                store.get_block(&cid)?;
+            }
+            Op::Gc => store.gc()?,
+        }
+    }
+    Ok(())
+}